@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Any error is fine, the only failure is a panic (or a crash).
+    let _ = thurgood::rc::try_parse(data);
+});
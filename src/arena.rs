@@ -0,0 +1,455 @@
+//! Parse Marshal data into a bump arena (requires the "arena" feature).
+//!
+//! This is a separate, read-only API for batch jobs that parse many documents and throw each
+//! tree away soon after. Every node is allocated from a `bumpalo::Bump` and freed all at once
+//! when the arena is reset or dropped, instead of one `Rc`/`Arc` at a time. Shared references
+//! within a document are arena-relative indices (`ObjectId`) instead of reference counts, which
+//! also means recursive values need no special handling.
+//!
+//! Nodes are kept close to the Marshal format, so unlike `RbAny` strings aren't decoded and
+//! their encoding stays in `ArenaNode::ivars`. The encoding of a symbol (`I:` followed by `E`)
+//! is read but not kept.
+//!
+//! ```rust
+//! use bumpalo::Bump;
+//! use thurgood::arena::{from_bytes_in, ArenaKind, ArenaValue};
+//!
+//! let bump = Bump::new();
+//! let doc = from_bytes_in(b"\x04\x08[\x07I\"\x08abc\x06:\x06ET@\x06", &bump).unwrap();
+//! let items = match doc.node(doc.root()).map(|n| n.kind) {
+//!     Some(ArenaKind::Array(items)) => items,
+//!     _ => panic!("expected an array"),
+//! };
+//! // Both elements are the same string
+//! assert_eq!(items[0], items[1]);
+//! assert!(matches!(doc.node(items[0]).unwrap().kind, ArenaKind::Str(b"abc")));
+//! ```
+
+use bumpalo::Bump;
+use std::convert::TryFrom;
+use crate::consts::*;
+use crate::error::{ThurgoodError, TResult};
+use crate::source::SliceSource;
+use crate::RbType;
+
+/// The index of an object within an `ArenaDoc`, the same number Ruby uses for `@N` references.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ObjectId(pub usize);
+
+/// A value in an `ArenaDoc`. Anything which Ruby gives an object index is an `Object`,
+/// look it up with `ArenaDoc::get`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArenaValue<'a> {
+    Nil,
+    True,
+    False,
+    Int(i32),
+    Symbol(&'a [u8]),
+    Object(ObjectId),
+}
+
+impl<'a> ArenaValue<'a> {
+    /// Returns the object index, if this is an object.
+    pub fn object_id(&self) -> Option<ObjectId> {
+        match self {
+            ArenaValue::Object(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    fn get_type(&self) -> RbType {
+        match self {
+            ArenaValue::Nil => RbType::Nil,
+            ArenaValue::True | ArenaValue::False => RbType::Bool,
+            ArenaValue::Int(_) => RbType::Int,
+            ArenaValue::Symbol(_) => RbType::Symbol,
+            ArenaValue::Object(_) => RbType::ObjectRef,
+        }
+    }
+}
+
+/// Field names and values of an object or struct, or the instance variables of a node.
+pub type ArenaFields<'a> = &'a [(&'a [u8], ArenaValue<'a>)];
+
+/// An object read from an `ArenaDoc`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ArenaNode<'a> {
+    pub kind: ArenaKind<'a>,
+    /// Instance variables written after the object (`I`), e.g. `E` for the encoding of a string.
+    pub ivars: ArenaFields<'a>,
+    /// The subclass name for a subclass of String, Regexp, Array, or Hash (`C`). A hash
+    /// with `compare_by_identity` set is written as a subclass named `Hash`.
+    pub user_class: Option<&'a [u8]>,
+    /// Modules the object was extended with (`e`), outermost first.
+    pub extended: &'a [&'a [u8]],
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArenaKind<'a> {
+    Float(f64),
+    /// `magnitude` is little-endian.
+    BigInt { negative: bool, magnitude: &'a [u8] },
+    Str(&'a [u8]),
+    /// `flags` is a combination of `consts::RE_*`.
+    Regex { source: &'a [u8], flags: u8 },
+    Array(&'a [ArenaValue<'a>]),
+    Hash { entries: &'a [(ArenaValue<'a>, ArenaValue<'a>)], default: Option<ArenaValue<'a>> },
+    Object { class: &'a [u8], fields: ArenaFields<'a> },
+    Struct { class: &'a [u8], fields: ArenaFields<'a> },
+    ClassRef(&'a [u8]),
+    ModuleRef(&'a [u8]),
+    ClassModuleRef(&'a [u8]),
+    /// An object written with `_dump`.
+    UserData { class: &'a [u8], data: &'a [u8] },
+    /// An object written with `marshal_dump`.
+    UserMarshal { class: &'a [u8], data: ArenaValue<'a> },
+    Data { class: &'a [u8], data: ArenaValue<'a> },
+}
+
+/// A document read into an arena. Everything it refers to lives as long as the arena.
+#[derive(Clone, Copy, Debug)]
+pub struct ArenaDoc<'a> {
+    root: ArenaValue<'a>,
+    objects: &'a [ArenaNode<'a>],
+}
+
+impl<'a> ArenaDoc<'a> {
+    /// Returns the top-level value.
+    pub fn root(&self) -> ArenaValue<'a> {
+        self.root
+    }
+
+    /// Returns the object with index `id`, if there is one.
+    pub fn get(&self, id: ObjectId) -> Option<&'a ArenaNode<'a>> {
+        self.objects.get(id.0)
+    }
+
+    /// Returns the object `value` refers to, or `None` if it isn't an object.
+    pub fn node(&self, value: ArenaValue<'a>) -> Option<&'a ArenaNode<'a>> {
+        self.get(value.object_id()?)
+    }
+
+    /// Returns every object in the document, in order of their object index.
+    pub fn objects(&self) -> &'a [ArenaNode<'a>] {
+        self.objects
+    }
+}
+
+/// Reads documents into an arena. Reusing one reader for many documents also reuses its
+/// scratch space.
+pub struct ArenaReader<'a> {
+    bump: &'a Bump,
+    /// If set, values nested more than this many levels deep report `ThurgoodError::TooDeep`.
+    pub max_depth: Option<usize>,
+    scratch: Scratch<'a>,
+}
+
+/// Stacks which values are collected on before being copied into the arena, so reading a
+/// node doesn't need a heap allocation of its own.
+#[derive(Default)]
+struct Scratch<'a> {
+    objects: Vec<ArenaNode<'a>>,
+    symbols: Vec<&'a [u8]>,
+    values: Vec<ArenaValue<'a>>,
+    entries: Vec<(ArenaValue<'a>, ArenaValue<'a>)>,
+    fields: Vec<(&'a [u8], ArenaValue<'a>)>,
+}
+
+impl<'a> Scratch<'a> {
+    fn clear(&mut self) {
+        self.objects.clear();
+        self.symbols.clear();
+        self.values.clear();
+        self.entries.clear();
+        self.fields.clear();
+    }
+}
+
+impl<'a> ArenaReader<'a> {
+    pub fn new(bump: &'a Bump) -> Self {
+        Self { bump, max_depth: None, scratch: Scratch::default() }
+    }
+
+    /// Read one document from `src`. The bytes which are kept are copied into the arena,
+    /// so `src` may be reused afterwards.
+    pub fn read(&mut self, src: &[u8]) -> TResult<ArenaDoc<'a>> {
+        self.scratch.clear();
+        let mut parser = Parser {
+            bump: self.bump,
+            src: SliceSource::new(src),
+            max_depth: self.max_depth,
+            depth: 0,
+            s: std::mem::take(&mut self.scratch),
+        };
+        let result = parser.read_doc();
+        self.scratch = parser.s;
+        result
+    }
+}
+
+/// Read one document into `bump`, see `ArenaReader`.
+pub fn from_bytes_in<'a>(src: &[u8], bump: &'a Bump) -> TResult<ArenaDoc<'a>> {
+    ArenaReader::new(bump).read(src)
+}
+
+struct Parser<'a, 's> {
+    bump: &'a Bump,
+    src: SliceSource<'s>,
+    max_depth: Option<usize>,
+    depth: usize,
+    s: Scratch<'a>,
+}
+
+impl<'a, 's> Parser<'a, 's> {
+    fn read_doc(&mut self) -> TResult<ArenaDoc<'a>> {
+        let header = self.src.take(2)?;
+        if header[0] != 4 {
+            return Err(ThurgoodError::Version { major: header[0], minor: header[1] });
+        }
+        let root = self.read_value()?;
+        let objects = self.bump.alloc_slice_copy(&self.s.objects);
+        Ok(ArenaDoc { root, objects })
+    }
+
+    fn read_value(&mut self) -> TResult<ArenaValue<'a>> {
+        self.nested(Self::read_value_inner)
+    }
+
+    /// Run `f` one level deeper, checking `max_depth` first.
+    fn nested<T, F>(&mut self, f: F) -> TResult<T> where F: FnOnce(&mut Self) -> TResult<T> {
+        if let Some(max_depth) = self.max_depth {
+            if self.depth >= max_depth {
+                return Err(ThurgoodError::TooDeep(max_depth));
+            }
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn read_value_inner(&mut self) -> TResult<ArenaValue<'a>> {
+        let position = Some(self.src.position());
+        let tag = self.read_byte()?;
+        match tag {
+            T_NIL => Ok(ArenaValue::Nil),
+            T_TRUE => Ok(ArenaValue::True),
+            T_FALSE => Ok(ArenaValue::False),
+            T_INT => Ok(ArenaValue::Int(self.read_int()?)),
+            T_SYMBOL => Ok(ArenaValue::Symbol(self.read_symbol()?)),
+            T_SYMBOL_REF => Ok(ArenaValue::Symbol(self.read_symbol_ref()?)),
+            T_OBJECT_REF => {
+                let index = self.read_int()?;
+                match usize::try_from(index) {
+                    Ok(index) if index < self.s.objects.len() => Ok(ArenaValue::Object(ObjectId(index))),
+                    Ok(index) => Err(ThurgoodError::ForwardObjectRef(index)),
+                    Err(_) => Err(ThurgoodError::BadObjectRef(index)),
+                }
+            },
+            T_INSTANCE if self.src.remaining().first() == Some(&T_SYMBOL) => {
+                // Symbols aren't objects, but the values of their ivars may be
+                self.read_byte()?;
+                let symbol = self.read_symbol()?;
+                self.read_fields()?;
+                Ok(ArenaValue::Symbol(symbol))
+            },
+            T_EXTENDED => {
+                let module = self.read_symbol_entry()?;
+                let value = self.read_value()?;
+                let id = value.object_id().ok_or_else(|| {
+                    ThurgoodError::UnexpectedType { expected: RbType::Object, found: value.get_type(), position }
+                })?;
+                let node = &mut self.s.objects[id.0];
+                let mut extended = Vec::with_capacity(node.extended.len() + 1);
+                extended.push(module);
+                extended.extend_from_slice(node.extended);
+                node.extended = self.bump.alloc_slice_copy(&extended);
+                Ok(value)
+            },
+            _ => {
+                let index = self.s.objects.len();
+                self.s.objects.push(ArenaNode {
+                    kind: ArenaKind::Array(&[]),
+                    ivars: &[],
+                    user_class: None,
+                    extended: &[],
+                });
+                let node = self.read_node(tag, position)?;
+                self.s.objects[index] = node;
+                Ok(ArenaValue::Object(ObjectId(index)))
+            },
+        }
+    }
+
+    /// Read an object after its type byte, without giving it an object index.
+    fn read_node(&mut self, tag: u8, position: Option<usize>) -> TResult<ArenaNode<'a>> {
+        let kind = match tag {
+            T_INSTANCE => {
+                let position = Some(self.src.position());
+                let tag = self.read_byte()?;
+                let mut node = self.nested(|p| p.read_node(tag, position))?;
+                node.ivars = self.read_fields()?;
+                return Ok(node);
+            },
+            T_USER_CLASS => {
+                let class = self.read_symbol_entry()?;
+                let position = Some(self.src.position());
+                let tag = self.read_byte()?;
+                let node = self.nested(|p| p.read_node(tag, position))?;
+                return Ok(ArenaNode { user_class: Some(class), ..node });
+            },
+            T_FLOAT => ArenaKind::Float(self.read_float()?),
+            T_BIGNUM => {
+                let negative = self.read_byte()? == b'-';
+                let len = self.read_len()?;
+                let len = len.checked_mul(2).ok_or(ThurgoodError::TooLarge(len))?;
+                ArenaKind::BigInt { negative, magnitude: self.read_bytes(len)? }
+            },
+            T_STRING => ArenaKind::Str(self.read_len_bytes()?),
+            T_REGEX => {
+                let source = self.read_len_bytes()?;
+                ArenaKind::Regex { source, flags: self.read_byte()? }
+            },
+            T_ARRAY => {
+                let len = self.read_len()?;
+                let start = self.s.values.len();
+                for _ in 0..len {
+                    let value = self.read_value()?;
+                    self.s.values.push(value);
+                }
+                let items = self.bump.alloc_slice_copy(&self.s.values[start..]);
+                self.s.values.truncate(start);
+                ArenaKind::Array(items)
+            },
+            T_HASH | T_HASH_DEFAULT => {
+                let len = self.read_len()?;
+                let start = self.s.entries.len();
+                for _ in 0..len {
+                    let key = self.read_value()?;
+                    let value = self.read_value()?;
+                    self.s.entries.push((key, value));
+                }
+                let entries = self.bump.alloc_slice_copy(&self.s.entries[start..]);
+                self.s.entries.truncate(start);
+                let default = if tag == T_HASH_DEFAULT { Some(self.read_value()?) } else { None };
+                ArenaKind::Hash { entries, default }
+            },
+            T_OBJECT => {
+                let class = self.read_symbol_entry()?;
+                ArenaKind::Object { class, fields: self.read_fields()? }
+            },
+            T_STRUCT => {
+                let class = self.read_symbol_entry()?;
+                ArenaKind::Struct { class, fields: self.read_fields()? }
+            },
+            T_CLASS => ArenaKind::ClassRef(self.read_len_bytes()?),
+            T_MODULE => ArenaKind::ModuleRef(self.read_len_bytes()?),
+            T_CLASS_MODULE => ArenaKind::ClassModuleRef(self.read_len_bytes()?),
+            T_USER_DEFINED => {
+                let class = self.read_symbol_entry()?;
+                ArenaKind::UserData { class, data: self.read_len_bytes()? }
+            },
+            T_USER_MARSHAL => {
+                let class = self.read_symbol_entry()?;
+                ArenaKind::UserMarshal { class, data: self.read_value()? }
+            },
+            T_DATA => {
+                let class = self.read_symbol_entry()?;
+                ArenaKind::Data { class, data: self.read_value()? }
+            },
+            _ => return Err(ThurgoodError::BadTypeByte { byte: tag, position }),
+        };
+        Ok(ArenaNode { kind, ivars: &[], user_class: None, extended: &[] })
+    }
+
+    /// Read a count followed by that many symbol and value pairs.
+    fn read_fields(&mut self) -> TResult<ArenaFields<'a>> {
+        let len = self.read_len()?;
+        let start = self.s.fields.len();
+        for _ in 0..len {
+            let name = self.read_symbol_entry()?;
+            let value = self.read_value()?;
+            self.s.fields.push((name, value));
+        }
+        let fields = self.bump.alloc_slice_copy(&self.s.fields[start..]);
+        self.s.fields.truncate(start);
+        Ok(fields)
+    }
+
+    /// Read a symbol or symbol reference, including its type byte.
+    fn read_symbol_entry(&mut self) -> TResult<&'a [u8]> {
+        let position = Some(self.src.position());
+        match self.read_value()? {
+            ArenaValue::Symbol(name) => Ok(name),
+            other => Err(ThurgoodError::UnexpectedType { expected: RbType::Symbol, found: other.get_type(), position }),
+        }
+    }
+
+    fn read_symbol(&mut self) -> TResult<&'a [u8]> {
+        let name = self.read_len_bytes()?;
+        self.s.symbols.push(name);
+        Ok(name)
+    }
+
+    fn read_symbol_ref(&mut self) -> TResult<&'a [u8]> {
+        let index = self.read_int()?;
+        usize::try_from(index).ok()
+            .and_then(|i| self.s.symbols.get(i).copied())
+            .ok_or(ThurgoodError::BadSymbolRef(index))
+    }
+
+    fn read_float(&mut self) -> TResult<f64> {
+        let len = self.read_len()?;
+        let buf = self.src.take(len)?;
+        // Like `RbReader`, accept a NULL-terminated float too
+        let last = buf.iter().position(|e| *e == 0).unwrap_or(buf.len());
+        let start = self.src.position() - len;
+        let decoded = std::str::from_utf8(&buf[0..last])
+            .map_err(|e| ThurgoodError::invalid_utf8(buf, e, Some(start)))?;
+        match decoded {
+            "inf" => Ok(f64::INFINITY),
+            "-inf" => Ok(f64::NEG_INFINITY),
+            "nan" => Ok(f64::NAN),
+            _ => Ok(decoded.parse::<f64>()?),
+        }
+    }
+
+    fn read_int(&mut self) -> TResult<i32> {
+        let first = self.read_byte()?;
+        match first as i8 {
+            0 => Ok(0),
+            n @ 1..=4 => {
+                let mut buf = [0u8; 4];
+                buf[..n as usize].copy_from_slice(self.src.take(n as usize)?);
+                Ok(i32::from_le_bytes(buf))
+            },
+            n @ -4..=-1 => {
+                let len = n.unsigned_abs() as usize;
+                let mut buf = [0xffu8; 4];
+                buf[..len].copy_from_slice(self.src.take(len)?);
+                Ok(i32::from_le_bytes(buf))
+            },
+            n if n > 0 => Ok(n as i32 - 5),
+            n => Ok(n as i32 + 5),
+        }
+    }
+
+    fn read_len(&mut self) -> TResult<usize> {
+        let len = self.read_int()?;
+        usize::try_from(len).map_err(|_| ThurgoodError::BadLength(len))
+    }
+
+    /// Read a length followed by that many bytes, and copy them into the arena.
+    fn read_len_bytes(&mut self) -> TResult<&'a [u8]> {
+        let len = self.read_len()?;
+        self.read_bytes(len)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> TResult<&'a [u8]> {
+        Ok(self.bump.alloc_slice_copy(self.src.take(len)?))
+    }
+
+    fn read_byte(&mut self) -> TResult<u8> {
+        Ok(self.src.take(1)?[0])
+    }
+}
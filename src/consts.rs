@@ -24,3 +24,66 @@ pub const T_USER_CLASS: u8 = 'C' as u8;
 pub const T_USER_DEFINED: u8 = 'u' as u8;
 pub const T_USER_MARSHAL: u8 = 'U' as u8;
 pub const T_EXTENDED: u8 = 'e' as u8;
+
+/// The type byte at the start of each value, as a typed alternative to the `T_*` constants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MarshalTag {
+    True = T_TRUE,
+    False = T_FALSE,
+    Nil = T_NIL,
+    Int = T_INT,
+    Symbol = T_SYMBOL,
+    SymbolRef = T_SYMBOL_REF,
+    ObjectRef = T_OBJECT_REF,
+    Instance = T_INSTANCE,
+    Array = T_ARRAY,
+    Bignum = T_BIGNUM,
+    Class = T_CLASS,
+    Module = T_MODULE,
+    ClassModule = T_CLASS_MODULE,
+    Data = T_DATA,
+    Float = T_FLOAT,
+    Hash = T_HASH,
+    HashDefault = T_HASH_DEFAULT,
+    Object = T_OBJECT,
+    Regex = T_REGEX,
+    String = T_STRING,
+    Struct = T_STRUCT,
+    UserClass = T_USER_CLASS,
+    UserDefined = T_USER_DEFINED,
+    UserMarshal = T_USER_MARSHAL,
+    Extended = T_EXTENDED,
+}
+
+impl MarshalTag {
+    /// Every tag, e.g. for tools which list the types a stream may contain.
+    pub const ALL: [MarshalTag; 25] = [
+        Self::True, Self::False, Self::Nil, Self::Int, Self::Symbol, Self::SymbolRef,
+        Self::ObjectRef, Self::Instance, Self::Array, Self::Bignum, Self::Class, Self::Module,
+        Self::ClassModule, Self::Data, Self::Float, Self::Hash, Self::HashDefault, Self::Object,
+        Self::Regex, Self::String, Self::Struct, Self::UserClass, Self::UserDefined,
+        Self::UserMarshal, Self::Extended,
+    ];
+
+    /// Returns the tag for a type byte, or None if it isn't one.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|t| t.to_byte() == byte)
+    }
+
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+}
+
+// Regex flag bits, as returned by `Regexp#options`.
+// The low bits are the `i`, `x`, and `m` options, the high bits describe the encoding.
+pub const RE_IGNORECASE: u32 = 1;
+pub const RE_EXTENDED: u32 = 2;
+pub const RE_MULTILINE: u32 = 4;
+/// Mask of the `i`, `x`, and `m` option bits.
+pub const RE_OPTIONS_MASK: u32 = RE_IGNORECASE | RE_EXTENDED | RE_MULTILINE;
+/// Set for regexes with a fixed encoding, e.g. `/foo/u` or a literal containing non-ASCII.
+pub const RE_FIXED_ENCODING: u32 = 16;
+/// Set for binary regexes, i.e. `/foo/n`.
+pub const RE_NO_ENCODING: u32 = 32;
@@ -1,31 +1,101 @@
+use std::str::Utf8Error;
 use thiserror::Error;
 use crate::RbType;
 
+/// The most bytes to show in the `snippet` of a `ThurgoodError::Utf8`.
+const UTF8_SNIPPET_LEN: usize = 8;
+
 #[derive(Error, Debug)]
 pub enum ThurgoodError {
     #[error(transparent)]
     IO(#[from] std::io::Error),
-    #[error(transparent)]
-    Utf8(#[from] std::str::Utf8Error),
+    /// A string which should be UTF-8 isn't. `valid_up_to` is how much of the string is valid,
+    /// `position` is the offset of the first invalid byte in the input (if the source keeps
+    /// track of it), and `snippet` shows the bytes from there in hex, e.g. `ff 00 41`.
+    /// `snippet` is a `Box<str>` to keep this error (and so every `TResult`) small.
+    #[error("Invalid UTF-8 after {valid_up_to} bytes of a string{}: {snippet}",
+        .position.map(|p| format!(" at offset {}", p)).unwrap_or_default())]
+    Utf8 { valid_up_to: usize, position: Option<usize>, snippet: Box<str> },
     #[error(transparent)]
     ParseFloat(#[from] std::num::ParseFloatError),
-    #[error("Invalid Marshal version")]
-    Version(String),
+    #[error("Unsupported Marshal version {major}.{minor}, only version 4 is supported")]
+    Version { major: u8, minor: u8 },
+    /// The input doesn't start with a Marshal header, and looks like some other format.
+    /// `hint` is a best guess at what it is.
+    #[error("Input isn't Marshal data: {hint}")]
+    NotMarshal { hint: &'static str },
     #[error("Invalid symbol reference number {0}")]
-    BadSymbolRef(usize),
+    BadSymbolRef(i32),
     #[error("Invalid object reference number {0}")]
-    BadObjectRef(usize),
+    BadObjectRef(i32),
+    #[error("Object reference number {0} refers to an object which hasn't been read yet")]
+    ForwardObjectRef(usize),
+    #[error("Invalid length {0}")]
+    BadLength(i32),
+    #[error("{0} is too large to be written as a Marshal integer")]
+    TooLarge(usize),
     #[error("Invalid instance type")]
     BadInstanceType(char),
-    #[error("Unexpected Ruby type")]
-    UnexpectedType { expected: RbType, found: RbType },
-    #[error("Unknown type byte")]
-    BadTypeByte(u8),
+    /// `position` is the offset of the unexpected value in the input, if it was being read.
+    #[error("Expected {} but found {}{}", .expected.ruby_name(), .found.ruby_name(),
+        .position.map(|p| format!(" at offset {}", p)).unwrap_or_default())]
+    UnexpectedType { expected: RbType, found: RbType, position: Option<usize> },
+    /// `position` is the offset of the byte in the input, if the source keeps track of it.
+    #[error("Unknown type byte {byte:#04x}{}", .position.map(|p| format!(" at offset {}", p)).unwrap_or_default())]
+    BadTypeByte { byte: u8, position: Option<usize> },
+    #[error("Values are nested more than {0} levels deep")]
+    TooDeep(usize),
+    /// A string or symbol is longer than `RbReader::max_string_len` or `max_symbol_len`.
+    /// `position` is the offset of its length in the input, if the source keeps track of it.
+    #[error("{} of {len} bytes is longer than the limit of {max} bytes{}", .kind.ruby_name(),
+        .position.map(|p| format!(" at offset {}", p)).unwrap_or_default())]
+    TooLong { kind: RbType, len: usize, max: usize, position: Option<usize> },
+    /// A symbol was refused by `RbReader::on_symbol`.
+    #[error("Symbol {0} isn't allowed")]
+    RejectedSymbol(String),
+    #[error("Duplicate hash key {0}")]
+    DuplicateHashKey(String),
+    #[error("Cannot apply patch: {0}")]
+    BadPatch(String),
+    #[error("Cannot write a recursive value without object references")]
+    RecursiveValue,
+    #[error("Invalid write sequence: {0}")]
+    BadStream(String),
+    #[error("Output needs {needed} bytes but the buffer only has {available}")]
+    BufferTooSmall { needed: usize, available: usize },
+    /// The output would be longer than `RbWriterOptions::max_output_len`.
+    #[error("Output is longer than the limit of {limit} bytes")]
+    OutputTooLarge { limit: usize },
+    /// Regex flags are written as one byte, so only values up to 0xff can be written.
+    #[error("Regex flags {0:#x} don't fit in a byte")]
+    BadRegexFlags(u32),
+    #[error("Cannot serialize value at {path}: {reason}")]
+    Unserializable { path: String, reason: String },
+    /// The contents of a `Data` or `UserMarshal` value of this class couldn't be read, and
+    /// `RbReader::lenient_user_data` can only keep them as raw bytes if nothing follows them.
+    #[error("Cannot skip the unreadable contents of {0}, since more values follow them")]
+    UndecodedNotLast(String),
 }
 
 impl ThurgoodError {
     pub fn unexpected_type(expected: RbType, found: RbType) -> Self {
-        Self::UnexpectedType { expected, found }
+        Self::UnexpectedType { expected, found, position: None }
+    }
+
+    /// Construct a `Utf8` error for `buf`, where `start` is the offset of `buf` in the input,
+    /// if known.
+    pub fn invalid_utf8(buf: &[u8], source: Utf8Error, start: Option<usize>) -> Self {
+        let bad = &buf[source.valid_up_to()..];
+        let mut snippet = bad.iter()
+            .take(UTF8_SNIPPET_LEN)
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if bad.len() > UTF8_SNIPPET_LEN {
+            snippet.push_str(" ...");
+        }
+        let valid_up_to = source.valid_up_to();
+        Self::Utf8 { valid_up_to, position: start.map(|p| p + valid_up_to), snippet: snippet.into() }
     }
 }
 
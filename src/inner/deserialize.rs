@@ -11,18 +11,31 @@ References:
 - Calling `Marshal.dump` on various things in Ruby
 */
 
+use std::fs::File;
 use std::io;
-use std::convert::TryInto;
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use num_bigint::{BigInt, Sign};
 use crate::{
     consts::*,
     error::*,
+    source::{ByteSource, IoSource, SliceSource},
     RbType,
 };
-use super::{RbAny, RbClass, RbFields, RbHash, RbObject, RbRef, RbSymbol, RbUserData, rc_get_ptr};
+use super::{to_vec, RawInts, RbAny, RbClass, RbFields, RbHash, RbObject, RbRef, RbSymbol, RbUserData, RcType, SymbolEncoding, SymbolInterner, rc_get_ptr};
+use super::serialize::int_len;
 
-fn bytes_to_string(buf: &[u8]) -> TResult<String> {
-    Ok(std::str::from_utf8(buf)?.to_owned())
+/// The most elements to preallocate for a collection. Lengths come from the input, so a
+/// bogus length must not be able to reserve gigabytes before the data turns out to be missing.
+const MAX_RESERVE: usize = 1 << 16;
+
+/// Convert `buf` to a string, where `end` is the offset in the input just after `buf`, if known.
+fn bytes_to_string(buf: &[u8], end: Option<usize>) -> TResult<String> {
+    match std::str::from_utf8(buf) {
+        Ok(s) => Ok(s.to_owned()),
+        Err(e) => Err(ThurgoodError::invalid_utf8(buf, e, end.map(|end| end - buf.len()))),
+    }
 }
 
 
@@ -30,113 +43,469 @@ fn bytes_to_string(buf: &[u8]) -> TResult<String> {
 pub struct RbReader<R> {
     src: R,
     symbols: Vec<RbSymbol>,
+    /// Symbols the table starts with for each document, see `with_symbols`.
+    initial_symbols: Vec<RbSymbol>,
     objects: Vec<RbAny>,
     sym_e: RbSymbol,
-    /// If true, reads non-utf8 strings (which should be utf-8) as `RbRef::StrI` instead.
-    /// If false, this situation reports an error.
+    sym_k: RbSymbol,
+    sym_encoding: RbSymbol,
+    /// Version of the last document read, see `version`.
+    version: Option<(u8, u8)>,
+    /// If set, this is the buffer being read and symbols refer to it instead of copying.
+    shared: Option<RcType<[u8]>>,
+    /// If set, symbol names are stored here instead of in each symbol, see `with_interner`.
+    interner: Option<SymbolInterner>,
+    /// Scratch space for reading symbol names which are looked up in `interner`.
+    symbol_buf: Vec<u8>,
+    /// Raw text of the floats in the last document read by object index, only recorded if
+    /// `record_raw_floats` is true.
+    raw_floats: HashMap<usize, Vec<u8>>,
+    /// Raw text of the float currently being read.
+    pending_raw_float: Option<Vec<u8>>,
+    /// If true, the exact text of each float is recorded as it's read, see `raw_float`.
+    /// This is useful for debugging precision issues.
+    pub record_raw_floats: bool,
+    /// Integers which weren't in the shortest form, only recorded if `record_raw_ints` is true.
+    raw_ints: RawInts,
+    /// Number of integers read so far in this document, the key into `raw_ints`.
+    int_count: usize,
+    /// If true, integers which weren't written in the shortest form (which Ruby never does,
+    /// but other tools might) are recorded, see `raw_ints`.
+    pub record_raw_ints: bool,
+    /// If true, reads strings which are marked as UTF-8 but aren't valid UTF-8 as `RbRef::StrI`
+    /// (keeping their encoding) instead. If false, this situation reports an error.
     pub allow_bin_strings: bool,
+    /// If true, a hash with two equal keys (or an object with two fields of the same name)
+    /// reports `ThurgoodError::DuplicateHashKey`. Ruby never writes these, so they indicate
+    /// corrupt data. If false, the last value for a key wins.
+    pub strict_hash_keys: bool,
+    /// If set, values nested more than this many levels deep report `ThurgoodError::TooDeep`.
+    /// Reading is recursive, so without a limit deeply nested input can overflow the stack.
+    pub max_depth: Option<usize>,
+    /// If set, strings longer than this many bytes report `ThurgoodError::TooLong`. This is a
+    /// sanity check on the data rather than a memory limit, e.g. for rejecting corrupt input.
+    pub max_string_len: Option<usize>,
+    /// If set, symbols longer than this many bytes report `ThurgoodError::TooLong`. Names in
+    /// real data are short, so a long one almost certainly means the input is corrupt.
+    pub max_symbol_len: Option<usize>,
+    /// If true, the contents of a `Data` or `UserMarshal` value which can't be read (e.g. an
+    /// unknown type byte) are kept as raw bytes instead of reporting an error, see
+    /// `RbClass::undecoded`. Limits such as `max_depth` and errors reading the input are
+    /// reported as usual.
+    ///
+    /// Marshal doesn't record how long the contents are, so there's no way to find where they
+    /// end, and the raw bytes always run to the end of the input. This can only recover a value
+    /// which is the last thing in the input: if a container around it expects more values, that
+    /// reports `ThurgoodError::UndecodedNotLast` (unless a lenient value further out can take
+    /// the rest of the input instead). In particular the rest of a stream holding several
+    /// documents is taken too, so don't use this with `read_counting` or `read_value` on such
+    /// a stream.
+    ///
+    /// `RbWriter` writes the raw bytes back unchanged, so a document read this way round-trips
+    /// as long as nothing before the undecoded value changes.
+    pub lenient_user_data: bool,
+    /// If set, this is called with the name of each new symbol (but not symbol references)
+    /// before it's added to the symbol table. An error stops reading and is returned as-is,
+    /// e.g. `ThurgoodError::RejectedSymbol` to only allow known names. Like `max_symbol_len`, the name has already been read.
+    pub on_symbol: Option<SymbolHook>,
+    /// How many values are currently being read, see `max_depth`.
+    depth: usize,
+    /// True if the next hash is the contents of a `Hash` user class, which is how Ruby marks
+    /// a hash made with `compare_by_identity`.
+    next_hash_by_identity: bool,
+    /// Class of the `Data` or `UserMarshal` value whose raw contents took the rest of the
+    /// input, see `lenient_user_data`.
+    undecoded_class: Option<RbSymbol>,
+    /// True if the root array is being read by `array_elements`, so it's never constructed
+    /// and object 0 can't be referred to.
+    streaming_root: bool,
 }
 
-impl<R> RbReader<R> where
+/// A function which checks the name of each symbol read, see `RbReader::on_symbol`.
+pub type SymbolHook = fn(&[u8]) -> TResult<()>;
+
+impl<R> RbReader<IoSource<R>> where
     R: io::Read
 {
+    /// Construct a reader for any `io::Read`.
     pub fn new(src: R) -> Self {
+        Self::with_source(IoSource::new(src))
+    }
+}
+
+impl<'a> RbReader<SliceSource<'a>> {
+    /// Construct a reader which reads directly from a slice of bytes.
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::with_source(SliceSource::new(data))
+    }
+
+    /// Construct a reader which reads directly from a shared buffer. Symbols will refer
+    /// to the buffer instead of copying their contents, and so keep it alive, see
+    /// `from_bytes_shared`.
+    pub fn from_shared(data: &'a RcType<[u8]>) -> Self {
+        let mut rd = Self::from_slice(data);
+        rd.shared = Some(data.clone());
+        rd
+    }
+}
+
+impl<R> RbReader<R> where
+    R: ByteSource
+{
+    /// Construct a reader for the given byte source.
+    pub fn with_source(src: R) -> Self {
         Self {
             src,
             symbols: Vec::new(),
+            initial_symbols: Vec::new(),
             // Documentation says that object indexes start at 1, actually the root object is
             // at index 0, and since objects can't recursively reference, this works out.
             objects: vec![],
             // Cached copy of this symbol so we can easily test for string encodings
             sym_e: RbSymbol::from_str("E"),
+            sym_k: RbSymbol::from_str("K"),
+            sym_encoding: RbSymbol::from_str("encoding"),
+            version: None,
+            shared: None,
+            interner: None,
+            symbol_buf: Vec::new(),
+            raw_floats: HashMap::new(),
+            pending_raw_float: None,
+            record_raw_floats: false,
+            raw_ints: HashMap::new(),
+            int_count: 0,
+            record_raw_ints: false,
             allow_bin_strings: false,
+            strict_hash_keys: false,
+            next_hash_by_identity: false,
+            undecoded_class: None,
+            streaming_root: false,
+            max_depth: None,
+            max_string_len: None,
+            max_symbol_len: None,
+            lenient_user_data: false,
+            on_symbol: None,
+            depth: 0,
         }
     }
 
+    /// Pre-populate the symbol table, so that `;0` in the input refers to `symbols[0]` and so
+    /// on, while symbols read from the input are numbered after these. This is useful for
+    /// reading a fragment of a larger stream, or for testing symbol references.
+    ///
+    /// The writer knows nothing of these symbols, so data read with the wrong table (or
+    /// written back out without it) will silently have its symbol references mixed up.
+    pub fn with_symbols(mut self, symbols: Vec<RbSymbol>) -> Self {
+        self.symbols = symbols.clone();
+        self.initial_symbols = symbols;
+        self
+    }
+
+    /// Store symbol names in `interner`, so that symbols with the same name share their
+    /// storage across every document read with it, see `SymbolInterner`. This takes priority
+    /// over `from_shared`, so symbols don't keep the buffer they were read from alive.
+    ///
+    /// Use `take_interner` to get it back afterwards for the next reader.
+    pub fn with_interner(mut self, interner: SymbolInterner) -> Self {
+        self.interner = Some(interner);
+        self
+    }
+
+    /// Remove and return the interner given to `with_interner`.
+    pub fn take_interner(&mut self) -> Option<SymbolInterner> {
+        self.interner.take()
+    }
+
+    /// Read one Marshal document. This may be called repeatedly to read several documents
+    /// written one after another, each with its own symbol and object tables.
+    ///
+    /// Any minor version is accepted as long as the major version is 4. This is deliberately
+    /// more lenient than Ruby, which rejects minor versions above 8 and warns about lower ones,
+    /// so data from a newer Ruby can be read if it only uses types this crate knows about (see
+    /// `version`).
     pub fn read(&mut self) -> TResult<RbAny> {
+        self.read_version()?;
+        self.read_body()
+    }
+
+    /// Start reading a document by reading just its two version bytes, returning the
+    /// `(major, minor)` version. Call `read_body` next to read the rest of the document.
+    ///
+    /// This checks the version the same way `read` does, so unsupported data fails before
+    /// any of it is parsed.
+    pub fn read_version(&mut self) -> TResult<(u8, u8)> {
+        self.symbols.clone_from(&self.initial_symbols);
+        self.objects.clear();
+        self.depth = 0;
+        self.streaming_root = false;
+        self.undecoded_class = None;
+        self.raw_floats.clear();
+        self.raw_ints.clear();
+        self.int_count = 0;
         let mut buf2 = [0u8;2];
         self.src.read_exact(&mut buf2)?;
-        if !(buf2[0] == 4 && buf2[1] == 8) {
-            return Err(ThurgoodError::Version(format!("{}.{}", buf2[0], buf2[1])));
+        if buf2[0] != 4 {
+            if let Some(hint) = not_marshal_hint(buf2) {
+                return Err(ThurgoodError::NotMarshal { hint });
+            }
+            return Err(ThurgoodError::Version { major: buf2[0], minor: buf2[1] });
         }
+        self.version = Some((buf2[0], buf2[1]));
+        Ok((buf2[0], buf2[1]))
+    }
+
+    /// Read the body of a document whose header was already read by `read_version`.
+    pub fn read_body(&mut self) -> TResult<RbAny> {
+        self.read_entry()
+    }
+
+    /// Read a document whose root is an array one element at a time, e.g. a file holding a huge
+    /// array of records. Only one element is held at once (along with the symbol and object
+    /// tables, which later elements may refer back to).
+    ///
+    /// If the header can't be read, or the root isn't a plain array, the first item is an error
+    /// and there are no more. A reference back to the root array itself (which Ruby allows)
+    /// is reported as `ThurgoodError::ForwardObjectRef(0)`, since the root is never constructed.
+    pub fn array_elements(mut self) -> impl Iterator<Item = TResult<RbAny>> {
+        let start = self.read_version().and_then(|_| {
+            let tag = self.read_tag()?;
+            if tag == MarshalTag::Array {
+                self.alloc_object();
+                self.streaming_root = true;
+                return self.read_len();
+            }
+            let position = self.src.position().map(|p| p - 1);
+            let found = self.read_tagged(tag)?.get_type();
+            Err(ThurgoodError::UnexpectedType { expected: RbType::Array, found, position })
+        });
+        let (remaining, error) = match start {
+            Ok(len) => (len, None),
+            Err(e) => (0, Some(e)),
+        };
+        // Elements are nested inside the root, as far as `max_depth` is concerned
+        self.depth = 1;
+        ArrayElements { reader: self, remaining, error }
+    }
+
+    /// Start over with a new source, returning the old one. The symbol and object tables
+    /// (see `read_value`) and recorded floats are cleared, while the options, `with_symbols`
+    /// and `with_interner` are kept, so a reader can be reused instead of constructing a new one.
+    ///
+    /// Symbols no longer refer to the buffer given to `from_shared`, since it belongs to the old
+    /// source.
+    pub fn reset(&mut self, src: R) -> R {
+        self.symbols.clone_from(&self.initial_symbols);
+        self.objects.clear();
+        self.depth = 0;
+        self.version = None;
+        self.shared = None;
+        self.raw_floats.clear();
+        self.pending_raw_float = None;
+        self.raw_ints.clear();
+        self.int_count = 0;
+        self.next_hash_by_identity = false;
+        self.streaming_root = false;
+        self.undecoded_class = None;
+        std::mem::replace(&mut self.src, src)
+    }
+
+    /// Returns the `(major, minor)` version of the last document read, if any. Everything
+    /// written since Ruby 1.8 is 4.8, so anything else likely came from a newer Ruby.
+    pub fn version(&self) -> Option<(u8, u8)> {
+        self.version
+    }
+
+    /// Read one Marshal document like `read`, and also return the number of bytes it took up.
+    /// Anything after the document is left unread, so this can read Marshal data which is
+    /// embedded in some other format.
+    ///
+    /// Both `RbReader::new` and `RbReader::from_slice` keep track of the position, while a
+    /// custom `ByteSource` which doesn't will return an `io::ErrorKind::Unsupported` error.
+    pub fn read_counting(&mut self) -> TResult<(RbAny, u64)> {
+        let no_position = || io::Error::new(io::ErrorKind::Unsupported, "byte source doesn't track its position");
+        let start = self.src.position().ok_or_else(no_position)?;
+        let value = self.read()?;
+        let end = self.src.position().ok_or_else(no_position)?;
+        Ok((value, (end - start) as u64))
+    }
+
+    /// Read a single value without the `\x04\x08` version header, for Marshal values which
+    /// are embedded in some other format. The caller is responsible for checking the version.
+    ///
+    /// Unlike `read`, the symbol and object tables are kept from previous calls, so later
+    /// values may refer to symbols and objects read by earlier ones.
+    pub fn read_value(&mut self) -> TResult<RbAny> {
         self.read_entry()
     }
 
     fn read_entry(&mut self) -> TResult<RbAny> {
-        let c = self.read_byte()?;
-        match c {
-            T_TRUE => { Ok(RbAny::True) },
-            T_FALSE => { Ok(RbAny::False) },
-            T_NIL => { Ok(RbAny::Nil) },
-            T_INT => { Ok(RbAny::Int(self.read_int()?)) },
-            T_SYMBOL => { self.read_symbol() },
-            T_SYMBOL_REF => { self.read_symbol_ref() },
-            T_OBJECT_REF => { self.read_object_ref() },
-            _ => self.read_ref(c),
+        // Raw contents kept by `lenient_user_data` took the rest of the input, which wasn't
+        // really theirs if another value is wanted
+        if self.undecoded_class.is_some() {
+            return Err(self.undecoded_not_last());
+        }
+        self.nested(Self::read_entry_inner)
+    }
+
+    // Separate so that building the error doesn't add to the stack frame of `read_entry`,
+    // which every level of nesting goes through.
+    #[cold]
+    #[inline(never)]
+    fn undecoded_not_last(&self) -> ThurgoodError {
+        let name = self.undecoded_class.as_ref().map(|n| String::from_utf8_lossy(n.as_bytes()).into_owned());
+        ThurgoodError::UndecodedNotLast(name.unwrap_or_default())
+    }
+
+    /// Run `f` one level deeper, checking `max_depth` first. Anything which reads values
+    /// recursively without going through `read_entry` must use this, or deeply nested input
+    /// could overflow the stack.
+    fn nested<T, F>(&mut self, f: F) -> TResult<T>
+        where F: FnOnce(&mut Self) -> TResult<T>
+    {
+        if let Some(max_depth) = self.max_depth {
+            if self.depth >= max_depth {
+                return Err(ThurgoodError::TooDeep(max_depth));
+            }
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn read_entry_inner(&mut self) -> TResult<RbAny> {
+        let tag = self.read_tag()?;
+        self.read_tagged(tag)
+    }
+
+    /// Read a value after its type byte.
+    fn read_tagged(&mut self, tag: MarshalTag) -> TResult<RbAny> {
+        match tag {
+            MarshalTag::True => { Ok(RbAny::True) },
+            MarshalTag::False => { Ok(RbAny::False) },
+            MarshalTag::Nil => { Ok(RbAny::Nil) },
+            MarshalTag::Int => { Ok(RbAny::Int(self.read_int()?)) },
+            MarshalTag::Symbol => { self.read_symbol() },
+            MarshalTag::Instance => { self.read_instance_entry() },
+            MarshalTag::SymbolRef => { self.read_symbol_ref() },
+            MarshalTag::ObjectRef => { self.read_object_ref() },
+            _ => self.read_ref(tag),
         }
     }
 
-    fn read_ref(&mut self, type_byte: u8) -> TResult<RbAny> {
-        if type_byte == T_EXTENDED {
+    /// Read a type byte, reporting `ThurgoodError::BadTypeByte` if it isn't one.
+    fn read_tag(&mut self) -> TResult<MarshalTag> {
+        let byte = self.read_byte()?;
+        MarshalTag::from_byte(byte).ok_or_else(|| {
+            let position = self.src.position().map(|p| p - 1);
+            ThurgoodError::BadTypeByte { byte, position }
+        })
+    }
+
+    fn read_ref(&mut self, tag: MarshalTag) -> TResult<RbAny> {
+        if tag == MarshalTag::Extended {
             let module = self.read_entry_symbol()?;
             let object = self.read_entry()?;
             Ok(RbRef::Extended { module, object }.into_any())
         } else {
             let o_index = self.alloc_object();
-            let obj = match type_byte {
-                T_INSTANCE => {
-                    self.read_instance()
-                },
-                T_ARRAY => {
-                    self.read_array()
-                },
-                T_BIGNUM => {
-                    self.read_bignum()
-                },
-                T_CLASS => {
-                    Ok(RbRef::ClassRef(self.read_class_mod_ref()?))
-                },
-                T_MODULE => {
-                    Ok(RbRef::ModuleRef(self.read_class_mod_ref()?))
-                },
-                T_CLASS_MODULE => {
-                    Ok(RbRef::ClassModuleRef(self.read_class_mod_ref()?))
-                },
-                T_DATA => {
-                    Ok(RbRef::Data(self.read_rb_class()?))
-                },
-                T_FLOAT => {
-                    Ok(RbRef::from(self.read_float()?))
-                },
-                T_HASH => { self.read_hash(false) },
-                T_HASH_DEFAULT => { self.read_hash(true) },
-                T_REGEX => { self.read_regex() },
-                T_STRING => {
-                    self.read_string()
-                },
-                T_OBJECT => {
-                    Ok(RbRef::Object(self.read_rb_object()?))
-                },
-                T_STRUCT => {
-                    Ok(RbRef::Struct(self.read_rb_object()?))
-                },
-                T_USER_CLASS => {
-                    self.read_user_class()
-                },
-                T_USER_DEFINED => {
-                    let name = self.read_entry_symbol()?;
-                    let data = self.read_len_bytes()?;
-                    Ok(RbRef::UserData(RbUserData { name, data }))
-                },
-                T_USER_MARSHAL => {
-                    Ok(RbRef::UserMarshal(self.read_rb_class()?))
-                },
-                _ => { Err(ThurgoodError::BadTypeByte(type_byte)) }
-            }?;
-            Ok(self.set_object(o_index, obj))
+            let obj = self.read_ref_body(tag)?;
+            let value = self.set_object(o_index, obj);
+            if let Some(raw) = self.pending_raw_float.take() {
+                self.raw_floats.insert(o_index, raw);
+            }
+            Ok(value)
+        }
+    }
+
+    /// Read an object after its type byte, without giving it an object index.
+    fn read_ref_body(&mut self, tag: MarshalTag) -> TResult<RbRef> {
+        match tag {
+            MarshalTag::Instance => {
+                let instance_type = self.read_byte()?;
+                self.read_instance(instance_type)
+            },
+            MarshalTag::Array => {
+                self.read_array()
+            },
+            MarshalTag::Bignum => {
+                self.read_bignum()
+            },
+            MarshalTag::Class => {
+                Ok(RbRef::ClassRef(self.read_class_mod_ref()?))
+            },
+            MarshalTag::Module => {
+                Ok(RbRef::ModuleRef(self.read_class_mod_ref()?))
+            },
+            MarshalTag::ClassModule => {
+                Ok(RbRef::ClassModuleRef(self.read_class_mod_ref()?))
+            },
+            MarshalTag::Data => {
+                Ok(RbRef::Data(self.read_rb_class()?))
+            },
+            MarshalTag::Float => {
+                Ok(RbRef::from(self.read_float()?))
+            },
+            MarshalTag::Hash => { self.read_hash(false) },
+            MarshalTag::HashDefault => { self.read_hash(true) },
+            MarshalTag::Regex => { self.read_regex() },
+            MarshalTag::String => {
+                self.read_string()
+            },
+            MarshalTag::Object => {
+                Ok(RbRef::Object(self.read_rb_object()?))
+            },
+            MarshalTag::Struct => {
+                Ok(RbRef::Struct(self.read_rb_object()?))
+            },
+            MarshalTag::UserClass => {
+                self.read_user_class()
+            },
+            MarshalTag::UserDefined => {
+                self.read_user_defined()
+            },
+            MarshalTag::UserMarshal => {
+                Ok(RbRef::UserMarshal(self.read_rb_class()?))
+            },
+            // These aren't objects, e.g. `C:\x0aMyStr0`
+            MarshalTag::True | MarshalTag::False | MarshalTag::Nil | MarshalTag::Int
+                | MarshalTag::Symbol | MarshalTag::SymbolRef | MarshalTag::ObjectRef
+                | MarshalTag::Extended => {
+                let position = self.src.position().map(|p| p - 1);
+                Err(ThurgoodError::BadTypeByte { byte: tag.to_byte(), position })
+            }
         }
     }
 
+    /// Returns the exact text that `value` was parsed from, if `value` is a float from the last
+    /// document read by this reader and `record_raw_floats` was set. For NULL-terminated floats
+    /// this excludes the terminator and anything after it.
+    ///
+    /// Only the float itself is matched, not a copy (e.g. after `make_mut`) with the same value.
+    pub fn raw_float(&self, value: &RbAny) -> Option<&[u8]> {
+        let ptr = rc_get_ptr(value.as_rc()?);
+        self.raw_floats.iter()
+            .find(|(index, _)| self.objects[**index].as_rc().map(rc_get_ptr) == Some(ptr))
+            .map(|(_, raw)| raw.as_slice())
+    }
+
+    /// Returns the integers of the last document read which weren't written in the shortest form,
+    /// if `record_raw_ints` was set. Pass these to `RbWriter::with_raw_ints` to write them the
+    /// same way again.
+    pub fn raw_ints(&self) -> &RawInts {
+        &self.raw_ints
+    }
+
+    /// Returns the object index (as used by `@N` object references) of each object read so far.
+    ///
+    /// Objects which were never referenced are included too, since Ruby assigns every object an
+    /// index whether or not it's referenced later.
+    pub fn object_indices(&self) -> HashMap<*const RbRef, usize> {
+        self.objects.iter().enumerate()
+            .filter_map(|(i, obj)| obj.as_rc().map(|r| (rc_get_ptr(r), i)))
+            .collect()
+    }
+
     /// Allocate space for an object in the object list.
     /// The object will start out as Nil and MUST be replaced later.
     fn alloc_object(&mut self) -> usize {
@@ -155,7 +524,9 @@ impl<R> RbReader<R> where
             // Bypass mutability rules here. This is safe because no other code has access to
             // any of the Rc/Arc/etc. created here until the read() function returns. Until that
             // point this RbReader is the only "real" owner, no matter how many references there
-            // are to this object.
+            // are to this object. The same holds for the `arc` build: none of the Arcs can have
+            // been sent to another thread yet, so there's nothing else that could observe the write.
+            // The `arc_matches_rc` test checks both builds agree on recursive data.
             unsafe {
                 let raw_ptr = rc_get_ptr(self.objects[index].as_rc().unwrap());
                 *(raw_ptr as *mut RbRef) = obj;
@@ -167,27 +538,30 @@ impl<R> RbReader<R> where
     /// Read and return variable-sized integer from the data stream.
     /// This does NOT parse a type byte as there are many varints used in the encoding.
     fn read_int(&mut self) -> TResult<i32> {
+        let index = self.int_count;
+        self.int_count += 1;
         let mut buf = [0u8;4];
         self.src.read_exact(&mut buf[0..1])?;
         let is_neg = buf[0] >= 128;
         // Special cases for 0 or multi-byte values
         if buf[0] <= 0x04 || buf[0] >= 0xfc {
-            let bytes_to_read = (if is_neg { -(buf[0] as i8) } else { buf[0] as i8 }) as usize;
+            let bytes_to_read = (buf[0] as i8).unsigned_abs() as usize;
             // If it's 0x00 then we just return 0
             if bytes_to_read == 0 {
                 return Ok(0);
             }
-            // Read the correct number of bytes. The rest will still be 0 so it's fine to convert using little-endian
+            // Read the correct number of bytes. Negative values are two's complement with the
+            // high bytes (which are all 0xff) left out, so fill those in before converting.
+            let first = buf[0];
+            buf = if is_neg { [0xff;4] } else { [0;4] };
             self.src.read_exact(&mut buf[0..bytes_to_read])?;
-            let u_val = u32::from_le_bytes(buf[0..4].try_into()
-                .expect("Something is VERY wrong, maybe a hardware error.")) as i32;
-            
-            // Return the resulting value
-            if is_neg {
-                Ok(-u_val)
-            } else {
-                Ok(u_val)
+            let value = i32::from_le_bytes(buf);
+            if self.record_raw_ints && 1 + bytes_to_read != int_len(value) {
+                let mut raw = vec![first];
+                raw.extend_from_slice(&buf[0..bytes_to_read]);
+                self.raw_ints.insert(index, (value, raw));
             }
+            Ok(value)
         // General case of single-byte value
         } else {
             let b0 = buf[0] as i8;
@@ -201,96 +575,241 @@ impl<R> RbReader<R> where
 
     /// Parse a new symbol (no type byte)
     fn read_symbol(&mut self) -> TResult<RbAny> {
-        let symbol_len = self.read_int()? as usize;
-        let mut buf = vec![0; symbol_len];
-        self.src.read_exact(&mut buf)?;
-        self.symbols.push(RbSymbol::new(buf));
+        let symbol_len = self.read_len_max(RbType::Symbol, self.max_symbol_len)?;
+        let sym = if let Some(interner) = &mut self.interner {
+            // The name is read into a reused buffer, so a name the interner already has
+            // doesn't allocate. Like `read_vec`, don't allocate much up front for a length
+            // which came from the input.
+            let name = &mut self.symbol_buf;
+            if symbol_len <= MAX_RESERVE {
+                name.resize(symbol_len, 0);
+                self.src.read_exact(name)?;
+            } else {
+                *name = self.src.read_vec(symbol_len)?;
+            }
+            interner.intern(name)
+        } else {
+            match (&self.shared, self.src.position()) {
+                (Some(buf), Some(start)) => {
+                    self.src.skip(symbol_len)?;
+                    RbSymbol::from_shared(buf.clone(), start..(start + symbol_len))
+                },
+                _ => RbSymbol::new(self.src.read_vec(symbol_len)?),
+            }
+        };
+        // A name which isn't ASCII and isn't wrapped in an instance with an encoding (see
+        // `read_instance_entry`) is binary
+        let sym = if sym.as_bytes().is_ascii() { sym } else { sym.with_encoding(SymbolEncoding::Binary) };
+        if let Some(hook) = self.on_symbol {
+            hook(sym.as_bytes())?;
+        }
+        self.symbols.push(sym);
         Ok(RbAny::Symbol(self.symbols[self.symbols.len() - 1].clone()))
     }
 
     /// Parse a symbol reference (no type byte)
     fn read_symbol_ref(&mut self) -> TResult<RbAny> {
-        let symbol_index = self.read_int()? as usize;
-        if symbol_index < self.symbols.len() {
-            Ok(RbAny::Symbol(self.symbols[symbol_index].clone()))
-        } else {
-            Err(ThurgoodError::BadSymbolRef(symbol_index))
+        let symbol_index = self.read_int()?;
+        match usize::try_from(symbol_index).ok().and_then(|i| self.symbols.get(i)) {
+            Some(sym) => Ok(RbAny::Symbol(sym.clone())),
+            None => Err(ThurgoodError::BadSymbolRef(symbol_index)),
         }
     }
 
     /// Read the next entry (including type byte) and assert that it's a symbol.
     /// Returns a reference to the symbol instead of an RbAny.
     fn read_entry_symbol(&mut self) -> TResult<RbSymbol> {
+        let position = self.src.position();
         let r = self.read_entry()?;
         if let RbAny::Symbol(s) = r { 
             Ok(s)
         } else {
-            Err(ThurgoodError::UnexpectedType { expected: RbType::Symbol, found: r.get_type() })
+            Err(ThurgoodError::UnexpectedType { expected: RbType::Symbol, found: r.get_type(), position })
         }
     }
 
     fn read_object_ref(&mut self) -> TResult<RbAny> {
-        let index = self.read_int()? as usize;
+        let index = self.read_int()?;
+        if index < 0 {
+            return Err(ThurgoodError::BadObjectRef(index));
+        }
+        let index = index as usize;
+        if index == 0 && self.streaming_root {
+            return Err(ThurgoodError::ForwardObjectRef(index));
+        }
         if index < self.objects.len() {
             // println!("Object # {}", index);
             let base = &mut self.objects[index];
-            // If the base is nil, we need to make it an Rc and use unsafe hackery later to set the value
+            // If the base is nil the object has been started but not finished, so this is a
+            // recursive reference. We need to make it an Rc and use unsafe hackery later to set the value.
+            // The placeholder may become a hash key, so it's something which is hashed and compared
+            // by address (like any object that can contain itself), never equal to another key.
             if base.is_nil() {
-                *base = RbRef::from(1.0f32).into_any();
+                *base = RbRef::Array(Vec::new()).into_any();
             }
             Ok(base.clone())
         } else {
-            Err(ThurgoodError::BadObjectRef(index))
+            // Ruby only references objects which have already been started, so this stream is corrupt.
+            Err(ThurgoodError::ForwardObjectRef(index))
         }
     }
 
-    /// Parse and return an object/string/regex with extra fields.
-    /// It's important to note that instanced strings and regexes basically get added
-    /// to the object array TWICE.
-    fn read_instance(&mut self) -> TResult<RbRef> {
+    /// Read an instance (after the type byte), which is either a symbol with an encoding or
+    /// an object.
+    fn read_instance_entry(&mut self) -> TResult<RbAny> {
         let type_byte = self.read_byte()?;
+        if type_byte != T_SYMBOL {
+            let o_index = self.alloc_object();
+            let obj = self.read_instance(type_byte)?;
+            return Ok(self.set_object(o_index, obj));
+        }
+        // Symbols aren't objects, so this doesn't take up an object index, but the encoding
+        // name (if it's not just `E`) does.
+        let sym_index = self.symbols.len();
+        self.read_symbol()?;
+        let num_fields = self.read_len()?;
+        let pairs = self.read_pairs(num_fields)?;
+        let encoding = if self.is_utf8(&pairs) {
+            SymbolEncoding::Utf8
+        } else if let Some(RbAny::False) = pairs.get(&self.sym_e) {
+            SymbolEncoding::Named("US-ASCII".to_owned())
+        } else {
+            // The name is a plain (binary) string
+            let name = pairs.get(&self.sym_encoding)
+                .and_then(|v| v.as_bytes())
+                .and_then(|v| std::str::from_utf8(v).ok());
+            match name {
+                Some(name) => SymbolEncoding::Named(name.to_owned()),
+                None => SymbolEncoding::Binary,
+            }
+        };
+        let sym = self.symbols[sym_index].clone().with_encoding(encoding);
+        self.symbols[sym_index] = sym.clone();
+        Ok(RbAny::Symbol(sym))
+    }
+
+    /// Parse and return an object/string/regex with extra fields. Like any other object, this
+    /// only takes up one object index, which the caller is responsible for.
+    fn read_instance(&mut self, type_byte: u8) -> TResult<RbRef> {
         match type_byte {
+            T_USER_CLASS => {
+                // Ruby writes the `I` before the class name, e.g. `IC:\x0aMyStr"...` for a
+                // subclass of String, while the ivars come after the contents as usual.
+                let name = self.read_entry_symbol()?;
+                let type_byte = self.read_byte()?;
+                self.next_hash_by_identity = name.eq_str("Hash") && matches!(type_byte, T_HASH | T_HASH_DEFAULT);
+                let data = self.nested(|rd| rd.read_instance(type_byte))?;
+                Ok(RbRef::UserClass(RbClass { name, data: data.into_any() }))
+            },
             T_OBJECT => {
                 let mut obj = self.read_rb_object()?;
-                let num_pairs = self.read_int()? as usize;
+                let num_pairs = self.read_len()?;
                 let pairs = self.read_pairs(num_pairs)?;
                 // Append fields to object
                 obj.extend_from_pairs(&pairs)?;
                 Ok(RbRef::Object(obj))
             },
-            T_STRING => {
-                // Read the string data
-                let data = self.read_len_bytes()?;
-                // Gather extra pairs of data so we can confirm the string type
-                let num_fields = self.read_int()? as usize;
-                let pairs = self.read_pairs(num_fields)?;
-                let obj = if self.is_utf8(&pairs) {
-                    RbRef::Str(bytes_to_string(&data)?)
-                } else {
-                    RbRef::StrI { content: data, metadata: pairs }
-                };
-                Ok(obj)
-            },
-            T_REGEX => {
-                // Read the regex data
-                let data = self.read_len_bytes()?;
-                let flags = self.read_int()? as u32;
-                // Parse the remaining fields
-                let num_fields = self.read_int()? as usize;
-                let pairs = self.read_pairs(num_fields)?;
-                let obj = if self.is_utf8(&pairs) {
-                    RbRef::Regex { content: bytes_to_string(&data)?, flags }
-                } else {
-                    RbRef::RegexI { content: data, flags, metadata: pairs }
-                };
-                Ok(obj)
-            },
+            T_STRING => self.read_instance_string(),
+            T_REGEX => self.read_instance_regex(),
+            T_ARRAY | T_HASH | T_HASH_DEFAULT | T_USER_DEFINED => self.read_instance_ivars(type_byte),
             _ => {
                 Err(ThurgoodError::BadInstanceType(type_byte as char))
             }
         }
     }
 
+    // The cases of `read_instance` which have more than a few locals are separate functions,
+    // since nested user classes recurse through `read_instance` and each level takes up its
+    // whole stack frame.
+
+    fn read_instance_string(&mut self) -> TResult<RbRef> {
+        // Read the string data
+        let data = self.read_string_bytes()?;
+        let end = self.src.position();
+        // Gather extra pairs of data so we can confirm the string type
+        let num_fields = self.read_len()?;
+        let mut pairs = self.read_pairs(num_fields)?;
+        self.normalize_encoding(&mut pairs);
+        let obj = if self.is_utf8(&pairs) {
+            match String::from_utf8(data) {
+                Ok(s) => RbRef::Str(s),
+                Err(e) if self.allow_bin_strings => {
+                    RbRef::StrI { content: e.into_bytes(), metadata: pairs }
+                },
+                Err(e) => {
+                    let start = end.map(|end| end - e.as_bytes().len());
+                    return Err(ThurgoodError::invalid_utf8(e.as_bytes(), e.utf8_error(), start));
+                },
+            }
+        } else {
+            RbRef::StrI { content: data, metadata: pairs }
+        };
+        Ok(obj)
+    }
+
+    fn read_instance_regex(&mut self) -> TResult<RbRef> {
+        // Read the regex data
+        let data = self.read_len_bytes()?;
+        let end = self.src.position();
+        // Flags are a single raw byte, not an int, see `consts::RE_*`
+        let flags = self.read_byte()? as u32;
+        // Parse the remaining fields
+        let num_fields = self.read_len()?;
+        let mut pairs = self.read_pairs(num_fields)?;
+        self.normalize_encoding(&mut pairs);
+        let obj = if self.is_utf8(&pairs) {
+            RbRef::Regex { content: bytes_to_string(&data, end)?, flags }
+        } else {
+            RbRef::RegexI { content: data, flags, metadata: pairs }
+        };
+        Ok(obj)
+    }
+
+    fn read_instance_ivars(&mut self, type_byte: u8) -> TResult<RbRef> {
+        let object = match type_byte {
+            T_ARRAY => self.read_array()?,
+            T_HASH => self.read_hash(false)?,
+            T_HASH_DEFAULT => self.read_hash(true)?,
+            _ => self.read_user_defined()?,
+        };
+        let num_fields = self.read_len()?;
+        let mut ivars = self.read_pairs(num_fields)?;
+        let mut object = object;
+        let mut flagged = false;
+        // Ruby writes the ruby2_keywords flag of a hash as the first ivar
+        if let RbRef::Hash(hash) = &mut object {
+            if ivars.get_index(0) == Some((&self.sym_k, &RbAny::True)) {
+                ivars.shift_remove_index(0);
+                hash.ruby2_keywords = true;
+                flagged = true;
+            }
+        }
+        if flagged && ivars.is_empty() {
+            Ok(object)
+        } else {
+            Ok(RbRef::WithIvars { object: Box::new(object), ivars })
+        }
+    }
+
+    /// Replace an `encoding` ivar naming `UTF-8` or `US-ASCII` with `E`, which is how Ruby
+    /// writes those encodings itself, so such strings are read like any other UTF-8 or ASCII
+    /// string. Ruby reads both forms the same way, and writes them back as `E`.
+    fn normalize_encoding(&self, pairs: &mut RbFields) {
+        let flag = match pairs.get(&self.sym_encoding).and_then(|v| v.as_bytes()) {
+            Some(b"UTF-8") => RbAny::True,
+            Some(b"US-ASCII") => RbAny::False,
+            _ => return,
+        };
+        let old: Vec<_> = pairs.drain(..).collect();
+        for (key, val) in old {
+            if key == self.sym_encoding {
+                pairs.insert(self.sym_e.clone(), flag.clone());
+            } else {
+                pairs.insert(key, val);
+            }
+        }
+    }
+
     fn is_utf8(&self, pairs: &RbFields) -> bool {
         for it in pairs.iter() {
             if it.0 == &self.sym_e && it.1 == &RbAny::True {
@@ -300,31 +819,24 @@ impl<R> RbReader<R> where
         return false;
     }
 
-    /// Read a string (no specified encoding) from the data stream
+    /// Read a string with no encoding from the data stream. Ruby writes binary (`ASCII-8BIT`)
+    /// strings this way, so it may hold any bytes and is kept as a binary `StrI`.
     fn read_string(&mut self) -> TResult<RbRef> {
-        let data = self.read_len_bytes()?;
-        match std::str::from_utf8(&data) {
-            Ok(s) => Ok(RbRef::Str(s.to_owned())),
-            Err(e) => {
-                if self.allow_bin_strings {
-                    Ok(RbRef::StrI { content: data, metadata: RbFields::new() })
-                } else {
-                    Err(e.into())
-                }
-            }
-        }
+        let data = self.read_string_bytes()?;
+        Ok(RbRef::new_string_bin(data))
     }
 
-    /// Read `count` key-value pairs from the stream, storing them in and returning an RbHash.
-    /// The keys may be anything.
+    /// Read `count` fields (instance variables or struct members) from the stream. Unlike
+    /// hash keys, which are read by `read_hash`, field names must be symbols.
     fn read_pairs(&mut self, count: usize) -> TResult<RbFields> {
         let mut result = RbFields::new();
+        result.reserve(count.min(MAX_RESERVE));
         for _ in 0..count {
-            let key = self.read_entry()?;
-            let key_sym = key.as_symbol()
-                .ok_or_else(|| ThurgoodError::unexpected_type(RbType::Symbol, key.get_type()))?;
+            let key = self.read_entry_symbol()?;
             let val = self.read_entry()?;
-            result.insert(key_sym.clone(), val);
+            if result.insert(key.clone(), val).is_some() && self.strict_hash_keys {
+                return Err(ThurgoodError::DuplicateHashKey(format!("{:?}", key.as_any())));
+            }
         }
         return Ok(result);
     }
@@ -332,19 +844,25 @@ impl<R> RbReader<R> where
     /// Read, track, and return an array of values (no type byte)
     fn read_array(&mut self) -> TResult<RbRef> {
         // Read the data for real
-        let array_size = self.read_int()?;
-        let mut data = Vec::new();
+        let array_size = self.read_len()?;
+        let mut data = Vec::with_capacity(array_size.min(MAX_RESERVE));
         for _ in 0..array_size {
             data.push(self.read_entry()?);
         }
         Ok(RbRef::Array(data))
     }
 
+    /// Read the class name and raw bytes of a `_dump`ed object (no type byte)
+    fn read_user_defined(&mut self) -> TResult<RbRef> {
+        let name = self.read_entry_symbol()?;
+        let data = self.read_len_bytes()?;
+        Ok(RbRef::UserData(RbUserData { name, data }))
+    }
+
     fn read_bignum(&mut self) -> TResult<RbRef> {
         let c_sign = self.read_byte()? as char;
-        let data_len = self.read_int()? as usize * 2;
-        let mut buf = vec![0u8; data_len];
-        self.src.read_exact(&mut buf)?;
+        let data_len = self.read_len()? * 2;
+        let buf = self.src.read_vec(data_len)?;
         let v_sign = if c_sign == '+' { Sign::Plus } else { Sign::Minus };
         // return the object
         Ok(RbRef::BigInt(BigInt::from_bytes_le(v_sign, &buf)))
@@ -354,13 +872,31 @@ impl<R> RbReader<R> where
     /// This is a helper function for the many things that are formatted the same.
     fn read_rb_class(&mut self) -> TResult<RbClass> {
         let name = self.read_entry_symbol()?;
-        let data = self.read_entry()?;
-        Ok(RbClass { name, data })
+        let mark = if self.lenient_user_data { self.src.begin_record() } else { None };
+        let mark = match mark {
+            Some(mark) => mark,
+            None => return Ok(RbClass { name, data: self.read_entry()? }),
+        };
+        let (n_symbols, n_objects) = (self.symbols.len(), self.objects.len());
+        let result = match self.read_entry() {
+            Err(e) if is_format_error(&e) => self.src.take_rest_since(mark).map(|raw| {
+                // Nothing can refer to what was read of the contents, since the rest of the
+                // input is part of them
+                self.symbols.truncate(n_symbols);
+                self.objects.truncate(n_objects);
+                self.pending_raw_float = None;
+                self.undecoded_class = Some(name.clone());
+                RbClass::new_undecoded(name, raw)
+            }),
+            r => r.map(|data| RbClass { name, data }),
+        };
+        self.src.end_record();
+        result
     }
 
     fn read_class_mod_ref(&mut self) -> TResult<String> {
         let buf = self.read_len_bytes()?;
-        Ok(bytes_to_string(&buf)?)
+        bytes_to_string(&buf, self.src.position())
     }
 
     fn read_float(&mut self) -> TResult<f64> {
@@ -368,7 +904,12 @@ impl<R> RbReader<R> where
         // Apparently this CAN be a C string, so we need to check for a NULL terminator.
         // Default to the buffer length.
         let last = buf.iter().position(|e| *e == 0).unwrap_or(buf.len());
-        let decoded = std::str::from_utf8(&buf[0..last])?;
+        if self.record_raw_floats {
+            self.pending_raw_float = Some(buf[0..last].to_vec());
+        }
+        let start = self.src.position().map(|end| end - buf.len());
+        let decoded = std::str::from_utf8(&buf[0..last])
+            .map_err(|e| ThurgoodError::invalid_utf8(&buf, e, start))?;
         match decoded {
             "inf" => Ok(f64::INFINITY),
             "-inf" => Ok(f64::NEG_INFINITY),
@@ -380,12 +921,22 @@ impl<R> RbReader<R> where
     /// Read a hash from the stream (no type byte). If `has_default` is true then read
     /// an additional default value from the stream.
     fn read_hash(&mut self, has_default: bool) -> TResult<RbRef> {
+        let by_identity = std::mem::take(&mut self.next_hash_by_identity);
         // Read the hash
-        let num_pairs = self.read_int()? as usize;
+        let num_pairs = self.read_len()?;
         let mut nhash = RbHash::new();
+        nhash.compare_by_identity = by_identity;
+        nhash.reserve(num_pairs.min(MAX_RESERVE));
+        // Keys are compared by their serialized form, since `RbAny` compares references by
+        // pointer while Ruby compares hash keys by value.
+        let mut key_bytes = HashSet::new();
         for _ in 0..num_pairs {
             let key = self.read_entry()?;
             let val = self.read_entry()?;
+            // Keys of an identity hash may be equal as long as they're separate objects
+            if self.strict_hash_keys && !by_identity && !key_bytes.insert(to_vec(&key)?) {
+                return Err(ThurgoodError::DuplicateHashKey(format!("{:?}", key)));
+            }
             nhash.insert(key, val);
         }
         if has_default {
@@ -398,37 +949,122 @@ impl<R> RbReader<R> where
     /// Read a regex assuming UTF-8 / ASCII encoding.
     fn read_regex(&mut self) -> TResult<RbRef> {
         let content = self.read_len_bytes()?;
+        let end = self.src.position();
         let flags = self.read_byte()? as u32;
         // Track and return object
-        Ok(RbRef::Regex { content: bytes_to_string(&content)?, flags })
+        Ok(RbRef::Regex { content: bytes_to_string(&content, end)?, flags })
+    }
+
+    /// Read a length or count, which must not be negative.
+    fn read_len(&mut self) -> TResult<usize> {
+        let len = self.read_int()?;
+        usize::try_from(len).map_err(|_| ThurgoodError::BadLength(len))
     }
 
     /// Read a variable-sized integer, then read that number of bytes and return it as a Vec<u8>
     fn read_len_bytes(&mut self) -> TResult<Vec<u8>> {
-        let str_len = self.read_int()? as usize;
-        let mut buf = vec![0u8; str_len];
-        self.src.read_exact(&mut buf)?;
-        Ok(buf)
+        let str_len = self.read_len()?;
+        self.src.read_vec(str_len)
+    }
+
+    /// Read a length, which must not be more than `max` if it's set. `kind` is what the
+    /// length is for, to report in the error.
+    fn read_len_max(&mut self, kind: RbType, max: Option<usize>) -> TResult<usize> {
+        let position = self.src.position();
+        let len = self.read_len()?;
+        match max {
+            Some(max) if len > max => Err(ThurgoodError::TooLong { kind, len, max, position }),
+            _ => Ok(len),
+        }
+    }
+
+    /// Like `read_len_bytes`, but checks `max_string_len`.
+    fn read_string_bytes(&mut self) -> TResult<Vec<u8>> {
+        let str_len = self.read_len_max(RbType::Str, self.max_string_len)?;
+        self.src.read_vec(str_len)
     }
 
     fn read_rb_object(&mut self) -> TResult<RbObject> {
         let name = self.read_entry_symbol()?;
-        let pair_count = self.read_int()? as usize;
+        let pair_count = self.read_len()?;
         let fields = self.read_pairs(pair_count)?;
         let mut obj = RbObject::new(&name);
         obj.extend_from_pairs(&fields)?;
         Ok(obj)
     }
 
+    /// Read a subclass of String, Regexp, Array, or Hash (no type byte). Unlike `Data` and
+    /// `UserMarshal`, the contents are the object itself rather than a separate object, so
+    /// they don't get an object index of their own.
     fn read_user_class(&mut self) -> TResult<RbRef> {
-        Ok(RbRef::UserClass(self.read_rb_class()?))
+        let name = self.read_entry_symbol()?;
+        let tag = self.read_tag()?;
+        self.next_hash_by_identity = name.eq_str("Hash") && matches!(tag, MarshalTag::Hash | MarshalTag::HashDefault);
+        let data = self.nested(|rd| rd.read_ref_body(tag))?;
+        Ok(RbRef::UserClass(RbClass { name, data: data.into_any() }))
     }
 
     /// Read a string byte from the stream. Convenience method.
     fn read_byte(&mut self) -> TResult<u8> {
-        let mut buf = [0u8; 1];
-        self.src.read_exact(&mut buf)?;
-        Ok(buf[0])
+        self.src.read_byte()
+    }
+}
+
+/// Returns true if `e` means the input isn't valid Marshal data, rather than a limit being
+/// reached or the input being cut short, see `RbReader::lenient_user_data`.
+fn is_format_error(e: &ThurgoodError) -> bool {
+    matches!(e, ThurgoodError::Utf8 { .. } | ThurgoodError::ParseFloat(_) | ThurgoodError::BadSymbolRef(_)
+        | ThurgoodError::BadObjectRef(_) | ThurgoodError::ForwardObjectRef(_) | ThurgoodError::BadLength(_)
+        | ThurgoodError::BadInstanceType(_) | ThurgoodError::UnexpectedType { .. }
+        | ThurgoodError::BadTypeByte { .. } | ThurgoodError::DuplicateHashKey(_)
+        | ThurgoodError::UndecodedNotLast(_))
+}
+
+/// Iterator returned by `RbReader::array_elements`.
+struct ArrayElements<R> {
+    reader: RbReader<R>,
+    remaining: usize,
+    /// An error reading the start of the document, which is returned first.
+    error: Option<ThurgoodError>,
+}
+
+impl<R: ByteSource> Iterator for ArrayElements<R> {
+    type Item = TResult<RbAny>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let result = self.reader.read_entry();
+        if result.is_err() {
+            // The position in the stream is unknown, so nothing after this can be read
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.remaining + self.error.is_some() as usize;
+        (n, Some(n))
+    }
+}
+
+/// Guess what kind of data starts with `magic` when it isn't Marshal data, to give a more
+/// helpful error than an unsupported version. This only looks at the first two bytes, so
+/// it's a best guess.
+fn not_marshal_hint(magic: [u8; 2]) -> Option<&'static str> {
+    match magic {
+        [0x1f, 0x8b] => Some("input looks gzip-compressed"),
+        // The second byte of a zlib header makes the first two a multiple of 31
+        [0x78, flags] if (0x7800 | flags as u16).is_multiple_of(31) => Some("input looks zlib-compressed"),
+        // "\x04\x08" encodes as "BAg"
+        [b'B', b'A'] => Some("input looks like base64-encoded Marshal data"),
+        [b'0', b'4'] => Some("input looks like hex-encoded Marshal data"),
+        _ => None,
     }
 }
 
@@ -440,3 +1076,46 @@ pub fn from_reader<R: io::Read>(src: R) -> TResult<RbAny> {
     let mut de = RbReader::new(src);
     de.read()
 }
+
+/// Deserialize an `RbAny` from a shared buffer.
+/// 
+/// Symbols in the result refer to `src` directly instead of copying their names, which
+/// saves an allocation for each unique symbol. Each of those symbols holds a reference to
+/// the whole buffer, so it stays alive for as long as any symbol from it does, including
+/// symbols copied out of the result into other data. Call `RbAny::detach_symbols` on the
+/// result (or `RbSymbol::detached` on a single symbol) before keeping it around after the
+/// rest of the input is no longer needed.
+pub fn from_bytes_shared(src: &RcType<[u8]>) -> TResult<RbAny> {
+    let mut de = RbReader::from_shared(src);
+    de.read()
+}
+
+/// Deserialize an `RbAny` from the file at `path`.
+///
+/// The reader makes many small reads, so the file is read through a `BufReader`.
+pub fn from_file<P: AsRef<Path>>(path: P) -> TResult<RbAny> {
+    from_reader(io::BufReader::new(File::open(path)?))
+}
+
+/// Deserialize an `RbAny` from a slice of bytes.
+/// 
+/// This is equivalent to `from_reader` but reads directly from the slice.
+pub fn from_bytes(src: &[u8]) -> TResult<RbAny> {
+    let mut de = RbReader::from_slice(src);
+    de.read()
+}
+
+/// How deeply `try_parse` allows values to be nested.
+pub const TRY_PARSE_MAX_DEPTH: usize = 256;
+
+/// Deserialize an `RbAny` from a slice of bytes, for input which can't be trusted.
+///
+/// This returns an error instead of panicking, overflowing the stack, or allocating memory
+/// out of proportion to the input, no matter what `src` contains, which also makes it a good
+/// fuzzing target. It's `from_bytes` with values nested more than `TRY_PARSE_MAX_DEPTH` levels
+/// deep rejected, so some valid (very deeply nested) data can only be read by `from_bytes`.
+pub fn try_parse(src: &[u8]) -> TResult<RbAny> {
+    let mut de = RbReader::from_slice(src);
+    de.max_depth = Some(TRY_PARSE_MAX_DEPTH);
+    de.read()
+}
@@ -1,4 +1,5 @@
 use super::*;
+use std::collections::HashSet;
 use std::fmt::{self, Write};
 use std::io;
 
@@ -137,3 +138,318 @@ pub fn dump_ruby_pretty<W: io::Write>(mut dst: W, root: &RbAny, max_depth: usize
     let d = DumperWrap { root, max_depth };
     write!(dst, "{}", d)
 }
+
+/// Options for `inspect_pretty`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InspectOptions {
+    /// Containers nested deeper than this are written as `[...]`, `{...}`, or `#<Name ...>`.
+    pub max_depth: usize,
+    /// Number of spaces to indent each level by.
+    pub indent: usize,
+    /// Write everything on one line like Ruby's `inspect`, e.g. `[1, 2]` or `#<Foo @a=1>`,
+    /// ignoring `indent`.
+    pub single_line: bool,
+}
+
+impl Default for InspectOptions {
+    fn default() -> Self {
+        Self { max_depth: 16, indent: 2, single_line: false }
+    }
+}
+
+/// Write `root` as Ruby literal syntax, one element per line, e.g. for snapshot tests.
+///
+/// Arrays, hashes, strings, symbols, numbers, and regexes are written as Ruby literals. Objects
+/// and other values without a literal form use Ruby's `inspect` style, e.g. `#<Foo @a=1>`.
+/// Shared values are written in full each time they appear, while recursive references and
+/// anything deeper than `opts.max_depth` are abbreviated like Ruby does, e.g. `[...]`.
+pub fn inspect_pretty<W: io::Write>(mut dst: W, root: &RbAny, opts: &InspectOptions) -> io::Result<()> {
+    let mut insp = Inspector { dst: &mut dst, opts, ancestors: HashSet::new() };
+    insp.any(root, 0)
+}
+
+/// Returns `root` written on one line like Ruby's `inspect`, cut off after `max_len`
+/// characters with `...` appended, see `RbAny::preview`.
+///
+/// Writing stops as soon as the limit is reached, so this is cheap even for huge values.
+pub fn preview(root: &RbAny, max_len: usize) -> String {
+    let opts = InspectOptions { single_line: true, ..Default::default() };
+    let mut dst = Budget { out: Vec::new(), chars: 0, max_len, full: false };
+    // The only error is running out of space
+    let _ = inspect_pretty(&mut dst, root, &opts);
+    let mut text = String::from_utf8(dst.out)
+        .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+    if dst.full {
+        text.push_str("...");
+    }
+    text
+}
+
+/// Collects at most `max_len` characters, then fails so the `Inspector` stops early.
+struct Budget {
+    out: Vec<u8>,
+    chars: usize,
+    max_len: usize,
+    full: bool,
+}
+
+impl io::Write for Budget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (i, &b) in buf.iter().enumerate() {
+            // Count the first byte of each UTF-8 sequence
+            if b & 0xC0 != 0x80 {
+                if self.chars == self.max_len {
+                    self.full = true;
+                    if i == 0 {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "preview is full"));
+                    }
+                    return Ok(i);
+                }
+                self.chars += 1;
+            }
+            self.out.push(b);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Inspector<'a, W> {
+    dst: &'a mut W,
+    opts: &'a InspectOptions,
+    ancestors: HashSet<*const RbRef>,
+}
+
+impl<'a, W: io::Write> Inspector<'a, W> {
+    fn any(&mut self, value: &RbAny, depth: usize) -> io::Result<()> {
+        match value {
+            RbAny::Int(v) => write!(self.dst, "{}", v),
+            RbAny::True => write!(self.dst, "true"),
+            RbAny::False => write!(self.dst, "false"),
+            RbAny::Nil => write!(self.dst, "nil"),
+            RbAny::Symbol(v) => self.symbol(v),
+            RbAny::Ref(r) => {
+                let ptr = rc_get_ptr(r);
+                if r.contains_ref() && (depth >= self.opts.max_depth || self.ancestors.contains(&ptr)) {
+                    return self.abbreviated(r);
+                }
+                self.ancestors.insert(ptr);
+                let result = self.rbref(r, depth);
+                self.ancestors.remove(&ptr);
+                result
+            },
+        }
+    }
+
+    fn rbref(&mut self, value: &RbRef, depth: usize) -> io::Result<()> {
+        match value {
+            RbRef::Float(v) => {
+                if v.0.is_nan() {
+                    write!(self.dst, "Float::NAN")
+                } else if v.0.is_infinite() {
+                    write!(self.dst, "{}Float::INFINITY", if v.0 < 0.0 { "-" } else { "" })
+                } else {
+                    write!(self.dst, "{:?}", v.0)
+                }
+            },
+            RbRef::BigInt(v) => write!(self.dst, "{}", v),
+            RbRef::Str(v) => self.string(v.as_bytes()),
+            RbRef::StrI { content, .. } => self.string(content),
+            RbRef::Regex { content, flags } => self.regex(content.as_bytes(), *flags),
+            RbRef::RegexI { content, flags, .. } => self.regex(content, *flags),
+            RbRef::Array(v) => {
+                if v.is_empty() {
+                    return write!(self.dst, "[]");
+                }
+                write!(self.dst, "[")?;
+                self.list(v.iter(), depth, |insp, it, depth| insp.any(it, depth))?;
+                write!(self.dst, "]")
+            },
+            RbRef::Hash(v) => {
+                if let Some(def) = &v.default {
+                    write!(self.dst, "Hash.new(")?;
+                    self.any(def, depth)?;
+                    write!(self.dst, ").merge!(")?;
+                }
+                if v.is_empty() {
+                    write!(self.dst, "{{}}")?;
+                } else {
+                    write!(self.dst, "{{")?;
+                    self.list(v.iter(), depth, |insp, (key, val), depth| {
+                        insp.any(key, depth)?;
+                        write!(insp.dst, " => ")?;
+                        insp.any(val, depth)
+                    })?;
+                    write!(self.dst, "}}")?;
+                }
+                if v.default.is_some() {
+                    write!(self.dst, ")")?;
+                }
+                Ok(())
+            },
+            RbRef::Object(v) => {
+                write!(self.dst, "#<{}", sym_name(&v.name))?;
+                self.fields(&v.fields, depth)?;
+                write!(self.dst, ">")
+            },
+            RbRef::Struct(v) => {
+                write!(self.dst, "#<struct {}", sym_name(&v.name))?;
+                self.fields(&v.fields, depth)?;
+                write!(self.dst, ">")
+            },
+            RbRef::ClassRef(v) | RbRef::ModuleRef(v) | RbRef::ClassModuleRef(v) => write!(self.dst, "{}", v),
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+                write!(self.dst, "#<{} ", sym_name(&v.name))?;
+                self.any(&v.data, depth)?;
+                write!(self.dst, ">")
+            },
+            RbRef::UserData(v) => {
+                write!(self.dst, "#<{} _dump=", sym_name(&v.name))?;
+                self.string(&v.data)?;
+                write!(self.dst, ">")
+            },
+            RbRef::Extended { module, object } => {
+                self.any(object, depth)?;
+                write!(self.dst, ".extend({})", sym_name(module))
+            },
+            RbRef::WithIvars { object, ivars } => {
+                write!(self.dst, "#<{} ", object.class_name().unwrap_or("?"))?;
+                self.rbref(object, depth)?;
+                self.fields(ivars, depth)?;
+                write!(self.dst, ">")
+            },
+        }
+    }
+
+    /// Write each item on its own line, separated by commas, indented one level past `depth`.
+    fn list<I, T, F>(&mut self, items: I, depth: usize, mut f: F) -> io::Result<()>
+        where I: Iterator<Item = T>, F: FnMut(&mut Self, T, usize) -> io::Result<()>
+    {
+        if self.opts.single_line {
+            for (i, it) in items.enumerate() {
+                if i > 0 {
+                    write!(self.dst, ", ")?;
+                }
+                f(self, it, depth + 1)?;
+            }
+            return Ok(());
+        }
+        for (i, it) in items.enumerate() {
+            writeln!(self.dst, "{}", if i > 0 { "," } else { "" })?;
+            self.indent(depth + 1)?;
+            f(self, it, depth + 1)?;
+        }
+        writeln!(self.dst)?;
+        self.indent(depth)
+    }
+
+    fn fields(&mut self, fields: &RbFields, depth: usize) -> io::Result<()> {
+        if fields.is_empty() {
+            return Ok(());
+        }
+        if self.opts.single_line {
+            write!(self.dst, " ")?;
+        }
+        self.list(fields.iter(), depth, |insp, (key, val), depth| {
+            write!(insp.dst, "{}=", sym_name(key))?;
+            insp.any(val, depth)
+        })
+    }
+
+    fn abbreviated(&mut self, value: &RbRef) -> io::Result<()> {
+        match value {
+            RbRef::Array(_) => write!(self.dst, "[...]"),
+            RbRef::Hash(_) => write!(self.dst, "{{...}}"),
+            _ => write!(self.dst, "#<{} ...>", value.class_name().unwrap_or("?")),
+        }
+    }
+
+    fn indent(&mut self, depth: usize) -> io::Result<()> {
+        write!(self.dst, "{:width$}", "", width = depth * self.opts.indent)
+    }
+
+    fn symbol(&mut self, sym: &RbSymbol) -> io::Result<()> {
+        let plain = match sym.as_str() {
+            Some(s) => is_plain_symbol(s),
+            None => false,
+        };
+        if plain {
+            write!(self.dst, ":{}", sym_name(sym))
+        } else {
+            write!(self.dst, ":")?;
+            self.string(sym.as_bytes())
+        }
+    }
+
+    fn string(&mut self, data: &[u8]) -> io::Result<()> {
+        write!(self.dst, "\"{}\"", escape_ruby(data, b'"'))
+    }
+
+    fn regex(&mut self, data: &[u8], flags: u32) -> io::Result<()> {
+        write!(self.dst, "/{}/", escape_ruby(data, b'/'))?;
+        for (bit, c) in [(1, 'i'), (4, 'm'), (2, 'x')].iter() {
+            if flags & bit != 0 {
+                write!(self.dst, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sym_name(sym: &RbSymbol) -> String {
+    String::from_utf8_lossy(sym.as_bytes()).into_owned()
+}
+
+/// Returns true if `:name` is a valid symbol literal without quotes.
+fn is_plain_symbol(name: &str) -> bool {
+    let ident = name.trim_start_matches("@@").trim_start_matches('@').trim_start_matches('$');
+    let ident = ident.strip_suffix(|c| c == '?' || c == '!' || c == '=').unwrap_or(ident);
+    let mut chars = ident.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+/// Escape `data` for use inside a Ruby string or regex literal delimited by `delim`.
+fn escape_ruby(data: &[u8], delim: u8) -> String {
+    let text = String::from_utf8_lossy(data);
+    let valid = matches!(text, std::borrow::Cow::Borrowed(_));
+    let mut out = String::new();
+    if !valid {
+        // Not UTF-8, so escape every byte which isn't printable ASCII
+        for &b in data {
+            match b {
+                b' '..=b'~' => push_escaped(&mut out, b as char, delim, None),
+                _ => out.push_str(&format!("\\x{:02X}", b)),
+            }
+        }
+        return out;
+    }
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        push_escaped(&mut out, c, delim, chars.peek().copied());
+    }
+    out
+}
+
+fn push_escaped(out: &mut String, c: char, delim: u8, next: Option<char>) {
+    match c {
+        '\\' => out.push_str("\\\\"),
+        '\n' => out.push_str("\\n"),
+        '\t' => out.push_str("\\t"),
+        '\r' => out.push_str("\\r"),
+        '\x1B' => out.push_str("\\e"),
+        // Avoid interpolation
+        '#' if matches!(next, Some('{') | Some('$') | Some('@')) => out.push_str("\\#"),
+        _ if c as u32 == delim as u32 => {
+            out.push('\\');
+            out.push(c);
+        },
+        _ if c.is_control() => out.push_str(&format!("\\x{:02X}", c as u32)),
+        _ => out.push(c),
+    }
+}
@@ -1,10 +1,20 @@
 mod rb_any;
 mod rb_compare;
+mod rb_date;
+mod rb_debug;
+mod rb_diff;
 mod rb_ref;
 mod rb_hash;
 mod rb_float;
 mod rb_misc;
 mod rb_object;
+mod rb_patch;
+mod rb_path;
+mod rb_regex;
+mod rb_set;
+mod rb_stream;
+mod rb_validate;
+mod rb_walk;
 mod helper;
 mod deserialize;
 mod serialize;
@@ -12,18 +22,35 @@ pub mod dump;
 
 #[cfg(feature = "json")]
 mod rb_json;
+#[cfg(feature = "yaml")]
+mod rb_yaml;
+#[cfg(feature = "encodings")]
+mod rb_encoding;
+#[cfg(feature = "digest")]
+mod rb_digest;
 
 // This is so we can safely define the ref type in the parent module
 pub use super::{RcType, rc_get_ptr};
 
-pub use rb_any::RbAny;
+pub use rb_any::{NormalizeInts, PruneNil, RbAny};
+pub use rb_compare::{RbCompare, RbCompareOptions};
+pub use rb_date::{RbDate, RbDateTime, DATE_ITALY};
+pub use rb_diff::DiffEntry;
 pub use rb_float::RbFloat;
-pub use rb_hash::RbHash;
-pub use rb_misc::{RbClass, RbFields, RbSymbol, RbUserData};
+pub use rb_hash::{DuplicateKeys, RbHash};
+#[cfg(feature = "json")]
+pub use rb_json::{PlainJsonObjects, RbToJson};
+pub use rb_misc::{RbClass, RbFields, RbSymbol, RbUserData, SymbolEncoding, SymbolInterner, UserDataHandler, UserDataRegistry};
 pub use rb_ref::RbRef;
-pub use rb_object::RbObject;
-pub use serialize::{to_writer, RbWriter};
-pub use deserialize::{from_reader, RbReader};
+pub use rb_object::{RbObject, RbObjectBuilder};
+pub use rb_patch::{PatchOp, RbPatch};
+pub use rb_path::RbPath;
+pub use rb_regex::RbRegexInfo;
+pub use rb_set::RbSet;
+pub use rb_stream::RbStreamWriter;
+pub use rb_walk::NodeInfo;
+pub use serialize::{serialized_len, to_file, to_slice, to_vec, to_writer, ObjectHook, RawInts, RbWriter, RbWriterOptions};
+pub use deserialize::{from_bytes, from_bytes_shared, from_file, from_reader, try_parse, RbReader, SymbolHook, TRY_PARSE_MAX_DEPTH};
 
 // Re-export error type for convenience
 pub use crate::error::ThurgoodError as Error;
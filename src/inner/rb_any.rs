@@ -1,6 +1,12 @@
-use std::{cmp::Ordering, fmt, hash::{Hash, Hasher}};
-use super::{RbHash, RbObject, RbRef, RbSymbol, RcType, rb_compare::RbCompare, rc_get_ptr};
-use crate::RbType;
+use std::{cmp::Ordering, collections::HashMap, convert::TryFrom, fmt, hash::{Hash, Hasher}, io, ops::ControlFlow};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use super::{RbFields, RbHash, RbObject, RbPatch, RbPath, RbRef, RbSymbol, RbUserData, RbWriter, RcType, rb_compare::RbCompare, RbCompareOptions, rc_get_ptr};
+use super::rb_walk::{NodeInfo, RbCollectUserData, RbCopy, RbExplode, RbFlatten, RbNodes, RbReplaceNode, RbShape, RbWalk, RbWalkMut};
+use super::rb_debug::RbDebug;
+use super::rb_diff::{DiffEntry, RbDiff};
+use super::rb_validate::RbValidate;
+use crate::{RbType, TResult, ThurgoodError};
 use std::fmt::Formatter;
 
 macro_rules! match_opt {
@@ -9,8 +15,34 @@ macro_rules! match_opt {
     };
 }
 
+/// The smallest integer Ruby writes as a fixnum rather than a bignum.
+const FIXNUM_MIN: i32 = -(1 << 30);
+/// The largest integer Ruby writes as a fixnum rather than a bignum.
+const FIXNUM_MAX: i32 = (1 << 30) - 1;
+
+/// Which form `RbAny::normalize_ints` converts integers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalizeInts {
+    /// Convert `BigInt`s which fit in an `Int` to `Int`.
+    Demote,
+    /// Convert `Int`s outside of Ruby's fixnum range to `BigInt`.
+    Promote,
+}
+
+/// What `RbAny::prune_nil` removes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruneNil {
+    /// Remove object fields and hash entries which are `nil`.
+    NilOnly,
+    /// Also remove object fields and hash entries which are empty arrays or hashes (see
+    /// `is_empty_container`), including those which only become empty once pruned.
+    NilAndEmpty,
+}
+
 /// Represents any valid Ruby value.
 /// 
+/// Note that `RbAny::from("x")` (and `"x".into()`) produces a String, not a Symbol. Use
+/// `RbAny::sym("x")` for a Symbol, and `RbAny::string("x")` to make the intent explicit.
 #[derive(Clone, Eq, PartialOrd, Ord)]
 pub enum RbAny {
     Int(i32),
@@ -27,6 +59,40 @@ impl RbAny {
         RbAny::Symbol(RbSymbol::new(bytes))
     }
 
+    /// Construct a Symbol, e.g. `RbAny::sym("name")` for `:name`.
+    pub fn sym<S: Into<RbSymbol>>(name: S) -> RbAny {
+        RbAny::Symbol(name.into())
+    }
+
+    /// Construct a String. This is the same as `RbAny::from`, but reads unambiguously next
+    /// to `RbAny::sym`.
+    pub fn string<S: Into<String>>(content: S) -> RbAny {
+        RbAny::from(content.into())
+    }
+
+    /// Construct an Array from anything which converts into `RbAny`, e.g.
+    /// `RbAny::array_of([1, 2, 3])` or `RbAny::array_of(vec!["a", "b"])`.
+    pub fn array_of<I, T>(elements: I) -> RbAny
+    where I: IntoIterator<Item = T>, T: Into<RbAny> {
+        RbAny::from(elements.into_iter().map(Into::into).collect::<Vec<RbAny>>())
+    }
+
+    /// Construct a Hash from key-value pairs which convert into `RbAny`, e.g.
+    /// `RbAny::hash_of([("a", 1), ("b", 2)])`. Later duplicate keys replace earlier ones, though
+    /// like `RbHash::insert`, strings and other references are only the same key if they're
+    /// the same reference.
+    pub fn hash_of<I, K, V>(pairs: I) -> RbAny
+    where I: IntoIterator<Item = (K, V)>, K: Into<RbAny>, V: Into<RbAny> {
+        let pairs = pairs.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+        RbAny::from(RbHash::from_pairs(pairs))
+    }
+
+    /// Returns this value extended with `module`, like Ruby's `extend`. Calls may be chained,
+    /// e.g. `v.extend_with("A").extend_with("B")` matches `v.extend(A); v.extend(B)`.
+    pub fn extend_with<M: Into<RbSymbol>>(self, module: M) -> RbAny {
+        RbRef::new_extended(module, self).into_any()
+    }
+
     /// Returns the generic type of the Ruby object.
     pub fn get_type(&self) -> RbType {
         match self {
@@ -38,6 +104,25 @@ impl RbAny {
         }
     }
 
+    /// Returns the name of this value's Ruby class, see `RbRef::class_name`.
+    pub fn class_name(&self) -> Option<&str> {
+        match self {
+            RbAny::Int(_) => Some("Integer"),
+            RbAny::True => Some("TrueClass"),
+            RbAny::False => Some("FalseClass"),
+            RbAny::Nil => Some("NilClass"),
+            RbAny::Symbol(_) => Some("Symbol"),
+            RbAny::Ref(r) => r.class_name(),
+        }
+    }
+
+    /// Returns true if this value's class is exactly `name`, e.g. `"Foo"` for an object of
+    /// class `Foo` or `"Array"` for an array. Unlike Ruby's `is_a?` superclasses and included
+    /// modules aren't considered, since they aren't stored in Marshal data.
+    pub fn is_a(&self, name: &str) -> bool {
+        self.class_name() == Some(name)
+    }
+
     /// Returns true if this RbAny is Nil.
     pub fn is_nil(&self) -> bool {
         match self { Self::Nil => true, _ => false }
@@ -48,6 +133,22 @@ impl RbAny {
         match_opt!(self { RbAny::Int(v) => *v })
     }
 
+    /// If `Any` is a number (an `Int`, `BigInt`, or `Float`) returns it as an `f64`, otherwise
+    /// returns None. See `RbRef::as_float` to only accept floats.
+    ///
+    /// `BigInt`s beyond 2**53 are rounded to the nearest `f64`, and ones too large for an `f64`
+    /// become infinity.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            RbAny::Int(v) => Some(*v as f64),
+            _ => match self.as_rbref()? {
+                RbRef::BigInt(v) => v.to_f64(),
+                RbRef::Float(v) => Some(v.0),
+                _ => None,
+            },
+        }
+    }
+
     /// If `Any` is a boolean, returns the value, otherwise returns None.
     pub fn as_bool(&self) -> Option<bool> {
         match self {
@@ -57,11 +158,22 @@ impl RbAny {
         }
     }
 
+    /// Returns false for `Nil` and `False` and true for everything else, like a Ruby condition.
+    /// Unlike in many languages `0` and `""` are truthy. See `as_bool` for a strict conversion.
+    pub fn truthy(&self) -> bool {
+        !matches!(self, RbAny::Nil | RbAny::False)
+    }
+
     /// If `Any` is a Symbol, returns the symbol, otherwise returns None.
     pub fn as_symbol(&self) -> Option<&RbSymbol> {
         match self { Self::Symbol(r) => Some(r), _ => None }
     }
 
+    /// If `Any` is a Symbol with a UTF-8 name, returns the name, otherwise returns None.
+    pub fn symbol_str(&self) -> Option<&str> {
+        self.as_symbol()?.as_str()
+    }
+
     /// If `Any` is an object reference, returns a reference to it `RbRef`, otherwise returns None.
     pub fn as_rbref(&self) -> Option<&RbRef> {
         match self { RbAny::Ref(ref r) => Some(r), _ => None }
@@ -72,6 +184,78 @@ impl RbAny {
         match self { RbAny::Ref(ref mut r) => RcType::get_mut(r), _ => None }
     }
 
+    /// If `Any` is an object reference, returns a mutable reference to it, cloning the object
+    /// first if it's shared. Other references to the original object are unaffected, they
+    /// keep pointing to the original rather than the copy this now refers to.
+    pub fn make_mut(&mut self) -> Option<&mut RbRef> {
+        match self { RbAny::Ref(ref mut r) => Some(RcType::make_mut(r)), _ => None }
+    }
+
+    /// Replace this value with `new`, returning the old value.
+    pub fn replace(&mut self, new: RbAny) -> RbAny {
+        std::mem::replace(self, new)
+    }
+
+    /// Replace this value with `Nil`, returning the old value.
+    pub fn take(&mut self) -> RbAny {
+        self.replace(RbAny::Nil)
+    }
+
+    /// Replace the child at `key` (see `get`) with `new`, returning the old child, or None if
+    /// there's no such child.
+    ///
+    /// If this container is shared it's cloned first (see `make_mut`), so aliases of it, both
+    /// within this tree and outside of it, still see the old child. Children of `Data`,
+    /// `UserClass`, `UserMarshal`, and `Extended` values are only replaced if the wrapped
+    /// value isn't shared, see `RbRef::get_child_mut`.
+    pub fn replace_child<K: Into<RbAny>>(&mut self, key: K, new: RbAny) -> Option<RbAny> {
+        let child = self.make_mut()?.get_child_mut(&key.into())?;
+        Some(child.replace(new))
+    }
+
+    /// Replace the value at `path` (see `find_child`) with `new`, returning the old value, or
+    /// None if there's no such value. An empty path replaces this value itself.
+    ///
+    /// Like `replace_child`, shared containers along the path are cloned first, so other
+    /// references to them (within this tree or outside of it) still see the old value.
+    pub fn replace_at<'a, I>(&mut self, path: I, new: RbAny) -> Option<RbAny>
+        where I: IntoIterator<Item=&'a RbAny>
+    {
+        let mut current = self;
+        for key in path {
+            current = current.make_mut()?.get_child_mut(key)?;
+        }
+        Some(current.replace(new))
+    }
+
+    /// Replace every reference to the same object as `target` (see `pointer_id`) in this tree
+    /// with `replacement`, returning the number of references replaced. Values which are merely
+    /// equal to `target` are left alone, see `walk_mut` for replacing by value. If `target`
+    /// isn't a reference nothing is replaced, and if this value is `target` it's replaced
+    /// itself.
+    ///
+    /// The containers holding `target` (and the containers holding those, up to this value)
+    /// are copied first if they're shared, as in `walk_mut`, while the rest of the tree is
+    /// untouched. Cycles are handled: a cycle through `target` is broken where `target` is
+    /// replaced, a copied container which refers back to itself refers to its copy, and
+    /// `target` itself and `replacement` aren't searched.
+    pub fn replace_node(&mut self, target: &RbAny, replacement: RbAny) -> usize {
+        match target.as_rc() {
+            Some(t) => RbReplaceNode::new(t, replacement).replace(self),
+            None => 0,
+        }
+    }
+
+    /// Returns an identifier for the object this refers to, or None if this isn't a reference.
+    /// Two values have the same id exactly when they refer to the same object, which is useful
+    /// for tracking which objects have been processed.
+    ///
+    /// The id is the object's address, so it's only stable while the object is alive; once
+    /// it's dropped the id may be reused by a new object.
+    pub fn pointer_id(&self) -> Option<usize> {
+        self.as_rc().map(|r| rc_get_ptr(r) as usize)
+    }
+
     pub fn as_rc(&self) -> Option<&RcType<RbRef>> {
         match self { RbAny::Ref(r) => Some(r), _ => None }
     }
@@ -97,10 +281,90 @@ impl RbAny {
     pub fn as_object_mut(&mut self) -> Option<&mut RbObject> {
         self.as_rbref_mut().and_then(|v| v.as_object_mut())
     }
+    /// Returns the struct if this is an instance of a `Struct` subclass (e.g. `Point =
+    /// Struct.new(:x, :y)`), whose fields are its members in order. Objects of other classes
+    /// are `as_object` instead.
+    pub fn as_struct(&self) -> Option<&RbObject> {
+        self.as_rbref().and_then(|v| v.as_struct())
+    }
+    pub fn as_struct_mut(&mut self) -> Option<&mut RbObject> {
+        self.as_rbref_mut().and_then(|v| v.as_struct_mut())
+    }
+    /// Returns true if this is a struct, see `as_struct`.
+    pub fn is_struct(&self) -> bool {
+        self.as_struct().is_some()
+    }
     pub fn as_string(&self) -> Option<&String> {
         self.as_rbref().and_then(|v| v.as_string())
     }
+    /// Returns the raw bytes of a `Str`, `StrI`, or `Symbol`, regardless of encoding.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            RbAny::Symbol(v) => Some(v.as_bytes()),
+            _ => match self.as_rbref()? {
+                RbRef::Str(v) => Some(v.as_bytes()),
+                RbRef::StrI { content, .. } => Some(content),
+                _ => None,
+            },
+        }
+    }
+
+    /// Iterate over the elements of this array, or nothing if this isn't an array.
+    pub fn iter_array(&self) -> impl Iterator<Item = &RbAny> {
+        self.as_array().into_iter().flatten()
+    }
+
+    /// Iterate over the entries of this hash, or nothing if this isn't a hash.
+    pub fn iter_hash(&self) -> impl Iterator<Item = (&RbAny, &RbAny)> {
+        self.as_hash().into_iter().flat_map(|h| h.iter())
+    }
+
+    /// Iterate over the entries of a hash or the fields of an object or struct as `(key, value)`
+    /// pairs, so both can be handled by the same code. Hash keys are given as-is and field
+    /// names as `RbAny::Symbol`s. Returns None for any other type.
+    ///
+    /// Keys are cloned, which is cheap since values share their contents.
+    pub fn entries(&self) -> Option<impl Iterator<Item = (RbAny, &RbAny)>> {
+        let (hash, fields) = match self.as_rbref()? {
+            RbRef::Object(v) | RbRef::Struct(v) => (None, Some(&v.fields)),
+            r => (Some(r.as_hash()?), None),
+        };
+        let hash_entries = hash.into_iter().flat_map(|h| h.iter()).map(|(k, v)| (k.clone(), v));
+        let field_entries = fields.into_iter().flat_map(|f| f.iter()).map(|(k, v)| (RbAny::Symbol(k.clone()), v));
+        Some(hash_entries.chain(field_entries))
+    }
 
+    /// Returns the number of elements in an array or hash, or the number of fields in an
+    /// object or struct. Returns None for any other type.
+    pub fn len(&self) -> Option<usize> {
+        match self.as_rbref()? {
+            RbRef::Array(v) => Some(v.len()),
+            RbRef::Hash(v) => Some(v.len()),
+            RbRef::Object(v) | RbRef::Struct(v) => Some(v.fields.len()),
+            _ => None,
+        }
+    }
+
+    /// Returns true if `len` is zero, or None if `len` would return None.
+    pub fn is_empty(&self) -> Option<bool> {
+        self.len().map(|n| n == 0)
+    }
+
+    /// Returns the child of this value at `key`, see `RbRef::get_child`. The key is converted
+    /// into an `RbAny` first, so `value.get(0)` indexes an array, `value.get("@name")` gets an
+    /// object field, and `value.get(RbAny::symbol_from("name"))` gets a hash entry.
+    /// Returns None if the key isn't found or has the wrong type.
+    pub fn get<K: Into<RbAny>>(&self, key: K) -> Option<&RbAny> {
+        self.as_rbref()?.get_child(&key.into())
+    }
+
+    /// Mutable version of `get`, see `RbRef::get_child_mut`.
+    pub fn get_mut<K: Into<RbAny>>(&mut self, key: K) -> Option<&mut RbAny> {
+        self.as_rbref_mut()?.get_child_mut(&key.into())
+    }
+
+    /// Follow `path` from `root`, calling `RbRef::get_child` with each key in turn. See
+    /// `RbPath` for building paths.
     pub fn find_child<'a, I>(root: &'a RbAny, path: I) -> Option<&'a RbAny>
         where I: IntoIterator<Item=&'a RbAny>
     {
@@ -111,6 +375,482 @@ impl RbAny {
         Some(current)
     }
 
+    /// Returns the differences between this value and `other`, e.g. to find out why a round
+    /// trip doesn't match. Each `DiffEntry` has a path which `find_child` accepts, and its
+    /// `Display` form is a readable one-line summary.
+    ///
+    /// Arrays are compared element by element, hashes by key, and objects and structs with the
+    /// same class name field by field. Anything else is compared with `deep_eq` and reported as
+    /// changed as a whole. A pair of shared (or recursive) values is only compared the first
+    /// time it's reached, so its differences are reported under the first path to it.
+    pub fn diff(&self, other: &RbAny) -> Vec<DiffEntry> {
+        RbDiff::new().diff(self, other)
+    }
+
+    /// Call `f` on this value and every value reachable from it, including hash keys.
+    ///
+    /// Each shared object is only visited once, so this terminates on recursive data.
+    pub fn walk<'a, F>(&'a self, mut f: F) where F: FnMut(&'a RbAny) {
+        RbWalk::new().walk(self, &mut f)
+    }
+
+    /// Like `walk`, but stops as soon as `f` returns `ControlFlow::Break`, returning the break
+    /// value, or None if every value was visited. Use this to search for a value without
+    /// visiting the rest of the tree once it's found.
+    pub fn try_walk<'a, B, F>(&'a self, mut f: F) -> Option<B> where F: FnMut(&'a RbAny) -> ControlFlow<B> {
+        RbWalk::new().try_walk(self, &mut f)
+    }
+
+    /// Returns every value reachable from this one as a flat list, in the same order as `walk`,
+    /// with the index of the node each was first reached from. The root is first and has no
+    /// parent.
+    ///
+    /// Shared values are only listed once, so this is a convenient starting point for
+    /// analyses like finding the largest strings or counting objects by class.
+    pub fn nodes(&self) -> Vec<NodeInfo<'_>> {
+        RbNodes::new().collect(self)
+    }
+
+    /// Returns a rough estimate of how many characters this value takes as text, e.g. from
+    /// `to_json` or `to_yaml`, for preallocating the output buffer.
+    ///
+    /// This is a guess, not a bound. Each shared object is only counted once, so heavily shared
+    /// trees aren't overestimated, while the `@N` references to them aren't counted at all.
+    pub fn size_hint(&self) -> usize {
+        let mut size = 0;
+        self.walk(|node| size += node_size_hint(node));
+        size
+    }
+
+    /// Returns true if no value is reachable from this one more than once, meaning there's no
+    /// sharing and no cycles. Such a value converts to JSON without any `@N` references.
+    pub fn is_tree(&self) -> bool {
+        RbShape::new(false).check(self)
+    }
+
+    /// Returns true if no value contains itself, though values may be shared. Such a value
+    /// can be `explode`d into a tree.
+    pub fn is_acyclic(&self) -> bool {
+        RbShape::new(true).check(self)
+    }
+
+    /// Returns a SHA-256 digest of this value's contents, which is stable across processes, for
+    /// deduplicating or detecting changes to stored data. This requires the "digest" feature.
+    ///
+    /// Values with the same structure have the same digest, however their objects are shared or
+    /// numbered. The order of hash entries and object fields doesn't matter, while the order of
+    /// array elements and struct members does. A recursive reference is digested by how many
+    /// levels up it points, so recursive values are handled deterministically too.
+    #[cfg(feature = "digest")]
+    pub fn content_digest(&self) -> [u8; 32] {
+        super::rb_digest::RbDigest::new().digest(self)
+    }
+
+    /// Call `f` on this value and every value reachable from it, with mutable access.
+    ///
+    /// Each shared object is only visited once. Shared objects are cloned (copy-on-write)
+    /// before being visited so that other references to them outside of this tree are
+    /// unaffected, while references to them from within this tree are updated to point to
+    /// the copy, including references from a value back to itself. See `RbAny::as_rbref_mut`
+    /// for accessing the contents of a reference.
+    pub fn walk_mut<F>(&mut self, mut f: F) where F: FnMut(&mut RbAny) {
+        RbWalkMut::new().walk_mut(self, &mut f)
+    }
+
+    /// Returns a copy of this value with the contents of every `Str` and `StrI` passed through
+    /// `f`, e.g. to re-encode or redact them, leaving this value unchanged.
+    ///
+    /// Each shared object is copied (and each shared string mapped) once, and is still shared
+    /// in the result, including by recursive references. A `Str` which `f` turns into invalid
+    /// UTF-8 becomes a `StrI` with no encoding, while a `StrI` keeps its ivars.
+    pub fn map_strings<F>(&self, f: F) -> RbAny where F: Fn(&[u8]) -> Vec<u8> {
+        let edit = |node: &mut RbRef| match node {
+            RbRef::Str(content) => {
+                let new = f(content.as_bytes());
+                *node = match String::from_utf8(new) {
+                    Ok(s) => RbRef::Str(s),
+                    Err(e) => RbRef::StrI { content: e.into_bytes(), metadata: RbFields::new() },
+                };
+            },
+            RbRef::StrI { content, .. } => *content = f(content),
+            _ => {},
+        };
+        let mut copier = RbCopy::new();
+        copier.edit = Some(&edit);
+        copier.copy(self)
+    }
+
+    /// Convert every `StrI` which `RbRef::decode_str` can decode into a UTF-8 `Str`, e.g. for
+    /// data from older Ruby programs which stored Japanese text as `Shift_JIS`.
+    ///
+    /// The strings are written back as UTF-8, so this is opt-in rather than done when reading.
+    /// Binary strings, and strings with ivars other than their encoding, are left unchanged.
+    #[cfg(feature = "encodings")]
+    pub fn decode_strings(&mut self) {
+        self.walk_mut(|value| {
+            let decoded = match value.as_rbref() {
+                Some(v @ RbRef::StrI { metadata, .. }) => {
+                    let only_encoding = metadata.keys().all(|k| k.eq_str("E") || k.eq_str("encoding"));
+                    if only_encoding { v.decode_str() } else { None }
+                },
+                _ => None,
+            };
+            if let Some(s) = decoded {
+                *value = RbRef::Str(s).into_any();
+            }
+        });
+    }
+
+    /// Apply a sparse update to this value, changing only what `patch` mentions.
+    ///
+    /// - If both are hashes, each entry of `patch` is applied to the entry with an equal key,
+    ///   or inserted if there isn't one.
+    /// - If both are objects (or both structs) with the same class name, each field of `patch`
+    ///   is applied to the field with the same name, or inserted if there isn't one.
+    /// - If this is an array and `patch` is a hash whose keys are all `Int`s, each entry of
+    ///   `patch` is applied to the element at that index. An index which is out of bounds
+    ///   returns `ThurgoodError::BadPatch`.
+    /// - Otherwise (including arrays patched with arrays) this value is replaced by `patch`.
+    ///
+    /// Shared values are copied before being changed, so aliases of a patched value (whether
+    /// elsewhere in this tree or outside of it) are unaffected. A patch which contains itself
+    /// where it would be merged returns `ThurgoodError::BadPatch`. On error, entries before the
+    /// failing one have already been applied.
+    pub fn apply_patch(&mut self, patch: &RbAny) -> TResult<()> {
+        super::rb_patch::apply_patch(self, patch)
+    }
+
+    /// Compute the changes which turn `old` into `new`, see `RbPatch`. Apply them to (a copy
+    /// of) `old` with `RbPatch::apply` to reconstruct `new`.
+    pub fn make_patch(old: &RbAny, new: &RbAny) -> RbPatch {
+        RbPatch::new(old, new)
+    }
+
+    /// Returns a deep copy of this value which shares nothing with it, so it can be kept or
+    /// mutated independently of the original. In contrast `clone` only copies the reference,
+    /// so the copy shares its contents with the original.
+    ///
+    /// Sharing within the value is preserved: an object referred to twice is copied once and
+    /// referred to twice by the copy, and a value which contains itself is copied into a value
+    /// which contains the copy. See `explode` for a copy in which nothing is shared at all.
+    pub fn to_owned_deep(&self) -> RbAny {
+        RbCopy::new().copy(self)
+    }
+
+    /// Returns a deep copy of this value in which nothing is shared: every reference that
+    /// appears more than once is copied separately each time, so the result is a plain tree.
+    /// This is useful before exporting to formats which can't represent sharing.
+    ///
+    /// Recursive data can't be copied this way, so a reference back to one of its own
+    /// ancestors is replaced with `Nil`. Note that the result may be much larger than the
+    /// original if large values are shared many times.
+    pub fn explode(&self) -> RbAny {
+        RbExplode::new().explode(self)
+    }
+
+    /// Returns every `UserData` (an object written with `_dump`) in this tree along with its
+    /// path, e.g. to decode them elsewhere and put the results back with `replace_at`.
+    ///
+    /// Only values which `find_child` can reach are included, so those in instance variables
+    /// of non-objects or in hash keys and defaults are not. Shared values are only traversed
+    /// the first time they're reached, so recursive data terminates.
+    pub fn collect_user_data(&self) -> Vec<(RbPath, &RbUserData)> {
+        RbCollectUserData::new().collect(self)
+    }
+
+    /// Returns each scalar leaf of this tree along with its path, e.g. `users[0].@name`.
+    ///
+    /// Array elements are written as `[0]`, object fields and hash entries with string or
+    /// symbol keys as `.name`, and other hash keys using their `Debug` form, e.g. `[Int(1)]`.
+    /// Strings, floats, and other values which don't contain references are leaves, while
+    /// containers are traversed. Shared values are only traversed the first time they're
+    /// reached, so recursive data terminates. Hash default values are not included.
+    pub fn flatten(&self) -> impl Iterator<Item = (String, &RbAny)> {
+        RbFlatten::new().flatten(self).into_iter()
+    }
+
+    /// Call `f` on every `Object` or `Struct` in the tree with the class name `name`.
+    pub fn objects_named<'a, F>(&'a self, name: &str, mut f: F) where F: FnMut(&'a RbObject) {
+        self.walk(|v| {
+            if let Some(RbRef::Object(obj) | RbRef::Struct(obj)) = v.as_rbref() {
+                if obj.name.as_str() == Some(name) {
+                    f(obj);
+                }
+            }
+        })
+    }
+
+    /// Call `f` on every `Object` or `Struct` in the tree with the class name `name`,
+    /// with mutable access. This follows the same copy-on-write rules as `walk_mut`.
+    pub fn objects_named_mut<F>(&mut self, name: &str, mut f: F) where F: FnMut(&mut RbObject) {
+        self.walk_mut(|v| {
+            if let Some(RbRef::Object(obj) | RbRef::Struct(obj)) = v.as_rbref_mut() {
+                if obj.name.as_str() == Some(name) {
+                    f(obj);
+                }
+            }
+        })
+    }
+
+    /// Rename the class or module `from` to `to` everywhere it's stored in this tree, returning
+    /// the number of names changed. This covers the class names of objects, structs, and
+    /// user-defined types, class and module references, and the modules of `Extended` values.
+    ///
+    /// This follows the same copy-on-write rules as `walk_mut`, and all renamed values share
+    /// a single `RbSymbol` for the new name.
+    pub fn rename_class(&mut self, from: &str, to: &str) -> usize {
+        let to_sym = RbSymbol::from(to);
+        let mut count = 0;
+        self.walk_mut(|v| {
+            if let Some(r) = v.as_rbref_mut() {
+                count += rename_class_ref(r, from, to, &to_sym);
+            }
+        });
+        count
+    }
+
+    /// Replace every occurrence of the symbol `from` with `to`, and return the number of
+    /// occurrences replaced. This includes symbol values (e.g. hash keys), field and instance
+    /// variable names, class names, and modules in `Extended`. Names in `ClassRef`, `ModuleRef`
+    /// and `ClassModuleRef` are strings rather than symbols, see `rename_class` for those.
+    ///
+    /// A renamed field keeps its position. If the object already has a field named `to`, that
+    /// field's value is replaced. Shared values are copied as in `walk_mut`.
+    pub fn rename_symbol(&mut self, from: &RbSymbol, to: &RbSymbol) -> usize {
+        let mut count = 0;
+        self.walk_mut(|v| {
+            if let RbAny::Symbol(sym) = v {
+                if sym == from {
+                    *sym = to.clone();
+                    count += 1;
+                }
+            } else if let Some(r) = v.as_rbref_mut() {
+                count += rename_symbol_ref(r, from, to);
+            }
+        });
+        count
+    }
+
+    /// Replace every symbol in this tree which refers to a shared buffer (see
+    /// `from_bytes_shared`) with one that owns its name, so the tree no longer keeps the buffer
+    /// alive. Returns the number of symbols replaced. Each name is only copied once, and
+    /// shared values are copied as in `walk_mut`.
+    pub fn detach_symbols(&mut self) -> usize {
+        let mut copies = HashMap::new();
+        let mut count = 0;
+        self.walk_mut(|v| {
+            let mut detach = |sym: &mut RbSymbol| {
+                if sym.is_shared() {
+                    *sym = copies.entry(sym.clone()).or_insert_with(|| sym.detached()).clone();
+                    count += 1;
+                }
+            };
+            if let RbAny::Symbol(sym) = v {
+                detach(sym);
+            } else if let Some(r) = v.as_rbref_mut() {
+                detach_symbol_ref(r, &mut detach);
+            }
+        });
+        count
+    }
+
+    /// Returns true if this is an array or hash with no entries. A hash with a default value
+    /// isn't empty, since the default is still data.
+    pub fn is_empty_container(&self) -> bool {
+        match self.as_rbref() {
+            Some(RbRef::Array(v)) => v.is_empty(),
+            Some(RbRef::Hash(v)) => v.is_empty() && v.default.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Remove the object fields and hash entries whose values are `nil` (and, depending on
+    /// `mode`, empty arrays and hashes) throughout this tree, e.g. to tidy up generated data
+    /// before writing it. Returns the number of fields and entries removed.
+    ///
+    /// Array elements and struct members are never removed, since that would change their
+    /// meaning. This value itself is never removed, even if it ends up empty. Shared objects
+    /// are copied first, see `walk_mut`.
+    pub fn prune_nil(&mut self, mode: PruneNil) -> usize {
+        let prune = |v: &RbAny| match v {
+            RbAny::Nil => true,
+            _ => mode == PruneNil::NilAndEmpty && v.is_empty_container(),
+        };
+        let mut total = 0;
+        // Containers may only become empty once their own contents are pruned, so repeat until
+        // nothing changes
+        loop {
+            let mut count = 0;
+            self.walk_mut(|v| match v.as_rbref_mut() {
+                Some(RbRef::Object(obj)) => {
+                    let before = obj.fields.len();
+                    obj.fields.retain(|_, val| !prune(val));
+                    count += before - obj.fields.len();
+                },
+                Some(RbRef::Hash(hash)) => {
+                    let before = hash.len();
+                    hash.retain(|_, val| !prune(val));
+                    count += before - hash.len();
+                },
+                _ => {},
+            });
+            total += count;
+            if count == 0 || mode == PruneNil::NilOnly {
+                return total;
+            }
+        }
+    }
+
+    /// Convert integers to a single form so that dumps which store the same number differently
+    /// (e.g. as `BigInt` in one and `Int` in another) compare equal with `deep_eq`. Returns the
+    /// number of values changed.
+    ///
+    /// - `NormalizeInts::Demote` converts each `BigInt` which fits in an `Int` to an `Int`.
+    /// - `NormalizeInts::Promote` converts each `Int` outside of Ruby's fixnum range
+    ///   (-2**30 to 2**30 - 1) to a `BigInt`, which is how Ruby itself writes them.
+    ///
+    /// Hash keys are normalized too, see `walk_mut`.
+    pub fn normalize_ints(&mut self, mode: NormalizeInts) -> usize {
+        let mut count = 0;
+        self.walk_mut(|v| {
+            let changed = match (mode, &*v) {
+                (NormalizeInts::Demote, RbAny::Ref(r)) => match r.as_ref() {
+                    RbRef::BigInt(n) => n.to_i32().map(RbAny::Int),
+                    _ => None,
+                },
+                (NormalizeInts::Promote, RbAny::Int(n)) if !(FIXNUM_MIN..=FIXNUM_MAX).contains(n) => {
+                    Some(RbRef::BigInt(BigInt::from(*n)).into_any())
+                },
+                _ => None,
+            };
+            if let Some(n) = changed {
+                *v = n;
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// Convert this value to `ty` using basic Ruby conversions, or return None if there isn't
+    /// one. A value which is already of type `ty` is returned as-is. Supported conversions:
+    ///
+    /// - `Str`: from `Int`, `BigInt`, and `Float` (like `to_s`), `Symbol` (its name), `Bool`
+    ///   (`"true"` or `"false"`), and `Nil` (`""`).
+    /// - `Int` and `BigInt`: from `Int`, `BigInt`, a finite `Float` (truncated, like `to_i`),
+    ///   and a string containing only a decimal integer, ignoring surrounding whitespace. The
+    ///   result is an `Int` if it fits in Ruby's fixnum range and a `BigInt` otherwise.
+    /// - `Float`: from `Int`, `BigInt`, and a string containing only a decimal number.
+    /// - `Symbol`: from a string.
+    /// - `Bool`: from the strings `"true"` and `"false"`.
+    /// - `Array`: from a `Hash`, as a list of `[key, value]` pairs (like `to_a`).
+    ///
+    /// Strings may be `Str` or `StrI` with UTF-8 content.
+    pub fn coerce_to(&self, ty: RbType) -> Option<RbAny> {
+        let from = self.get_type();
+        if from == ty || (ty == RbType::BigInt && from == RbType::Int) {
+            return Some(self.clone());
+        }
+        let text = String::try_from(self).ok();
+        match ty {
+            RbType::Str => {
+                let s = match self {
+                    RbAny::Int(v) => v.to_string(),
+                    RbAny::True => "true".to_owned(),
+                    RbAny::False => "false".to_owned(),
+                    RbAny::Nil => String::new(),
+                    RbAny::Symbol(v) => v.as_str()?.to_owned(),
+                    RbAny::Ref(r) => match r.as_ref() {
+                        RbRef::BigInt(v) => v.to_string(),
+                        RbRef::Float(v) => ruby_float_to_s(v.0),
+                        _ => return None,
+                    },
+                };
+                Some(RbAny::from(s))
+            },
+            RbType::Int | RbType::BigInt => {
+                let n = match self.as_rbref() {
+                    Some(RbRef::BigInt(v)) => v.clone(),
+                    Some(RbRef::Float(v)) if v.0.is_finite() => BigInt::from_f64(v.0.trunc())?,
+                    _ => {
+                        let t = text?;
+                        let t = t.trim();
+                        if t.is_empty() || !t.trim_start_matches(['-', '+']).bytes().all(|b| b.is_ascii_digit()) {
+                            return None;
+                        }
+                        t.parse::<BigInt>().ok()?
+                    },
+                };
+                Some(int_from_bigint(n))
+            },
+            RbType::Float => {
+                let f = match self {
+                    RbAny::Int(v) => *v as f64,
+                    _ => match self.as_rbref() {
+                        Some(RbRef::BigInt(v)) => v.to_f64()?,
+                        _ => {
+                            let t = text?;
+                            let t = t.trim();
+                            if !t.bytes().all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b)) {
+                                return None;
+                            }
+                            t.parse::<f64>().ok()?
+                        },
+                    },
+                };
+                Some(RbAny::from(f))
+            },
+            RbType::Symbol => Some(RbAny::Symbol(RbSymbol::from(text?))),
+            RbType::Bool => match text?.as_str() {
+                "true" => Some(RbAny::True),
+                "false" => Some(RbAny::False),
+                _ => None,
+            },
+            RbType::Array => {
+                let hash = self.as_hash()?;
+                let pairs = hash.iter().map(|(k, v)| RbAny::from(vec![k.clone(), v.clone()])).collect::<Vec<_>>();
+                Some(RbAny::from(pairs))
+            },
+            _ => None,
+        }
+    }
+
+    /// Check that this value can be written as valid Marshal data, without writing it.
+    ///
+    /// On failure this returns `ThurgoodError::Unserializable` with the path to the offending
+    /// value, e.g. `root[:users][0].@pattern`.
+    pub fn validate(&self) -> TResult<()> {
+        RbValidate::new().validate(self)
+    }
+
+    /// Returns the object index (as used by `@N` object references) that each object in this
+    /// tree would receive if it were written, see `RbWriter::object_indices`. This is useful
+    /// for checking how sharing will be preserved before writing. Values which can't be written
+    /// (see `validate`) return the writer's error.
+    pub fn object_indices(&self) -> TResult<HashMap<*const RbRef, usize>> {
+        let mut wr = RbWriter::new(io::sink());
+        wr.write(self)?;
+        Ok(wr.object_indices().clone())
+    }
+
+    /// Returns the number of bytes this value takes when written on its own, without the
+    /// version header, for comparing the sizes of parts of a document.
+    ///
+    /// Values shared within this one are counted the way they'd be written here: in full the
+    /// first time and as an `@N` reference after that. As part of a larger document it may be
+    /// smaller, since symbols and objects written earlier are referred to instead.
+    pub fn serialized_len(&self) -> TResult<usize> {
+        RbWriter::new(io::sink()).write_value(self)
+    }
+
+    /// Returns a one-line, `inspect`-like description of this value for logs or previews,
+    /// e.g. `[1, "a", #<Foo @b=nil>]`, cut off after `max_len` characters with `...` appended.
+    ///
+    /// Only as much of the value as fits is visited, so previewing a huge tree is cheap.
+    /// Recursive values and anything nested too deeply are abbreviated like `inspect_pretty`.
+    pub fn preview(&self, max_len: usize) -> String {
+        super::dump::preview(self, max_len)
+    }
+
     pub fn deep_cmp(&self, other: &Self) -> Ordering {
         RbCompare::new().cmp(self, other)
     }
@@ -119,10 +859,274 @@ impl RbAny {
         self.deep_cmp(other).is_eq()
     }
 
+    /// Returns true if both values have the same structure and contents, ignoring identity.
+    ///
+    /// Two trees built independently compare equal as long as they look the same, including
+    /// recursive values (a value containing itself equals a copy built the same way) and
+    /// floats which are NaN. Sharing doesn't matter either, so `[a, a]` equals `[a, b]` if `a`
+    /// and `b` have the same contents. In contrast `==` compares references by address, and
+    /// `deep_eq` compares by content but falls back to comparing addresses for NaNs and for
+    /// some recursive values. See `RbCompareOptions::structural`.
+    pub fn structural_eq(&self, other: &Self) -> bool {
+        self.deep_eq_with(other, RbCompareOptions { structural: true, ..Default::default() })
+    }
+
+    /// Like `deep_eq`, but with looser comparison rules, see `RbCompareOptions`.
+    pub fn deep_eq_with(&self, other: &Self, options: RbCompareOptions) -> bool {
+        RbCompare::with_options(options).eq(self, other)
+    }
+
     #[cfg(feature = "json")]
     pub fn to_json(&self) -> Option<serde_json::Value> {
         super::rb_json::RbToJson::new().to_json(self)
     }
+
+    /// Write the same JSON as `to_json` to `dst`, without building the whole `Value` in memory
+    /// first. `dst` should be buffered, since the output is written in many small pieces.
+    ///
+    /// Returns an `io::ErrorKind::InvalidData` error where `to_json` would return None, in
+    /// which case part of the output may already have been written.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: io::Write>(&self, dst: W) -> TResult<()> {
+        super::rb_json::RbJsonWriter::new(dst).write(self)
+    }
+
+    /// Convert ordinary JSON (without the `@` tags produced by `to_json`) into plain Ruby data.
+    ///
+    /// Objects become hashes with string keys (in the same order), arrays become arrays, strings
+    /// become `Str`, and null becomes `Nil`. Integers that don't fit in an `Int` become a `BigInt`
+    /// and any other number becomes a `Float`.
+    ///
+    /// There's nothing to tell whether a JSON object stands for a hash or an object, so they
+    /// always become hashes. See `from_plain_json_with` for symbol keys, and
+    /// `from_plain_json_as_object` for building an object.
+    #[cfg(feature = "json")]
+    pub fn from_plain_json(value: &serde_json::Value) -> RbAny {
+        Self::from_plain_json_with(value, super::PlainJsonObjects::default())
+    }
+
+    /// Like `from_plain_json`, but with JSON objects converted as `objects` says, e.g. into
+    /// hashes with symbol keys.
+    #[cfg(feature = "json")]
+    pub fn from_plain_json_with(value: &serde_json::Value, objects: super::PlainJsonObjects) -> RbAny {
+        super::rb_json::from_plain_json(value, objects)
+    }
+
+    /// Convert a JSON object into an object of class `name`, with a field for each key (adding
+    /// `@` to names which don't start with it). Values are converted like `from_plain_json`,
+    /// so nested JSON objects become hashes. Returns None if `value` isn't a JSON object.
+    #[cfg(feature = "json")]
+    pub fn from_plain_json_as_object<N: Into<RbSymbol>>(value: &serde_json::Value, name: N) -> Option<RbObject> {
+        super::rb_json::from_plain_json_as_object(value, name.into())
+    }
+
+    /// Convert this value into a YAML document, using Ruby's (Psych) tags such as
+    /// `!ruby/symbol` and `!ruby/object:Name`. See `RbToYaml` for details.
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> Option<String> {
+        super::rb_yaml::RbToYaml::new().to_yaml(self)
+    }
+}
+
+impl crate::RbAnyLike for RbAny {
+    type Ref = RbRef;
+    type Ptr = RcType<RbRef>;
+
+    fn get_type(&self) -> RbType {
+        RbAny::get_type(self)
+    }
+
+    fn as_ptr(&self) -> Option<&Self::Ptr> {
+        self.as_rc()
+    }
+
+    fn as_ptr_mut(&mut self) -> Option<&mut Self::Ptr> {
+        self.as_rc_mut()
+    }
+
+    fn from_ptr(ptr: Self::Ptr) -> Self {
+        RbAny::Ref(ptr)
+    }
+
+    fn deep_eq(&self, other: &Self) -> bool {
+        RbAny::deep_eq(self, other)
+    }
+
+    fn is_nil(&self) -> bool {
+        RbAny::is_nil(self)
+    }
+
+    fn as_int(&self) -> Option<i32> {
+        RbAny::as_int(self)
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        RbAny::as_bool(self)
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        RbAny::as_bytes(self)
+    }
+
+    fn symbol_str(&self) -> Option<&str> {
+        RbAny::symbol_str(self)
+    }
+
+    fn class_name(&self) -> Option<&str> {
+        RbAny::class_name(self)
+    }
+
+    fn as_array(&self) -> Option<&[Self]> {
+        RbAny::as_array(self).map(Vec::as_slice)
+    }
+
+    fn get_child(&self, key: &Self) -> Option<&Self> {
+        self.as_rbref()?.get_child(key)
+    }
+
+    fn entries<'a>(&'a self) -> Option<Box<dyn Iterator<Item = (Self, &'a Self)> + 'a>> {
+        Some(Box::new(RbAny::entries(self)?))
+    }
+}
+
+/// Returns `n` as an `Int` if it's in Ruby's fixnum range, otherwise as a `BigInt`.
+fn int_from_bigint(n: BigInt) -> RbAny {
+    match n.to_i32() {
+        Some(v) if (FIXNUM_MIN..=FIXNUM_MAX).contains(&v) => RbAny::Int(v),
+        _ => RbRef::BigInt(n).into_any(),
+    }
+}
+
+/// Estimated text size of `value` without its children, for `RbAny::size_hint`. Includes a
+/// few characters for the separator and indentation around each value.
+fn node_size_hint(value: &RbAny) -> usize {
+    // Type tags like `{"@":"Name","@id":1,"data":}` or `!ruby/object:Name`
+    const TAG: usize = 28;
+    let fields = |fields: &RbFields| fields.keys().map(|k| k.len() + 4).sum::<usize>();
+    let size = match value {
+        RbAny::Int(v) => v.unsigned_abs().to_string().len() + 1,
+        RbAny::True | RbAny::False | RbAny::Nil => 5,
+        RbAny::Symbol(v) => v.len() + 2,
+        RbAny::Ref(r) => match r.as_ref() {
+            RbRef::Float(_) => 20,
+            RbRef::BigInt(v) => v.bits() as usize * 3 / 10 + 2,
+            RbRef::Array(_) => TAG,
+            // JSON writes each entry as a `[key, value]` pair
+            RbRef::Hash(v) => TAG + v.len() * 3,
+            RbRef::Str(v) => v.len() + 2,
+            // Encoded strings may be written as escapes or base64
+            RbRef::StrI { content, metadata } => content.len() * 4 / 3 + TAG + fields(metadata),
+            RbRef::Regex { content, .. } => content.len() + TAG,
+            RbRef::RegexI { content, metadata, .. } => content.len() * 4 / 3 + TAG + fields(metadata),
+            RbRef::Struct(v) | RbRef::Object(v) => v.name.len() + TAG + fields(&v.fields),
+            RbRef::ClassRef(v) | RbRef::ModuleRef(v) | RbRef::ClassModuleRef(v) => v.len() + TAG,
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => v.name.len() + TAG,
+            RbRef::UserData(v) => v.name.len() + v.data.len() * 4 / 3 + TAG,
+            RbRef::Extended { module, .. } => module.len() + TAG,
+            RbRef::WithIvars { ivars, .. } => TAG + fields(ivars),
+        },
+    };
+    size + 2
+}
+
+/// Format `v` like Ruby's `Float#to_s`, e.g. `100.0`, `1.0e+20`, or `Infinity`. Like
+/// `RbWriter`'s float format this doesn't depend on the locale.
+fn ruby_float_to_s(v: f64) -> String {
+    if v.is_nan() {
+        return "NaN".to_owned();
+    }
+    if v.is_infinite() {
+        return if v < 0.0 { "-Infinity" } else { "Infinity" }.to_owned();
+    }
+    let sci = format!("{:e}", v);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if v == 0.0 || (-4..16).contains(&exp) {
+        return format!("{:?}", v);
+    }
+    let dot = if mantissa.contains('.') { "" } else { ".0" };
+    format!("{}{}e{}{:02}", mantissa, dot, if exp < 0 { '-' } else { '+' }, exp.abs())
+}
+
+fn rename_symbol_ref(node: &mut RbRef, from: &RbSymbol, to: &RbSymbol) -> usize {
+    let rename_sym = |sym: &mut RbSymbol| {
+        if sym == from {
+            *sym = to.clone();
+            1
+        } else {
+            0
+        }
+    };
+    let rename_fields = |fields: &mut RbFields| {
+        if !fields.contains_key(from) {
+            return 0;
+        }
+        let entries: Vec<_> = fields.drain(..).collect();
+        for (key, val) in entries {
+            fields.insert(if &key == from { to.clone() } else { key }, val);
+        }
+        1
+    };
+    match node {
+        RbRef::Object(v) | RbRef::Struct(v) => rename_sym(&mut v.name) + rename_fields(&mut v.fields),
+        RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => rename_sym(&mut v.name),
+        RbRef::UserData(v) => rename_sym(&mut v.name),
+        RbRef::Extended { module, .. } => rename_sym(module),
+        RbRef::StrI { metadata, .. } | RbRef::RegexI { metadata, .. } => rename_fields(metadata),
+        RbRef::WithIvars { object, ivars } => rename_symbol_ref(object, from, to) + rename_fields(ivars),
+        _ => 0,
+    }
+}
+
+fn detach_symbol_ref(node: &mut RbRef, detach: &mut dyn FnMut(&mut RbSymbol)) {
+    fn detach_fields(fields: &mut RbFields, detach: &mut dyn FnMut(&mut RbSymbol)) {
+        if !fields.keys().any(RbSymbol::is_shared) {
+            return;
+        }
+        let entries: Vec<_> = fields.drain(..).collect();
+        for (mut key, val) in entries {
+            detach(&mut key);
+            fields.insert(key, val);
+        }
+    }
+    match node {
+        RbRef::Object(v) | RbRef::Struct(v) => {
+            detach(&mut v.name);
+            detach_fields(&mut v.fields, detach);
+        },
+        RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => detach(&mut v.name),
+        RbRef::UserData(v) => detach(&mut v.name),
+        RbRef::Extended { module, .. } => detach(module),
+        RbRef::StrI { metadata, .. } | RbRef::RegexI { metadata, .. } => detach_fields(metadata, detach),
+        RbRef::WithIvars { object, ivars } => {
+            detach_symbol_ref(object, detach);
+            detach_fields(ivars, detach);
+        },
+        _ => {},
+    }
+}
+
+fn rename_class_ref(node: &mut RbRef, from: &str, to: &str, to_sym: &RbSymbol) -> usize {
+    let rename_sym = |sym: &mut RbSymbol| {
+        if sym.eq_str(from) {
+            *sym = to_sym.clone();
+            1
+        } else {
+            0
+        }
+    };
+    match node {
+        RbRef::Object(v) | RbRef::Struct(v) => rename_sym(&mut v.name),
+        RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => rename_sym(&mut v.name),
+        RbRef::UserData(v) => rename_sym(&mut v.name),
+        RbRef::Extended { module, .. } => rename_sym(module),
+        RbRef::ClassRef(v) | RbRef::ModuleRef(v) | RbRef::ClassModuleRef(v) if v == from => {
+            *v = to.to_owned();
+            1
+        },
+        RbRef::WithIvars { object, .. } => rename_class_ref(object, from, to, to_sym),
+        _ => 0,
+    }
 }
 
 impl PartialEq for RbAny {
@@ -142,22 +1146,10 @@ impl PartialEq for RbAny {
     }
 }
 
+/// Prints a readable summary of the value, see `RbDebug`.
 impl fmt::Debug for RbAny {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Int(v) => write!(f, "Int({})", v),
-            Self::True => write!(f, "True"),
-            Self::False => write!(f, "False"),
-            Self::Nil => write!(f, "Nil"),
-            Self::Symbol(v) => write!(f, "{:?}", v),
-            Self::Ref(v) => {
-                if v.contains_ref() {
-                    write!(f, "{:?}", rc_get_ptr(v))
-                } else {
-                    v.fmt(f)
-                }
-            },                
-        }
+        RbDebug::new().fmt(self, f, 0)
     }
 }
 
@@ -167,11 +1159,14 @@ impl Default for RbAny {
     }
 }
 
+/// References are hashed by pointer, except class and module references which are hashed by
+/// name, since Ruby only has one class with a given name.
 impl Hash for RbAny {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
-            Self::Ref(r) => {
-                state.write_usize(rc_get_ptr(r) as usize);
+            Self::Ref(r) => match r.as_ref() {
+                RbRef::ClassRef(v) | RbRef::ModuleRef(v) | RbRef::ClassModuleRef(v) => v.hash(state),
+                _ => state.write_usize(rc_get_ptr(r) as usize),
             },
             _ => {
                 core::mem::discriminant(self).hash(state);
@@ -184,10 +1179,65 @@ impl From<i32> for RbAny { fn from(v: i32) -> Self { RbAny::Int(v) } }
 impl From<f32> for RbAny { fn from(v: f32) -> Self { Self::from(RbRef::from(v)) } }
 impl From<f64> for RbAny { fn from(v: f64) -> Self { Self::from(RbRef::from(v)) } }
 impl From<bool> for RbAny { fn from(v: bool) -> Self { if v { RbAny::True } else { RbAny::False } } }
+// Strings convert to `RbRef::Str`, see `RbAny::sym` for Symbols
 impl From<String> for RbAny { fn from(v: String) -> Self { Self::from(RbRef::Str(v)) } }
 impl From<&str> for RbAny { fn from(v: &str) -> Self { Self::from(RbRef::Str(v.to_owned())) } }
+// Bytes convert to a binary string (`ASCII-8BIT`, see `RbRef::new_string_bin`), not a UTF-8
+// one. There's no `From<Vec<u8>>` since `RbAny::from(vec![])` would become ambiguous, use
+// `RbRef::from` for owned bytes instead.
+impl From<&[u8]> for RbAny { fn from(v: &[u8]) -> Self { Self::from(RbRef::new_string_bin(v.to_vec())) } }
 impl From<RbRef> for RbAny { fn from(v: RbRef) -> Self { RbAny::Ref(RcType::new(v)) } }
 impl From<Vec<RbAny>> for RbAny { fn from(v: Vec<RbAny>) -> Self { Self::from(RbRef::Array(v)) } }
 impl From<RbHash> for RbAny { fn from(v: RbHash) -> Self { Self::from(RbRef::Hash(v)) } }
 impl From<RbSymbol> for RbAny { fn from(v: RbSymbol) -> Self { RbAny::Symbol(v) } }
 impl From<&RbSymbol> for RbAny { fn from(v: &RbSymbol) -> Self { RbAny::Symbol(v.clone()) } }
+
+impl TryFrom<&RbAny> for i64 {
+    type Error = ThurgoodError;
+    /// Accepts an `Int`, or a `BigInt` which fits in an `i64`.
+    fn try_from(value: &RbAny) -> TResult<Self> {
+        match value {
+            RbAny::Int(v) => Ok(*v as i64),
+            _ => match value.as_rbref() {
+                Some(RbRef::BigInt(v)) => v.to_i64()
+                    .ok_or_else(|| ThurgoodError::unexpected_type(RbType::Int, RbType::BigInt)),
+                _ => Err(ThurgoodError::unexpected_type(RbType::Int, value.get_type())),
+            },
+        }
+    }
+}
+impl TryFrom<&RbAny> for f64 {
+    type Error = ThurgoodError;
+    fn try_from(value: &RbAny) -> TResult<Self> {
+        match value.as_rbref() {
+            Some(RbRef::Float(v)) => Ok(v.0),
+            _ => Err(ThurgoodError::unexpected_type(RbType::Float, value.get_type())),
+        }
+    }
+}
+impl TryFrom<&RbAny> for bool {
+    type Error = ThurgoodError;
+    fn try_from(value: &RbAny) -> TResult<Self> {
+        value.as_bool().ok_or_else(|| ThurgoodError::unexpected_type(RbType::Bool, value.get_type()))
+    }
+}
+impl TryFrom<&RbAny> for String {
+    type Error = ThurgoodError;
+    /// Accepts a `Str`, or a `StrI` whose content is valid UTF-8.
+    fn try_from(value: &RbAny) -> TResult<Self> {
+        match value.as_rbref() {
+            Some(RbRef::Str(v)) => Ok(v.clone()),
+            Some(RbRef::StrI { content, .. }) => match std::str::from_utf8(content) {
+                Ok(s) => Ok(s.to_owned()),
+                Err(e) => Err(ThurgoodError::invalid_utf8(content, e, None)),
+            },
+            _ => Err(ThurgoodError::unexpected_type(RbType::Str, value.get_type())),
+        }
+    }
+}
+impl TryFrom<&RbAny> for Vec<RbAny> {
+    type Error = ThurgoodError;
+    fn try_from(value: &RbAny) -> TResult<Self> {
+        value.as_array().cloned().ok_or_else(|| ThurgoodError::unexpected_type(RbType::Array, value.get_type()))
+    }
+}
@@ -1,5 +1,7 @@
 use std::{cmp::Ordering, collections::HashMap};
-use super::{RbAny, RbHash, RbObject, RbRef, RbSymbol, RbFields, rc_get_ptr};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use super::{RbAny, RbFloat, RbHash, RbObject, RbRef, RbSymbol, RbFields, rc_get_ptr};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct RefPair(*const RbRef, *const RbRef);
@@ -13,22 +15,74 @@ impl RefPair {
     }
 }
 
+/// Options for `RbCompare`. The defaults match how values round-trip through Marshal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RbCompareOptions {
+    /// If true, `Int`, `BigInt`, and `Float` values compare by numeric value, like Ruby's `==`,
+    /// so `3 == 3.0` and a `BigInt` equals an `Int` with the same value.
+    pub numeric: bool,
+    /// If true, the fields of objects and structs (and instance variables) are compared by
+    /// name, and hash entries by key, ignoring the order they were added in.
+    pub ignore_field_order: bool,
+    /// If true, references compare by content alone. Normally, when two references can't be
+    /// ordered by content (floats which are NaN, or a value containing itself) they're ordered
+    /// by address, so such values only equal themselves. With this set NaN floats compare by
+    /// their bits and a comparison which reaches itself again counts as equal.
+    pub structural: bool,
+    /// If set, floats which differ by at most this much compare as equal, see
+    /// `RbFloat::approx_eq`. This also applies to floats compared with `numeric`. Note that
+    /// this makes equality intransitive, so it's meant for comparing two values rather than
+    /// for sorting.
+    pub float_tolerance: Option<f64>,
+}
+
+/// Compares two values structurally, this is what `RbAny::deep_cmp` uses.
+///
+/// By default values of different types are never equal, so `Int(3)` and `Float(3.0)` differ,
+/// matching how they round-trip through Marshal. See `RbCompareOptions` for looser comparisons.
 pub struct RbCompare {
-    seen: HashMap<RefPair, Option<Ordering>>
+    seen: HashMap<RefPair, Option<Ordering>>,
+    options: RbCompareOptions,
+}
+
+impl Default for RbCompare {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RbCompare {
     pub fn new() -> Self {
+        Self::with_options(RbCompareOptions::default())
+    }
+
+    pub fn with_options(options: RbCompareOptions) -> Self {
         Self {
             seen: HashMap::new(),
+            options,
         }
     }
 
+    /// Construct a comparer where `Int`, `BigInt`, and `Float` values compare by numeric value,
+    /// see `RbCompareOptions::numeric`.
+    pub fn numeric() -> Self {
+        Self::with_options(RbCompareOptions { numeric: true, ..Default::default() })
+    }
+
     pub fn cmp(&mut self, lhs: &RbAny, rhs: &RbAny) -> Ordering {
         self.cmp_any(lhs, rhs).unwrap()
     }
 
+    pub fn eq(&mut self, lhs: &RbAny, rhs: &RbAny) -> bool {
+        self.cmp(lhs, rhs).is_eq()
+    }
+
     fn cmp_any(&mut self, lhs: &RbAny, rhs: &RbAny) -> Option<Ordering> {
+        if self.options.numeric {
+            if let Some(c) = self.cmp_numeric(lhs, rhs) {
+                return Some(c);
+            }
+        }
         match (lhs, rhs) {
             (RbAny::Int(l0), RbAny::Int(r0)) => Some(l0.cmp(r0)),
             (RbAny::Symbol(l0), RbAny::Symbol(r0)) => Some(l0.cmp(r0)),
@@ -45,6 +99,10 @@ impl RbCompare {
                     if new_ord.is_some() {
                         self.seen.insert(pair, new_ord);
                         new_ord
+                    } else if self.options.structural {
+                        // Only reached when this pair is part of a cycle still being compared
+                        self.seen.insert(pair, Some(Ordering::Equal));
+                        Some(Ordering::Equal)
                     } else {
                         let new_ord = Some(l_ptr.cmp(&r_ptr));
                         self.seen.insert(pair, new_ord);
@@ -71,6 +129,10 @@ impl RbCompare {
                 self.cmp_symbol_any(&l0.name, &l0.data, &r0.name, &r0.data),
             (En::Extended { module: l0mod, object: l0obj }, En::Extended { module: r0mod, object: r0obj}) =>
                 self.cmp_symbol_any(l0mod, l0obj, r0mod, r0obj),
+            (En::Float(a), En::Float(b)) if self.float_approx_eq(a.0, b.0) =>
+                Some(Ordering::Equal),
+            (En::Float(a), En::Float(b)) if self.options.structural =>
+                Some(a.0.total_cmp(&b.0)),
             (En::Float(a), En::Float(b)) =>
                 a.partial_cmp(b),
             (En::Hash(l0), En::Hash(r0)) =>
@@ -105,6 +167,10 @@ impl RbCompare {
             },
             (En::UserMarshal(l0), En::UserMarshal(r0)) =>
                 self.cmp_symbol_any(&l0.name, &l0.data, &r0.name, &r0.data),
+            (En::WithIvars { object: l_obj, ivars: l_ivars }, En::WithIvars { object: r_obj, ivars: r_ivars }) => {
+                let c0 = self.cmp_ref(l_obj, r_obj)?;
+                if c0.is_eq() { self.cmp_fields(l_ivars, r_ivars) } else { Some(c0) }
+            },
             _ => Some(lhs.ordinal().cmp(&rhs.ordinal())),
         }
     }
@@ -131,6 +197,17 @@ impl RbCompare {
     }
 
     fn cmp_hash(&mut self, l0: &RbHash, r0: &RbHash) -> Option<Ordering> {
+        let c0 = l0.len().cmp(&r0.len());
+        if c0.is_ne() { return Some(c0); }
+        let c0 = l0.ruby2_keywords.cmp(&r0.ruby2_keywords);
+        if c0.is_ne() { return Some(c0); }
+        let c0 = l0.compare_by_identity.cmp(&r0.compare_by_identity);
+        if c0.is_ne() { return Some(c0); }
+        if self.options.ignore_field_order {
+            let l_entries = self.sorted_entries(l0.iter());
+            let r_entries = self.sorted_entries(r0.iter());
+            return self.cmp_entries(&l_entries, &r_entries);
+        }
         for ((lkey, lval), (rkey, rval)) in l0.iter().zip(r0.iter()) {
             let c0 = self.cmp_any(lkey, rkey).unwrap_or(Ordering::Equal);
             if c0.is_ne() { return Some(c0); }
@@ -164,6 +241,19 @@ impl RbCompare {
     fn cmp_fields(&mut self, l_meta: &RbFields, r_meta: &RbFields) -> Option<Ordering> {
         let c0 = l_meta.len().cmp(&r_meta.len());
         if c0.is_ne() { return Some(c0); }
+        if self.options.ignore_field_order {
+            let mut l_fields: Vec<_> = l_meta.iter().collect();
+            let mut r_fields: Vec<_> = r_meta.iter().collect();
+            l_fields.sort_by(|a, b| a.0.cmp(b.0));
+            r_fields.sort_by(|a, b| a.0.cmp(b.0));
+            for (lh, rh) in l_fields.iter().zip(r_fields.iter()) {
+                let c0 = lh.0.cmp(rh.0);
+                if c0.is_ne() { return Some(c0); }
+                let c1 = self.cmp_any(lh.1, rh.1).unwrap_or(Ordering::Equal);
+                if c1.is_ne() { return Some(c1); }
+            }
+            return Some(Ordering::Equal);
+        }
         for i in 0..l_meta.len() {
             let lh_o = l_meta.get_index(i);
             let rh_o = r_meta.get_index(i);
@@ -177,6 +267,56 @@ impl RbCompare {
         return Some(Ordering::Equal);
     }
 
+    /// Returns the entries of a hash sorted by key, for comparing hashes ignoring order.
+    fn sorted_entries<'a>(&self, entries: impl Iterator<Item = (&'a RbAny, &'a RbAny)>) -> Vec<(&'a RbAny, &'a RbAny)> {
+        // Keys are sorted with a separate comparer so that comparing keys within one hash
+        // doesn't affect the results cached for comparing the two hashes.
+        let mut keys = RbCompare::with_options(self.options.clone());
+        let mut entries: Vec<_> = entries.collect();
+        entries.sort_by(|a, b| keys.cmp(a.0, b.0));
+        entries
+    }
+
+    fn cmp_entries(&mut self, l0: &[(&RbAny, &RbAny)], r0: &[(&RbAny, &RbAny)]) -> Option<Ordering> {
+        for ((lkey, lval), (rkey, rval)) in l0.iter().zip(r0.iter()) {
+            let c0 = self.cmp_any(lkey, rkey).unwrap_or(Ordering::Equal);
+            if c0.is_ne() { return Some(c0); }
+            let c1 = self.cmp_any(lval, rval).unwrap_or(Ordering::Equal);
+            if c1.is_ne() { return Some(c1); }
+        }
+        Some(Ordering::Equal)
+    }
+
+    /// Compare two numbers by value, or returns None if either isn't a number.
+    fn cmp_numeric(&self, lhs: &RbAny, rhs: &RbAny) -> Option<Ordering> {
+        let (l, r) = (Number::from_any(lhs)?, Number::from_any(rhs)?);
+        let c = match (&l, &r) {
+            (Number::Int(a), Number::Int(b)) => a.cmp(b),
+            (Number::Float(a), Number::Float(b)) if self.float_approx_eq(*a, *b) => Ordering::Equal,
+            (Number::Float(a), Number::Float(b)) => a.partial_cmp(b).unwrap_or_else(|| a.total_cmp(b)),
+            (Number::Int(a), Number::Float(b)) => Self::cmp_int_float(a, *b),
+            (Number::Float(a), Number::Int(b)) => Self::cmp_int_float(b, *a).reverse(),
+        };
+        Some(c)
+    }
+
+    /// Returns true if `float_tolerance` is set and `a` and `b` are within it.
+    fn float_approx_eq(&self, a: f64, b: f64) -> bool {
+        matches!(self.options.float_tolerance, Some(eps) if RbFloat(a).approx_eq(&RbFloat(b), eps))
+    }
+
+    fn cmp_int_float(a: &BigInt, b: f64) -> Ordering {
+        if b.is_nan() {
+            return Ordering::Less;
+        }
+        if b.fract() == 0.0 {
+            if let Some(b) = BigInt::from_f64(b) {
+                return a.cmp(&b);
+            }
+        }
+        a.to_f64().unwrap_or(f64::NAN).partial_cmp(&b).unwrap_or(Ordering::Less)
+    }
+
     fn any_id(a: &RbAny) -> i32 {
         match a {
             RbAny::Nil => 0,
@@ -188,3 +328,21 @@ impl RbCompare {
         }
     }
 }
+
+/// A number being compared in numeric mode.
+enum Number {
+    Int(BigInt),
+    Float(f64),
+}
+impl Number {
+    fn from_any(value: &RbAny) -> Option<Self> {
+        match value {
+            RbAny::Int(v) => Some(Number::Int(BigInt::from(*v))),
+            _ => match value.as_rbref()? {
+                RbRef::BigInt(v) => Some(Number::Int(v.clone())),
+                RbRef::Float(v) => Some(Number::Float(v.0)),
+                _ => None,
+            },
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use super::{RbAny, RbClass, RbRef, RbSymbol};
+#[cfg(feature = "json")]
+use serde_json::{Map, Value};
+#[cfg(feature = "json")]
+use super::helper::json::JsonMapExt;
+
+/// The default calendar reform date used by Ruby (`Date::ITALY`).
+pub const DATE_ITALY: f64 = 2299161.0;
+
+const SECONDS_PER_DAY: i32 = 86400;
+
+/// The data Ruby's `Date#marshal_dump` produces, shared by `Date` and `DateTime`.
+///
+/// The fields are `[nth, jd, df, sf, of, sg]` where `jd` is the (UTC) Julian day number,
+/// `df` is seconds into the (UTC) day, `sf` is fractional seconds in nanoseconds, `of` is the
+/// UTC offset in seconds, and `sg` is the Julian day of the calendar reform.
+#[derive(Clone, Debug, PartialEq)]
+struct DateData {
+    nth: i32,
+    jd: i32,
+    df: i32,
+    sf: RbAny,
+    of: i32,
+    sg: f64,
+}
+
+impl DateData {
+    fn from_class(value: &RbClass, class_name: &str) -> Option<Self> {
+        if value.name.as_str() != Some(class_name) {
+            return None;
+        }
+        let ar = value.data.as_array()?;
+        if ar.len() != 6 {
+            return None;
+        }
+        let sg = ar[5].as_rbref().and_then(|r| r.as_float())?.0;
+        Some(Self {
+            nth: ar[0].as_int()?,
+            jd: ar[1].as_int()?,
+            df: ar[2].as_int()?,
+            sf: ar[3].clone(),
+            of: ar[4].as_int()?,
+            sg,
+        })
+    }
+
+    fn to_any(&self, class_name: &str) -> RbAny {
+        let data = RbAny::from(vec![
+            RbAny::Int(self.nth),
+            RbAny::Int(self.jd),
+            RbAny::Int(self.df),
+            self.sf.clone(),
+            RbAny::Int(self.of),
+            RbAny::from(self.sg),
+        ]);
+        RbRef::UserMarshal(RbClass { name: RbSymbol::from(class_name), data }).into_any()
+    }
+
+    /// Local Julian day number and seconds into the local day.
+    fn local(&self) -> (i32, i32) {
+        let local_df = self.df + self.of;
+        (self.jd + local_df.div_euclid(SECONDS_PER_DAY), local_df.rem_euclid(SECONDS_PER_DAY))
+    }
+
+    fn civil(&self) -> (i32, i32, i32) {
+        jd_to_civil(self.local().0, self.sg)
+    }
+}
+
+/// Convert a Julian day number into a (year, month, day) triple. This mirrors `c_jd_to_civil`
+/// in Ruby's date library, using the Julian calendar for days before `sg`.
+fn jd_to_civil(jd: i32, sg: f64) -> (i32, i32, i32) {
+    let jd = jd as f64;
+    let a = if jd < sg {
+        jd
+    } else {
+        let x = ((jd - 1867216.25) / 36524.25).floor();
+        jd + 1.0 + x - (x / 4.0).floor()
+    };
+    let b = a + 1524.0;
+    let c = ((b - 122.1) / 365.25).floor();
+    let d = (365.25 * c).floor();
+    let e = ((b - d) / 30.6001).floor();
+    let dom = b - d - (30.6001 * e).floor();
+    let (y, m) = if e <= 13.0 {
+        (c - 4716.0, e - 1.0)
+    } else {
+        (c - 4715.0, e - 13.0)
+    };
+    (y as i32, m as i32, dom as i32)
+}
+
+/// Convert a (year, month, day) triple into a Julian day number. This mirrors `c_civil_to_jd`
+/// in Ruby's date library, using the Julian calendar for days before `sg`.
+fn civil_to_jd(year: i32, month: i32, day: i32, sg: f64) -> i32 {
+    let (mut y, mut m) = (year as f64, month as f64);
+    if m <= 2.0 {
+        y -= 1.0;
+        m += 12.0;
+    }
+    let a = (y / 100.0).floor();
+    let b = 2.0 - a + (a / 4.0).floor();
+    let mut jd = (365.25 * (y + 4716.0)).floor() + (30.6001 * (m + 1.0)).floor() + day as f64 + b - 1524.0;
+    if jd < sg {
+        jd -= b;
+    }
+    jd as i32
+}
+
+/// A Ruby `Date`, which is marshalled as a user-marshal object named `Date`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RbDate(DateData);
+
+impl RbDate {
+    /// Construct a date from the given (civil) year, month, and day, using `Date::ITALY`
+    /// as the calendar reform date just like `Date.new`.
+    pub fn new(year: i32, month: i32, day: i32) -> Self {
+        Self(DateData {
+            nth: 0,
+            jd: civil_to_jd(year, month, day, DATE_ITALY),
+            df: 0,
+            sf: RbAny::Int(0),
+            of: 0,
+            sg: DATE_ITALY,
+        })
+    }
+
+    /// Returns the `Date` stored in `value`, or None if `value` isn't a `Date`.
+    ///
+    /// Dates far enough in the past or future that Ruby stores an `nth` period aren't supported.
+    pub fn from_any(value: &RbAny) -> Option<Self> {
+        match value.as_rbref()? {
+            RbRef::UserMarshal(v) => Self::from_class(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the `Date` stored in the contents of a user-marshal object.
+    pub fn from_class(value: &RbClass) -> Option<Self> {
+        DateData::from_class(value, "Date").filter(|d| d.nth == 0).map(Self)
+    }
+
+    /// Convert this into an `RbAny` with the same layout that Ruby uses.
+    pub fn to_any(&self) -> RbAny {
+        self.0.to_any("Date")
+    }
+
+    /// Returns the Julian day number.
+    pub fn jd(&self) -> i32 {
+        self.0.local().0
+    }
+
+    pub fn year(&self) -> i32 {
+        self.0.civil().0
+    }
+
+    /// Returns the month, from 1 to 12.
+    pub fn month(&self) -> i32 {
+        self.0.civil().1
+    }
+
+    /// Returns the day of the month, starting at 1.
+    pub fn day(&self) -> i32 {
+        self.0.civil().2
+    }
+
+    /// Construct a JSON value from this object.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Value {
+        let (year, month, day) = self.0.civil();
+        let mut map = Map::new();
+        map.ezset("@", "Date");
+        map.ezset("year", year);
+        map.ezset("month", month);
+        map.ezset("day", day);
+        Value::Object(map)
+    }
+}
+
+/// A Ruby `DateTime`, which is marshalled as a user-marshal object named `DateTime`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RbDateTime(DateData);
+
+impl RbDateTime {
+    /// Construct a date-time from the given local time and UTC offset (in seconds), using
+    /// `Date::ITALY` as the calendar reform date just like `DateTime.new`.
+    pub fn new(year: i32, month: i32, day: i32, hour: i32, minute: i32, second: i32, offset: i32) -> Self {
+        let local_jd = civil_to_jd(year, month, day, DATE_ITALY);
+        let utc_df = hour * 3600 + minute * 60 + second - offset;
+        Self(DateData {
+            nth: 0,
+            jd: local_jd + utc_df.div_euclid(SECONDS_PER_DAY),
+            df: utc_df.rem_euclid(SECONDS_PER_DAY),
+            sf: RbAny::Int(0),
+            of: offset,
+            sg: DATE_ITALY,
+        })
+    }
+
+    /// Returns the `DateTime` stored in `value`, or None if `value` isn't a `DateTime`.
+    ///
+    /// Dates far enough in the past or future that Ruby stores an `nth` period aren't supported.
+    pub fn from_any(value: &RbAny) -> Option<Self> {
+        match value.as_rbref()? {
+            RbRef::UserMarshal(v) => Self::from_class(v),
+            _ => None,
+        }
+    }
+
+    /// Returns the `DateTime` stored in the contents of a user-marshal object.
+    pub fn from_class(value: &RbClass) -> Option<Self> {
+        DateData::from_class(value, "DateTime").filter(|d| d.nth == 0).map(Self)
+    }
+
+    /// Convert this into an `RbAny` with the same layout that Ruby uses.
+    pub fn to_any(&self) -> RbAny {
+        self.0.to_any("DateTime")
+    }
+
+    /// Returns the (local) Julian day number.
+    pub fn jd(&self) -> i32 {
+        self.0.local().0
+    }
+
+    pub fn year(&self) -> i32 {
+        self.0.civil().0
+    }
+
+    /// Returns the month, from 1 to 12.
+    pub fn month(&self) -> i32 {
+        self.0.civil().1
+    }
+
+    /// Returns the day of the month, starting at 1.
+    pub fn day(&self) -> i32 {
+        self.0.civil().2
+    }
+
+    pub fn hour(&self) -> i32 {
+        self.0.local().1 / 3600
+    }
+
+    pub fn minute(&self) -> i32 {
+        self.0.local().1 % 3600 / 60
+    }
+
+    pub fn second(&self) -> i32 {
+        self.0.local().1 % 60
+    }
+
+    /// Returns the fractional part of the second in nanoseconds, if it's stored as an integer.
+    pub fn nanosecond(&self) -> Option<i32> {
+        self.0.sf.as_int()
+    }
+
+    /// Returns the offset from UTC in seconds.
+    pub fn offset(&self) -> i32 {
+        self.0.of
+    }
+
+    /// Construct a JSON value from this object.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Value {
+        let (year, month, day) = self.0.civil();
+        let mut map = Map::new();
+        map.ezset("@", "DateTime");
+        map.ezset("year", year);
+        map.ezset("month", month);
+        map.ezset("day", day);
+        map.ezset("hour", self.hour());
+        map.ezset("minute", self.minute());
+        map.ezset("second", self.second());
+        if let Some(ns) = self.nanosecond() {
+            map.ezset("nanosecond", ns);
+        }
+        map.ezset("offset", self.offset());
+        Value::Object(map)
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fmt::{self, Formatter};
+use super::{RbAny, RbFields, RbRef, RbSymbol, RcType, rc_get_ptr};
+
+/// Values nested deeper than this are summarized instead of printed in full.
+const MAX_DEPTH: usize = 4;
+
+/// Implements `Debug` for `RbAny`.
+///
+/// Containers are printed with their contents, up to `MAX_DEPTH` levels deep, after which
+/// they're summarized (e.g. `[len 3]` or `Object(Foo){2}`). A shared value is labelled
+/// `&N` the first time it's printed and every later occurrence is printed as `#<ref N>`,
+/// which also stops recursive data from printing forever.
+pub struct RbDebug {
+    seen: HashMap<*const RbRef, usize>,
+    next_id: usize,
+}
+
+impl RbDebug {
+    pub fn new() -> Self {
+        Self {
+            seen: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn fmt(&mut self, value: &RbAny, f: &mut Formatter<'_>, depth: usize) -> fmt::Result {
+        let r = match value {
+            RbAny::Int(v) => return write!(f, "Int({})", v),
+            RbAny::True => return write!(f, "True"),
+            RbAny::False => return write!(f, "False"),
+            RbAny::Nil => return write!(f, "Nil"),
+            RbAny::Symbol(v) => return write!(f, "{:?}", v),
+            RbAny::Ref(r) => r,
+        };
+        let ptr = rc_get_ptr(r);
+        if let Some(id) = self.seen.get(&ptr) {
+            return write!(f, "#<ref {}>", id);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.seen.insert(ptr, id);
+        if RcType::strong_count(r) > 1 {
+            write!(f, "&{} ", id)?;
+        }
+        if !r.contains_ref() {
+            fmt::Debug::fmt(r.as_ref(), f)
+        } else if depth >= MAX_DEPTH {
+            self.fmt_summary(r, f)
+        } else {
+            self.fmt_full(r, f, depth + 1)
+        }
+    }
+
+    fn fmt_summary(&mut self, value: &RbRef, f: &mut Formatter<'_>) -> fmt::Result {
+        match value {
+            RbRef::Array(v) => write!(f, "[len {}]", v.len()),
+            RbRef::Hash(v) => write!(f, "{{len {}}}", v.len()),
+            RbRef::Object(v) => write!(f, "Object({}){{{}}}", name(&v.name), v.fields.len()),
+            RbRef::Struct(v) => write!(f, "Struct({}){{{}}}", name(&v.name), v.fields.len()),
+            RbRef::StrI { content, .. } => write!(f, "StrI({:?})", String::from_utf8_lossy(content)),
+            RbRef::RegexI { content, flags, .. } => write!(f, "RegexI(/{}/{})", String::from_utf8_lossy(content), flags),
+            RbRef::Data(v) => write!(f, "Data({})", name(&v.name)),
+            RbRef::UserClass(v) => write!(f, "UserClass({})", name(&v.name)),
+            RbRef::UserMarshal(v) => write!(f, "UserMarshal({})", name(&v.name)),
+            RbRef::Extended { module, .. } => write!(f, "Extended({})", name(module)),
+            RbRef::WithIvars { object, .. } => self.fmt_summary(object, f),
+            _ => fmt::Debug::fmt(value, f),
+        }
+    }
+
+    fn fmt_full(&mut self, value: &RbRef, f: &mut Formatter<'_>, depth: usize) -> fmt::Result {
+        match value {
+            RbRef::Array(v) => {
+                write!(f, "[")?;
+                for (i, it) in v.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    self.fmt(it, f, depth)?;
+                }
+                write!(f, "]")
+            },
+            RbRef::Hash(v) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in v.iter().enumerate() {
+                    if i > 0 { write!(f, ", ")?; }
+                    self.fmt(key, f, depth)?;
+                    write!(f, " => ")?;
+                    self.fmt(val, f, depth)?;
+                }
+                if let Some(def) = &v.default {
+                    if !v.is_empty() { write!(f, ", ")?; }
+                    write!(f, "default => ")?;
+                    self.fmt(def, f, depth)?;
+                }
+                write!(f, "}}")
+            },
+            RbRef::Object(v) => {
+                write!(f, "Object({})", name(&v.name))?;
+                self.fmt_fields(&v.fields, f, depth)
+            },
+            RbRef::Struct(v) => {
+                write!(f, "Struct({})", name(&v.name))?;
+                self.fmt_fields(&v.fields, f, depth)
+            },
+            RbRef::StrI { metadata, .. } | RbRef::RegexI { metadata, .. } => {
+                self.fmt_summary(value, f)?;
+                self.fmt_fields(metadata, f, depth)
+            },
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+                self.fmt_summary(value, f)?;
+                write!(f, "(")?;
+                self.fmt(&v.data, f, depth)?;
+                write!(f, ")")
+            },
+            RbRef::Extended { object, .. } => {
+                self.fmt_summary(value, f)?;
+                write!(f, "(")?;
+                self.fmt(object, f, depth)?;
+                write!(f, ")")
+            },
+            RbRef::WithIvars { object, ivars } => {
+                self.fmt_full(object, f, depth)?;
+                self.fmt_fields(ivars, f, depth)
+            },
+            _ => fmt::Debug::fmt(value, f),
+        }
+    }
+
+    fn fmt_fields(&mut self, fields: &RbFields, f: &mut Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (key, val)) in fields.iter().enumerate() {
+            if i > 0 { write!(f, ", ")?; }
+            write!(f, "{}: ", name(key))?;
+            self.fmt(val, f, depth)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+fn name(sym: &RbSymbol) -> String {
+    String::from_utf8_lossy(sym.as_bytes()).into_owned()
+}
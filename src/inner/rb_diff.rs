@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+use std::fmt;
+use super::{RbAny, RbFields, RbRef, rc_get_ptr};
+use super::rb_ref::hash_index;
+
+/// One difference between two values, see `RbAny::diff`.
+///
+/// `path` is the list of keys leading to the difference, in the form `RbAny::find_child`
+/// takes: array indexes are `Int`s, object fields are `Symbol`s, and hash entries use their key.
+#[derive(Clone, Debug)]
+pub enum DiffEntry {
+    /// The value at `path` is `old` in the first tree and `new` in the second.
+    Changed { path: Vec<RbAny>, old: RbAny, new: RbAny },
+    /// `path` only exists in the second tree.
+    Added { path: Vec<RbAny>, value: RbAny },
+    /// `path` only exists in the first tree.
+    Removed { path: Vec<RbAny>, value: RbAny },
+}
+
+impl DiffEntry {
+    pub fn path(&self) -> &[RbAny] {
+        match self {
+            DiffEntry::Changed { path, .. } | DiffEntry::Added { path, .. }
+                | DiffEntry::Removed { path, .. } => path,
+        }
+    }
+}
+
+/// Writes one line like `~ users[0].@name: Str("Jack") -> Str("Jane")`, `+ path: value`, or
+/// `- path: value`, with values in their `Debug` form.
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sigil = match self {
+            DiffEntry::Changed { .. } => '~',
+            DiffEntry::Added { .. } => '+',
+            DiffEntry::Removed { .. } => '-',
+        };
+        write!(f, "{} ", sigil)?;
+        if self.path().is_empty() {
+            write!(f, "<root>")?;
+        }
+        for (i, key) in self.path().iter().enumerate() {
+            match key {
+                RbAny::Int(v) => write!(f, "[{}]", v)?,
+                RbAny::Symbol(sym) => {
+                    let sep = if i == 0 { "" } else { "." };
+                    write!(f, "{}{}", sep, String::from_utf8_lossy(sym.as_bytes()))?
+                },
+                _ => write!(f, "[{:?}]", key)?,
+            }
+        }
+        write!(f, ": ")?;
+        match self {
+            DiffEntry::Changed { old, new, .. } => {
+                fmt_value(f, old)?;
+                write!(f, " -> ")?;
+                fmt_value(f, new)
+            },
+            DiffEntry::Added { value, .. } | DiffEntry::Removed { value, .. } => fmt_value(f, value),
+        }
+    }
+}
+
+/// Write `value` in its `Debug` form, leaving out the `&N` label for strings and other values
+/// which don't contain references, since the diff itself holds a reference to them.
+fn fmt_value(f: &mut fmt::Formatter<'_>, value: &RbAny) -> fmt::Result {
+    match value {
+        RbAny::Ref(r) if !r.contains_ref() => write!(f, "{:?}", r.as_ref()),
+        _ => write!(f, "{:?}", value),
+    }
+}
+
+/// Finds the differences between two trees, see `RbAny::diff`.
+pub struct RbDiff {
+    /// Pairs of nodes which have already been compared (or are being compared).
+    seen: HashSet<(*const RbRef, *const RbRef)>,
+    /// If true, `seen` only holds the pairs being compared, so shared values are compared
+    /// (and their differences reported) under every path to them, see `every_path`.
+    every_path: bool,
+    path: Vec<RbAny>,
+    entries: Vec<DiffEntry>,
+}
+
+impl RbDiff {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            every_path: false,
+            path: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Construct a differ which reports the differences in a shared value under every path
+    /// to it, rather than just the first. Recursive values are still only compared once
+    /// along each path.
+    pub fn every_path() -> Self {
+        Self { every_path: true, ..Self::new() }
+    }
+
+    pub fn diff(mut self, old: &RbAny, new: &RbAny) -> Vec<DiffEntry> {
+        self.diff_any(old, new);
+        self.entries
+    }
+
+    fn diff_any(&mut self, old: &RbAny, new: &RbAny) {
+        let (l, r) = match (old, new) {
+            (RbAny::Ref(l), RbAny::Ref(r)) => (l, r),
+            _ => {
+                if !old.deep_eq(new) {
+                    self.changed(old, new);
+                }
+                return;
+            },
+        };
+        let pair = (rc_get_ptr(l), rc_get_ptr(r));
+        if pair.0 == pair.1 || !self.seen.insert(pair) {
+            return;
+        }
+        self.diff_ref(l, r, old, new);
+        if self.every_path {
+            self.seen.remove(&pair);
+        }
+    }
+
+    /// Compare the contents of two nodes, where `old` and `new` are what to report if they
+    /// can't be compared piece by piece.
+    fn diff_ref(&mut self, l: &RbRef, r: &RbRef, old: &RbAny, new: &RbAny) {
+        match (l, r) {
+            (RbRef::Array(l0), RbRef::Array(r0)) => {
+                for (i, (a, b)) in l0.iter().zip(r0.iter()).enumerate() {
+                    self.at(RbAny::Int(i as i32), |this| this.diff_any(a, b));
+                }
+                for (i, it) in l0.iter().enumerate().skip(r0.len()) {
+                    self.at(RbAny::Int(i as i32), |this| this.removed(it));
+                }
+                for (i, it) in r0.iter().enumerate().skip(l0.len()) {
+                    self.at(RbAny::Int(i as i32), |this| this.added(it));
+                }
+            },
+            (RbRef::Hash(l0), RbRef::Hash(r0)) if l0.ruby2_keywords == r0.ruby2_keywords
+                && l0.compare_by_identity == r0.compare_by_identity
+                && defaults_eq(&l0.default, &r0.default) => {
+                for (key, val) in l0.iter() {
+                    match hash_index(r0, key) {
+                        Some(i) => self.at(key.clone(), |this| this.diff_any(val, &r0[i])),
+                        None => self.at(key.clone(), |this| this.removed(val)),
+                    }
+                }
+                for (key, val) in r0.iter() {
+                    if hash_index(l0, key).is_none() {
+                        self.at(key.clone(), |this| this.added(val));
+                    }
+                }
+            },
+            (RbRef::Object(l0), RbRef::Object(r0)) | (RbRef::Struct(l0), RbRef::Struct(r0))
+                if l0.name == r0.name => {
+                self.diff_fields(&l0.fields, &r0.fields);
+            },
+            (RbRef::Data(l0), RbRef::Data(r0)) | (RbRef::UserClass(l0), RbRef::UserClass(r0))
+                | (RbRef::UserMarshal(l0), RbRef::UserMarshal(r0)) if l0.name == r0.name => {
+                // The wrapped value's children are reached through this one, see `get_child`
+                self.diff_any(&l0.data, &r0.data);
+            },
+            (RbRef::Extended { module: l_mod, object: l_obj }, RbRef::Extended { module: r_mod, object: r_obj })
+                if l_mod == r_mod => {
+                self.diff_any(l_obj, r_obj);
+            },
+            (RbRef::WithIvars { object: l_obj, ivars: l_ivars }, RbRef::WithIvars { object: r_obj, ivars: r_ivars })
+                if fields_eq(l_ivars, r_ivars) => {
+                self.diff_ref(l_obj, r_obj, old, new);
+            },
+            _ => {
+                if !old.deep_eq(new) {
+                    self.changed(old, new);
+                }
+            },
+        }
+    }
+
+    fn diff_fields(&mut self, l0: &RbFields, r0: &RbFields) {
+        for (key, val) in l0.iter() {
+            match r0.get(key) {
+                Some(other) => self.at(key.as_any(), |this| this.diff_any(val, other)),
+                None => self.at(key.as_any(), |this| this.removed(val)),
+            }
+        }
+        for (key, val) in r0.iter() {
+            if !l0.contains_key(key) {
+                self.at(key.as_any(), |this| this.added(val));
+            }
+        }
+    }
+
+    /// Call `f` with `key` added to the current path.
+    fn at<F: FnOnce(&mut Self)>(&mut self, key: RbAny, f: F) {
+        self.path.push(key);
+        f(self);
+        self.path.pop();
+    }
+
+    fn changed(&mut self, old: &RbAny, new: &RbAny) {
+        self.entries.push(DiffEntry::Changed { path: self.path.clone(), old: old.clone(), new: new.clone() });
+    }
+
+    fn added(&mut self, value: &RbAny) {
+        self.entries.push(DiffEntry::Added { path: self.path.clone(), value: value.clone() });
+    }
+
+    fn removed(&mut self, value: &RbAny) {
+        self.entries.push(DiffEntry::Removed { path: self.path.clone(), value: value.clone() });
+    }
+}
+
+fn defaults_eq(l: &Option<Box<RbAny>>, r: &Option<Box<RbAny>>) -> bool {
+    match (l, r) {
+        (Some(l), Some(r)) => l.deep_eq(r),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn fields_eq(l: &RbFields, r: &RbFields) -> bool {
+    l.len() == r.len() && l.iter().all(|(key, val)| matches!(r.get(key), Some(other) if val.deep_eq(other)))
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use super::{RbAny, RbFields, RbRef, rc_get_ptr};
+
+/// Computes a digest of a tree which doesn't depend on object identity, see
+/// `RbAny::content_digest`.
+///
+/// Each node is digested from the digests of its children (sorting them where the order
+/// doesn't matter). A reference back to an ancestor is digested by how many levels up it
+/// points, so only nodes which don't refer outside of themselves can be cached.
+pub struct RbDigest {
+    /// Nodes which are currently being digested, and their depth.
+    ancestors: HashMap<*const RbRef, usize>,
+    /// Digests of nodes which don't refer to any of their ancestors.
+    done: HashMap<*const RbRef, [u8; 32]>,
+}
+
+impl RbDigest {
+    pub fn new() -> Self {
+        Self {
+            ancestors: HashMap::new(),
+            done: HashMap::new(),
+        }
+    }
+
+    pub fn digest(&mut self, value: &RbAny) -> [u8; 32] {
+        self.visit(value).0
+    }
+
+    /// Returns the digest of `value` and the depth of the shallowest ancestor it refers to.
+    fn visit(&mut self, value: &RbAny) -> ([u8; 32], usize) {
+        let mut h = Sha256::new();
+        let r = match value {
+            RbAny::Int(v) => {
+                h.update(b"i");
+                h.update(&v.to_le_bytes());
+                return (h.finalize().into(), usize::MAX);
+            },
+            RbAny::True => return (digest_bytes(b"T"), usize::MAX),
+            RbAny::False => return (digest_bytes(b"F"), usize::MAX),
+            RbAny::Nil => return (digest_bytes(b"0"), usize::MAX),
+            RbAny::Symbol(sym) => {
+                h.update(b":");
+                update_len_bytes(&mut h, sym.as_bytes());
+                return (h.finalize().into(), usize::MAX);
+            },
+            RbAny::Ref(r) => r,
+        };
+        let ptr = rc_get_ptr(r);
+        if let Some(digest) = self.done.get(&ptr) {
+            return (*digest, usize::MAX);
+        }
+        let depth = self.ancestors.len();
+        if let Some(&ancestor) = self.ancestors.get(&ptr) {
+            h.update(b"@");
+            h.update(&((depth - ancestor) as u64).to_le_bytes());
+            return (h.finalize().into(), ancestor);
+        }
+        self.ancestors.insert(ptr, depth);
+        let mut shallowest = usize::MAX;
+        self.visit_ref(r, &mut h, &mut shallowest);
+        self.ancestors.remove(&ptr);
+        let digest = h.finalize().into();
+        if shallowest >= depth {
+            self.done.insert(ptr, digest);
+        }
+        (digest, shallowest)
+    }
+
+    fn visit_ref(&mut self, node: &RbRef, h: &mut Sha256, shallowest: &mut usize) {
+        match node {
+            RbRef::Float(v) => {
+                h.update(b"f");
+                let bits = if v.0.is_nan() { f64::NAN.to_bits() } else { v.0.to_bits() };
+                h.update(&bits.to_le_bytes());
+            },
+            RbRef::BigInt(v) => {
+                h.update(b"l");
+                update_len_bytes(h, &v.to_signed_bytes_le());
+            },
+            RbRef::Array(v) => {
+                h.update(b"[");
+                h.update(&(v.len() as u64).to_le_bytes());
+                for it in v.iter() {
+                    h.update(&self.visit_child(it, shallowest));
+                }
+            },
+            RbRef::Hash(v) => {
+                h.update(if v.ruby2_keywords { b"K" } else { b"{" });
+                if v.compare_by_identity {
+                    h.update(b"C");
+                }
+                let entries = v.map.iter()
+                    .map(|(key, val)| {
+                        let mut entry = Sha256::new();
+                        entry.update(&self.visit_child(key, shallowest));
+                        entry.update(&self.visit_child(val, shallowest));
+                        entry.finalize().into()
+                    })
+                    .collect();
+                update_entries(h, entries, false);
+                if let Some(def) = &v.default {
+                    h.update(b"=");
+                    h.update(&self.visit_child(def, shallowest));
+                }
+            },
+            RbRef::Object(v) => {
+                h.update(b"o");
+                update_len_bytes(h, v.name.as_bytes());
+                let entries = self.field_digests(&v.fields, shallowest);
+                update_entries(h, entries, false);
+            },
+            RbRef::Struct(v) => {
+                h.update(b"S");
+                update_len_bytes(h, v.name.as_bytes());
+                let entries = self.field_digests(&v.fields, shallowest);
+                update_entries(h, entries, true);
+            },
+            RbRef::Str(v) => {
+                h.update(b"\"");
+                update_len_bytes(h, v.as_bytes());
+            },
+            RbRef::StrI { content, metadata } => {
+                h.update(b"s");
+                update_len_bytes(h, content);
+                let entries = self.field_digests(metadata, shallowest);
+                update_entries(h, entries, false);
+            },
+            RbRef::Regex { content, flags } => {
+                h.update(b"/");
+                update_len_bytes(h, content.as_bytes());
+                h.update(&flags.to_le_bytes());
+            },
+            RbRef::RegexI { content, flags, metadata } => {
+                h.update(b"r");
+                update_len_bytes(h, content);
+                h.update(&flags.to_le_bytes());
+                let entries = self.field_digests(metadata, shallowest);
+                update_entries(h, entries, false);
+            },
+            RbRef::ClassRef(v) => {
+                h.update(b"c");
+                update_len_bytes(h, v.as_bytes());
+            },
+            RbRef::ModuleRef(v) => {
+                h.update(b"m");
+                update_len_bytes(h, v.as_bytes());
+            },
+            RbRef::ClassModuleRef(v) => {
+                h.update(b"M");
+                update_len_bytes(h, v.as_bytes());
+            },
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+                h.update(match node {
+                    RbRef::Data(_) => b"d",
+                    RbRef::UserClass(_) => b"C",
+                    _ => b"U",
+                });
+                update_len_bytes(h, v.name.as_bytes());
+                h.update(&self.visit_child(&v.data, shallowest));
+            },
+            RbRef::UserData(v) => {
+                h.update(b"u");
+                update_len_bytes(h, v.name.as_bytes());
+                update_len_bytes(h, &v.data);
+            },
+            RbRef::Extended { module, object } => {
+                h.update(b"e");
+                update_len_bytes(h, module.as_bytes());
+                h.update(&self.visit_child(object, shallowest));
+            },
+            RbRef::WithIvars { object, ivars } => {
+                h.update(b"I");
+                let mut inner = Sha256::new();
+                self.visit_ref(object, &mut inner, shallowest);
+                h.update(inner.finalize());
+                let entries = self.field_digests(ivars, shallowest);
+                update_entries(h, entries, false);
+            },
+        }
+    }
+
+    /// Returns the digest of `value`, a child of the node being digested.
+    fn visit_child(&mut self, value: &RbAny, shallowest: &mut usize) -> [u8; 32] {
+        let (digest, depth) = self.visit(value);
+        *shallowest = (*shallowest).min(depth);
+        digest
+    }
+
+    fn field_digests(&mut self, fields: &RbFields, shallowest: &mut usize) -> Vec<[u8; 32]> {
+        fields.iter()
+            .map(|(key, val)| {
+                let mut entry = Sha256::new();
+                update_len_bytes(&mut entry, key.as_bytes());
+                entry.update(&self.visit_child(val, shallowest));
+                entry.finalize().into()
+            })
+            .collect()
+    }
+}
+
+fn digest_bytes(data: &[u8]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(data);
+    h.finalize().into()
+}
+
+fn update_len_bytes(h: &mut Sha256, data: &[u8]) {
+    h.update(&(data.len() as u64).to_le_bytes());
+    h.update(data);
+}
+
+/// Add the digests of some entries to `h`, sorting them first unless `ordered` is true.
+fn update_entries(h: &mut Sha256, mut entries: Vec<[u8; 32]>, ordered: bool) {
+    if !ordered {
+        entries.sort_unstable();
+    }
+    h.update(&(entries.len() as u64).to_le_bytes());
+    for it in entries.iter() {
+        h.update(it);
+    }
+}
@@ -0,0 +1,50 @@
+use encoding_rs::Encoding;
+use super::{RbAny, RbFields, RbSymbol};
+
+/// Ruby names for encodings which `encoding_rs` knows under another name. Most names (e.g.
+/// `Shift_JIS`, `EUC-JP`, `Windows-1252`, `ISO-8859-1`) are also WHATWG labels and don't need
+/// to be listed.
+const ALIASES: &[(&str, &str)] = &[
+    ("Windows-31J", "Shift_JIS"),
+    ("CP932", "Shift_JIS"),
+    ("SJIS", "Shift_JIS"),
+    ("MacJapanese", "Shift_JIS"),
+    ("eucJP-ms", "EUC-JP"),
+    ("CP51932", "EUC-JP"),
+    ("EUC-JIS-2004", "EUC-JP"),
+    ("CP936", "GBK"),
+    ("GB12345", "GBK"),
+    ("CP949", "EUC-KR"),
+    ("CP950", "Big5"),
+    ("Big5-HKSCS", "Big5"),
+    ("CP1251", "Windows-1251"),
+    ("CP1252", "Windows-1252"),
+];
+
+/// Returns the encoding with the given Ruby name, e.g. `"Shift_JIS"` or `"Windows-31J"`, if
+/// `encoding_rs` supports it. Binary (`ASCII-8BIT`) isn't a text encoding, so it has none.
+pub(super) fn for_ruby_name(name: &str) -> Option<&'static Encoding> {
+    if name.eq_ignore_ascii_case("ASCII-8BIT") || name.eq_ignore_ascii_case("BINARY") {
+        return None;
+    }
+    let label = ALIASES.iter()
+        .find(|(ruby, _)| ruby.eq_ignore_ascii_case(name))
+        .map_or(name, |(_, label)| label);
+    Encoding::for_label_no_replacement(label.as_bytes())
+}
+
+/// Returns the encoding of a string with the given ivars, see `RbRef::new_string_bin_with`.
+pub(super) fn for_metadata(metadata: &RbFields) -> Option<&'static Encoding> {
+    match metadata.get(&RbSymbol::from("E")) {
+        // US-ASCII is a subset of UTF-8
+        Some(RbAny::True) | Some(RbAny::False) => return Some(encoding_rs::UTF_8),
+        _ => {},
+    }
+    let name = metadata.get(&RbSymbol::from("encoding"))?.as_bytes()?;
+    for_ruby_name(std::str::from_utf8(name).ok()?)
+}
+
+/// Decode `content` from `encoding`, or None if it isn't valid in that encoding.
+pub(super) fn decode(encoding: &'static Encoding, content: &[u8]) -> Option<String> {
+    encoding.decode_without_bom_handling_and_without_replacement(content).map(|s| s.into_owned())
+}
@@ -6,6 +6,24 @@ use std::ops::{Deref, DerefMut};
 /// of the rules of f32 because they only implement PartialOrd and PartialEq.
 #[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
 pub struct RbFloat(pub f64);
+impl RbFloat {
+    /// Ruby's `Float::NAN`. Marshal writes every NaN as `nan`, so the sign and payload bits
+    /// of a NaN aren't preserved.
+    pub fn nan() -> Self { Self(f64::NAN) }
+    /// Ruby's `Float::INFINITY`.
+    pub fn inf() -> Self { Self(f64::INFINITY) }
+    /// Ruby's `-Float::INFINITY`.
+    pub fn neg_inf() -> Self { Self(f64::NEG_INFINITY) }
+
+    /// Returns true if this and `other` differ by at most `epsilon`, e.g. to ignore the last
+    /// digit changing after a trip through another tool. Equal infinities are approximately
+    /// equal, while NaN is never approximately equal to anything, including itself.
+    ///
+    /// `Eq` and `Hash` still compare exactly, since they're used for hash keys.
+    pub fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self.0 == other.0 || (self.0 - other.0).abs() <= epsilon
+    }
+}
 impl Hash for RbFloat {
     fn hash<H: Hasher>(&self, h: &mut H) { h.write_u64(self.0.to_bits()); }
 }
@@ -1,11 +1,22 @@
-use super::RbAny;
-use std::{cmp::Ordering, ops::{Deref, DerefMut}};
-use indexmap::IndexMap;
+use super::{RbAny, RbCompare, RbRef, rc_get_ptr};
+use std::{cmp::Ordering, hash::BuildHasher, ops::{Deref, DerefMut}};
+use indexmap::{IndexMap, map::{RawEntryApiV1, raw_entry_v1::RawEntryMut}};
 
 #[derive(Clone, Eq, Debug)]
 pub struct RbHash {
     pub map: IndexMap<RbAny, RbAny>,
     pub default: Option<Box<RbAny>>,
+    /// True if this hash is flagged for passing as keyword arguments (see Ruby's
+    /// `Hash.ruby2_keywords_hash`), which Marshal records as an instance variable named `K`.
+    pub ruby2_keywords: bool,
+    /// True if this hash compares its keys by identity (see Ruby's `Hash#compare_by_identity`),
+    /// so equal keys which are separate objects, like two `"a"` strings, are separate entries
+    /// when added with `insert`. Looking keys up still compares them by value.
+    ///
+    /// Marshal records this by writing the hash as a `Hash` user class (`C:\x09Hash{...`). The
+    /// reader keeps that `RbRef::UserClass` and sets this on the hash inside it, and the writer
+    /// adds it to a hash with this set which isn't already inside one.
+    pub compare_by_identity: bool,
 }
 impl RbHash {
     // Construct a new, empty RbHash with no default value.
@@ -13,9 +24,82 @@ impl RbHash {
         Self {
             map: IndexMap::new(),
             default: None,
+            ruby2_keywords: false,
+            compare_by_identity: false,
         }
     }
 
+    /// Construct a new, empty RbHash with the given default value (e.g. `Hash.new(0)`).
+    pub fn with_default(value: RbAny) -> Self {
+        Self {
+            map: IndexMap::new(),
+            default: Some(Box::new(value)),
+            ruby2_keywords: false,
+            compare_by_identity: false,
+        }
+    }
+
+    /// Insert a key-value pair, returning the old value if the key was already present (the
+    /// key itself isn't replaced). If `compare_by_identity` is set, a key which is a reference
+    /// is only present if it's the same object, otherwise this is `IndexMap::insert`.
+    pub fn insert(&mut self, key: RbAny, value: RbAny) -> Option<RbAny> {
+        if !self.compare_by_identity {
+            return self.map.insert(key, value);
+        }
+        let hash = self.map.hasher().hash_one(&key);
+        match self.map.raw_entry_mut_v1().from_hash(hash, |k| same_key(k, &key)) {
+            RawEntryMut::Occupied(mut entry) => Some(entry.insert(value)),
+            RawEntryMut::Vacant(entry) => {
+                entry.insert_hashed_nocheck(hash, key, value);
+                None
+            },
+        }
+    }
+
+    /// Returns true if this hash has a default value.
+    pub fn has_default(&self) -> bool {
+        self.default.is_some()
+    }
+
+    /// Returns the default value of this hash, if it has one.
+    pub fn default_value(&self) -> Option<&RbAny> {
+        self.default.as_deref()
+    }
+
+    pub fn default_value_mut(&mut self) -> Option<&mut RbAny> {
+        self.default.as_deref_mut()
+    }
+
+    /// Set the default value of this hash, returning the previous default if there was one.
+    pub fn set_default(&mut self, value: RbAny) -> Option<RbAny> {
+        self.default.replace(Box::new(value)).map(|v| *v)
+    }
+
+    /// Remove and return the default value of this hash, if it has one.
+    pub fn take_default(&mut self) -> Option<RbAny> {
+        self.default.take().map(|v| *v)
+    }
+
+    /// Keep only the entries for which `f` returns true, preserving their order. The default
+    /// value (if any) is kept either way.
+    pub fn retain<F>(&mut self, f: F)
+        where F: FnMut(&RbAny, &mut RbAny) -> bool
+    {
+        self.map.retain(f);
+    }
+
+    /// Sort the entries of this hash by key using `RbCompare` (see `RbAny::deep_cmp`), so
+    /// hashes with the same entries added in a different order compare equal and serialize
+    /// the same way.
+    ///
+    /// Ruby hashes keep their insertion order, which Marshal preserves, so this changes the
+    /// serialized output and the order Ruby iterates the loaded hash in. The default value
+    /// isn't affected.
+    pub fn sort_keys(&mut self) {
+        let mut compare = RbCompare::new();
+        self.map.sort_by(|k1, _, k2, _| compare.cmp(k1, k2));
+    }
+
     /// Construct a RbHash from an array of key-value pairs
     pub fn from_pairs(pairs: Vec<(RbAny, RbAny)>) -> Self {
         let mut map = IndexMap::new();
@@ -24,14 +108,80 @@ impl RbHash {
         }
         Self {
             map,
-            default: None
+            default: None,
+            ruby2_keywords: false,
+            compare_by_identity: false,
+        }
+    }
+
+    /// Returns a copy of the entries of this hash as key-value pairs, in order. This is the
+    /// reverse of `from_pairs`, so the default value isn't included.
+    pub fn to_pairs(&self) -> Vec<(RbAny, RbAny)> {
+        self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Consume this hash, returning its entries as key-value pairs, in order. The default
+    /// value is dropped.
+    pub fn into_pairs(self) -> Vec<(RbAny, RbAny)> {
+        self.map.into_iter().collect()
+    }
+
+    /// Remove entries whose keys are `deep_eq` to the key of another entry, returning how many
+    /// were removed. `keep` picks which of the duplicates stays, the others are dropped and
+    /// the remaining entries keep their order.
+    ///
+    /// Keys which are references are hashed by identity, so two arrays (or objects) with the
+    /// same contents can both be keys of an `RbHash`, which Ruby wouldn't allow. This is meant
+    /// for cleaning up generated hashes before writing them.
+    pub fn dedup_keys(&mut self, keep: DuplicateKeys) -> usize {
+        let len = self.map.len();
+        let mut entries: Vec<(RbAny, RbAny)> = self.map.drain(..).collect();
+        if keep == DuplicateKeys::KeepLast {
+            entries.reverse();
         }
+        let mut kept: Vec<(RbAny, RbAny)> = Vec::with_capacity(len);
+        for (k, v) in entries {
+            // Other keys are only equal if they hash the same, which the map already handles
+            let dup = matches!(k, RbAny::Ref(_)) && kept.iter().any(|(k2, _)| k2.deep_eq(&k));
+            if !dup {
+                kept.push((k, v));
+            }
+        }
+        if keep == DuplicateKeys::KeepLast {
+            kept.reverse();
+        }
+        self.map.extend(kept);
+        len - self.map.len()
+    }
+}
+
+/// Returns true if `a` and `b` are the same key of a hash which compares by identity. That's
+/// the same object for references, except class and module references which refer to the same
+/// class or module if they're equal, like all other values.
+fn same_key(a: &RbAny, b: &RbAny) -> bool {
+    match (a, b) {
+        (RbAny::Ref(l), RbAny::Ref(r)) => match l.as_ref() {
+            RbRef::ClassRef(_) | RbRef::ModuleRef(_) | RbRef::ClassModuleRef(_) => a == b,
+            _ => rc_get_ptr(l) == rc_get_ptr(r),
+        },
+        _ => a == b,
     }
 }
 
+/// Which entry `RbHash::dedup_keys` keeps when several have equal keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// Keep the entry added first.
+    KeepFirst,
+    /// Keep the entry added last, like assigning each entry in order would in Ruby, except
+    /// that the kept entry stays where it was added.
+    KeepLast,
+}
+
 impl PartialEq for RbHash {
     fn eq(&self, other: &Self) -> bool {
-        if self.map.len() != other.map.len() {
+        if self.map.len() != other.map.len() || self.ruby2_keywords != other.ruby2_keywords
+            || self.compare_by_identity != other.compare_by_identity {
             return false;
         }
         for (k,v) in self.map.iter() {
@@ -46,6 +196,10 @@ impl Ord for RbHash {
     fn cmp(&self, other: &Self) -> Ordering {
         let c0 = self.map.len().cmp(&other.map.len());
         if c0.is_ne() { return c0; }
+        let c0 = self.ruby2_keywords.cmp(&other.ruby2_keywords);
+        if c0.is_ne() { return c0; }
+        let c0 = self.compare_by_identity.cmp(&other.compare_by_identity);
+        if c0.is_ne() { return c0; }
         for i in 0..self.map.len() {
             let lh = self.map.get_index(i).unwrap();
             let rh = other.map.get_index(i).unwrap();
@@ -1,12 +1,22 @@
 use serde_json::{Value, Map, Number};
 use std::collections::HashMap;
-use super::{RbAny, RbClass, RbHash, RbObject, RbRef, RbUserData, rc_get_ptr};
+use std::io;
+use super::{RbAny, RbClass, RbDate, RbDateTime, RbFields, RbHash, RbObject, RbRef, RbSet, RbSymbol, RbUserData, rc_get_ptr};
+use crate::TResult;
 use base64::engine::general_purpose::STANDARD as BASE_64;
 use base64::Engine as _;
+use num_bigint::BigInt;
+use std::convert::TryFrom;
 
 pub struct RbToJson {
     seen: HashMap<*const RbRef, usize>,
     next_id: usize,
+    /// If true, objects and structs are converted to plain JSON objects of their fields, with
+    /// the `@` dropped from instance variable names, e.g. `{"name":"Alice"}` instead of
+    /// `{"@":"User","@id":1,"fields":{"@name":"Alice"}}`. The class name is lost, so this is
+    /// meant for consumers which don't care about Ruby types. An object which appears more than
+    /// once is still referred to as `"@N"`, use `RbAny::explode` first to avoid that.
+    pub flatten_objects: bool,
 }
 
 impl RbToJson {
@@ -14,6 +24,7 @@ impl RbToJson {
         Self {
             seen: HashMap::new(),
             next_id: 1,
+            flatten_objects: false,
         }
     }
 
@@ -64,7 +75,13 @@ impl RbToJson {
                 Value::Object(map)
             },
             RbRef::Str(v) => Value::String(v.clone()),
-            RbRef::StrI { .. } => todo!(),
+            RbRef::StrI { content, .. } => {
+                let mut map = Map::new();
+                map.ezset("data-b64", BASE_64.encode(content));
+                map.ezset("@", "String");
+                map.ezset("@id", obj_id);
+                Value::Object(map)
+            },
             // TODO use an object and include flags
             RbRef::Regex { content, flags } => {
                 let mut map = Map::new();
@@ -84,21 +101,45 @@ impl RbToJson {
             },
             RbRef::Hash(hash) => self.conv_hash(hash)?,
             RbRef::Struct(v) => self.conv_object(v)?,
-            RbRef::Object(v) => self.conv_object(v)?,
+            RbRef::Object(v) => match RbSet::from_object(v) {
+                Some(set) => self.conv_set(&set)?,
+                None => self.conv_object(v)?,
+            },
             RbRef::ClassRef(v) => Value::from(v.as_str()),
             RbRef::ModuleRef(v) => Value::from(v.as_str()),
             RbRef::ClassModuleRef(v) => Value::from(v.as_str()),
             RbRef::Data(v) => self.conv_class(v)?,
             RbRef::UserClass(v) => self.conv_class(v)?,
             RbRef::UserData(v) => self.conv_user_data(v)?,
-            RbRef::UserMarshal(v) => self.conv_class(v)?,
+            RbRef::UserMarshal(v) => {
+                if let Some(date) = RbDate::from_class(v) {
+                    date.to_json()
+                } else if let Some(date) = RbDateTime::from_class(v) {
+                    date.to_json()
+                } else if let Some(set) = RbSet::from_ref(value) {
+                    self.conv_set(&set)?
+                } else {
+                    self.conv_class(v)?
+                }
+            },
             RbRef::Extended { module, object } => {
                 let mut map = Map::new();
                 map.ezset("object", self.conv_any(object)?);
                 map.ezset("module", module.to_json()?);
                 map.ezset("@", "@extended@");
                 Value::Object(map)
-            }
+            },
+            RbRef::WithIvars { object, ivars } => {
+                let mut value = self.conv_ref(object)?;
+                let mut fields = Map::new();
+                for (key, val) in ivars.iter() {
+                    fields.insert(key.as_str()?.to_owned(), self.conv_any(val)?);
+                }
+                if let Value::Object(map) = &mut value {
+                    map.ezset("ivars", fields);
+                }
+                value
+            },
         };
         Some(r)
     }
@@ -112,6 +153,14 @@ impl RbToJson {
 
     /// Return a new JSON object representing this object.
     fn conv_object(&mut self, value: &RbObject) -> Option<Value> {
+        if self.flatten_objects {
+            let mut map = Map::new();
+            for (key, val) in value.fields.iter() {
+                let key = key.as_str()?;
+                map.insert(key.strip_prefix('@').unwrap_or(key).to_owned(), self.conv_any(val)?);
+            }
+            return Some(Value::Object(map));
+        }
         let mut map = Map::new();
         map.ezset("@", value.name.as_str()?);
         map.ezset("@id", self.next_id - 1);
@@ -125,6 +174,19 @@ impl RbToJson {
         Some(Value::Object(map))
     }
 
+    /// Return a new JSON object listing the members of a `Set`, rather than its `@hash`.
+    fn conv_set(&mut self, value: &RbSet) -> Option<Value> {
+        let mut map = Map::new();
+        map.ezset("@", "Set");
+        map.ezset("@id", self.next_id - 1);
+        let mut ar = Vec::with_capacity(value.len());
+        for it in value.elements() {
+            ar.push(self.conv_any(it)?);
+        }
+        map.ezset("data", Value::Array(ar));
+        Some(Value::Object(map))
+    }
+
     fn conv_hash(&mut self, value: &RbHash) -> Option<Value> {
         let mut map = Map::new();
         map.ezset("@", "Hash");
@@ -151,6 +213,305 @@ impl RbToJson {
     }
 }
 
+impl Default for RbToJson {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the same JSON as `RbToJson` directly to `dst`, without building a `Value` for
+/// anything which contains other values, see `RbAny::write_json`.
+pub struct RbJsonWriter<W> {
+    dst: W,
+    /// Converts values which don't contain references, and keeps track of `@id`s.
+    conv: RbToJson,
+    /// True if nothing has been written in the current JSON object or array yet.
+    first: bool,
+}
+
+impl<W: io::Write> RbJsonWriter<W> {
+    pub fn new(dst: W) -> Self {
+        Self {
+            dst,
+            conv: RbToJson::new(),
+            first: true,
+        }
+    }
+
+    pub fn write(&mut self, value: &RbAny) -> TResult<()> {
+        self.write_any(value)
+    }
+
+    /// Same as `RbToJson::conv_any`.
+    fn write_any(&mut self, value: &RbAny) -> TResult<()> {
+        match value {
+            RbAny::Ref(r) if r.contains_ref() => {
+                let ptr = rc_get_ptr(r);
+                if let Some(obj_id) = self.conv.seen.get(&ptr) {
+                    let obj_id = format!("@{}", obj_id);
+                    self.scalar(&obj_id)
+                } else {
+                    self.conv.seen.insert(ptr, self.conv.next_id);
+                    self.conv.next_id += 1;
+                    self.write_ref(r, None)
+                }
+            },
+            _ => {
+                let value = self.conv.conv_any(value).ok_or_else(unconvertible)?;
+                self.scalar(&value)
+            },
+        }
+    }
+
+    /// Same as `RbToJson::conv_ref`, with `ivars` being the instance variables to add to the
+    /// object, if this is the object of a `WithIvars`.
+    fn write_ref(&mut self, value: &RbRef, ivars: Option<&RbFields>) -> TResult<()> {
+        let obj_id = self.conv.next_id - 1;
+        if let Some(set) = RbSet::from_ref(value) {
+            self.begin_object()?;
+            self.entry("@", "Set")?;
+            self.entry("@id", obj_id)?;
+            self.key("data")?;
+            self.begin_array()?;
+            for it in set.elements() {
+                self.element()?;
+                self.write_any(it)?;
+            }
+            self.end_array()?;
+            return self.end_object(ivars);
+        }
+        match value {
+            RbRef::Array(v) => {
+                self.begin_object()?;
+                self.entry("@", "Array")?;
+                self.entry("@id", obj_id)?;
+                self.key("data")?;
+                self.begin_array()?;
+                for it in v.iter() {
+                    self.element()?;
+                    self.write_any(it)?;
+                }
+                self.end_array()?;
+                self.end_object(ivars)
+            },
+            RbRef::Hash(v) => {
+                self.begin_object()?;
+                self.entry("@", "Hash")?;
+                self.entry("@id", obj_id)?;
+                self.key("data")?;
+                self.begin_array()?;
+                for (key, val) in v.map.iter() {
+                    self.element()?;
+                    self.begin_array()?;
+                    self.element()?;
+                    self.write_any(key)?;
+                    self.element()?;
+                    self.write_any(val)?;
+                    self.end_array()?;
+                }
+                self.end_array()?;
+                if let Some(def) = &v.default {
+                    self.key("default")?;
+                    self.write_any(def)?;
+                }
+                self.end_object(ivars)
+            },
+            RbRef::Struct(v) | RbRef::Object(v) => {
+                self.begin_object()?;
+                self.entry("@", v.name.as_str().ok_or_else(unconvertible)?)?;
+                self.entry("@id", obj_id)?;
+                self.key("fields")?;
+                self.write_fields(&v.fields)?;
+                self.end_object(ivars)
+            },
+            RbRef::UserMarshal(v) if RbDate::from_class(v).is_some() || RbDateTime::from_class(v).is_some() => {
+                let value = self.conv.conv_ref(value).ok_or_else(unconvertible)?;
+                self.write_leaf(value, ivars)
+            },
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+                self.begin_object()?;
+                self.entry("@", v.name.as_str().ok_or_else(unconvertible)?)?;
+                self.key("data")?;
+                self.write_any(&v.data)?;
+                self.end_object(ivars)
+            },
+            RbRef::Extended { module, object } => {
+                self.begin_object()?;
+                self.key("object")?;
+                self.write_any(object)?;
+                self.entry("module", module.to_json().ok_or_else(unconvertible)?)?;
+                self.entry("@", "@extended@")?;
+                self.end_object(ivars)
+            },
+            RbRef::WithIvars { object, ivars } => self.write_ref(object, Some(ivars)),
+            _ => {
+                let value = self.conv.conv_ref(value).ok_or_else(unconvertible)?;
+                self.write_leaf(value, ivars)
+            },
+        }
+    }
+
+    /// Write a value converted by `RbToJson`, adding `ivars` if it's an object. Like
+    /// `RbToJson`, `ivars` are converted (and then dropped) if it isn't.
+    fn write_leaf(&mut self, value: Value, ivars: Option<&RbFields>) -> TResult<()> {
+        match (value, ivars) {
+            (Value::Object(map), Some(_)) => {
+                self.begin_object()?;
+                for (key, val) in map.iter() {
+                    self.entry(key, val)?;
+                }
+                self.end_object(ivars)
+            },
+            (value, _) => {
+                self.scalar(&value)?;
+                for val in ivars.into_iter().flat_map(|v| v.values()) {
+                    self.conv.conv_any(val).ok_or_else(unconvertible)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn write_fields(&mut self, fields: &RbFields) -> TResult<()> {
+        self.begin_object()?;
+        for (key, val) in fields.iter() {
+            self.key(key.as_str().ok_or_else(unconvertible)?)?;
+            self.write_any(val)?;
+        }
+        self.end_object(None)
+    }
+
+    fn begin_object(&mut self) -> TResult<()> {
+        self.first = true;
+        Ok(self.dst.write_all(b"{")?)
+    }
+
+    /// Write `ivars` as the `ivars` entry if there are any, then close the object.
+    fn end_object(&mut self, ivars: Option<&RbFields>) -> TResult<()> {
+        if let Some(ivars) = ivars {
+            self.key("ivars")?;
+            self.write_fields(ivars)?;
+        }
+        self.first = false;
+        Ok(self.dst.write_all(b"}")?)
+    }
+
+    fn begin_array(&mut self) -> TResult<()> {
+        self.first = true;
+        Ok(self.dst.write_all(b"[")?)
+    }
+
+    fn end_array(&mut self) -> TResult<()> {
+        self.first = false;
+        Ok(self.dst.write_all(b"]")?)
+    }
+
+    /// Start the next element of an array.
+    fn element(&mut self) -> TResult<()> {
+        if !std::mem::replace(&mut self.first, false) {
+            self.dst.write_all(b",")?;
+        }
+        Ok(())
+    }
+
+    /// Start the next entry of an object.
+    fn key(&mut self, key: &str) -> TResult<()> {
+        self.element()?;
+        self.scalar(key)?;
+        Ok(self.dst.write_all(b":")?)
+    }
+
+    fn entry<V: serde::Serialize>(&mut self, key: &str, value: V) -> TResult<()> {
+        self.key(key)?;
+        self.scalar(&value)
+    }
+
+    fn scalar<V: serde::Serialize + ?Sized>(&mut self, value: &V) -> TResult<()> {
+        serde_json::to_writer(&mut self.dst, value).map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+fn unconvertible() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "value can't be converted to JSON")
+}
+
+impl RbHash {
+    /// Convert this hash directly into a JSON object, without the `@` tags `RbAny::to_json`
+    /// adds for the hash itself. Values are converted with `RbAny::to_json`.
+    ///
+    /// Returns None if any key isn't a UTF-8 string or symbol, or if a value can't be converted,
+    /// so the caller can fall back to `RbAny::to_json`. Note that a string and a symbol with
+    /// the same name become the same JSON key, in which case the later entry wins.
+    pub fn to_json_object(&self) -> Option<Value> {
+        let mut conv = RbToJson::new();
+        let mut map = Map::new();
+        for (key, val) in self.iter() {
+            let key = match key {
+                RbAny::Symbol(sym) => sym.as_str()?,
+                _ => key.as_string()?.as_str(),
+            };
+            map.insert(key.to_owned(), conv.to_json(val)?);
+        }
+        Some(Value::Object(map))
+    }
+}
+
+/// How `RbAny::from_plain_json_with` converts JSON objects, which could stand for either a
+/// Ruby `Hash` or an object's instance variables. See `RbAny::from_plain_json_as_object` for
+/// building an object.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlainJsonObjects {
+    /// A `Hash` with string keys, e.g. what Ruby's `JSON.parse` returns (the default).
+    #[default]
+    StringKeys,
+    /// A `Hash` with symbol keys, e.g. what `JSON.parse(s, symbolize_names: true)` returns.
+    SymbolKeys,
+}
+
+/// Convert ordinary (untagged) JSON into Ruby data, see `RbAny::from_plain_json_with`.
+pub fn from_plain_json(value: &Value, objects: PlainJsonObjects) -> RbAny {
+    match value {
+        Value::Null => RbAny::Nil,
+        Value::Bool(v) => RbAny::from(*v),
+        Value::Number(v) => {
+            if let Some(n) = v.as_i64() {
+                match i32::try_from(n) {
+                    Ok(n) => RbAny::Int(n),
+                    Err(_) => RbAny::from(RbRef::BigInt(BigInt::from(n))),
+                }
+            } else if let Some(n) = v.as_u64() {
+                RbAny::from(RbRef::BigInt(BigInt::from(n)))
+            } else {
+                RbAny::from(v.as_f64().unwrap_or(f64::NAN))
+            }
+        },
+        Value::String(v) => RbAny::from(v.as_str()),
+        Value::Array(v) => RbAny::from(v.iter().map(|it| from_plain_json(it, objects)).collect::<Vec<_>>()),
+        Value::Object(v) => {
+            let mut hash = RbHash::new();
+            for (key, val) in v.iter() {
+                let key = match objects {
+                    PlainJsonObjects::StringKeys => RbAny::from(key.as_str()),
+                    PlainJsonObjects::SymbolKeys => RbAny::symbol_from(key),
+                };
+                hash.insert(key, from_plain_json(val, objects));
+            }
+            RbAny::from(hash)
+        },
+    }
+}
+
+/// Convert a JSON object into an object of class `name`, see `RbAny::from_plain_json_as_object`.
+pub fn from_plain_json_as_object(value: &Value, name: RbSymbol) -> Option<RbObject> {
+    let mut obj = RbObject::empty(name);
+    for (key, val) in value.as_object()?.iter() {
+        let key = if key.starts_with('@') { key.clone() } else { format!("@{}", key) };
+        obj.insert(key.as_str(), from_plain_json(val, PlainJsonObjects::default()));
+    }
+    Some(obj)
+}
+
 pub trait JsonMapExt {
     fn ezset<K, V>(&mut self, key: K, value: V) where K: AsRef<str>, V: Into<Value>;
 }
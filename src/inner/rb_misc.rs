@@ -1,7 +1,11 @@
 use std::cmp::{Eq, Ordering, PartialEq};
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::ops::{Deref, DerefMut};
-use super::{RbAny, RcType};
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut, Range};
+use super::{RbAny, RbRef, RcType};
+use crate::TResult;
 use indexmap::IndexMap;
 #[cfg(feature = "json")]
 use serde_json::Value;
@@ -13,27 +17,109 @@ use serde_json::Value;
 /// 
 /// Most `Symbol`s will be a UTF-8 string, however the Ruby specification places no definite
 /// bounds, meaning that 
-#[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone)]
 pub struct RbSymbol {
     /// Raw data representing the symbol name. Specifically does NOT have to have an encoding
-    data: RcType<Vec<u8>>,
+    data: SymbolData,
+    /// Encoding of the name, this only matters for names which aren't ASCII.
+    encoding: SymbolEncoding,
 }
+
+/// The encoding of a symbol's name.
+///
+/// Marshal only records the encoding of symbols which aren't ASCII, by wrapping the first
+/// occurrence of the symbol in an instance with an encoding field, like a String.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SymbolEncoding {
+    /// UTF-8, the default.
+    Utf8,
+    /// Binary data (ASCII-8BIT), written without an encoding field.
+    Binary,
+    /// Any other encoding by name, e.g. `"Shift_JIS"`.
+    Named(String),
+}
+
+/// Storage for the bytes of an `RbSymbol`.
+#[derive(Clone)]
+enum SymbolData {
+    /// The symbol owns its data.
+    Owned(RcType<Vec<u8>>),
+    /// The symbol is a range of a larger, shared buffer (see `from_bytes_shared`).
+    Shared(RcType<[u8]>, Range<usize>),
+}
+
 impl RbSymbol {
     /// Construct an RbSymbol from raw data
     pub fn new(data: Vec<u8>) -> RbSymbol {
         Self {
-            data: RcType::new(data),
+            data: SymbolData::Owned(RcType::new(data)),
+            encoding: SymbolEncoding::Utf8,
+        }
+    }
+
+    /// Construct an RbSymbol which refers to `range` within `buf` rather than copying it. The
+    /// reader only calls this with ranges it has already read.
+    pub(crate) fn from_shared(buf: RcType<[u8]>, range: Range<usize>) -> RbSymbol {
+        debug_assert!(range.start <= range.end && range.end <= buf.len(), "symbol range out of bounds");
+        Self {
+            data: SymbolData::Shared(buf, range),
+            encoding: SymbolEncoding::Utf8,
         }
     }
 
+    /// Returns true if this symbol refers to a shared buffer (see `from_bytes_shared`) rather
+    /// than owning its name. Such a symbol keeps the whole buffer alive.
+    pub fn is_shared(&self) -> bool {
+        matches!(self.data, SymbolData::Shared(..))
+    }
+
+    /// Returns a copy of this symbol which owns its name, so it no longer keeps a shared
+    /// buffer alive. See `RbAny::detach_symbols` to do this for a whole tree.
+    pub fn detached(&self) -> RbSymbol {
+        Self::new(self.as_bytes().to_vec()).with_encoding(self.encoding.clone())
+    }
+
+    /// Returns the encoding of the symbol's name.
+    pub fn encoding(&self) -> &SymbolEncoding {
+        &self.encoding
+    }
+
+    /// Returns this symbol with its encoding set to `encoding`.
+    ///
+    /// Note that symbols compare equal (and are deduplicated when written) based only on
+    /// their bytes, so the encoding of a name which also appears with another encoding may
+    /// not be preserved.
+    pub fn with_encoding(mut self, encoding: SymbolEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
     /// Get the raw bytes of the symbol.
     pub fn as_bytes(&self) -> &[u8] {
-        &self.data
+        match &self.data {
+            SymbolData::Owned(v) => v,
+            SymbolData::Shared(buf, range) => &buf[range.clone()],
+        }
+    }
+
+    /// Returns the length of the symbol in bytes.
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Attempt to get the symbol as a UTF-8 string.
     pub fn as_str(&self) -> Option<&str> {
-        std::str::from_utf8(&self.data).ok()
+        std::str::from_utf8(self.as_bytes()).ok()
+    }
+
+    /// Returns true if this symbol's name is `name`. Symbols which aren't valid UTF-8 are
+    /// never equal to a `&str`. This is the same as `sym == name`.
+    pub fn eq_str(&self, name: &str) -> bool {
+        self.as_bytes() == name.as_bytes()
     }
 
     /// Return a clone of this, converted to an `RbAny`.
@@ -43,7 +129,7 @@ impl RbSymbol {
 
     /// Construct an RbSymbol from a string.
     pub fn from_str<S: AsRef<str>>(v: S) -> Self {
-        Self { data: RcType::new(Vec::from(v.as_ref().as_bytes())) }
+        Self::new(Vec::from(v.as_ref().as_bytes()))
     }
 
     /// Construct a JSON value from this object.
@@ -54,7 +140,48 @@ impl RbSymbol {
 }
 impl Default for RbSymbol {
     fn default() -> Self {
-        Self { data: RcType::new(Vec::new()) }
+        Self::new(Vec::new())
+    }
+}
+impl PartialEq for RbSymbol {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+impl Eq for RbSymbol {}
+impl PartialEq<str> for RbSymbol {
+    fn eq(&self, other: &str) -> bool {
+        self.eq_str(other)
+    }
+}
+impl PartialEq<&str> for RbSymbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.eq_str(other)
+    }
+}
+impl PartialEq<RbSymbol> for str {
+    fn eq(&self, other: &RbSymbol) -> bool {
+        other.eq_str(self)
+    }
+}
+impl PartialEq<RbSymbol> for &str {
+    fn eq(&self, other: &RbSymbol) -> bool {
+        other.eq_str(self)
+    }
+}
+impl PartialOrd for RbSymbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for RbSymbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+impl Hash for RbSymbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
     }
 }
 
@@ -68,12 +195,78 @@ impl Into<RbSymbol> for &RbSymbol {
     fn into(self) -> RbSymbol { self.clone() }
 }
 
+/// Shares the storage of symbol names across documents, see `RbReader::with_interner`.
+///
+/// Each reader has its own symbol table, so reading many related documents otherwise
+/// allocates a fresh copy of common names like `@name` for every document. Symbols from an
+/// interner still compare (and are written) exactly like any other symbol.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolInterner {
+    names: HashSet<InternedName>,
+    /// Number of calls to `intern` which found an existing name.
+    reused: usize,
+}
+
+impl SymbolInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a symbol named `name`, sharing its storage with every other symbol of the same
+    /// name returned by this interner.
+    pub fn intern(&mut self, name: &[u8]) -> RbSymbol {
+        let data = match self.names.get(name) {
+            Some(v) => {
+                self.reused += 1;
+                v.0.clone()
+            },
+            None => {
+                let v = RcType::new(name.to_vec());
+                self.names.insert(InternedName(v.clone()));
+                v
+            },
+        };
+        RbSymbol { data: SymbolData::Owned(data), encoding: SymbolEncoding::Utf8 }
+    }
+
+    /// Returns the number of distinct names stored.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Returns how many times `intern` reused an existing name instead of allocating a new
+    /// one, which is useful for measuring whether interning is worthwhile.
+    pub fn reused(&self) -> usize {
+        self.reused
+    }
+
+    /// Forget all names. Symbols which were already returned keep their storage.
+    pub fn clear(&mut self) {
+        self.names.clear();
+        self.reused = 0;
+    }
+}
+
+/// A name stored in a `SymbolInterner`, which can be looked up by its bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct InternedName(RcType<Vec<u8>>);
+
+impl Borrow<[u8]> for InternedName {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl fmt::Debug for RbSymbol {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         if let Some(s) = self.as_str() {
             write!(f, "RbSymbol(\"{}\")", s)
         } else {
-            write!(f, "RbSymbol({:?})", self.data)
+            write!(f, "RbSymbol({:?})", self.as_bytes())
         }
     }
 }
@@ -115,12 +308,37 @@ impl DerefMut for RbFields {
 }
 
 
+/// Name of the `UserData` which holds the raw bytes of an `RbClass` whose contents couldn't be
+/// read. Class names can't start with `@`, so this never clashes with real data.
+const UNDECODED_NAME: &str = "@undecoded";
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct RbClass {
     pub name: RbSymbol,
     pub data: RbAny,
 }
 impl RbClass {
+    /// Construct a class whose contents couldn't be read and are kept as `raw` bytes, see
+    /// `RbReader::lenient_user_data`.
+    pub fn new_undecoded(name: RbSymbol, raw: Vec<u8>) -> Self {
+        let data = RbRef::UserData(RbUserData { name: RbSymbol::from(UNDECODED_NAME), data: raw }).into_any();
+        Self { name, data }
+    }
+
+    /// Returns the raw bytes of the contents if they couldn't be read, see
+    /// `RbReader::lenient_user_data`.
+    pub fn undecoded(&self) -> Option<&[u8]> {
+        match self.data.as_rbref()? {
+            RbRef::UserData(v) if v.name.eq_str(UNDECODED_NAME) => Some(&v.data),
+            _ => None,
+        }
+    }
+
+    /// Get the value wrapped by this class, e.g. the result of `_dump_data` for `Data` or
+    /// `marshal_dump` for `UserMarshal`.
+    pub fn wrapped_data(&self) -> &RbAny {
+        &self.data
+    }
 }
 
 
@@ -130,4 +348,49 @@ pub struct RbUserData {
     pub data: Vec<u8>,
 }
 impl RbUserData {
+    /// Get the raw bytes of the user data, as produced by the class's `_dump` method.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the user data as text, or None if it isn't valid UTF-8. This is useful for classes
+    /// whose `_dump` method returns a string, e.g. JSON.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.data).ok()
+    }
+
+    /// Decode the data using the handler registered in `registry` for this class name.
+    ///
+    /// Returns None if there is no handler for this class, in which case the raw
+    /// bytes are still available with `as_bytes()`.
+    pub fn decode_with(&self, registry: &UserDataRegistry) -> Option<TResult<RbAny>> {
+        registry.get(&self.name).map(|handler| handler(&self.data))
+    }
+}
+
+/// A function which decodes the payload of an `RbUserData`.
+pub type UserDataHandler = fn(&[u8]) -> TResult<RbAny>;
+
+/// A set of `UserDataHandler`s keyed by class name, allowing user data to be decoded
+/// lazily after loading. See `RbUserData::decode_with`.
+#[derive(Debug, Clone, Default)]
+pub struct UserDataRegistry {
+    handlers: HashMap<RbSymbol, UserDataHandler>,
+}
+impl UserDataRegistry {
+    /// Construct a new registry with no handlers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` as the decoder for user data with the class name `name`,
+    /// replacing any previous handler for that class.
+    pub fn register<N: Into<RbSymbol>>(&mut self, name: N, handler: UserDataHandler) {
+        self.handlers.insert(name.into(), handler);
+    }
+
+    /// Returns the handler for the given class name, if one is registered.
+    pub fn get(&self, name: &RbSymbol) -> Option<UserDataHandler> {
+        self.handlers.get(name).copied()
+    }
 }
\ No newline at end of file
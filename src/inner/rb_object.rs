@@ -1,7 +1,12 @@
+use std::borrow::Cow;
 use std::cmp::{Eq, PartialEq, Ordering};
+use std::collections::HashMap;
 use super::{RbAny, RbFields, RbRef, RbSymbol};
 use crate::{ThurgoodError as Error};
 
+/// Maximum length of the values shown by `RbObject::matches`.
+const PREVIEW_LEN: usize = 60;
+
 /// A Ruby Object (or Struct) that has a type name and a set of fields, this is a serialized
 /// instance of a class.
 /// 
@@ -21,6 +26,24 @@ impl RbObject {
         Self { name: name.clone(), fields: RbFields::new() }
     }
 
+    /// Construct an object of class `name` with no fields, like `Object.new` or any object
+    /// whose instance variables were never set. Ruby writes these as `o:\x08Foo\x00`.
+    pub fn empty<N: Into<RbSymbol>>(name: N) -> Self {
+        Self { name: name.into(), fields: RbFields::new() }
+    }
+
+    /// Returns true if this object has no fields. Note that this is the same for an object which
+    /// had no instance variables in Ruby and one which hasn't been filled in yet.
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Start building an object of class `name`, adding fields in order with
+    /// `RbObjectBuilder::field`, e.g. `RbObject::builder("Foo").field("@age", 25).build()`.
+    pub fn builder<N: Into<RbSymbol>>(name: N) -> RbObjectBuilder {
+        RbObjectBuilder { object: Self::empty(name) }
+    }
+
     /// Construct a new Object with the given name and fields.
     pub fn new_from_slice<N, K>(name: N, items: &[(K, RbAny)]) -> Self
     where
@@ -68,6 +91,31 @@ impl RbObject {
         self.fields.get_mut(&key)
     }
 
+    /// Returns true if this object has a field named `key`.
+    pub fn contains_field<Q: Into<RbSymbol>>(&self, key: Q) -> bool {
+        self.fields.contains_key(&key.into())
+    }
+
+    /// Iterate over the names of this object's fields, in order.
+    pub fn field_names(&self) -> impl Iterator<Item = &RbSymbol> {
+        self.fields.keys()
+    }
+
+    /// Iterate over the names of this object's fields, in order. Same as `field_names`.
+    pub fn keys(&self) -> impl Iterator<Item = &RbSymbol> {
+        self.fields.keys()
+    }
+
+    /// Iterate over the values of this object's fields, in order.
+    pub fn values(&self) -> impl Iterator<Item = &RbAny> {
+        self.fields.values()
+    }
+
+    /// Iterate over the values of this object's fields, in order, with mutable access.
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut RbAny> {
+        self.fields.values_mut()
+    }
+
     /// Inserts a key-value pair into the map.
     ///
     /// If the map did not have this key present, `None` is returned.
@@ -77,6 +125,13 @@ impl RbObject {
         self.fields.insert(key.into(), value)
     }
 
+    /// Keep only the fields for which `f` returns true, preserving their order.
+    pub fn retain<F>(&mut self, f: F)
+        where F: FnMut(&RbSymbol, &mut RbAny) -> bool
+    {
+        self.fields.retain(f);
+    }
+
     /// Assume each pair is an `(RbSymbol, RbAny)` and add each pair to the list of fields.
     /// If one of the keys is not an `RbSymbol` return an error, otherwise return `Ok(())`.
     pub fn extend_from_pairs(&mut self, pairs: &RbFields) -> Result<(), Error> {
@@ -86,6 +141,69 @@ impl RbObject {
         Ok(())
     }
 
+    /// Rearrange the fields of this object to match `order`.
+    ///
+    /// Fields named in `order` come first, in that order. Names in `order` which aren't fields
+    /// of this object are ignored, and fields which aren't named in `order` are moved to the end
+    /// while keeping their current relative order. Note that this changes the serialized output.
+    pub fn reorder_fields(&mut self, order: &[RbSymbol]) {
+        let mut rank = HashMap::new();
+        for (i, key) in order.iter().enumerate() {
+            rank.entry(key).or_insert(i);
+        }
+        let rank_of = |k: &RbSymbol| rank.get(k).copied().unwrap_or(usize::MAX);
+        self.fields.sort_by(|k1, _, k2, _| rank_of(k1).cmp(&rank_of(k2)));
+    }
+
+    /// Sort the fields of this object by name (comparing the raw bytes of each symbol), so
+    /// objects with the same fields in a different order compare equal with `deep_eq`.
+    ///
+    /// Field names are unique, so there are never ties. Note that this changes the serialized
+    /// output, see `reorder_fields` for choosing a specific order.
+    pub fn sort_fields(&mut self) {
+        self.fields.sort_by(|k1, _, k2, _| k1.as_bytes().cmp(k2.as_bytes()));
+    }
+
+    /// Check that this object has exactly the fields in `expected`, in any order, comparing
+    /// each with `RbAny::deep_eq`. This is meant for test assertions, e.g.
+    /// `obj.matches(&[("@name", RbAny::from("bob"))]).unwrap()`.
+    ///
+    /// On failure the error lists every mismatch on its own line: fields with different
+    /// values, missing fields, and fields which weren't expected.
+    pub fn matches(&self, expected: &[(&str, RbAny)]) -> Result<(), String> {
+        let mut problems = Vec::new();
+        for (name, value) in expected {
+            match self.fields.get(&RbSymbol::from(*name)) {
+                Some(actual) if actual.deep_eq(value) => {},
+                Some(actual) => problems.push(format!("{}: expected {}, found {}",
+                    name, value.preview(PREVIEW_LEN), actual.preview(PREVIEW_LEN))),
+                None => problems.push(format!("{}: missing, expected {}", name, value.preview(PREVIEW_LEN))),
+            }
+        }
+        for (name, actual) in self.iter_str_lossy() {
+            if !expected.iter().any(|(k, _)| *k == name) {
+                problems.push(format!("{}: unexpected field = {}", name, actual.preview(PREVIEW_LEN)));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} doesn't match:\n{}", String::from_utf8_lossy(self.name.as_bytes()), problems.join("\n")))
+        }
+    }
+
+    /// Iterate over the fields of this object with their names as `&str`.
+    ///
+    /// Fields whose names aren't valid UTF-8 are skipped, see `iter_str_lossy` to include them.
+    pub fn iter_str(&self) -> impl Iterator<Item = (&str, &RbAny)> {
+        self.fields.iter().filter_map(|(k, v)| Some((k.as_str()?, v)))
+    }
+
+    /// Iterate over the fields of this object with their names as strings. Names which aren't
+    /// valid UTF-8 have invalid sequences replaced with U+FFFD, like `String::from_utf8_lossy`.
+    pub fn iter_str_lossy(&self) -> impl Iterator<Item = (Cow<'_, str>, &RbAny)> {
+        self.fields.iter().map(|(k, v)| (String::from_utf8_lossy(k.as_bytes()), v))
+    }
 
     /// Convert this into an `RbRef::Object`.
     pub fn into_object(self) -> RbRef {
@@ -98,6 +216,32 @@ impl RbObject {
     }
 }
 
+/// Builds an `RbObject` one field at a time, see `RbObject::builder`.
+#[derive(Clone, Debug)]
+pub struct RbObjectBuilder {
+    object: RbObject,
+}
+
+impl RbObjectBuilder {
+    /// Add a field, or replace its value (keeping its position) if it was already added.
+    pub fn field<K: Into<RbSymbol>, V: Into<RbAny>>(mut self, key: K, value: V) -> Self {
+        self.object.insert(key, value.into());
+        self
+    }
+
+    /// Add a field only if `value` is `Some`, which is handy for optional fields.
+    pub fn field_opt<K: Into<RbSymbol>, V: Into<RbAny>>(self, key: K, value: Option<V>) -> Self {
+        match value {
+            Some(v) => self.field(key, v),
+            None => self,
+        }
+    }
+
+    pub fn build(self) -> RbObject {
+        self.object
+    }
+}
+
 impl PartialOrd<Self> for RbObject {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let c = self.name.cmp(&other.name);
@@ -0,0 +1,284 @@
+use std::convert::TryFrom;
+use super::{RbAny, RbClass, RbFields, RbRef, RcType, rc_get_ptr};
+use super::rb_diff::{DiffEntry, RbDiff};
+use super::rb_ref::hash_index;
+use crate::error::{ThurgoodError, TResult};
+
+/// Apply `patch` to `target`, see `RbAny::apply_patch` for the merge rules.
+pub fn apply_patch(target: &mut RbAny, patch: &RbAny) -> TResult<()> {
+    apply_patch_inner(target, patch, &mut Vec::new())
+}
+
+/// `active` holds the parts of the patch currently being merged, so a patch which contains
+/// itself reports an error instead of recursing forever.
+fn apply_patch_inner(target: &mut RbAny, patch: &RbAny, active: &mut Vec<*const RbRef>) -> TResult<()> {
+    let (t, p) = match (target.as_rc_mut(), patch.as_rc()) {
+        (Some(t), Some(p)) if can_merge(t, p) => (t, p),
+        _ => {
+            *target = patch.clone();
+            return Ok(());
+        }
+    };
+    let ptr = rc_get_ptr(p);
+    if active.contains(&ptr) {
+        return Err(ThurgoodError::BadPatch("the patch contains itself".to_owned()));
+    }
+    active.push(ptr);
+    match (RcType::make_mut(t), p.as_ref()) {
+        (RbRef::Hash(t), RbRef::Hash(p)) => {
+            for (key, val) in p.iter() {
+                match hash_index(t, key) {
+                    Some(i) => apply_patch_inner(&mut t[i], val, active)?,
+                    None => { t.insert(key.clone(), val.clone()); },
+                }
+            }
+        },
+        (RbRef::Object(t) | RbRef::Struct(t), RbRef::Object(p) | RbRef::Struct(p)) => {
+            patch_fields(&mut t.fields, &p.fields, active)?;
+        },
+        (RbRef::Array(t), RbRef::Hash(p)) => {
+            for (key, val) in p.iter() {
+                let len = t.len();
+                let slot = key.as_int()
+                    .filter(|i| *i >= 0)
+                    .and_then(|i| t.get_mut(i as usize))
+                    .ok_or_else(|| ThurgoodError::BadPatch(
+                        format!("{:?} is not a valid index into an array of length {}", key, len)))?;
+                apply_patch_inner(slot, val, active)?;
+            }
+        },
+        (t, p) => return Err(ThurgoodError::BadPatch(
+            format!("cannot merge a {} into a {}", p.get_type().ruby_name(), t.get_type().ruby_name()))),
+    }
+    active.pop();
+    Ok(())
+}
+
+/// Returns true if `patch` should be merged into `target` instead of replacing it.
+fn can_merge(target: &RbRef, patch: &RbRef) -> bool {
+    match (target, patch) {
+        (RbRef::Hash(_), RbRef::Hash(_)) => true,
+        (RbRef::Object(t), RbRef::Object(p)) | (RbRef::Struct(t), RbRef::Struct(p)) => t.name == p.name,
+        (RbRef::Array(_), RbRef::Hash(p)) => p.keys().all(|k| k.as_int().is_some()),
+        _ => false,
+    }
+}
+
+fn patch_fields(target: &mut RbFields, patch: &RbFields, active: &mut Vec<*const RbRef>) -> TResult<()> {
+    for (key, val) in patch.iter() {
+        match target.get_mut(key) {
+            Some(slot) => apply_patch_inner(slot, val, active)?,
+            None => { target.insert(key.clone(), val.clone()); },
+        }
+    }
+    Ok(())
+}
+
+/// One change made by an `RbPatch`. Paths are lists of keys in the form `RbAny::find_child`
+/// takes, and the last key says where in its parent the change happens.
+#[derive(Clone, Debug)]
+pub enum PatchOp {
+    /// Replace the value at `path`, or the whole document if `path` is empty.
+    Replace { path: Vec<RbAny>, value: RbAny },
+    /// Add a hash entry or object field. For an array the last key is an index, and the value
+    /// is inserted before the element at that index (which may be the length of the array,
+    /// to append), shifting the elements after it up by one.
+    Insert { path: Vec<RbAny>, value: RbAny },
+    /// Remove a hash entry or object field, keeping the order of the rest. For an array the
+    /// last key is an index, and the elements after it shift down by one.
+    Remove { path: Vec<RbAny> },
+}
+
+impl PatchOp {
+    pub fn path(&self) -> &[RbAny] {
+        match self {
+            PatchOp::Replace { path, .. } | PatchOp::Insert { path, .. } | PatchOp::Remove { path } => path,
+        }
+    }
+}
+
+/// A list of changes which turns one document into another, see `RbAny::make_patch`.
+///
+/// The changes are applied in order, so the indexes of array edits take earlier edits to the
+/// same array into account. A patch can be stored as Marshal data itself with `to_any`,
+/// e.g. to send just the changes to a large document.
+#[derive(Clone, Debug, Default)]
+pub struct RbPatch {
+    pub ops: Vec<PatchOp>,
+}
+
+impl RbPatch {
+    /// Compute the changes from `old` to `new`. Values are compared as in `RbAny::diff`, and
+    /// arrays are compared position by position, so elements added or removed at the end are
+    /// cheap while an element inserted at the front changes every element after it.
+    pub fn new(old: &RbAny, new: &RbAny) -> Self {
+        let mut ops = Vec::new();
+        // Removals from the end of an array are reported in increasing order, but have to be
+        // applied from the last one so that the indexes stay valid
+        let mut removed: Vec<Vec<RbAny>> = Vec::new();
+        for entry in RbDiff::every_path().diff(old, new) {
+            let op = match entry {
+                DiffEntry::Removed { path, .. } => {
+                    if matches!(removed.last(), Some(p) if !same_parent(p, &path)) {
+                        ops.extend(removed.drain(..).rev().map(|path| PatchOp::Remove { path }));
+                    }
+                    removed.push(path);
+                    continue;
+                },
+                DiffEntry::Changed { path, new, .. } => PatchOp::Replace { path, value: new },
+                DiffEntry::Added { path, value } => PatchOp::Insert { path, value },
+            };
+            ops.extend(removed.drain(..).rev().map(|path| PatchOp::Remove { path }));
+            ops.push(op);
+        }
+        ops.extend(removed.drain(..).rev().map(|path| PatchOp::Remove { path }));
+        Self { ops }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Apply the changes to `target`, which should be the `old` document the patch was made
+    /// from. Afterwards `target` is equal to `new` (see `RbAny::deep_eq`), except that a value
+    /// which is shared within the document is copied at each path where it changed.
+    ///
+    /// Shared values are copied before being changed, as with `RbAny::apply_patch`. A path
+    /// which doesn't exist in `target` returns `ThurgoodError::BadPatch`, in which case the
+    /// changes before it have already been applied.
+    pub fn apply(&self, target: &mut RbAny) -> TResult<()> {
+        for op in self.ops.iter() {
+            apply_op(target, op)?;
+        }
+        Ok(())
+    }
+
+    /// Convert this into an array of changes, each of which is `[:replace, path, value]`,
+    /// `[:insert, path, value]`, or `[:remove, path]` with `path` an array of keys.
+    pub fn to_any(&self) -> RbAny {
+        let ops = self.ops.iter().map(|op| {
+            let (name, value) = match op {
+                PatchOp::Replace { value, .. } => ("replace", Some(value)),
+                PatchOp::Insert { value, .. } => ("insert", Some(value)),
+                PatchOp::Remove { .. } => ("remove", None),
+            };
+            let mut ar = vec![RbAny::sym(name), RbAny::from(op.path().to_vec())];
+            ar.extend(value.cloned());
+            RbAny::from(ar)
+        });
+        RbAny::from(ops.collect::<Vec<_>>())
+    }
+
+    /// Read a patch in the form written by `to_any`, returning `ThurgoodError::BadPatch` if
+    /// `value` isn't one.
+    pub fn from_any(value: &RbAny) -> TResult<Self> {
+        let bad = || ThurgoodError::BadPatch(format!("{:?} is not a list of changes", value));
+        let mut ops = Vec::new();
+        for it in value.as_array().ok_or_else(bad)? {
+            let op = match it.as_array().map(|v| v.as_slice()) {
+                Some([name, path, value]) if name.symbol_str() == Some("replace") => {
+                    PatchOp::Replace { path: path.as_array().ok_or_else(bad)?.clone(), value: value.clone() }
+                },
+                Some([name, path, value]) if name.symbol_str() == Some("insert") => {
+                    PatchOp::Insert { path: path.as_array().ok_or_else(bad)?.clone(), value: value.clone() }
+                },
+                Some([name, path]) if name.symbol_str() == Some("remove") => {
+                    PatchOp::Remove { path: path.as_array().ok_or_else(bad)?.clone() }
+                },
+                _ => return Err(bad()),
+            };
+            ops.push(op);
+        }
+        Ok(Self { ops })
+    }
+}
+
+/// Returns true if two paths lead to children of the same value.
+fn same_parent(a: &[RbAny], b: &[RbAny]) -> bool {
+    a.len() == b.len() && !a.is_empty()
+        && a[..a.len() - 1].iter().zip(b.iter()).all(|(x, y)| x.deep_eq(y))
+}
+
+fn apply_op(target: &mut RbAny, op: &PatchOp) -> TResult<()> {
+    let path = op.path();
+    let (last, parents) = match path.split_last() {
+        Some(v) => v,
+        None => return match op {
+            PatchOp::Replace { value, .. } => {
+                *target = value.clone();
+                Ok(())
+            },
+            _ => Err(ThurgoodError::BadPatch("Only a replacement can have an empty path".to_owned())),
+        },
+    };
+    let missing = || ThurgoodError::BadPatch(format!("{:?} does not exist", path));
+    let mut current = target;
+    for key in parents {
+        current = child_mut(container_mut(current).ok_or_else(missing)?, key).ok_or_else(missing)?;
+    }
+    let parent = container_mut(current).ok_or_else(missing)?;
+    match op {
+        PatchOp::Replace { value, .. } => {
+            *child_mut(parent, last).ok_or_else(missing)? = value.clone();
+        },
+        PatchOp::Insert { value, .. } => match parent {
+            RbRef::Array(v) => {
+                let index = last.as_int().filter(|i| *i >= 0 && *i as usize <= v.len()).ok_or_else(missing)?;
+                v.insert(index as usize, value.clone());
+            },
+            RbRef::Hash(v) => {
+                match hash_index(v, last) {
+                    Some(i) => v[i] = value.clone(),
+                    None => { v.insert(last.clone(), value.clone()); },
+                }
+            },
+            RbRef::Object(v) | RbRef::Struct(v) => {
+                v.insert(last.as_symbol().ok_or_else(missing)?.clone(), value.clone());
+            },
+            _ => return Err(missing()),
+        },
+        PatchOp::Remove { .. } => match parent {
+            RbRef::Array(v) => {
+                let index = last.as_int().filter(|i| *i >= 0 && (*i as usize) < v.len()).ok_or_else(missing)?;
+                v.remove(index as usize);
+            },
+            RbRef::Hash(v) => {
+                let i = hash_index(v, last).ok_or_else(missing)?;
+                v.shift_remove_index(i);
+            },
+            RbRef::Object(v) | RbRef::Struct(v) => {
+                v.fields.shift_remove(last.as_symbol().ok_or_else(missing)?).ok_or_else(missing)?;
+            },
+            _ => return Err(missing()),
+        },
+    }
+    Ok(())
+}
+
+/// Returns the node whose children are reached through `value`, copying it (and the value
+/// it wraps, for wrapper types) first if it's shared.
+fn container_mut(value: &mut RbAny) -> Option<&mut RbRef> {
+    let mut node = value.make_mut()?;
+    loop {
+        node = match node {
+            RbRef::Data(RbClass { data: inner, .. }) | RbRef::UserClass(RbClass { data: inner, .. })
+                | RbRef::UserMarshal(RbClass { data: inner, .. }) | RbRef::Extended { object: inner, .. } => {
+                inner.make_mut()?
+            },
+            RbRef::WithIvars { object, .. } => object,
+            _ => return Some(node),
+        };
+    }
+}
+
+fn child_mut<'a>(node: &'a mut RbRef, key: &RbAny) -> Option<&'a mut RbAny> {
+    match node {
+        RbRef::Array(v) => v.get_mut(usize::try_from(key.as_int()?).ok()?),
+        RbRef::Hash(v) => {
+            let i = hash_index(v, key)?;
+            Some(&mut v[i])
+        },
+        RbRef::Object(v) | RbRef::Struct(v) => v.fields.get_mut(key.as_symbol()?),
+        _ => None,
+    }
+}
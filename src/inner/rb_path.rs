@@ -0,0 +1,66 @@
+use std::ops::Deref;
+use super::{RbAny, RbSymbol};
+
+/// A path to a value nested inside a tree, for `RbAny::find_child`.
+///
+/// Each segment says what kind of key it is, since the same Rust value can mean different
+/// things in Ruby, e.g. a `"name"` hash key is different from a `:name` hash key.
+///
+/// ```
+/// use thurgood::rc::{from_bytes, RbAny, RbPath};
+/// // {"users" => [#<User @name="Alice">]}
+/// let root = from_bytes(b"\x04\x08{\x06I\"\x0ausers\x06:\x06ET[\x06o:\x09User\x06:\x0a@nameI\"\x0aAlice\x06;\x00T").unwrap();
+/// let path = RbPath::new().key("users").index(0).sym("@name");
+/// assert_eq!(RbAny::find_child(&root, &path).unwrap().as_string().unwrap(), "Alice");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RbPath(Vec<RbAny>);
+
+impl RbPath {
+    /// Construct an empty path, which refers to the root itself.
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Add an array index.
+    pub fn index(mut self, index: i32) -> Self {
+        self.0.push(RbAny::Int(index));
+        self
+    }
+
+    /// Add a symbol, which is either an object field (e.g. `"@name"`) or a symbol hash key.
+    pub fn sym<S: Into<RbSymbol>>(mut self, name: S) -> Self {
+        self.0.push(RbAny::Symbol(name.into()));
+        self
+    }
+
+    /// Add a hash key of any type, e.g. `"name"` for a string key.
+    pub fn key<K: Into<RbAny>>(mut self, key: K) -> Self {
+        self.0.push(key.into());
+        self
+    }
+
+    /// Returns the keys of this path, in the form `RbAny::find_child` takes.
+    pub fn into_keys(self) -> Vec<RbAny> {
+        self.0
+    }
+}
+
+impl From<Vec<RbAny>> for RbPath {
+    fn from(keys: Vec<RbAny>) -> Self {
+        Self(keys)
+    }
+}
+
+impl Deref for RbPath {
+    type Target = [RbAny];
+    fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl<'a> IntoIterator for &'a RbPath {
+    type Item = &'a RbAny;
+    type IntoIter = std::slice::Iter<'a, RbAny>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
@@ -1,6 +1,8 @@
 use num_bigint::BigInt;
 use super::{RbFloat, RbAny, RbSymbol, RbFields, RbClass, RbObject, RbHash, RbUserData};
 use crate::RbType;
+#[cfg(feature = "encodings")]
+use super::rb_encoding;
 
 macro_rules! match_opt {
     ($var:ident { $the_match:pat => $the_result:expr }) => {
@@ -18,9 +20,14 @@ pub enum RbRef {
     Str(String),
     /// String with some alternate encoding or additional fields
     StrI { content: Vec<u8>, metadata: RbFields },
-    /// utf-8 encoded regex
+    /// utf-8 encoded regex.
+    ///
+    /// `flags` holds all of `Regexp#options`: both the option bits (`consts::RE_OPTIONS_MASK`)
+    /// and the encoding bits (`consts::RE_FIXED_ENCODING`, `consts::RE_NO_ENCODING`). It's
+    /// written back unchanged as a single byte, so anything read round-trips, while values
+    /// above 0xff can't be written and report `ThurgoodError::BadRegexFlags`.
     Regex { content: String, flags: u32 },
-    /// Regex with some alternate encoding
+    /// Regex with some alternate encoding, `flags` are the same as for `Regex`
     RegexI { content: Vec<u8>, flags: u32, metadata: RbFields },
     /// A ruby hashmap
     Hash(RbHash),
@@ -28,8 +35,13 @@ pub enum RbRef {
     Struct(RbObject),
     /// An Object. Identical to a struct except for the type ID
     Object(RbObject),
+    /// A reference to a class by name, e.g. `String` (type byte `c`).
     ClassRef(String),
+    /// A reference to a module by name, e.g. `Comparable` (type byte `m`).
     ModuleRef(String),
+    /// A reference to a class or module by name, written by old versions of Ruby (type byte
+    /// `M`). This is written back as `M`, and isn't equal to a `ClassRef` or `ModuleRef` of
+    /// the same name, so they can be different keys of the same hash.
     ClassModuleRef(String),
     /// A Data object
     Data(RbClass),
@@ -41,6 +53,11 @@ pub enum RbRef {
     UserMarshal(RbClass),
     /// Extended object
     Extended { module: RbSymbol, object: RbAny },
+    /// An Array, Hash, or user-defined object with instance variables (the `I` prefix).
+    ///
+    /// Strings and regexes with instance variables use `StrI` and `RegexI` instead. This is
+    /// treated like the wrapped object by `get_type`, `as_array`, `as_hash`, and `get_child`.
+    WithIvars { object: Box<RbRef>, ivars: RbFields },
 }
 impl RbRef {
     pub fn get_type(&self) -> RbType {
@@ -61,12 +78,34 @@ impl RbRef {
             RbRef::UserData(_) => RbType::UserData,
             RbRef::UserMarshal(_) => RbType::UserMarshal,
             RbRef::Extended { .. } => RbType::Extended,
+            RbRef::WithIvars { object, .. } => object.get_type(),
+        }
+    }
+
+    /// Returns the name of this value's Ruby class, e.g. `"Array"` or the class name of an
+    /// object. Returns None if the class name isn't valid UTF-8.
+    pub fn class_name(&self) -> Option<&str> {
+        match self {
+            RbRef::Float(_) => Some("Float"),
+            RbRef::BigInt(_) => Some("Integer"),
+            RbRef::Array(_) => Some("Array"),
+            RbRef::Str(_) | RbRef::StrI { .. } => Some("String"),
+            RbRef::Regex { .. } | RbRef::RegexI { .. } => Some("Regexp"),
+            RbRef::Hash(_) => Some("Hash"),
+            RbRef::ClassRef(_) => Some("Class"),
+            RbRef::ModuleRef(_) | RbRef::ClassModuleRef(_) => Some("Module"),
+            RbRef::Struct(v) | RbRef::Object(v) => v.name.as_str(),
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => v.name.as_str(),
+            RbRef::UserData(v) => v.name.as_str(),
+            RbRef::Extended { object, .. } => object.class_name(),
+            RbRef::WithIvars { object, .. } => object.class_name(),
         }
     }
 
     /// Convenience method to get the a child of this object. For Arrays, `key` MUST be
     /// an `RbAny::Int`, for `Hash` key can be anything, and for all other objects key MUST
-    /// be `RbAny::Symbol`. If the key isn't found or types are invalid, returns None.
+    /// be `RbAny::Symbol` or a `Str` holding the field name. If the key isn't found or types
+    /// are invalid, returns None.
     pub fn get_child(&self, key: &RbAny) -> Option<&RbAny> {
         match &self {
             RbRef::Float(_) | RbRef::BigInt(_) | RbRef::Str(_) | RbRef::StrI { .. }
@@ -79,15 +118,64 @@ impl RbRef {
             RbRef::Extended { object, .. } => {
                 object.as_rbref().and_then(|c| c.get_child(key))
             },
+            RbRef::WithIvars { object, .. } => object.get_child(key),
             RbRef::Array(v) => {
                 key.as_int().and_then(|k| v.get(k as usize))
             },
-            RbRef::Hash(v) => v.get(key),
-            RbRef::Struct(v) => v.get(key.as_symbol()?),
-            RbRef::Object(v) => v.get(key.as_symbol()?),
+            RbRef::Hash(v) => v.get_index(hash_index(v, key)?).map(|(_, v)| v),
+            RbRef::Struct(v) => v.get(field_key(key)?),
+            RbRef::Object(v) => v.get(field_key(key)?),
+        }
+    }
+
+    /// Mutable version of `get_child`. Like `RbAny::as_rbref_mut`, this returns None when
+    /// passing through a reference which is shared.
+    pub fn get_child_mut(&mut self, key: &RbAny) -> Option<&mut RbAny> {
+        match self {
+            RbRef::Float(_) | RbRef::BigInt(_) | RbRef::Str(_) | RbRef::StrI { .. }
+                | RbRef::Regex { .. } | RbRef::RegexI { .. } | RbRef::ClassRef( _ )
+                | RbRef::ModuleRef( _ ) | RbRef::ClassModuleRef( _ ) | RbRef::UserData(_)
+                => None,
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+                v.data.as_rbref_mut().and_then(|c| c.get_child_mut(key))
+            },
+            RbRef::Extended { object, .. } => {
+                object.as_rbref_mut().and_then(|c| c.get_child_mut(key))
+            },
+            RbRef::WithIvars { object, .. } => object.get_child_mut(key),
+            RbRef::Array(v) => {
+                key.as_int().and_then(move |k| v.get_mut(k as usize))
+            },
+            RbRef::Hash(v) => {
+                let index = hash_index(v, key)?;
+                v.get_index_mut(index).map(|(_, v)| v)
+            },
+            RbRef::Struct(v) => v.get_mut(field_key(key)?),
+            RbRef::Object(v) => v.get_mut(field_key(key)?),
         }
     }
 
+    /// Construct a binary (`ASCII-8BIT`) string, which may hold any bytes.
+    pub fn new_string_bin(content: Vec<u8>) -> Self {
+        Self::StrI { content, metadata: RbFields::new() }
+    }
+
+    /// Construct a string of bytes in the encoding named `encoding`, e.g. `"Shift_JIS"`. This
+    /// records the encoding the way Ruby does: `UTF-8` and `US-ASCII` as `E` (`true` or `false`),
+    /// other encodings by name, and `ASCII-8BIT` (or `BINARY`) not at all.
+    pub fn new_string_bin_with<S: Into<String>>(content: Vec<u8>, encoding: S) -> Self {
+        let encoding = encoding.into();
+        let mut metadata = RbFields::new();
+        match encoding.as_str() {
+            "UTF-8" => { metadata.insert(RbSymbol::from("E"), RbAny::True); },
+            "US-ASCII" => { metadata.insert(RbSymbol::from("E"), RbAny::False); },
+            "ASCII-8BIT" | "BINARY" => {},
+            // Like Ruby, the name itself is a binary string
+            _ => { metadata.insert(RbSymbol::from("encoding"), RbAny::from(encoding.as_bytes())); },
+        }
+        Self::StrI { content, metadata }
+    }
+
     pub fn new_regex(content: String, flags: u32) -> RbRef {
         Self::Regex { content, flags }
     }
@@ -96,6 +184,34 @@ impl RbRef {
         Self::Object(RbObject::new_from_slice(name.into(), pairs))
     }
 
+    /// Construct user-defined data, as written by a class's `_dump` method.
+    pub fn new_user_data<N: Into<RbSymbol>>(name: N, data: Vec<u8>) -> Self {
+        Self::UserData(RbUserData { name: name.into(), data })
+    }
+
+    /// Construct an object of class `name` serialized with `marshal_dump`, which returned `data`.
+    pub fn new_user_marshal<N: Into<RbSymbol>>(name: N, data: RbAny) -> Self {
+        Self::UserMarshal(RbClass { name: name.into(), data })
+    }
+
+    /// Construct a C extension object of class `name`, which `_dump_data` returned `data` for.
+    pub fn new_data<N: Into<RbSymbol>>(name: N, data: RbAny) -> Self {
+        Self::Data(RbClass { name: name.into(), data })
+    }
+
+    /// Construct an instance of `name`, a subclass of String, Regexp, Array, or Hash.
+    pub fn new_user_class<N: Into<RbSymbol>>(name: N, data: RbAny) -> Self {
+        Self::UserClass(RbClass { name: name.into(), data })
+    }
+
+    /// Construct `object` extended with `module`, as with `object.extend(module)`.
+    ///
+    /// An object extended with several modules is nested, outermost module first. See
+    /// `RbAny::extend_with` to build these.
+    pub fn new_extended<M: Into<RbSymbol>>(module: M, object: RbAny) -> Self {
+        Self::Extended { module: module.into(), object }
+    }
+
     pub fn into_any(self) -> RbAny {
         RbAny::from(self)
     }
@@ -125,6 +241,7 @@ impl RbRef {
             Self::UserClass(_) => 15,
             Self::UserData { .. } => 16,
             Self::UserMarshal(_) => 17,
+            Self::WithIvars { .. } => 18,
         }
     }
 
@@ -136,11 +253,9 @@ impl RbRef {
     pub fn partial_eq(&self, other: &Self) -> Option<bool> {
         match (self, other) {
             (RbRef::BigInt(l0), RbRef::BigInt(r0)) => Some(l0 == r0),
-            (
-                RbRef::ClassModuleRef(l0),
-                RbRef::ClassModuleRef(r0)|RbRef::ClassRef(r0)|RbRef::ModuleRef(r0)
-            ) => Some(l0 == r0),
-            (RbRef::ClassRef(l0)|RbRef::ModuleRef(l0), RbRef::ClassModuleRef(r0)) => Some(l0 == r0),
+            (RbRef::ClassRef(l0), RbRef::ClassRef(r0)) => Some(l0 == r0),
+            (RbRef::ModuleRef(l0), RbRef::ModuleRef(r0)) => Some(l0 == r0),
+            (RbRef::ClassModuleRef(l0), RbRef::ClassModuleRef(r0)) => Some(l0 == r0),
             (RbRef::Float(l0), RbRef::Float(r0)) => Some(l0 == r0),
             (
                 RbRef::Regex { content: l_con, flags: l_flags },
@@ -176,16 +291,40 @@ impl RbRef {
         match_opt!(self { RbRef::Float(ref mut v) => v })
     }
     pub fn as_array(&self) -> Option<&Vec<RbAny>> {
-        match_opt!(self { RbRef::Array(ref v) => v })
+        match self {
+            RbRef::Array(v) => Some(v),
+            RbRef::WithIvars { object, .. } => object.as_array(),
+            _ => None,
+        }
     }
     pub fn as_array_mut(&mut self) -> Option<&mut Vec<RbAny>> {
-        match_opt!(self { RbRef::Array(ref mut v) => v })
+        match self {
+            RbRef::Array(v) => Some(v),
+            RbRef::WithIvars { object, .. } => object.as_array_mut(),
+            _ => None,
+        }
     }
     pub fn as_hash(&self) -> Option<&RbHash> {
-        match_opt!(self { RbRef::Hash(ref v) => v })
+        match self {
+            RbRef::Hash(v) => Some(v),
+            RbRef::WithIvars { object, .. } => object.as_hash(),
+            _ => None,
+        }
     }
     pub fn as_hash_mut(&mut self) -> Option<&mut RbHash> {
-        match_opt!(self { RbRef::Hash(ref mut v) => v })
+        match self {
+            RbRef::Hash(v) => Some(v),
+            RbRef::WithIvars { object, .. } => object.as_hash_mut(),
+            _ => None,
+        }
+    }
+    /// Returns the instance variables of a `StrI`, `RegexI`, or `WithIvars`.
+    pub fn ivars(&self) -> Option<&RbFields> {
+        match self {
+            RbRef::StrI { metadata, .. } | RbRef::RegexI { metadata, .. } => Some(metadata),
+            RbRef::WithIvars { ivars, .. } => Some(ivars),
+            _ => None,
+        }
     }
     pub fn as_object(&self) -> Option<&RbObject> {
         match_opt!(self { RbRef::Object(ref v) => v })
@@ -199,15 +338,88 @@ impl RbRef {
     pub fn as_struct_mut(&mut self) -> Option<&mut RbObject> {
         match_opt!(self { RbRef::Struct(ref mut v) => v })
     }
+    pub fn as_data(&self) -> Option<&RbClass> {
+        match_opt!(self { RbRef::Data(ref v) => v })
+    }
+    pub fn as_data_mut(&mut self) -> Option<&mut RbClass> {
+        match_opt!(self { RbRef::Data(ref mut v) => v })
+    }
+    pub fn as_user_class(&self) -> Option<&RbClass> {
+        match_opt!(self { RbRef::UserClass(ref v) => v })
+    }
+    pub fn as_user_class_mut(&mut self) -> Option<&mut RbClass> {
+        match_opt!(self { RbRef::UserClass(ref mut v) => v })
+    }
+    pub fn as_user_marshal(&self) -> Option<&RbClass> {
+        match_opt!(self { RbRef::UserMarshal(ref v) => v })
+    }
+    pub fn as_user_marshal_mut(&mut self) -> Option<&mut RbClass> {
+        match_opt!(self { RbRef::UserMarshal(ref mut v) => v })
+    }
     pub fn as_string(&self) -> Option<&String> {
         match_opt!(self { RbRef::Str(ref v) => v })
     }
     pub fn as_string_mut(&mut self) -> Option<&mut String> {
         match_opt!(self { RbRef::Str(ref mut v) => v })
     }
+    /// Returns the contents of a string as UTF-8, decoding a `StrI` from the encoding it records
+    /// (e.g. `Shift_JIS`), see `new_string_bin_with`.
+    ///
+    /// Returns None if this isn't a string, if it's binary or in an encoding `encoding_rs`
+    /// doesn't support, or if the contents aren't valid in their encoding.
+    #[cfg(feature = "encodings")]
+    pub fn decode_str(&self) -> Option<String> {
+        match self {
+            RbRef::Str(v) => Some(v.clone()),
+            RbRef::StrI { content, metadata } => {
+                rb_encoding::decode(rb_encoding::for_metadata(metadata)?, content)
+            },
+            _ => None,
+        }
+    }
+    /// Returns the pattern and flags (see `consts::RE_*`) of a `Regex` or `RegexI`.
+    pub fn as_regex(&self) -> Option<(&[u8], u32)> {
+        match self {
+            RbRef::Regex { content, flags } => Some((content.as_bytes(), *flags)),
+            RbRef::RegexI { content, flags, .. } => Some((content, *flags)),
+            _ => None,
+        }
+    }
+    /// Returns the pattern of a `Regex`, which is always UTF-8. A `RegexI` returns None.
+    pub fn regex_pattern_str(&self) -> Option<&str> {
+        match_opt!(self { RbRef::Regex { ref content, .. } => content.as_str() })
+    }
+    /// Returns the flags of a `Regex` or `RegexI` for modification.
+    pub fn regex_flags_mut(&mut self) -> Option<&mut u32> {
+        match self {
+            RbRef::Regex { flags, .. } | RbRef::RegexI { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
 }
 
 impl From<f32> for RbRef { fn from(v: f32) -> Self { Self::from(v as f64) } }
 impl From<f64> for RbRef { fn from(v: f64) -> Self { RbRef::Float(RbFloat(v)) } }
 impl From<RbHash> for RbRef { fn from(v: RbHash) -> Self { RbRef::Hash(v) } }
 impl From<RbObject> for RbRef { fn from(v: RbObject) -> Self { RbRef::Object(v) } }
+// Bytes convert to a binary string (`ASCII-8BIT`), see `new_string_bin`
+impl From<Vec<u8>> for RbRef { fn from(v: Vec<u8>) -> Self { Self::new_string_bin(v) } }
+impl From<&[u8]> for RbRef { fn from(v: &[u8]) -> Self { Self::new_string_bin(v.to_vec()) } }
+
+/// Returns the symbol to look up in an object's fields for `key`.
+fn field_key(key: &RbAny) -> Option<RbSymbol> {
+    match key {
+        RbAny::Symbol(v) => Some(v.clone()),
+        _ => key.as_string().map(|v| RbSymbol::from(v.as_str())),
+    }
+}
+
+/// Returns the index of `key` in `hash`. Keys which are references are hashed by identity,
+/// so if there's no identical key this falls back to comparing each key structurally, which
+/// lets arrays and objects be used as lookup keys.
+pub(super) fn hash_index(hash: &RbHash, key: &RbAny) -> Option<usize> {
+    hash.get_index_of(key).or_else(|| match key {
+        RbAny::Ref(_) => hash.keys().position(|k| k.deep_eq(key)),
+        _ => None,
+    })
+}
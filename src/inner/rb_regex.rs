@@ -0,0 +1,59 @@
+use crate::consts::{RE_EXTENDED, RE_FIXED_ENCODING, RE_IGNORECASE, RE_MULTILINE, RE_NO_ENCODING};
+use super::RbRef;
+
+/// The flags of a regex (`Regexp#options`) split into fields, see `consts::RE_*`.
+///
+/// Bits which don't have a field of their own are kept in `other`, so `from_flags` and
+/// `to_flags` round-trip every value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RbRegexInfo {
+    /// The `i` option.
+    pub ignore_case: bool,
+    /// The `x` option.
+    pub extended: bool,
+    /// The `m` option.
+    pub multiline: bool,
+    /// The regex has a fixed encoding, e.g. `/foo/u` or a literal containing non-ASCII.
+    pub fixed_encoding: bool,
+    /// The regex is binary, i.e. `/foo/n`.
+    pub no_encoding: bool,
+    /// Any other bits, unchanged.
+    pub other: u32,
+}
+
+impl RbRegexInfo {
+    const KNOWN: u32 = RE_IGNORECASE | RE_EXTENDED | RE_MULTILINE | RE_FIXED_ENCODING | RE_NO_ENCODING;
+
+    pub fn from_flags(flags: u32) -> Self {
+        Self {
+            ignore_case: flags & RE_IGNORECASE != 0,
+            extended: flags & RE_EXTENDED != 0,
+            multiline: flags & RE_MULTILINE != 0,
+            fixed_encoding: flags & RE_FIXED_ENCODING != 0,
+            no_encoding: flags & RE_NO_ENCODING != 0,
+            other: flags & !Self::KNOWN,
+        }
+    }
+
+    pub fn to_flags(&self) -> u32 {
+        let bit = |set: bool, bit: u32| if set { bit } else { 0 };
+        bit(self.ignore_case, RE_IGNORECASE)
+            | bit(self.extended, RE_EXTENDED)
+            | bit(self.multiline, RE_MULTILINE)
+            | bit(self.fixed_encoding, RE_FIXED_ENCODING)
+            | bit(self.no_encoding, RE_NO_ENCODING)
+            | (self.other & !Self::KNOWN)
+    }
+}
+
+impl RbRef {
+    /// Construct a UTF-8 regex with the flags in `info`.
+    pub fn new_regex_with(content: String, info: RbRegexInfo) -> RbRef {
+        Self::Regex { content, flags: info.to_flags() }
+    }
+
+    /// Returns the flags of a `Regex` or `RegexI` split into fields.
+    pub fn regex_info(&self) -> Option<RbRegexInfo> {
+        self.as_regex().map(|(_, flags)| RbRegexInfo::from_flags(flags))
+    }
+}
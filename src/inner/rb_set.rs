@@ -0,0 +1,72 @@
+use super::{RbAny, RbHash, RbObject, RbRef, RbSymbol};
+
+/// A Ruby `Set`, which is marshalled as an object named `Set` with one field, `@hash`, holding
+/// a `Hash.new(false)` whose keys are the members of the set and whose values are all `true`.
+///
+/// Reading and writing a set doesn't go through this type, so sets always round-trip exactly.
+/// Use `from_any` to look at the members, and `to_any` to build a new set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RbSet {
+    hash: RbHash,
+}
+
+impl RbSet {
+    /// Construct a set containing `elements`. Duplicates are only kept once.
+    pub fn new<I: IntoIterator<Item = RbAny>>(elements: I) -> Self {
+        let mut hash = RbHash::with_default(RbAny::False);
+        for it in elements {
+            hash.insert(it, RbAny::True);
+        }
+        Self { hash }
+    }
+
+    /// Returns the `Set` stored in `value`, or None if `value` isn't a `Set`.
+    pub fn from_any(value: &RbAny) -> Option<Self> {
+        Self::from_ref(value.as_rbref()?)
+    }
+
+    /// Returns the `Set` stored in `value`, or None if `value` isn't a `Set`.
+    pub fn from_ref(value: &RbRef) -> Option<Self> {
+        match value {
+            RbRef::Object(v) => Self::from_object(v),
+            RbRef::UserMarshal(v) if v.name.eq_str("Set") => {
+                Some(Self { hash: v.data.as_hash()?.clone() })
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the `Set` stored in an object, or None if it isn't an object named `Set` with
+    /// just a `@hash` field.
+    pub fn from_object(value: &RbObject) -> Option<Self> {
+        if !value.name.eq_str("Set") || value.fields.len() != 1 {
+            return None;
+        }
+        Some(Self { hash: value.get("@hash")?.as_hash()?.clone() })
+    }
+
+    /// Iterate over the members of this set, in order.
+    pub fn elements(&self) -> impl Iterator<Item = &RbAny> {
+        self.hash.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hash.is_empty()
+    }
+
+    /// Returns true if `value` is a member of this set.
+    pub fn contains(&self, value: &RbAny) -> bool {
+        self.hash.contains_key(value)
+    }
+
+    /// Convert this into an `RbAny` with the same layout that Ruby uses.
+    pub fn to_any(&self) -> RbAny {
+        let mut obj = RbObject::empty("Set");
+        obj.insert(RbSymbol::from("@hash"), RbAny::from(self.hash.clone()));
+        RbRef::Object(obj).into_any()
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use crate::consts::*;
+use crate::error::{TResult, ThurgoodError};
+use super::{RbAny, RbFloat, RbRef, RbSymbol, RbWriter, RbWriterOptions};
+
+/// An open container, along with the number of values it still expects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Frame {
+    Array(usize),
+    Hash(usize),
+    Object(usize),
+}
+
+impl Frame {
+    fn remaining(&mut self) -> &mut usize {
+        match self {
+            Frame::Array(n) | Frame::Hash(n) | Frame::Object(n) => n,
+        }
+    }
+}
+
+/// Writes Marshal data one value at a time, without building an `RbAny` tree first.
+///
+/// Marshal stores the length of each collection before its contents, so lengths must be given
+/// up front in `begin_array`, `begin_hash`, and `begin_object`. Each container must then be
+/// given exactly that many values (for hashes and objects a key and a value per entry) before
+/// the matching `end_*` call, otherwise `ThurgoodError::BadStream` is returned.
+///
+/// ```rust
+/// # fn main() -> Result<(), thurgood::rc::Error> {
+/// use thurgood::rc::RbStreamWriter;
+///
+/// let mut wr = RbStreamWriter::new(Vec::new());
+/// wr.begin_array(2)?;
+/// wr.write_int(1)?;
+/// wr.write_str("two")?;
+/// wr.end_array()?;
+/// assert_eq!(wr.finish()?, b"\x04\x08[\x07i\x06I\"\x08two\x06:\x06ET");
+/// # Ok(())
+/// # }
+/// ```
+pub struct RbStreamWriter<W> {
+    wr: RbWriter<W>,
+    stack: Vec<Frame>,
+    started: bool,
+    done: bool,
+    /// Values passed to `write_value`. Objects are tracked by address, so they're kept alive
+    /// until the writer is finished to make sure the address isn't reused by another value.
+    pinned: Vec<RbAny>,
+}
+
+impl<W> RbStreamWriter<W> where
+    W: io::Write
+{
+    pub fn new(dst: W) -> Self {
+        Self::with_options(dst, RbWriterOptions::default())
+    }
+
+    /// Construct a stream writer which uses the given options.
+    pub fn with_options(dst: W, options: RbWriterOptions) -> Self {
+        Self {
+            wr: RbWriter::with_options(dst, options),
+            stack: Vec::new(),
+            started: false,
+            done: false,
+            pinned: Vec::new(),
+        }
+    }
+
+    pub fn write_nil(&mut self) -> TResult<()> {
+        self.write_value(&RbAny::Nil)
+    }
+
+    pub fn write_bool(&mut self, v: bool) -> TResult<()> {
+        self.write_value(&RbAny::from(v))
+    }
+
+    pub fn write_int(&mut self, v: i32) -> TResult<()> {
+        self.write_value(&RbAny::Int(v))
+    }
+
+    pub fn write_symbol<S: Into<RbSymbol>>(&mut self, v: S) -> TResult<()> {
+        self.write_value(&RbAny::Symbol(v.into()))
+    }
+
+    pub fn write_float(&mut self, v: f64) -> TResult<()> {
+        self.write_unshared(&RbRef::Float(RbFloat(v)))
+    }
+
+    /// Write a UTF-8 string.
+    pub fn write_str(&mut self, v: &str) -> TResult<()> {
+        self.write_unshared(&RbRef::Str(v.to_owned()))
+    }
+
+    /// Write a complete value. Objects written this way can be referenced again by writing
+    /// the same `RbAny` later, just like with `RbWriter`.
+    pub fn write_value(&mut self, v: &RbAny) -> TResult<()> {
+        self.begin_value(matches!(v, RbAny::Symbol(_)))?;
+        self.wr.write_entry(v)?;
+        if let RbAny::Ref(_) = v {
+            self.pinned.push(v.clone());
+        }
+        self.end_value();
+        Ok(())
+    }
+
+    /// Start an array with `len` elements.
+    pub fn begin_array(&mut self, len: usize) -> TResult<()> {
+        self.begin_container(T_ARRAY, Frame::Array(len), len)
+    }
+
+    pub fn end_array(&mut self) -> TResult<()> {
+        self.end_container(Frame::Array(0))
+    }
+
+    /// Start a hash with `len` entries. Each entry is written as a key followed by a value.
+    pub fn begin_hash(&mut self, len: usize) -> TResult<()> {
+        self.begin_container(T_HASH, Frame::Hash(entry_slots(len)?), len)
+    }
+
+    pub fn end_hash(&mut self) -> TResult<()> {
+        self.end_container(Frame::Hash(0))
+    }
+
+    /// Start an object of class `name` with `len` fields. Each field is written as a symbol
+    /// (e.g. `:@name`) followed by its value, and any other field name is an error.
+    pub fn begin_object<S: Into<RbSymbol>>(&mut self, name: S, len: usize) -> TResult<()> {
+        let slots = entry_slots(len)?;
+        self.begin_value(false)?;
+        self.wr.write_byte(T_OBJECT)?;
+        self.wr.write_symbol(&name.into())?;
+        self.wr.write_len(len)?;
+        self.wr.skip_object_index();
+        self.stack.push(Frame::Object(slots));
+        Ok(())
+    }
+
+    pub fn end_object(&mut self) -> TResult<()> {
+        self.end_container(Frame::Object(0))
+    }
+
+    /// Check that a complete value has been written and return the destination.
+    pub fn finish(self) -> TResult<W> {
+        if !self.done {
+            return Err(ThurgoodError::BadStream(match self.stack.last() {
+                Some(frame) => format!("{:?} is still open", frame),
+                None => "nothing was written".to_owned(),
+            }));
+        }
+        Ok(self.wr.into_inner())
+    }
+
+    /// Write a value which will never be referenced again.
+    fn write_unshared(&mut self, v: &RbRef) -> TResult<()> {
+        self.begin_value(false)?;
+        self.wr.write_ref_body(v)?;
+        self.wr.skip_object_index();
+        self.end_value();
+        Ok(())
+    }
+
+    fn begin_container(&mut self, type_byte: u8, frame: Frame, len: usize) -> TResult<()> {
+        self.begin_value(false)?;
+        self.wr.write_byte(type_byte)?;
+        self.wr.write_len(len)?;
+        self.wr.skip_object_index();
+        self.stack.push(frame);
+        Ok(())
+    }
+
+    fn end_container(&mut self, expected: Frame) -> TResult<()> {
+        match self.stack.last() {
+            Some(frame) if *frame == expected => {
+                self.stack.pop();
+                self.end_value();
+                Ok(())
+            },
+            Some(frame) => Err(ThurgoodError::BadStream(format!("expected {:?} to end, found {:?}", expected, frame))),
+            None => Err(ThurgoodError::BadStream(format!("{:?} ended but there is no open container", expected))),
+        }
+    }
+
+    /// Check that another value is allowed here, writing the header if this is the first one.
+    /// `is_symbol` says whether the value is a symbol, which field names of objects must be.
+    fn begin_value(&mut self, is_symbol: bool) -> TResult<()> {
+        if self.done {
+            return Err(ThurgoodError::BadStream("a complete value has already been written".to_owned()));
+        }
+        if let Some(frame) = self.stack.last_mut() {
+            if *frame.remaining() == 0 {
+                return Err(ThurgoodError::BadStream(format!("too many values for {:?}", frame)));
+            }
+            // Keys come first, so a key is expected whenever an even number of values is left
+            if let Frame::Object(n) = frame {
+                if *n % 2 == 0 && !is_symbol {
+                    return Err(ThurgoodError::BadStream("object field names must be symbols".to_owned()));
+                }
+            }
+        }
+        if !self.started {
+            self.wr.write_header()?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    /// Count a finished value against the enclosing container.
+    fn end_value(&mut self) {
+        match self.stack.last_mut() {
+            Some(frame) => *frame.remaining() -= 1,
+            None => self.done = true,
+        }
+    }
+}
+
+/// Returns the number of values a hash or object with `len` entries holds, a key and a value
+/// for each.
+fn entry_slots(len: usize) -> TResult<usize> {
+    len.checked_mul(2).ok_or(ThurgoodError::TooLarge(len))
+}
@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+use super::{RbAny, RbRef, RbFields, RbObject, rc_get_ptr};
+use crate::error::{ThurgoodError, TResult};
+
+/// Checks that a tree can be written as valid Marshal data, see `RbAny::validate`.
+pub struct RbValidate {
+    seen: HashSet<*const RbRef>,
+    path: Vec<String>,
+}
+
+impl RbValidate {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            path: Vec::new(),
+        }
+    }
+
+    pub fn validate(&mut self, value: &RbAny) -> TResult<()> {
+        let r = match value {
+            RbAny::Ref(r) => r,
+            _ => return Ok(()),
+        };
+        if !self.seen.insert(rc_get_ptr(r)) {
+            return Ok(());
+        }
+        self.validate_ref(r.as_ref())
+    }
+
+    fn validate_ref(&mut self, r: &RbRef) -> TResult<()> {
+        match r {
+            RbRef::Float(_) | RbRef::Str(_) => Ok(()),
+            RbRef::BigInt(v) => {
+                self.check_len(v.to_bytes_le().1.len() / 2, "bignum")
+            },
+            RbRef::Array(v) => {
+                self.check_len(v.len(), "array")?;
+                for (i, it) in v.iter().enumerate() {
+                    self.path.push(format!("[{}]", i));
+                    self.validate(it)?;
+                    self.path.pop();
+                }
+                Ok(())
+            },
+            RbRef::StrI { content, metadata } => {
+                self.check_len(content.len(), "string")?;
+                self.validate_fields(metadata)
+            },
+            RbRef::Regex { content, flags } => {
+                self.check_len(content.len(), "regex")?;
+                self.check_flags(*flags)
+            },
+            RbRef::RegexI { content, flags, metadata } => {
+                self.check_len(content.len(), "regex")?;
+                self.check_flags(*flags)?;
+                self.validate_fields(metadata)
+            },
+            RbRef::Hash(v) => {
+                self.check_len(v.len(), "hash")?;
+                for (key, val) in v.iter() {
+                    self.path.push(format!("[{}]", Self::describe_key(key)));
+                    self.validate(key)?;
+                    self.validate(val)?;
+                    self.path.pop();
+                }
+                if let Some(def) = &v.default {
+                    self.path.push(".default".to_owned());
+                    self.validate(def)?;
+                    self.path.pop();
+                }
+                Ok(())
+            },
+            RbRef::Struct(v) | RbRef::Object(v) => self.validate_object(v),
+            RbRef::ClassRef(v) | RbRef::ModuleRef(v) | RbRef::ClassModuleRef(v) => {
+                self.check_len(v.len(), "class name")
+            },
+            RbRef::Data(v) | RbRef::UserMarshal(v) => self.validate(&v.data),
+            RbRef::UserClass(v) => {
+                // Ruby only allows subclasses of builtin types here, see `TYPE_UCLASS` in marshal.c
+                let valid = match v.data.as_rbref() {
+                    Some(RbRef::Object(_) | RbRef::ClassRef(_) | RbRef::ModuleRef(_) | RbRef::ClassModuleRef(_))
+                        | None => false,
+                    Some(_) => true,
+                };
+                if !valid {
+                    return Err(self.error(format!("user class contains a {:?}", v.data.get_type())));
+                }
+                self.validate(&v.data)
+            },
+            RbRef::UserData(v) => self.check_len(v.data.len(), "user data"),
+            RbRef::Extended { object, .. } => self.validate(object),
+            RbRef::WithIvars { object, ivars } => {
+                // Only these types are read back with instance variables, see `read_instance`
+                match object.as_ref() {
+                    RbRef::Array(_) | RbRef::Hash(_) | RbRef::UserData(_) => {},
+                    _ => return Err(self.error(format!("instance variables on a {:?}", object.get_type()))),
+                }
+                self.validate_ref(object)?;
+                self.validate_fields(ivars)
+            },
+        }
+    }
+
+    fn validate_object(&mut self, obj: &RbObject) -> TResult<()> {
+        self.validate_fields(&obj.fields)
+    }
+
+    fn validate_fields(&mut self, fields: &RbFields) -> TResult<()> {
+        self.check_len(fields.len(), "field list")?;
+        for (key, val) in fields.iter() {
+            self.path.push(format!(".{}", String::from_utf8_lossy(key.as_bytes())));
+            self.validate(val)?;
+            self.path.pop();
+        }
+        Ok(())
+    }
+
+    /// Lengths are written as a Marshal integer, so they must fit in an `i32`.
+    fn check_len(&self, len: usize, what: &str) -> TResult<()> {
+        if len > i32::MAX as usize {
+            Err(self.error(format!("{} length {} is too large", what, len)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Regex flags are written as a single byte.
+    fn check_flags(&self, flags: u32) -> TResult<()> {
+        if flags > u8::MAX as u32 {
+            Err(self.error(format!("regex flags {:#x} don't fit in a byte", flags)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn describe_key(key: &RbAny) -> String {
+        match key {
+            RbAny::Int(v) => v.to_string(),
+            RbAny::Symbol(v) => format!(":{}", String::from_utf8_lossy(v.as_bytes())),
+            _ => match key.as_string() {
+                Some(s) => format!("{:?}", s),
+                None => format!("{:?}", key.get_type()),
+            },
+        }
+    }
+
+    fn error(&self, reason: String) -> ThurgoodError {
+        ThurgoodError::Unserializable { path: format!("root{}", self.path.concat()), reason }
+    }
+}
@@ -0,0 +1,765 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use crate::RbType;
+use super::{RbAny, RbClass, RbFields, RbPath, RbRef, RbUserData, RcType, rc_get_ptr};
+
+/// Visits every value in a tree exactly once.
+///
+/// Shared references are only descended into the first time they're encountered, which
+/// also guarantees termination on recursive data.
+pub struct RbWalk {
+    seen: HashSet<*const RbRef>,
+}
+
+impl RbWalk {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Call `f` on `value` and then on each of its children (depth-first, pre-order).
+    pub fn walk<'a, F>(&mut self, value: &'a RbAny, f: &mut F)
+        where F: FnMut(&'a RbAny)
+    {
+        if let RbAny::Ref(r) = value {
+            if !self.seen.insert(rc_get_ptr(r)) {
+                return;
+            }
+            f(value);
+            self.walk_children(r.as_ref(), f);
+        } else {
+            f(value);
+        }
+    }
+}
+
+impl RbWalk {
+    fn walk_children<'a, F>(&mut self, node: &'a RbRef, f: &mut F)
+        where F: FnMut(&'a RbAny)
+    {
+        for_each_child(node, &mut |child| self.walk(child, f));
+    }
+
+    /// Like `walk`, but stops as soon as `f` returns `Break`, returning its value.
+    pub fn try_walk<'a, B, F>(&mut self, value: &'a RbAny, f: &mut F) -> Option<B>
+        where F: FnMut(&'a RbAny) -> ControlFlow<B>
+    {
+        let mut result = None;
+        self.try_visit(value, f, &mut result);
+        result
+    }
+
+    fn try_visit<'a, B, F>(&mut self, value: &'a RbAny, f: &mut F, result: &mut Option<B>)
+        where F: FnMut(&'a RbAny) -> ControlFlow<B>
+    {
+        if result.is_some() {
+            return;
+        }
+        if let RbAny::Ref(r) = value {
+            if !self.seen.insert(rc_get_ptr(r)) {
+                return;
+            }
+        }
+        if let ControlFlow::Break(b) = f(value) {
+            *result = Some(b);
+            return;
+        }
+        if let RbAny::Ref(r) = value {
+            for_each_child(r.as_ref(), &mut |child| self.try_visit(child, f, result));
+        }
+    }
+}
+
+/// A value in the flat list returned by `RbAny::nodes`.
+#[derive(Debug, Clone)]
+pub struct NodeInfo<'a> {
+    pub value: &'a RbAny,
+    /// The type of `value`, see `RbAny::get_type`.
+    pub kind: RbType,
+    /// Index of the node this one was first reached from, or None for the root.
+    pub parent: Option<usize>,
+}
+
+/// Lists every value in a tree once along with its parent, see `RbAny::nodes`.
+pub struct RbNodes<'a> {
+    seen: HashSet<*const RbRef>,
+    nodes: Vec<NodeInfo<'a>>,
+}
+
+impl<'a> RbNodes<'a> {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    pub fn collect(mut self, value: &'a RbAny) -> Vec<NodeInfo<'a>> {
+        self.visit(value, None);
+        self.nodes
+    }
+
+    fn visit(&mut self, value: &'a RbAny, parent: Option<usize>) {
+        let r = match value {
+            RbAny::Ref(r) if !self.seen.insert(rc_get_ptr(r)) => return,
+            RbAny::Ref(r) => Some(r),
+            _ => None,
+        };
+        let index = self.nodes.len();
+        self.nodes.push(NodeInfo { value, kind: value.get_type(), parent });
+        if let Some(r) = r {
+            for_each_child(r.as_ref(), &mut |child| self.visit(child, Some(index)));
+        }
+    }
+}
+
+/// Checks the sharing structure of a tree, see `RbAny::is_tree` and `RbAny::is_acyclic`.
+pub struct RbShape {
+    /// Nodes which have been reached, and whether they've been fully walked.
+    state: HashMap<*const RbRef, bool>,
+    /// Whether a node may be reached more than once (but not from within itself).
+    allow_shared: bool,
+}
+
+impl RbShape {
+    pub fn new(allow_shared: bool) -> Self {
+        Self {
+            state: HashMap::new(),
+            allow_shared,
+        }
+    }
+
+    /// Returns false if `value` contains a cycle, or if it contains shared references
+    /// and `allow_shared` is false.
+    pub fn check(&mut self, value: &RbAny) -> bool {
+        let r = match value {
+            RbAny::Ref(r) => r,
+            _ => return true,
+        };
+        let ptr = rc_get_ptr(r);
+        match self.state.get(&ptr) {
+            Some(true) => return self.allow_shared,
+            Some(false) => return false,
+            None => {},
+        }
+        self.state.insert(ptr, false);
+        let mut ok = true;
+        for_each_child(r, &mut |child| ok = ok && self.check(child));
+        self.state.insert(ptr, true);
+        ok
+    }
+}
+
+/// Progress of a node during a mutable walk.
+enum Visit {
+    /// The node is still being walked. A reference to it from inside itself (a recursive
+    /// value) is left pointing at the original until the walk is finished, see `relink`.
+    Pending,
+    /// The node has been fully walked and may be shared freely.
+    Done(RcType<RbRef>),
+}
+
+/// Visits every value in a tree exactly once, with mutable access.
+///
+/// This uses copy-on-write: any node that is shared (either within the tree or with some
+/// value outside of it) is cloned before being handed to the callback, so mutations never
+/// affect aliases held elsewhere. Every other reference to that same node within the tree
+/// is then pointed at the copy, meaning the sharing structure of the tree (including
+/// recursive references) is preserved.
+///
+/// A reference from inside a node back to itself can't be pointed at the copy while the copy
+/// is being walked, so those are fixed up once the outermost `walk_mut` is finished, by
+/// copying the nodes they're reached through once more (see `relink`).
+pub struct RbWalkMut {
+    visited: HashMap<*const RbRef, Visit>,
+    /// Originals of nodes which were still being walked when a reference to them was found.
+    stale: HashSet<*const RbRef>,
+    depth: usize,
+}
+
+impl RbWalkMut {
+    pub fn new() -> Self {
+        Self {
+            visited: HashMap::new(),
+            stale: HashSet::new(),
+            depth: 0,
+        }
+    }
+
+    /// Call `f` on `value` and then on each of its children (depth-first, pre-order).
+    ///
+    /// When `f` is called on an `RbAny::Ref` the reference is guaranteed to be unique,
+    /// so `RbAny::as_rbref_mut` and friends will succeed.
+    pub fn walk_mut<F>(&mut self, value: &mut RbAny, f: &mut F)
+        where F: FnMut(&mut RbAny)
+    {
+        self.depth += 1;
+        self.visit(value, f);
+        self.depth -= 1;
+        if self.depth == 0 && !self.stale.is_empty() {
+            let stale = std::mem::take(&mut self.stale);
+            relink(value, &stale, &mut self.visited);
+        }
+    }
+
+    fn visit<F>(&mut self, value: &mut RbAny, f: &mut F)
+        where F: FnMut(&mut RbAny)
+    {
+        let mut detached = None;
+        if let RbAny::Ref(r) = value {
+            let ptr = rc_get_ptr(r);
+            match self.visited.get(&ptr) {
+                Some(Visit::Done(other)) => {
+                    if rc_get_ptr(other) != ptr {
+                        *r = other.clone();
+                    }
+                    return;
+                },
+                Some(Visit::Pending) => {
+                    self.stale.insert(ptr);
+                    return;
+                },
+                None => {},
+            }
+            RcType::make_mut(r);
+            let new_ptr = rc_get_ptr(r);
+            self.visited.insert(ptr, Visit::Pending);
+            self.visited.insert(new_ptr, Visit::Pending);
+            detached = Some((ptr, new_ptr));
+        }
+        f(value);
+        if let RbAny::Ref(r) = value {
+            let ptr = rc_get_ptr(r);
+            match detached {
+                Some((_, new_ptr)) if new_ptr == ptr => {
+                    self.walk_children(RcType::make_mut(r), f);
+                },
+                Some((_, new_ptr)) => {
+                    // The callback replaced the node, which has been dropped by now.
+                    self.visited.remove(&new_ptr);
+                },
+                None => {},
+            }
+            self.visited.insert(ptr, Visit::Done(r.clone()));
+            if let Some((old_ptr, _)) = detached {
+                self.visited.insert(old_ptr, Visit::Done(r.clone()));
+            }
+        } else if let Some((old_ptr, new_ptr)) = detached {
+            self.visited.remove(&old_ptr);
+            self.visited.remove(&new_ptr);
+        }
+    }
+
+    fn walk_children<F>(&mut self, node: &mut RbRef, f: &mut F)
+        where F: FnMut(&mut RbAny)
+    {
+        for_each_child_mut(node, |child| self.visit(child, f));
+    }
+}
+
+/// Point the references in `value` to the nodes in `stale` (the originals of nodes which were
+/// copied by a mutable walk) at the copies recorded in `visited`, which is updated to match.
+///
+/// These references are inside the copies themselves, which can't be changed while they're
+/// shared, so every node they're reached through is copied once more with `RbCopy`. Stale
+/// nodes which weren't replaced by another node are left alone.
+fn relink(value: &mut RbAny, stale: &HashSet<*const RbRef>, visited: &mut HashMap<*const RbRef, Visit>) {
+    let mut redirect = HashMap::new();
+    for ptr in stale {
+        if let Some(Visit::Done(r)) = visited.get(ptr) {
+            if rc_get_ptr(r) != *ptr {
+                redirect.insert(*ptr, r.clone());
+            }
+        }
+    }
+    if redirect.is_empty() {
+        return;
+    }
+    let targets = redirect.keys().copied().collect();
+    let mut copier = RbCopy::new();
+    copier.only = Some(find_holders(value, &targets));
+    copier.redirect = redirect;
+    *value = copier.copy(value);
+    for visit in visited.values_mut() {
+        if let Visit::Done(r) = visit {
+            if let Some(copy) = copier.copy_of(r) {
+                *r = copy;
+            }
+        }
+    }
+}
+
+/// Returns the nodes which hold one of `targets`, directly or through other nodes, by
+/// following the edges of the tree backwards from the targets. The targets themselves aren't
+/// searched, so they're only included if they hold another target.
+fn find_holders(value: &RbAny, targets: &HashSet<*const RbRef>) -> HashSet<*const RbRef> {
+    let mut parents: HashMap<*const RbRef, Vec<*const RbRef>> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut stack: Vec<&RcType<RbRef>> = value.as_rc().into_iter().collect();
+    while let Some(r) = stack.pop() {
+        let ptr = rc_get_ptr(r);
+        if targets.contains(&ptr) || !seen.insert(ptr) {
+            continue;
+        }
+        for_each_child(r.as_ref(), &mut |child| {
+            if let Some(c) = child.as_rc() {
+                parents.entry(rc_get_ptr(c)).or_default().push(ptr);
+                stack.push(c);
+            }
+        });
+    }
+    let mut holders = HashSet::new();
+    let mut stack: Vec<*const RbRef> = targets.iter().copied().collect();
+    while let Some(ptr) = stack.pop() {
+        for parent in parents.get(&ptr).into_iter().flatten() {
+            if holders.insert(*parent) {
+                stack.push(*parent);
+            }
+        }
+    }
+    holders
+}
+
+/// Progress of a node being copied by `RbCopy`.
+enum Copied {
+    /// The node is still being copied. If a reference from inside the node back to itself was
+    /// found, this is the placeholder it was given, which becomes the copy.
+    Pending(Option<RcType<RbRef>>),
+    Done(RcType<RbRef>),
+}
+
+/// Copies the nodes of a tree, or only some of them, into new nodes. Shared nodes are copied
+/// once and are shared the same way by the copy, and a node which refers back to itself is
+/// copied into a node which refers back to the copy.
+pub struct RbCopy<'a> {
+    /// If set, only these nodes are copied, and references to any other node are kept.
+    pub only: Option<HashSet<*const RbRef>>,
+    /// References to the keys are treated as references to the values, e.g. to point
+    /// references at a copy which was made earlier.
+    pub redirect: HashMap<*const RbRef, RcType<RbRef>>,
+    /// Called on each copy (after its children have been copied) before it's finished.
+    pub edit: Option<&'a dyn Fn(&mut RbRef)>,
+    copies: HashMap<*const RbRef, Copied>,
+}
+
+impl<'a> RbCopy<'a> {
+    pub fn new() -> Self {
+        Self {
+            only: None,
+            redirect: HashMap::new(),
+            edit: None,
+            copies: HashMap::new(),
+        }
+    }
+
+    /// Returns the copy of `node`, if it has been copied.
+    pub fn copy_of(&self, node: &RcType<RbRef>) -> Option<RcType<RbRef>> {
+        match self.copies.get(&rc_get_ptr(node)) {
+            Some(Copied::Done(copy)) => Some(copy.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn copy(&mut self, value: &RbAny) -> RbAny {
+        let r = match value {
+            RbAny::Ref(r) => self.redirect.get(&rc_get_ptr(r)).unwrap_or(r).clone(),
+            _ => return value.clone(),
+        };
+        let ptr = rc_get_ptr(&r);
+        match self.copies.get_mut(&ptr) {
+            Some(Copied::Done(copy)) => return RbAny::Ref(copy.clone()),
+            Some(Copied::Pending(placeholder)) => {
+                // The copy doesn't exist yet, so like `RbReader` does for recursive references,
+                // this gets a placeholder which the copy is written into once it's finished.
+                let placeholder = placeholder.get_or_insert_with(|| RcType::new(RbRef::Array(Vec::new())));
+                return RbAny::Ref(placeholder.clone());
+            },
+            None => {},
+        }
+        if let Some(only) = &self.only {
+            if !only.contains(&ptr) {
+                return RbAny::Ref(r);
+            }
+        }
+        self.copies.insert(ptr, Copied::Pending(None));
+        let mut node = r.as_ref().clone();
+        for_each_child_mut(&mut node, |child| *child = self.copy(child));
+        if let Some(edit) = self.edit {
+            edit(&mut node);
+        }
+        let copy = match self.copies.remove(&ptr) {
+            Some(Copied::Pending(Some(placeholder))) => {
+                // Bypass mutability rules here, as `RbReader::set_object` does. The placeholder
+                // was created by this copy, and the only other references to it are inside
+                // `node` and the other copies made since, none of which anything else has seen
+                // yet. In the `arc` build none of them can have been sent to another thread.
+                unsafe {
+                    *(rc_get_ptr(&placeholder) as *mut RbRef) = node;
+                }
+                placeholder
+            },
+            _ => RcType::new(node),
+        };
+        self.copies.insert(ptr, Copied::Done(copy.clone()));
+        RbAny::Ref(copy)
+    }
+}
+
+/// Replaces every reference to one node with another value, see `RbAny::replace_node`.
+///
+/// Only the nodes the target can be reached from are copied (as in `RbWalkMut`), so the rest
+/// of the tree keeps sharing its nodes with any aliases held elsewhere.
+pub struct RbReplaceNode {
+    target: *const RbRef,
+    replacement: RbAny,
+    /// Nodes which the target can be reached from, found by `find_holders`.
+    holders: HashSet<*const RbRef>,
+    visited: HashMap<*const RbRef, Visit>,
+    /// See `RbWalkMut::stale`.
+    stale: HashSet<*const RbRef>,
+    count: usize,
+}
+
+impl RbReplaceNode {
+    pub fn new(target: &RcType<RbRef>, replacement: RbAny) -> Self {
+        Self {
+            target: rc_get_ptr(target),
+            replacement,
+            holders: HashSet::new(),
+            visited: HashMap::new(),
+            stale: HashSet::new(),
+            count: 0,
+        }
+    }
+
+    /// Replace the target everywhere in `value`, returning the number of references replaced.
+    pub fn replace(mut self, value: &mut RbAny) -> usize {
+        self.holders = find_holders(value, &std::iter::once(self.target).collect());
+        self.visit(value);
+        if !self.stale.is_empty() {
+            relink(value, &self.stale, &mut self.visited);
+        }
+        self.count
+    }
+
+    fn visit(&mut self, value: &mut RbAny) {
+        let r = match value {
+            RbAny::Ref(r) => r,
+            _ => return,
+        };
+        let ptr = rc_get_ptr(r);
+        if ptr == self.target {
+            *value = self.replacement.clone();
+            self.count += 1;
+            return;
+        }
+        match self.visited.get(&ptr) {
+            Some(Visit::Done(other)) => {
+                if rc_get_ptr(other) != ptr {
+                    *r = other.clone();
+                }
+                return;
+            },
+            Some(Visit::Pending) => {
+                self.stale.insert(ptr);
+                return;
+            },
+            None if !self.holders.contains(&ptr) => return,
+            None => {},
+        }
+        RcType::make_mut(r);
+        let new_ptr = rc_get_ptr(r);
+        self.visited.insert(ptr, Visit::Pending);
+        self.visited.insert(new_ptr, Visit::Pending);
+        for_each_child_mut(RcType::make_mut(r), |child| self.visit(child));
+        self.visited.insert(ptr, Visit::Done(r.clone()));
+        self.visited.insert(new_ptr, Visit::Done(r.clone()));
+    }
+}
+
+/// Produces a copy of a tree with no shared references, see `RbAny::explode`.
+pub struct RbExplode {
+    ancestors: HashSet<*const RbRef>,
+}
+
+impl RbExplode {
+    pub fn new() -> Self {
+        Self {
+            ancestors: HashSet::new(),
+        }
+    }
+
+    pub fn explode(&mut self, value: &RbAny) -> RbAny {
+        let r = match value {
+            RbAny::Ref(r) => r,
+            _ => return value.clone(),
+        };
+        let ptr = rc_get_ptr(r);
+        if !self.ancestors.insert(ptr) {
+            // A reference to one of our own ancestors is a cycle, which can't be copied.
+            return RbAny::Nil;
+        }
+        let mut copy = r.as_ref().clone();
+        for_each_child_mut(&mut copy, |child| *child = self.explode(child));
+        self.ancestors.remove(&ptr);
+        RbAny::from(copy)
+    }
+}
+
+/// Collects the scalar leaves of a tree along with their paths, see `RbAny::flatten`.
+pub struct RbFlatten<'a> {
+    seen: HashSet<*const RbRef>,
+    path: String,
+    leaves: Vec<(String, &'a RbAny)>,
+}
+
+impl<'a> RbFlatten<'a> {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            path: String::new(),
+            leaves: Vec::new(),
+        }
+    }
+
+    pub fn flatten(mut self, value: &'a RbAny) -> Vec<(String, &'a RbAny)> {
+        self.visit(value);
+        self.leaves
+    }
+
+    fn visit(&mut self, value: &'a RbAny) {
+        let r = match value {
+            RbAny::Ref(r) => r,
+            _ => return self.leaves.push((self.path.clone(), value)),
+        };
+        match r.as_ref() {
+            RbRef::Float(_) | RbRef::BigInt(_) | RbRef::Str(_) | RbRef::StrI { .. }
+                | RbRef::Regex { .. } | RbRef::RegexI { .. } | RbRef::ClassRef(_)
+                | RbRef::ModuleRef(_) | RbRef::ClassModuleRef(_) | RbRef::UserData(_) => {
+                self.leaves.push((self.path.clone(), value));
+            },
+            _ => {
+                if self.seen.insert(rc_get_ptr(r)) {
+                    self.visit_children(r.as_ref());
+                }
+            },
+        }
+    }
+
+    fn visit_children(&mut self, node: &'a RbRef) {
+        match node {
+            RbRef::Array(v) => {
+                for (i, it) in v.iter().enumerate() {
+                    self.visit_at(format!("[{}]", i), it);
+                }
+            },
+            RbRef::Hash(v) => {
+                for (key, val) in v.iter() {
+                    let segment = match key {
+                        RbAny::Symbol(sym) => self.field_segment(&String::from_utf8_lossy(sym.as_bytes())),
+                        _ => match key.as_string() {
+                            Some(s) => self.field_segment(s),
+                            None => format!("[{:?}]", key),
+                        },
+                    };
+                    self.visit_at(segment, val);
+                }
+            },
+            RbRef::Object(v) | RbRef::Struct(v) => self.visit_fields(&v.fields),
+            RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => self.visit(&v.data),
+            RbRef::Extended { object, .. } => self.visit(object),
+            RbRef::WithIvars { object, ivars } => {
+                self.visit_children(object);
+                self.visit_fields(ivars);
+            },
+            _ => {},
+        }
+    }
+
+    fn visit_fields(&mut self, fields: &'a RbFields) {
+        for (key, val) in fields.iter() {
+            let segment = self.field_segment(&String::from_utf8_lossy(key.as_bytes()));
+            self.visit_at(segment, val);
+        }
+    }
+
+    fn field_segment(&self, name: &str) -> String {
+        if self.path.is_empty() { name.to_owned() } else { format!(".{}", name) }
+    }
+
+    fn visit_at(&mut self, segment: String, value: &'a RbAny) {
+        let len = self.path.len();
+        self.path.push_str(&segment);
+        self.visit(value);
+        self.path.truncate(len);
+    }
+}
+
+/// Collects the `UserData` values in a tree along with their paths, see
+/// `RbAny::collect_user_data`.
+pub struct RbCollectUserData<'a> {
+    seen: HashSet<*const RbRef>,
+    path: Vec<RbAny>,
+    found: Vec<(RbPath, &'a RbUserData)>,
+}
+
+impl<'a> RbCollectUserData<'a> {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            path: Vec::new(),
+            found: Vec::new(),
+        }
+    }
+
+    pub fn collect(mut self, value: &'a RbAny) -> Vec<(RbPath, &'a RbUserData)> {
+        self.visit(value);
+        self.found
+    }
+
+    fn visit(&mut self, value: &'a RbAny) {
+        if let RbAny::Ref(r) = value {
+            if let RbRef::UserData(v) = r.as_ref() {
+                self.found.push((RbPath::from(self.path.clone()), v));
+            } else if self.seen.insert(rc_get_ptr(r)) {
+                self.visit_children(r.as_ref());
+            }
+        }
+    }
+
+    /// Visit each child which `RbRef::get_child` can reach, so that every path found can be
+    /// passed to `RbAny::find_child`.
+    fn visit_children(&mut self, node: &'a RbRef) {
+        match node {
+            RbRef::Array(v) => {
+                for (i, it) in v.iter().enumerate() {
+                    self.visit_at(RbAny::Int(i as i32), it);
+                }
+            },
+            RbRef::Hash(v) => {
+                for (key, val) in v.iter() {
+                    self.visit_at(key.clone(), val);
+                }
+            },
+            RbRef::Object(v) | RbRef::Struct(v) => {
+                for (key, val) in v.fields.iter() {
+                    self.visit_at(key.as_any(), val);
+                }
+            },
+            RbRef::Data(RbClass { data: inner, .. }) | RbRef::UserClass(RbClass { data: inner, .. })
+                | RbRef::UserMarshal(RbClass { data: inner, .. }) | RbRef::Extended { object: inner, .. } => {
+                // The wrapped value's children are reached through this one
+                if let Some(inner) = inner.as_rc() {
+                    if self.seen.insert(rc_get_ptr(inner)) {
+                        self.visit_children(inner.as_ref());
+                    }
+                }
+            },
+            RbRef::WithIvars { object, .. } => self.visit_children(object),
+            _ => {},
+        }
+    }
+
+    fn visit_at(&mut self, key: RbAny, value: &'a RbAny) {
+        self.path.push(key);
+        self.visit(value);
+        self.path.pop();
+    }
+}
+
+/// Call `f` on each direct child of `node`, including hash keys and default values.
+fn for_each_child<'a>(node: &'a RbRef, f: &mut dyn FnMut(&'a RbAny)) {
+    match node {
+        RbRef::Array(v) => {
+            for it in v.iter() {
+                f(it);
+            }
+        },
+        RbRef::Hash(v) => {
+            for (key, val) in v.map.iter() {
+                f(key);
+                f(val);
+            }
+            if let Some(def) = &v.default {
+                f(def);
+            }
+        },
+        RbRef::Struct(v) | RbRef::Object(v) => {
+            for val in v.fields.values() {
+                f(val);
+            }
+        },
+        RbRef::StrI { metadata, .. } | RbRef::RegexI { metadata, .. } => {
+            for val in metadata.values() {
+                f(val);
+            }
+        },
+        RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+            f(&v.data);
+        },
+        RbRef::Extended { object, .. } => {
+            f(object);
+        },
+        RbRef::WithIvars { object, ivars } => {
+            for_each_child(object, f);
+            for val in ivars.values() {
+                f(val);
+            }
+        },
+        RbRef::Float(_) | RbRef::BigInt(_) | RbRef::Str(_) | RbRef::Regex { .. }
+            | RbRef::ClassRef(_) | RbRef::ModuleRef(_) | RbRef::ClassModuleRef(_)
+            | RbRef::UserData(_) => {},
+    }
+}
+
+fn for_each_child_mut(node: &mut RbRef, mut f: impl FnMut(&mut RbAny)) {
+    for_each_child_mut_dyn(node, &mut f)
+}
+
+fn for_each_child_mut_dyn(node: &mut RbRef, f: &mut dyn FnMut(&mut RbAny)) {
+    match node {
+        RbRef::Array(v) => {
+            for it in v.iter_mut() {
+                f(it);
+            }
+        },
+        RbRef::Hash(v) => {
+            // Keys can't be mutated in place, so the map is rebuilt.
+            let entries = std::mem::take(&mut v.map);
+            for (mut key, mut val) in entries.into_iter() {
+                f(&mut key);
+                f(&mut val);
+                v.insert(key, val);
+            }
+            if let Some(def) = &mut v.default {
+                f(def);
+            }
+        },
+        RbRef::Struct(v) | RbRef::Object(v) => {
+            for val in v.fields.values_mut() {
+                f(val);
+            }
+        },
+        RbRef::StrI { metadata, .. } | RbRef::RegexI { metadata, .. } => {
+            for val in metadata.values_mut() {
+                f(val);
+            }
+        },
+        RbRef::Data(v) | RbRef::UserClass(v) | RbRef::UserMarshal(v) => {
+            f(&mut v.data);
+        },
+        RbRef::Extended { object, .. } => {
+            f(object);
+        },
+        RbRef::WithIvars { object, ivars } => {
+            for_each_child_mut_dyn(object, f);
+            for val in ivars.values_mut() {
+                f(val);
+            }
+        },
+        RbRef::Float(_) | RbRef::BigInt(_) | RbRef::Str(_) | RbRef::Regex { .. }
+            | RbRef::ClassRef(_) | RbRef::ModuleRef(_) | RbRef::ClassModuleRef(_)
+            | RbRef::UserData(_) => {},
+    }
+}
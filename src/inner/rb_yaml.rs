@@ -0,0 +1,177 @@
+use serde_yaml::{Mapping, Number, Value};
+use serde_yaml::value::{Tag, TaggedValue};
+use std::collections::HashMap;
+use num_traits::ToPrimitive;
+use super::{RbAny, RbClass, RbDate, RbDateTime, RbFields, RbHash, RbObject, RbRef, RbSymbol, rc_get_ptr};
+use base64::engine::general_purpose::STANDARD as BASE_64;
+use base64::Engine as _;
+
+/// Converts an `RbAny` into YAML using the same tags as Ruby's Psych library where possible,
+/// e.g. `!ruby/symbol`, `!ruby/object:Name`, and `!binary`.
+///
+/// YAML anchors aren't supported by `serde_yaml`, so an object which has already been converted
+/// is written as `!ruby/ref N` instead, where `N` is the order in which that object was first seen
+/// (starting at 1), the same numbering as `@id` in the JSON output. Hash default values are not
+/// included.
+pub struct RbToYaml {
+    seen: HashMap<*const RbRef, usize>,
+    next_id: usize,
+}
+
+impl RbToYaml {
+    pub fn new() -> Self {
+        Self {
+            seen: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn to_yaml(&mut self, value: &RbAny) -> Option<String> {
+        serde_yaml::to_string(&self.conv_any(value)).ok()
+    }
+
+    fn conv_any(&mut self, value: &RbAny) -> Value {
+        match value {
+            RbAny::Int(v) => Value::from(*v),
+            RbAny::True => Value::Bool(true),
+            RbAny::False => Value::Bool(false),
+            RbAny::Nil => Value::Null,
+            RbAny::Symbol(sym) => tagged("ruby/symbol", Value::String(sym_str(sym))),
+            RbAny::Ref(r) => {
+                if r.contains_ref() {
+                    let ptr = rc_get_ptr(r);
+                    if let Some(obj_id) = self.seen.get(&ptr) {
+                        return tagged("ruby/ref", Value::from(*obj_id));
+                    }
+                    self.seen.insert(ptr, self.next_id);
+                    self.next_id += 1;
+                }
+                self.conv_ref(r)
+            }
+        }
+    }
+
+    fn conv_ref(&mut self, value: &RbRef) -> Value {
+        match value {
+            RbRef::Float(v) => Value::Number(Number::from(v.0)),
+            RbRef::BigInt(v) => match v.to_i64() {
+                Some(n) => Value::from(n),
+                None => tagged("ruby/integer", Value::String(v.to_string())),
+            },
+            RbRef::Array(v) => Value::Sequence(v.iter().map(|it| self.conv_any(it)).collect()),
+            RbRef::Str(v) => Value::String(v.clone()),
+            RbRef::StrI { content, .. } => conv_bytes(content),
+            RbRef::Regex { content, flags } => conv_regex(content.as_bytes(), *flags),
+            RbRef::RegexI { content, flags, .. } => conv_regex(content, *flags),
+            RbRef::Hash(v) => self.conv_hash(v),
+            RbRef::Struct(v) => self.conv_object("ruby/struct", v, false),
+            RbRef::Object(v) => self.conv_object("ruby/object", v, true),
+            RbRef::ClassRef(v) | RbRef::ClassModuleRef(v) => tagged("ruby/class", Value::String(v.clone())),
+            RbRef::ModuleRef(v) => tagged("ruby/module", Value::String(v.clone())),
+            RbRef::UserClass(v) => {
+                let kind = match v.data.as_rbref() {
+                    Some(RbRef::Array(_)) => "ruby/array",
+                    Some(RbRef::Hash(_)) => "ruby/hash",
+                    Some(RbRef::Str(_) | RbRef::StrI { .. }) => "ruby/string",
+                    _ => "ruby/object",
+                };
+                self.conv_class(kind, v)
+            },
+            RbRef::Data(v) => self.conv_class("ruby/object", v),
+            RbRef::UserMarshal(v) => {
+                if let Some(date) = RbDate::from_class(v) {
+                    let text = format!("{:04}-{:02}-{:02}", date.year(), date.month(), date.day());
+                    tagged("ruby/object:Date", Value::String(text))
+                } else if let Some(date) = RbDateTime::from_class(v) {
+                    let offset = date.offset();
+                    let text = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09} {}{:02}:{:02}",
+                        date.year(), date.month(), date.day(),
+                        date.hour(), date.minute(), date.second(), date.nanosecond().unwrap_or(0),
+                        if offset < 0 { '-' } else { '+' }, offset.abs() / 3600, offset.abs() % 3600 / 60);
+                    tagged("ruby/object:DateTime", Value::String(text))
+                } else {
+                    self.conv_class("ruby/object", v)
+                }
+            },
+            RbRef::UserData(v) => {
+                let mut map = Mapping::new();
+                map.insert(Value::from("_dump"), conv_bytes(&v.data));
+                tagged(&format!("ruby/object:{}", sym_str(&v.name)), Value::Mapping(map))
+            },
+            RbRef::Extended { module, object } => {
+                tagged(&format!("ruby/extended:{}", sym_str(module)), self.conv_any(object))
+            },
+            // Psych keeps the leading `@` on the instance variables of arrays and hashes
+            RbRef::WithIvars { object, ivars } => match object.as_ref() {
+                RbRef::Array(_) | RbRef::Hash(_) => {
+                    let (tag, key) = if let RbRef::Array(_) = object.as_ref() {
+                        ("ruby/array:Array", "internal")
+                    } else {
+                        ("ruby/hash-with-ivars", "elements")
+                    };
+                    let mut map = Mapping::new();
+                    map.insert(Value::from(key), self.conv_ref(object));
+                    map.insert(Value::from("ivars"), Value::Mapping(self.conv_fields(ivars, false)));
+                    tagged(tag, Value::Mapping(map))
+                },
+                _ => self.conv_ref(object),
+            },
+        }
+    }
+
+    fn conv_class(&mut self, kind: &str, value: &RbClass) -> Value {
+        tagged(&format!("{}:{}", kind, sym_str(&value.name)), self.conv_any(&value.data))
+    }
+
+    /// Psych writes instance variables without the leading `@`, so `strip_at` removes it.
+    fn conv_object(&mut self, kind: &str, value: &RbObject, strip_at: bool) -> Value {
+        let map = self.conv_fields(&value.fields, strip_at);
+        tagged(&format!("{}:{}", kind, sym_str(&value.name)), Value::Mapping(map))
+    }
+
+    fn conv_fields(&mut self, fields: &RbFields, strip_at: bool) -> Mapping {
+        let mut map = Mapping::new();
+        for (key, val) in fields.iter() {
+            let name = sym_str(key);
+            let name = if strip_at { name.trim_start_matches('@').to_owned() } else { name };
+            map.insert(Value::String(name), self.conv_any(val));
+        }
+        map
+    }
+
+    fn conv_hash(&mut self, value: &RbHash) -> Value {
+        let mut map = Mapping::new();
+        for (key, val) in value.iter() {
+            let key = self.conv_any(key);
+            let val = self.conv_any(val);
+            map.insert(key, val);
+        }
+        Value::Mapping(map)
+    }
+}
+
+fn tagged(tag: &str, value: Value) -> Value {
+    Value::Tagged(Box::new(TaggedValue { tag: Tag::new(tag), value }))
+}
+
+fn sym_str(sym: &RbSymbol) -> String {
+    String::from_utf8_lossy(sym.as_bytes()).into_owned()
+}
+
+fn conv_regex(content: &[u8], flags: u32) -> Value {
+    let mut text = format!("/{}/", String::from_utf8_lossy(content));
+    for (bit, c) in [(1, 'i'), (2, 'x'), (4, 'm')] {
+        if flags & bit != 0 {
+            text.push(c);
+        }
+    }
+    tagged("ruby/regexp", Value::String(text))
+}
+
+/// Strings which aren't valid UTF-8 are base64-encoded and tagged `!binary`, like Psych does.
+fn conv_bytes(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Value::String(s.to_owned()),
+        Err(_) => tagged("binary", Value::String(BASE_64.encode(bytes))),
+    }
+}
@@ -1,41 +1,208 @@
-use std::io;
-use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use crate::consts::*;
-use crate::error::{TResult};
-use super::{RbFloat, RbAny, RbFields, RbObject, RbRef, RbSymbol, RcType, rc_get_ptr};
+use crate::error::{TResult, ThurgoodError};
+use super::{RbFloat, RbAny, RbClass, RbFields, RbHash, RbObject, RbRef, RbSymbol, RcType, SymbolEncoding, rc_get_ptr};
 use num_traits::sign::Signed;
 
+/// Options which change how an `RbWriter` encodes values.
+///
+/// The defaults match the output of modern Ruby versions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RbWriterOptions {
+    /// Write floats as NULL-terminated strings (the length includes the `\0`), as some
+    /// legacy tools do. `RbReader` accepts both forms.
+    pub null_terminated_floats: bool,
+    /// Write `@N` object references for values which appear more than once (the default).
+    /// If false, shared values are written out in full each time they appear, so sharing is
+    /// lost and the output may be much larger. Recursive values can't be written this way
+    /// and return `ThurgoodError::RecursiveValue`.
+    pub emit_object_refs: bool,
+    /// Write the ivars of objects and other instances sorted by name (after any encoding),
+    /// rather than in the order they were added. This gives the same bytes for the same
+    /// fields whatever order they were built in, which is useful for comparing or hashing
+    /// output, but doesn't round-trip the ivar order Ruby wrote. Struct members are always
+    /// written in order, as Ruby requires.
+    pub sort_ivars: bool,
+    /// The most bytes to write. Writing anything larger fails with
+    /// `ThurgoodError::OutputTooLarge` before any byte past the limit is passed to the
+    /// destination, though everything up to the limit may have been. This counts everything
+    /// written since the writer was constructed or `reset`, so several `write_value` calls
+    /// share one budget.
+    pub max_output_len: Option<usize>,
+}
+
+impl Default for RbWriterOptions {
+    fn default() -> Self {
+        Self {
+            null_terminated_floats: false,
+            emit_object_refs: true,
+            sort_ivars: false,
+            max_output_len: None,
+        }
+    }
+}
+
+/// Integers which weren't written in the shortest form, keyed by the order they were read in
+/// (counting every integer, including lengths and indices), with their value and exact bytes.
+/// See `RbReader::record_raw_ints` and `RbWriter::with_raw_ints`.
+pub type RawInts = HashMap<usize, (i32, Vec<u8>)>;
+
+/// A callback which is told the object index of each object written, see
+/// `RbWriter::with_on_object`.
+pub type ObjectHook = Rc<RefCell<dyn FnMut(&RcType<RbRef>, usize)>>;
+
+/// Writes `RbAny` values in Marshal format.
+///
+/// Symbols are defined (`:`) the first time they're written and referred to (`;`) after that,
+/// in the same depth-first order Ruby's `Marshal.dump` visits them: an object's class name,
+/// then each ivar name followed by its value, and a string's contents before its encoding.
+/// Ruby writes ivars in the order they were first assigned, so building a value with its
+/// fields in that order gives exactly the bytes Ruby would. Where that order isn't known
+/// `RbWriterOptions::sort_ivars` gives a stable alternative.
 #[derive(Clone)]
 pub struct RbWriter<W> {
     dst: W,
+    options: RbWriterOptions,
     symbol_map: HashMap<RbSymbol, usize>,
     symbol_next: usize,
     object_map: HashMap<*const RbRef, usize>,
     object_next: usize,
+    /// Values currently being written, used to detect recursion when not writing references.
+    in_progress: HashSet<*const RbRef>,
     sym_e: RbSymbol,
+    /// Class name of the `UserClass` whose contents are being written, see `write_type`.
+    user_class: Option<RbSymbol>,
+    /// Integers to write exactly as they were read, see `with_raw_ints`.
+    raw_ints: RawInts,
+    /// Number of integers written so far, the key into `raw_ints`.
+    int_count: usize,
+    /// Called when an object is given an index, see `with_on_object`.
+    on_object: Option<ObjectHook>,
+    /// Number of bytes written so far, checked against `RbWriterOptions::max_output_len`.
+    output_len: usize,
 }
 
 impl<W> RbWriter<W> where
     W: io::Write
 {
     pub fn new(dst: W) -> Self {
+        Self::with_options(dst, RbWriterOptions::default())
+    }
+
+    /// Construct a writer which uses the given options.
+    pub fn with_options(dst: W, options: RbWriterOptions) -> Self {
         Self {
             dst,
+            options,
             symbol_map: HashMap::new(),
             symbol_next: 0,
             object_map: HashMap::new(),
             object_next: 0,
+            in_progress: HashSet::new(),
             sym_e: RbSymbol::from("E"),
+            user_class: None,
+            raw_ints: HashMap::new(),
+            int_count: 0,
+            on_object: None,
+            output_len: 0,
         }
     }
 
+    /// Write the integers in `raw` with exactly the bytes they were read with, rather than in
+    /// the shortest form, so that a document which wasn't written by Ruby can be reproduced
+    /// byte for byte. Get them from `RbReader::raw_ints` after reading with `record_raw_ints`.
+    ///
+    /// Integers are matched up by the order they're written in, so this only makes sense for
+    /// writing the document that was read, and an integer is only written the recorded way if
+    /// it still has the same value. `reset` clears them, since they belong to one document.
+    pub fn with_raw_ints(mut self, raw: RawInts) -> Self {
+        self.raw_ints = raw;
+        self
+    }
+
+    /// Call `f` with each object and the index it's given (as used by `@N` object references)
+    /// as it's written, for building a table of object indices alongside the output.
+    ///
+    /// This is only called the first time an object is written, not when it's written again as
+    /// a reference. `Extended` values don't get an index, so aren't reported. If
+    /// `RbWriterOptions::emit_object_refs` is off, a shared object is written in full each time
+    /// it appears, and is reported with a new index each time. Clones of this writer share `f`.
+    pub fn with_on_object<F>(mut self, f: F) -> Self where F: FnMut(&RcType<RbRef>, usize) + 'static {
+        self.on_object = Some(Rc::new(RefCell::new(f)));
+        self
+    }
+
     pub fn write(&mut self, data: &RbAny) -> TResult<usize> {
+        Ok(self.write_header()? + self.write_entry(data)?)
+    }
+
+    /// Write a single value without the `\x04\x08` version header, for embedding Marshal
+    /// values in some other format. The caller is responsible for writing the version.
+    ///
+    /// The symbol and object tables are kept from previous calls, so later values may refer
+    /// to symbols and objects written by earlier ones. Read them back with
+    /// `RbReader::read_value`.
+    pub fn write_value(&mut self, data: &RbAny) -> TResult<usize> {
+        self.write_entry(data)
+    }
+
+    /// Returns the number of unique symbols written so far, i.e. the number of symbol
+    /// definitions (`:`) as opposed to symbol references (`;`).
+    pub fn symbol_count(&self) -> usize {
+        self.symbol_next
+    }
+
+    /// Returns the number of objects written so far, i.e. the next `@N` object index. This
+    /// matches the size of Ruby's object table after loading the same data.
+    pub fn object_count(&self) -> usize {
+        self.object_next
+    }
+
+    /// Start over with a new destination, returning the old one. The symbol and object tables
+    /// are cleared (keeping their memory) while the options are kept, so a writer can be
+    /// reused for many documents and write exactly what a new one would.
+    pub fn reset(&mut self, dst: W) -> W {
+        self.symbol_map.clear();
+        self.symbol_next = 0;
+        self.object_map.clear();
+        self.object_next = 0;
+        self.in_progress.clear();
+        self.user_class = None;
+        self.int_count = 0;
+        self.raw_ints.clear();
+        self.output_len = 0;
+        std::mem::replace(&mut self.dst, dst)
+    }
+
+    /// Consume the writer, returning the underlying destination.
+    pub fn into_inner(self) -> W {
+        self.dst
+    }
+
+    pub(super) fn write_header(&mut self) -> TResult<usize> {
         let header = [4u8, 8u8];
-        self.dst.write(&header)?;
-        Ok(self.write_entry(data)? + 2)
+        self.write_raw(&header)?;
+        Ok(header.len())
+    }
+
+    /// Take the next object index for an object which isn't an `RbRef`, so it can't be
+    /// referenced again but later indices still line up with the reader's.
+    pub(super) fn skip_object_index(&mut self) {
+        self.object_next += 1;
+    }
+
+    /// Returns the object index (as used by `@N` object references) of each object written so far.
+    pub fn object_indices(&self) -> &HashMap<*const RbRef, usize> {
+        &self.object_map
     }
 
-    fn write_entry(&mut self, entry: &RbAny) -> TResult<usize> {
+    pub(super) fn write_entry(&mut self, entry: &RbAny) -> TResult<usize> {
         match entry {
             RbAny::Int(v) => Ok(self.write_byte(T_INT)? + self.write_int(*v)?),
             RbAny::True => self.write_byte(T_TRUE),
@@ -48,13 +215,21 @@ impl<W> RbWriter<W> where
 
     fn write_ref(&mut self, entry: &RcType<RbRef>) -> TResult<usize> {
         let rc_ptr = rc_get_ptr(entry);
-        if let Some(obj_index) = self.object_map.get(&rc_ptr) {
-            // For special cases we write the object anyways, to increase compatibility with the standard Ruby implementation.
-            // This shouldn't be required, but it works either way.
-            if !self.is_special_case(entry) {
-                let obj_index = *obj_index;
-                return Ok(self.write_byte(T_OBJECT_REF)? + self.write_int(obj_index as i32)?);
+        if !self.options.emit_object_refs {
+            if !self.in_progress.insert(rc_ptr) {
+                return Err(ThurgoodError::RecursiveValue);
+            }
+            // As below, "Extended" types don't take up an object index
+            if !matches!(entry.as_ref(), RbRef::Extended { .. }) {
+                self.new_object_index(entry);
             }
+            let result = self.write_ref_body(entry.as_ref());
+            self.in_progress.remove(&rc_ptr);
+            return result;
+        }
+        if let Some(obj_index) = self.object_map.get(&rc_ptr) {
+            let obj_index = *obj_index;
+            return Ok(self.write_byte(T_OBJECT_REF)? + self.write_len(obj_index)?);
         }
         {
             // Don't add an entry in the object index for "Extended" types
@@ -62,195 +237,231 @@ impl<W> RbWriter<W> where
                 RbRef::Extended { .. } => {},
                 _ => {
                     self.object_map.insert(rc_ptr, self.object_next);
-                    self.object_next += 1;
+                    self.new_object_index(entry);
                 }
             }
-            return match entry.as_ref() {
-                RbRef::Float(v) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_FLOAT)?;
-                    sz += self.write_float(v)?;
-                    Ok(sz)
-                },
+            self.write_ref_body(entry.as_ref())
+        }
+    }
 
-                // Write a BigInt
-                RbRef::BigInt(v) => {
-                    let mut sz = 0;
-                    let (_, bytes) = v.to_bytes_le();
-                    let b2 = [T_BIGNUM, if v.is_negative() { '-' } else { '+' } as u8];
-                    self.dst.write_all(&b2)?;
-                    sz += b2.len();
-                    sz += self.write_int((bytes.len() / 2) as i32)?;
-                    self.dst.write_all(&bytes)?;
-                    sz += bytes.len();
-                    Ok(sz)
-                },
+    /// Take the next object index for `entry`, and tell `on_object` about it.
+    fn new_object_index(&mut self, entry: &RcType<RbRef>) {
+        if let Some(hook) = &self.on_object {
+            (hook.borrow_mut())(entry, self.object_next);
+        }
+        self.object_next += 1;
+    }
 
-                // Write an array
-                RbRef::Array(v) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_ARRAY)?;
-                    sz += self.write_int(v.len() as i32)?;
-                    for item in v.iter() {
-                        sz += self.write_entry(item)?;
-                    }
-                    Ok(sz)
-                },
+    /// Write the body of a reference, without registering it in the object index.
+    pub(super) fn write_ref_body(&mut self, entry: &RbRef) -> TResult<usize> {
+        match entry {
+            RbRef::Float(v) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_FLOAT)?;
+                sz += self.write_float(v)?;
+                Ok(sz)
+            },
 
-                // Write a string. Actually we're writing a String Instance
-                RbRef::Str(v) => {
-                    let prefix = [T_INSTANCE, T_STRING];
-                    let mut sz = 0;
-                    self.dst.write_all(&prefix)?;
-                    sz += prefix.len();
-                    sz += self.write_len_bytes(v.as_bytes())?;
-                    // One field, key is :E, value is True
-                    sz += self.write_et()?;
-                    Ok(sz)
-                },
+            // Write a BigInt
+            RbRef::BigInt(v) => {
+                let mut sz = 0;
+                let (_, mut bytes) = v.to_bytes_le();
+                // The length is in 16-bit words, so pad to a whole word
+                if bytes.len() % 2 != 0 {
+                    bytes.push(0);
+                }
+                let b2 = [T_BIGNUM, if v.is_negative() { '-' } else { '+' } as u8];
+                self.write_raw(&b2)?;
+                sz += b2.len();
+                sz += self.write_len(bytes.len() / 2)?;
+                self.write_raw(&bytes)?;
+                sz += bytes.len();
+                Ok(sz)
+            },
 
-                // Write an instance string with unknown encoding
-                RbRef::StrI { content, metadata } => {
-                    let prefix = [T_INSTANCE, T_STRING];
-                    let mut sz = 0;
-                    self.dst.write_all(&prefix)?;
-                    sz += prefix.len();
-                    sz += self.write_len_bytes(&content)?;
-                    sz += self.write_fields(metadata)?;
-                    Ok(sz)
-                },
+            // Write an array
+            RbRef::Array(v) => {
+                let mut sz = 0;
+                sz += self.write_type(false, T_ARRAY)?;
+                sz += self.write_len(v.len())?;
+                for item in v.iter() {
+                    sz += self.write_entry(item)?;
+                }
+                Ok(sz)
+            },
 
-                // Write an instance regex with default encoding
-                RbRef::Regex { content, flags } => {
-                    let prefix = [T_INSTANCE, T_REGEX];
-                    let mut sz = 0;
-                    self.dst.write_all(&prefix)?;
-                    sz += prefix.len();
-                    sz += self.write_len_bytes(content.as_bytes())?;
-                    // Write regex flags
-                    sz += self.write_byte(*flags as u8)?;
-                    // One field, key is :E, value is True
-                    sz += self.write_et()?;
-                    Ok(sz)
-                },
+            // Write a string. Actually we're writing a String Instance
+            RbRef::Str(v) => {
+                let mut sz = 0;
+                sz += self.write_type(true, T_STRING)?;
+                sz += self.write_len_bytes(v.as_bytes())?;
+                // One field, key is :E, value is True
+                sz += self.write_et()?;
+                Ok(sz)
+            },
 
-                // Write an instance regex with unknown encoding or extra metadata
-                RbRef::RegexI { content, flags, metadata } => {
-                    let prefix = [T_INSTANCE, T_REGEX];
-                    let mut sz = 0;
-                    self.dst.write_all(&prefix)?;
-                    sz += prefix.len();
-                    sz += self.write_len_bytes(content.as_slice())?;
-                    // Write regex flags
-                    sz += self.write_byte(*flags as u8)?;
+            // Write an instance string with unknown encoding. Binary strings have no encoding,
+            // so like Ruby they're written without any ivars, as a plain `"`.
+            RbRef::StrI { content, metadata } => {
+                let mut sz = 0;
+                sz += self.write_type(!metadata.is_empty(), T_STRING)?;
+                sz += self.write_len_bytes(&content)?;
+                if !metadata.is_empty() {
                     sz += self.write_fields(metadata)?;
-                    Ok(sz)
-                },
+                }
+                Ok(sz)
+            },
 
-                // Write a hash
-                RbRef::Hash(v) => {
-                    let mut sz = 0;
-                    // Write type byte
-                    sz += if v.default.is_some() {
-                        self.write_byte(T_HASH_DEFAULT)?
-                    } else {
-                        self.write_byte(T_HASH)?
-                    };
-                    // Write entries
-                    sz += self.write_int(v.len() as i32)?;
-                    for (key, val) in v.iter() {
-                        sz += self.write_entry(key)?;
-                        sz += self.write_entry(val)?;
-                    }
-                    // Optionally write default value
-                    if let Some(ref def) = v.default {
-                        sz += self.write_entry(def)?;
-                    }
-                    Ok(sz)
-                },
+            // Write an instance regex with default encoding
+            RbRef::Regex { content, flags } => {
+                let flags = regex_flags_byte(*flags)?;
+                let mut sz = 0;
+                sz += self.write_type(true, T_REGEX)?;
+                sz += self.write_len_bytes(content.as_bytes())?;
+                // Write regex flags
+                sz += self.write_byte(flags)?;
+                // One field, key is :E, value is True
+                sz += self.write_et()?;
+                Ok(sz)
+            },
 
-                RbRef::Object(v) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_OBJECT)?;
-                    sz += self.write_object(v)?;
-                    Ok(sz)
-                },
+            // Write an instance regex with unknown encoding or extra metadata
+            RbRef::RegexI { content, flags, metadata } => {
+                let flags = regex_flags_byte(*flags)?;
+                let mut sz = 0;
+                sz += self.write_type(true, T_REGEX)?;
+                sz += self.write_len_bytes(content.as_slice())?;
+                // Write regex flags
+                sz += self.write_byte(flags)?;
+                sz += self.write_fields(metadata)?;
+                Ok(sz)
+            },
 
-                RbRef::Struct(v) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_STRUCT)?;
-                    sz += self.write_object(v)?;
-                    Ok(sz)
-                },
+            // Write a hash
+            RbRef::Hash(v) => {
+                if v.ruby2_keywords {
+                    return self.write_flagged_hash(v, &RbFields::new());
+                }
+                self.write_hash(v)
+            },
 
-                RbRef::ClassRef( v ) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_CLASS)?;
-                    sz += self.write_len_bytes(v.as_bytes())?;
-                    Ok(sz)
-                },
+            RbRef::Object(v) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_OBJECT)?;
+                sz += self.write_object(v, true)?;
+                Ok(sz)
+            },
 
-                RbRef::ModuleRef( v ) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_MODULE)?;
-                    sz += self.write_len_bytes(v.as_bytes())?;
-                    Ok(sz)
-                },
+            RbRef::Struct(v) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_STRUCT)?;
+                sz += self.write_object(v, false)?;
+                Ok(sz)
+            },
 
-                RbRef::ClassModuleRef( v) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_CLASS_MODULE)?;
-                    sz += self.write_len_bytes(v.as_bytes())?;
-                    Ok(sz)
-                },
+            RbRef::ClassRef( v ) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_CLASS)?;
+                sz += self.write_len_bytes(v.as_bytes())?;
+                Ok(sz)
+            },
 
-                RbRef::Data( v) => {
-                    self.write_typed_data(&v.name, &v.data, T_DATA)
-                },
+            RbRef::ModuleRef( v ) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_MODULE)?;
+                sz += self.write_len_bytes(v.as_bytes())?;
+                Ok(sz)
+            },
 
-                RbRef::UserClass( v ) => {
-                    self.write_typed_data(&v.name, &v.data, T_USER_CLASS)
-                },
-                RbRef::UserData(v) => {
-                    let mut sz = 0;
-                    sz += self.write_byte(T_USER_DEFINED)?;
-                    sz += self.write_symbol(&v.name)?;
-                    sz += self.write_len_bytes(&v.data)?;
-                    Ok(sz)
-                },
-                RbRef::UserMarshal( v ) => {
-                    self.write_typed_data(&v.name, &v.data, T_USER_MARSHAL)
-                },
-                RbRef::Extended { module, object } => {
-                    self.write_typed_data(module, object, T_EXTENDED)
-                },
-            };
-        }
-    }
+            RbRef::ClassModuleRef( v) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_CLASS_MODULE)?;
+                sz += self.write_len_bytes(v.as_bytes())?;
+                Ok(sz)
+            },
 
-    /// Return true if the object is a special case and should be serialized fully even if it could be an object reference.
-    /// 
-    /// The official Ruby implementation has some odd quirks, and this helps to replicate them.
-    fn is_special_case(&self, obj: &RcType<RbRef>) -> bool {
-        match obj.as_ref() {
-            RbRef::Array(v) => v.len() == 0,
-            _ => false,
+            RbRef::Data( v) => {
+                self.write_rb_class(v, T_DATA)
+            },
+
+            RbRef::UserClass( v ) => {
+                match &v.data {
+                    // The contents are the object itself, so they don't get an object index
+                    // of their own, see `write_type`.
+                    RbAny::Ref(data) if is_user_class_body(data) => {
+                        self.user_class = Some(v.name.clone());
+                        self.write_ref_body(data)
+                    },
+                    _ => self.write_typed_data(&v.name, &v.data, T_USER_CLASS),
+                }
+            },
+            RbRef::UserData(v) => {
+                let mut sz = 0;
+                sz += self.write_byte(T_USER_DEFINED)?;
+                sz += self.write_symbol(&v.name)?;
+                sz += self.write_len_bytes(&v.data)?;
+                Ok(sz)
+            },
+            RbRef::UserMarshal( v ) => {
+                self.write_rb_class(v, T_USER_MARSHAL)
+            },
+            RbRef::Extended { module, object } => {
+                self.write_typed_data(module, object, T_EXTENDED)
+            },
+            RbRef::WithIvars { object, ivars } => {
+                if let RbRef::Hash(v) = object.as_ref() {
+                    if v.ruby2_keywords {
+                        return self.write_flagged_hash(v, ivars);
+                    }
+                }
+                let mut sz = 0;
+                sz += self.write_byte(T_INSTANCE)?;
+                sz += self.write_ref_body(object)?;
+                sz += self.write_fields(ivars)?;
+                Ok(sz)
+            },
         }
     }
 
-    fn write_symbol(&mut self, sym: &RbSymbol) -> TResult<usize> {
+    pub(super) fn write_symbol(&mut self, sym: &RbSymbol) -> TResult<usize> {
         if let Some(sym_index) = self.symbol_map.get(sym) {
             // If we already have this symbol, just write a reference
             let sym_index = *sym_index;
-            Ok(self.write_byte(T_SYMBOL_REF)? + self.write_int(sym_index as i32)?)
+            Ok(self.write_byte(T_SYMBOL_REF)? + self.write_len(sym_index)?)
         } else {
             // Otherwise write a new symbol and add it to the symbol map
             self.symbol_map.insert(sym.clone(), self.symbol_next);
             self.symbol_next += 1;
+            // Like Ruby, only names which aren't ASCII record their encoding
+            let encoding = match sym.encoding() {
+                _ if sym.as_bytes().is_ascii() => None,
+                SymbolEncoding::Binary => None,
+                enc => Some(enc),
+            };
             // Write to the stream
             let mut sz = 0;
+            if encoding.is_some() {
+                sz += self.write_byte(T_INSTANCE)?;
+            }
             sz += self.write_byte(T_SYMBOL)?;
             sz += self.write_len_bytes(sym.as_bytes())?;
+            match encoding {
+                Some(SymbolEncoding::Named(name)) => {
+                    sz += self.write_len(1)?;
+                    sz += self.write_symbol(&RbSymbol::from_str("encoding"))?;
+                    // The name is written as a plain string, which takes up an object index
+                    sz += self.write_byte(T_STRING)?;
+                    sz += self.write_len_bytes(name.as_bytes())?;
+                    self.skip_object_index();
+                },
+                Some(_) => {
+                    sz += self.write_len(1)?;
+                    let sym_e = self.sym_e.clone();
+                    sz += self.write_symbol(&sym_e)?;
+                    sz += self.write_byte(T_TRUE)?;
+                },
+                None => {},
+            }
             Ok(sz)
         }
     }
@@ -264,8 +475,23 @@ impl<W> RbWriter<W> where
         Ok(sz)
     }
 
-    fn write_int(&mut self, v: i32) -> TResult<usize> {
+    /// Write a length, count, or index, which must fit in a Marshal integer.
+    pub(super) fn write_len(&mut self, n: usize) -> TResult<usize> {
+        let v = i32::try_from(n).map_err(|_| ThurgoodError::TooLarge(n))?;
+        self.write_int(v)
+    }
+
+    pub(super) fn write_int(&mut self, v: i32) -> TResult<usize> {
         let mut buf = [0u8; 5];
+        let index = self.int_count;
+        self.int_count += 1;
+        if let Some((value, raw)) = self.raw_ints.get(&index) {
+            if *value == v {
+                let raw = raw.clone();
+                self.write_raw(&raw)?;
+                return Ok(raw.len());
+            }
+        }
 
         match v {
             0 => self.write_byte(0),
@@ -273,56 +499,140 @@ impl<W> RbWriter<W> where
             -123 ..= -1 => self.write_byte((v - 5) as u8),
             _ => {
                 buf[1..].copy_from_slice(&v.to_le_bytes());
-                // Count how many bytes we need
-                let sz = 5 - (v.leading_zeros() / 8) as i32;
+                let sz = int_len(v) as i32;
                 if v > 0 {
                     buf[0] = (sz - 1) as u8;
                 } else {
                     buf[0] = (-sz + 1) as u8;
                 }
-                self.dst.write_all(&buf[0..sz as usize])?;
+                self.write_raw(&buf[0..sz as usize])?;
                 Ok(sz as usize)
             },
         }
     }
 
     fn write_float(&mut self, v: &RbFloat) -> TResult<usize> {
-        if v.0.is_infinite() {
+        let mut text = if v.0.is_infinite() {
             if v.0.is_sign_negative() {
-                self.write_len_bytes("-inf".as_bytes())
+                "-inf".to_owned()
             } else {
-                self.write_len_bytes("inf".as_bytes())
+                "inf".to_owned()
             }
         } else if v.0.is_nan() {
-            self.write_len_bytes("nan".as_bytes())
+            "nan".to_owned()
         } else {
-            self.write_len_bytes(v.0.to_string().as_bytes())
+            ruby_float_text(v.0)
+        };
+        if self.options.null_terminated_floats {
+            text.push('\0');
         }
+        self.write_len_bytes(text.as_bytes())
+    }
+
+    fn write_hash(&mut self, v: &RbHash) -> TResult<usize> {
+        // Ruby writes a hash which compares by identity as a `Hash` user class
+        if v.compare_by_identity && self.user_class.is_none() {
+            self.user_class = Some(RbSymbol::from_str("Hash"));
+        }
+        let mut sz = 0;
+        // Write type byte
+        sz += if v.default.is_some() {
+            self.write_type(false, T_HASH_DEFAULT)?
+        } else {
+            self.write_type(false, T_HASH)?
+        };
+        // Write entries
+        sz += self.write_len(v.len())?;
+        for (key, val) in v.iter() {
+            sz += self.write_entry(key)?;
+            sz += self.write_entry(val)?;
+        }
+        // Optionally write default value
+        if let Some(ref def) = v.default {
+            sz += self.write_entry(def)?;
+        }
+        Ok(sz)
+    }
+
+    /// Write a hash with the ruby2_keywords flag, which Ruby writes as an ivar (`K: true`)
+    /// before any others.
+    fn write_flagged_hash(&mut self, v: &RbHash, ivars: &RbFields) -> TResult<usize> {
+        let mut sz = 0;
+        sz += self.write_byte(T_INSTANCE)?;
+        sz += self.write_hash(v)?;
+        sz += self.write_len(ivars.len() + 1)?;
+        sz += self.write_symbol(&RbSymbol::from_str("K"))?;
+        sz += self.write_byte(T_TRUE)?;
+        sz += self.write_pairs(ivars, false)?;
+        Ok(sz)
     }
 
     /// Write a varint (n) denoting the number of *pairs* and then (n * 2) objects:
     /// the key, value pairs. Returns the number of bytes written.
+    ///
+    /// Like Ruby, the encoding (`E` or `encoding`) is written before any other ivars.
     fn write_fields(&mut self, pairs: &RbFields) -> TResult<usize> {
+        Ok(self.write_len(pairs.len())? + self.write_pairs(pairs, true)?)
+    }
+
+    fn write_object(&mut self, obj: &RbObject, sortable: bool) -> TResult<usize> {
         let mut sz = 0;
-        sz += self.write_int(pairs.len() as i32)?;
-        for (key, val) in pairs.iter() {
-            sz += self.write_entry(&key.as_any())?;
-            sz += self.write_entry(val)?;
+        sz += self.write_symbol(&obj.name)?;
+        sz += self.write_len(obj.fields.len())?;
+        if sortable {
+            sz += self.write_pairs(&obj.fields, false)?;
+        } else {
+            for (key, val) in obj.fields.iter() {
+                sz += self.write_symbol(key)?;
+                sz += self.write_entry(val)?;
+            }
         }
         Ok(sz)
     }
 
-    fn write_object(&mut self, obj: &RbObject) -> TResult<usize> {
+    /// Write the name and value of each ivar in `pairs`, without the count. They're sorted by
+    /// name if `sort_ivars` is set, and the encoding goes first if `encoding_first` is true.
+    fn write_pairs(&mut self, pairs: &RbFields, encoding_first: bool) -> TResult<usize> {
+        let mut order: Vec<_> = pairs.iter().collect();
+        if self.options.sort_ivars {
+            order.sort_by(|a, b| a.0.cmp(b.0));
+        }
+        if encoding_first {
+            // Stable, so the other ivars keep their order
+            order.sort_by_key(|(key, _)| !(key.eq_str("E") || key.eq_str("encoding")));
+        }
         let mut sz = 0;
-        sz += self.write_symbol(&obj.name)?;
-        sz += self.write_int(obj.fields.len() as i32)?;
-        for (key, val) in obj.fields.iter() {
+        for (key, val) in order {
             sz += self.write_symbol(key)?;
-            sz += self.write_entry(val)?;
+            sz += if key.eq_str("encoding") {
+                self.write_encoding_name(val)?
+            } else {
+                self.write_entry(val)?
+            };
         }
         Ok(sz)
     }
 
+    /// Write the value of an `encoding` ivar. Ruby writes the name as a plain string (`"`)
+    /// rather than one with an encoding of its own, though it still takes an object index.
+    fn write_encoding_name(&mut self, value: &RbAny) -> TResult<usize> {
+        let name = match value {
+            RbAny::Ref(v) => v,
+            _ => return self.write_entry(value),
+        };
+        let rc_ptr = rc_get_ptr(name);
+        match name.as_ref() {
+            RbRef::Str(v) if !self.object_map.contains_key(&rc_ptr) => {
+                if self.options.emit_object_refs {
+                    self.object_map.insert(rc_ptr, self.object_next);
+                }
+                self.object_next += 1;
+                Ok(self.write_byte(T_STRING)? + self.write_len_bytes(v.as_bytes())?)
+            },
+            _ => self.write_entry(value),
+        }
+    }
+
     fn write_typed_data(&mut self, name: &RbSymbol, data: &RbAny, type_byte: u8) -> TResult<usize> {
         let mut sz = 0;
         sz += self.write_byte(type_byte)?;
@@ -331,24 +641,219 @@ impl<W> RbWriter<W> where
         Ok(sz)
     }
 
+    /// Write a `Data` or `UserMarshal` value, with undecoded contents (see
+    /// `RbReader::lenient_user_data`) written back exactly as they were read.
+    fn write_rb_class(&mut self, class: &RbClass, type_byte: u8) -> TResult<usize> {
+        match class.undecoded() {
+            Some(raw) => {
+                let mut sz = 0;
+                sz += self.write_byte(type_byte)?;
+                sz += self.write_symbol(&class.name)?;
+                self.write_raw(raw)?;
+                Ok(sz + raw.len())
+            },
+            None => self.write_typed_data(&class.name, &class.data, type_byte),
+        }
+    }
+
+    /// Write the type byte of a value, after `I` if `instance` is true. If the value is the
+    /// contents of a `UserClass`, its class name goes between the two like Ruby, e.g.
+    /// `IC:\x0aMyStr"`.
+    fn write_type(&mut self, instance: bool, type_byte: u8) -> TResult<usize> {
+        let mut sz = 0;
+        if instance {
+            sz += self.write_byte(T_INSTANCE)?;
+        }
+        if let Some(name) = self.user_class.take() {
+            sz += self.write_byte(T_USER_CLASS)?;
+            sz += self.write_symbol(&name)?;
+        }
+        sz += self.write_byte(type_byte)?;
+        Ok(sz)
+    }
+
     /// Writes the number of bytes in `data` as a variable-length integer then writes `data`.
     /// Returns the total size of bytes written.
     fn write_len_bytes(&mut self, data: &[u8]) -> TResult<usize> {
-        let sz = self.write_int(data.len() as i32)?;
-        self.dst.write_all(data)?;
+        let sz = self.write_len(data.len())?;
+        self.write_raw(data)?;
         Ok(data.len() + sz)
     }
 
-    fn write_byte(&mut self, b: u8) -> TResult<usize> {
+    pub(super) fn write_byte(&mut self, b: u8) -> TResult<usize> {
         let buf = [b];
-        self.dst.write_all(&buf)?;
+        self.write_raw(&buf)?;
         Ok(1)
     }
+
+    /// Pass `data` on to the destination, unless it would go over `max_output_len`.
+    fn write_raw(&mut self, data: &[u8]) -> TResult<()> {
+        if let Some(limit) = self.options.max_output_len {
+            if data.len() > limit - self.output_len {
+                return Err(ThurgoodError::OutputTooLarge { limit });
+            }
+        }
+        self.dst.write_all(data)?;
+        self.output_len += data.len();
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes Ruby uses to write `v` as a Marshal integer. Like Ruby, negative
+/// values leave out their high bytes which are all 0xff.
+pub(super) fn int_len(v: i32) -> usize {
+    match v {
+        -123 ..= 122 => 1,
+        _ => {
+            let unused = if v > 0 { v.leading_zeros() } else { v.leading_ones() };
+            5 - (unused / 8) as usize
+        },
+    }
+}
+
+/// Returns the flags of a regex as the single byte Marshal stores them in.
+fn regex_flags_byte(flags: u32) -> TResult<u8> {
+    u8::try_from(flags).map_err(|_| ThurgoodError::BadRegexFlags(flags))
+}
+
+/// Returns true if `data` can be the contents of a `UserClass`, meaning it's written with
+/// `write_type`.
+fn is_user_class_body(data: &RbRef) -> bool {
+    match data {
+        RbRef::Array(_) | RbRef::Hash(_) | RbRef::Str(_) | RbRef::StrI { .. } | RbRef::Regex { .. }
+            | RbRef::RegexI { .. } => true,
+        RbRef::WithIvars { object, .. } => is_user_class_body(object),
+        _ => false,
+    }
+}
+
+/// Format a finite float the way Ruby's Marshal does (see `w_float` in marshal.c): the shortest
+/// digits which round-trip, in plain notation unless the exponent is large, e.g. `1.5`, `1e2`,
+/// `0.001`, `1e-5`. Rust's `{:e}` formatting also produces the shortest round-trip digits.
+///
+/// This only depends on `v`: Rust's formatting never uses the locale or any other global
+/// state, and the decimal point and exponent are written here, so the output is the same
+/// everywhere.
+fn ruby_float_text(v: f64) -> String {
+    if v == 0.0 {
+        return if v.is_sign_negative() { "-0" } else { "0" }.to_owned();
+    }
+    let sci = format!("{:e}", v.abs());
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let digits = mantissa.replace('.', "");
+    let ndigits = digits.len() as i32;
+    // Position of the decimal point relative to the start of `digits`
+    let decpt = exp.parse::<i32>().unwrap() + 1;
+    let mut text = String::new();
+    if v.is_sign_negative() {
+        text.push('-');
+    }
+    if decpt < -3 || decpt > ndigits {
+        text.push_str(&digits[..1]);
+        if ndigits > 1 {
+            text.push('.');
+            text.push_str(&digits[1..]);
+        }
+        text.push_str(&format!("e{}", decpt - 1));
+    } else if decpt > 0 {
+        text.push_str(&digits[..decpt as usize]);
+        if ndigits > decpt {
+            text.push('.');
+            text.push_str(&digits[decpt as usize..]);
+        }
+    } else {
+        text.push_str("0.");
+        text.push_str(&"0".repeat(-decpt as usize));
+        text.push_str(&digits);
+    }
+    text
 }
 
 /// Serialize an `RbAny` to an IO stream.
-/// 
+///
+/// The writer makes many small writes, so these are buffered before being passed on to `dst`.
+/// When writing to memory `to_vec` avoids the extra copy.
 pub fn to_writer<W: io::Write>(dst: W, value: &RbAny) -> TResult<usize> {
-    let mut wr = RbWriter::new(dst);
-    wr.write(value)
+    let mut buf = io::BufWriter::new(dst);
+    let sz = RbWriter::new(&mut buf).write(value)?;
+    buf.flush()?;
+    Ok(sz)
+}
+
+/// Serialize an `RbAny` into a new `Vec<u8>`, which is preallocated using a rough
+/// estimate of the output size.
+pub fn to_vec(value: &RbAny) -> TResult<Vec<u8>> {
+    let mut buf = Vec::with_capacity(estimate_len(value));
+    RbWriter::new(&mut buf).write(value)?;
+    Ok(buf)
+}
+
+/// Serialize an `RbAny` into `buf`, returning the number of bytes written. Nothing is
+/// allocated for the output, which makes this suitable for pre-sized buffers.
+///
+/// If the value doesn't fit this returns `ThurgoodError::BufferTooSmall` with the full size
+/// (as from `serialized_len`), and the contents of `buf` are unspecified.
+pub fn to_slice(buf: &mut [u8], value: &RbAny) -> TResult<usize> {
+    let available = buf.len();
+    let mut dst = SliceWriter { buf, pos: 0, overflow: false };
+    match RbWriter::new(&mut dst).write(value) {
+        Err(ThurgoodError::IO(_)) if dst.overflow => {
+            Err(ThurgoodError::BufferTooSmall { needed: serialized_len(value)?, available })
+        },
+        r => r,
+    }
+}
+
+/// Returns the number of bytes `value` takes when serialized, without storing the output.
+pub fn serialized_len(value: &RbAny) -> TResult<usize> {
+    RbWriter::new(io::sink()).write(value)
+}
+
+/// Writes into a fixed slice, failing rather than writing part of the data if it's full.
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    overflow: bool,
+}
+
+impl<'a> io::Write for SliceWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            self.overflow = true;
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer is full"));
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serialize an `RbAny` to the file at `path`, replacing it if it already exists.
+pub fn to_file<P: AsRef<Path>>(path: P, value: &RbAny) -> TResult<()> {
+    to_writer(File::create(path)?, value)?;
+    Ok(())
+}
+
+/// Guess how many bytes `value` will take when serialized. Most values take a few bytes,
+/// strings and symbols take (roughly) their length.
+fn estimate_len(value: &RbAny) -> usize {
+    let mut len = 2;
+    value.walk(|v| {
+        len += match v {
+            RbAny::Symbol(s) => s.len() + 2,
+            RbAny::Ref(r) => match r.as_ref() {
+                RbRef::Str(s) => s.len() + 8,
+                RbRef::StrI { content, .. } => content.len() + 8,
+                RbRef::UserData(d) => d.data.len() + 4,
+                _ => 4,
+            },
+            _ => 2,
+        };
+    });
+    len
 }
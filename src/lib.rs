@@ -30,6 +30,9 @@
 //! There are NO `RefMut`, `RwLock`, or `Mutex` in the code. If you want to mutate an object
 //! use `Rc::get_mut`, `Rc::make_mut`, or the equivalent `Arc` functions. 
 //! 
+//! To write code which reads values once for both, use the `RbAnyLike` and `RefCounted` traits,
+//! which both implementations share. Building or changing values needs the types of one module.
+//! 
 //! ## Errata
 //! * Floats are stored as strings, however due to the way the spec is written, they may be either
 //!   length-terminated OR NULL-terminated. Thurgood can parse either, but will only produce
@@ -39,13 +42,22 @@
 //!   and will ignore extra instance fields. In practice this shouldn't be an issue, and any string
 //!   with a non-standard encoding is stored appropriately, but this is a potential source of difference
 //!   when trying to round-trip data.
+//! * Raw strings are binary (`ASCII-8BIT`) in Ruby, so they're read as `RbRef::StrI` with no metadata
+//!   (even if they happen to be valid UTF-8), and written back as raw strings.
 //! * If `RbReader.allow_bin_strings` is set to true the reader will produce `RbRef::StrI` instances
-//!   when the input is a normal string, but not in UTF-8 encoding. This may impact round-trip byte-compatibility.
+//!   when the input is marked as UTF-8 but isn't valid UTF-8, instead of reporting an error.
 //! 
 pub mod consts;
 pub mod error;
+pub mod util;
 mod rb_type;
+mod ref_counted;
+mod source;
+#[cfg(feature = "arena")]
+pub mod arena;
 pub use rb_type::RbType;
+pub use ref_counted::{RbAnyLike, RefCounted};
+pub use source::{ByteSource, IoSource, SliceSource};
 pub use error::{ThurgoodError, TResult};
 
 pub mod rc;
@@ -59,12 +71,14 @@ pub mod arc;
 mod tests {
     use std::io;
 
-    use crate::{rc::*, consts::{T_STRING, T_INSTANCE}};
+    use crate::{rc::*, consts::T_STRING, util::{escape_bytes, unescape}};
     // use crate::inner::*;
 
     /// Parse a string into an `RbAny`
     fn reader_parse(s: &str) -> RbAny {
-        from_reader(io::Cursor::new(s.as_bytes())).expect("Parsing error")
+        let value = from_reader(io::Cursor::new(s.as_bytes())).expect("Parsing error");
+        assert!(value.deep_eq(&from_bytes(s.as_bytes()).expect("Parsing error")));
+        value
     }
 
     fn reader_parse_loose(s: &[u8]) -> RbAny {
@@ -81,7 +95,7 @@ mod tests {
     }
 
     fn assert_write(value: &RbAny, expected: &[u8]) {
-        assert_eq!(escape_str(writer_write(value).as_slice()), escape_str(expected));
+        assert_eq!(escape_bytes(writer_write(value).as_slice()), escape_bytes(expected));
     }
 
     #[test]
@@ -116,6 +130,34 @@ mod tests {
         assert_write(&exp, inp.as_bytes());
     }
 
+    #[test]
+    fn object_builder() {
+        let inp = "\x04\x08o:\x08Foo\x07:\n@nameI\"\tJack\x06:\x06ET:\t@agei\x1E";
+        let obj = RbObject::builder("Foo").field("@name", "Jack").field("@nick", RbAny::Nil).field("@age", 25).build();
+        assert_eq!(obj.field_names().map(|k| k.as_str().unwrap()).collect::<Vec<_>>(), vec!["@name", "@nick", "@age"]);
+        let obj = RbObject::builder("Foo")
+            .field("@name", "Jack")
+            .field_opt("@nick", None::<&str>)
+            .field_opt("@age", Some(25))
+            .build();
+        assert_eq!(obj, RbObject::new_from_slice("Foo", &[("@name", "Jack".into()), ("@age", 25.into())]));
+        assert_write(&obj.into_object().into_any(), inp.as_bytes());
+    }
+
+    #[test]
+    fn entries() {
+        // [{:a => 1, "b" => 2}, #<Foo @x=3>, Struct::Pt.new(4), [5]]
+        let value = reader_parse("\x04\x08[\x09{\x07:\x06ai\x06I\"\x06b\x06:\x06ETi\x07o:\x08Foo\x06:\x07@xi\x08S:\x07Pt\x06:\x06yi\x09[\x06i\x0a");
+        let hash: Vec<_> = value.get(0).unwrap().entries().unwrap().collect();
+        assert_eq!(hash, vec![(RbAny::sym("a"), &RbAny::Int(1)), (RbAny::from("b"), &RbAny::Int(2))]);
+        let obj: Vec<_> = value.get(1).unwrap().entries().unwrap().collect();
+        assert_eq!(obj, vec![(RbAny::sym("@x"), &RbAny::Int(3))]);
+        let st: Vec<_> = value.get(2).unwrap().entries().unwrap().collect();
+        assert_eq!(st, vec![(RbAny::sym("y"), &RbAny::Int(4))]);
+        assert!(value.get(3).unwrap().entries().is_none());
+        assert!(RbAny::Int(1).entries().is_none());
+    }
+
     #[test]
     fn modules() {
         let inp = "\x04\x08{\x07:\x07aao:\x0EBar::BazA\x00:\x07bbo:\x0EBar::BazB\x00";
@@ -200,22 +242,2758 @@ mod tests {
     #[test]
     fn invalid_utf8_string_allowed() {
         let inp = vec![0x04u8, 0x08, T_STRING, 0x08, 0xc3, 0x28, 0x34];
-        let out = vec![0x04u8, 0x08, T_INSTANCE, T_STRING, 0x08, 0xc3, 0x28, 0x34, 0x00];
-        let exp = RbRef::StrI { content: vec![0xc3, 0x28, 0x34], metadata: RbFields::new() }.into_any();
+        let out = inp.clone();
+        let exp = RbRef::new_string_bin(vec![0xc3, 0x28, 0x34]).into_any();
         assert!(reader_parse_loose(&inp).deep_eq(&exp));
         assert_write(&exp, &out);
+        // `Marshal.dump("".b)` and `Marshal.dump(["ab".b, :E])`: binary strings have no ivars,
+        // so they're written bare and nothing extra is added to the symbol table
+        assert_write(&RbRef::new_string_bin(vec![]).into_any(), b"\x04\x08\"\x00");
+        let arr = RbAny::from(vec![RbAny::from(&b"ab"[..]), RbAny::sym("E")]);
+        assert_write(&arr, b"\x04\x08[\x07\"\x07ab:\x06E");
+    }
+
+    #[test]
+    fn objects_named_copy_on_write() {
+        let inp = "\x04\x08[\no:\x08Foo\x07:\n@nameI\"\tJack\x06:\x06ET:\t@agei\x1E@\x06{\x06:\x08key@\x06o;\x00\x07;\x06I\"\tJane\x06;\x07T;\x08i\x1D@\t";
+        let original = reader_parse(inp);
+        let mut edited = original.clone();
+        let mut count = 0;
+        edited.objects_named_mut("Foo", |obj| {
+            let age = obj.get("@age").and_then(|v| v.as_int()).unwrap();
+            obj.insert("@age", RbAny::Int(age + 1));
+            count += 1;
+        });
+        assert_eq!(count, 2);
+        // The original tree is untouched
+        assert_eq!(writer_write(&original).as_slice(), inp.as_bytes());
+        // Sharing within the edited tree is preserved
+        let ar = edited.as_array().unwrap();
+        assert_eq!(ar[0].as_rc().map(rc_get_ptr), ar[1].as_rc().map(rc_get_ptr));
+        let mut ages = Vec::new();
+        edited.objects_named("Foo", |obj| ages.push(obj.get("@age").unwrap().clone()));
+        assert_eq!(ages, vec![RbAny::Int(26), RbAny::Int(25)]);
+        assert_eq!(writer_write(&edited).len(), inp.len());
+    }
+
+    #[test]
+    fn objects_named_recursive() {
+        let inp = "\x04\x08o:\x08Foo\x06:\n@self@\x00";
+        let mut value = reader_parse(inp);
+        let alias = value.clone();
+        value.objects_named_mut("Foo", |obj| {
+            obj.insert("@id", RbAny::Int(1));
+        });
+        // The object is visited once, and its reference to itself points at the edited copy
+        let obj = value.as_object().unwrap();
+        assert_eq!(obj.get("@self").and_then(|v| v.as_rc()).map(rc_get_ptr), value.as_rc().map(rc_get_ptr));
+        assert_eq!(obj.get("@id"), Some(&RbAny::Int(1)));
+        assert_eq!(alias.as_object().unwrap().get("@id"), None);
+        assert_eq!(alias.get("@self").unwrap().pointer_id(), alias.pointer_id());
+        assert_write(&value, b"\x04\x08o:\x08Foo\x07:\n@self@\x00:\x08@idi\x06");
+
+        // The same through a shared array: a = [o, o]; o.instance_variable_set(:@list, a)
+        let inp = "\x04\x08[\x07o:\x08Foo\x06:\n@list@\x00@\x06";
+        let mut value = reader_parse(inp);
+        let alias = value.clone();
+        value.objects_named_mut("Foo", |obj| {
+            obj.insert("@id", RbAny::Int(1));
+        });
+        let obj = value.get(0).unwrap();
+        assert_eq!(obj.pointer_id(), value.get(1).unwrap().pointer_id());
+        assert_eq!(obj.get("@list").unwrap().pointer_id(), value.pointer_id());
+        assert_write(&value, b"\x04\x08[\x07o:\x08Foo\x07:\n@list@\x00:\x08@idi\x06@\x06");
+        assert_write(&alias, inp.as_bytes());
+    }
+
+    #[test]
+    fn user_data_registry() {
+        fn decode_count(data: &[u8]) -> crate::TResult<RbAny> {
+            Ok(RbAny::Int(data.len() as i32))
+        }
+        let mut registry = UserDataRegistry::new();
+        registry.register("Counted", decode_count);
+        let counted = RbUserData { name: "Counted".into(), data: vec![1, 2, 3] };
+        let other = RbUserData { name: "Other".into(), data: vec![4, 5] };
+        assert_eq!(counted.decode_with(&registry).unwrap().unwrap(), RbAny::Int(3));
+        assert!(other.decode_with(&registry).is_none());
+        assert_eq!(other.as_bytes(), &[4, 5]);
+    }
+
+    #[test]
+    fn hash_with_default() {
+        let inp = "\x04\x08}\x06:\x06ai\x06i\x00";
+        let mut hash = RbHash::with_default(RbAny::Int(0));
+        hash.insert(RbAny::symbol_from("a"), RbAny::Int(1));
+        assert!(hash.has_default());
+        let exp = RbAny::from(hash.clone());
+        assert!(reader_parse(inp).deep_eq(&exp));
+        assert_write(&exp, inp.as_bytes());
+        assert_eq!(hash.set_default(RbAny::Nil), Some(RbAny::Int(0)));
+        assert_eq!(hash.take_default(), Some(RbAny::Nil));
+        assert!(!hash.has_default());
+    }
+
+    #[test]
+    fn escape_unescape() {
+        let raw = b"\x04\x08[\x07I\"\ttest\x06:\x06ET\\";
+        let escaped = escape_bytes(raw);
+        assert_eq!(escaped, "\\x04\\x08[\\x07I\"\\x09test\\x06:\\x06ET\\\\");
+        assert_eq!(unescape(&escaped), raw.to_vec());
+        assert_eq!(unescape("\\x04\\x08i\\x06\\n\\xZZ"), b"\x04\x08i\x06\n\\xZZ".to_vec());
+    }
+
+    #[test]
+    fn slice_reader() {
+        let inp = b"\x04\x08[\x07i\x06i\x07";
+        let mut rd = RbReader::from_slice(inp);
+        assert!(rd.read().unwrap().deep_eq(&RbAny::from(vec![RbAny::Int(1), RbAny::Int(2)])));
+        assert!(matches!(from_bytes(&inp[..5]), Err(Error::IO(_))));
+    }
+
+    #[test]
+    fn shared_symbols() {
+        let inp = "\x04\x08[\x07o:\x08Foo\x07:\n@nameI\"\tJack\x06:\x06ET:\t@agei\x1Eo;\x00\x07;\x06I\"\tJane\x06;\x07T;\x08i\x1D";
+        let buf: RcType<[u8]> = RcType::from(inp.as_bytes());
+        let value = from_bytes_shared(&buf).unwrap();
+        assert!(value.deep_eq(&reader_parse(inp)));
+        assert_eq!(value.as_array().unwrap()[0].as_object().unwrap().name, RbSymbol::from("Foo"));
+        assert_write(&value, inp.as_bytes());
+        // Every symbol holds on to the buffer until it's detached
+        assert!(value.as_array().unwrap()[0].as_object().unwrap().name.is_shared());
+        assert!(RcType::strong_count(&buf) > 1);
+        let mut value = value;
+        assert_eq!(value.detach_symbols(), 6);
+        assert_eq!(RcType::strong_count(&buf), 1);
+        assert!(!value.as_array().unwrap()[0].as_object().unwrap().name.is_shared());
+        assert!(value.deep_eq(&reader_parse(inp)));
+        assert_write(&value, inp.as_bytes());
+        assert_eq!(value.detach_symbols(), 0);
+    }
+
+    #[test]
+    fn reorder_fields() {
+        let mut obj = RbObject::new_from_slice("Foo", &[
+            ("@a", RbAny::Int(1)),
+            ("@b", RbAny::Int(2)),
+            ("@c", RbAny::Int(3)),
+            ("@d", RbAny::Int(4)),
+        ]);
+        obj.reorder_fields(&["@c".into(), "@x".into(), "@a".into()]);
+        let names: Vec<_> = obj.fields.keys().map(|k| k.as_str().unwrap()).collect();
+        assert_eq!(names, vec!["@c", "@a", "@b", "@d"]);
+    }
+
+    #[test]
+    fn raw_float_strings() {
+        let inp = b"\x04\x08[\x07f\x0D0.123\x00NOf\x0B1.2340";
+        let mut rd = RbReader::from_slice(inp);
+        rd.record_raw_floats = true;
+        let value = rd.read().unwrap();
+        let ar = value.as_array().unwrap();
+        assert_eq!(rd.raw_float(&ar[0]), Some(&b"0.123"[..]));
+        assert_eq!(rd.raw_float(&ar[1]), Some(&b"1.2340"[..]));
+        assert_eq!(rd.raw_float(&value), None);
+        // A copy isn't the float that was read, and the next document starts over
+        assert_eq!(rd.raw_float(&RbAny::from(0.123)), None);
+        let first = ar[0].clone();
+        rd.reset(crate::SliceSource::new(b"\x04\x08f\x060"));
+        rd.read().unwrap();
+        assert_eq!(rd.raw_float(&first), None);
+    }
+
+    #[test]
+    fn date_and_datetime() {
+        // Marshal.dump(Date.new(2020, 1, 1))
+        let inp = b"\x04\x08U:\tDate[\x0Bi\x00i\x03\xE2\x84%i\x00i\x00i\x00f\x0C2299161";
+        let value = from_bytes(inp).unwrap();
+        let date = RbDate::from_any(&value).unwrap();
+        assert_eq!((date.year(), date.month(), date.day()), (2020, 1, 1));
+        assert_eq!(date, RbDate::new(2020, 1, 1));
+        assert_write(&date.to_any(), inp);
+        assert_write(&value, inp);
+        // Dates before the calendar reform use the Julian calendar
+        let old = RbDate::new(1000, 2, 29);
+        assert_eq!((old.year(), old.month(), old.day()), (1000, 2, 29));
+
+        let dt = RbDateTime::new(2020, 1, 1, 0, 30, 15, 3600);
+        let dt = RbDateTime::from_any(&dt.to_any()).unwrap();
+        assert_eq!((dt.year(), dt.month(), dt.day()), (2020, 1, 1));
+        assert_eq!((dt.hour(), dt.minute(), dt.second(), dt.offset()), (0, 30, 15, 3600));
+        assert!(RbDate::from_any(&dt.to_any()).is_none());
+    }
+
+    #[test]
+    fn set() {
+        // Marshal.dump(Set[1, 2, 3])
+        let inp = b"\x04\x08o:\x08Set\x06:\x0a@hash}\x08i\x06Ti\x07Ti\x08TF";
+        let value = from_bytes(inp).unwrap();
+        let set = RbSet::from_any(&value).unwrap();
+        assert_eq!(set.elements().cloned().collect::<Vec<_>>(), vec![RbAny::Int(1), RbAny::Int(2), RbAny::Int(3)]);
+        assert!(set.contains(&RbAny::Int(2)));
+        assert_eq!(set, RbSet::new(vec![RbAny::Int(1), RbAny::Int(2), RbAny::Int(3)]));
+        assert_write(&value, inp);
+        assert_write(&set.to_any(), inp);
+        assert!(RbSet::from_any(&RbAny::from(vec![RbAny::Int(1)])).is_none());
+
+        #[cfg(feature = "json")]
+        {
+            let json = serde_json::json!({"@": "Set", "@id": 1, "data": [1, 2, 3]});
+            assert_eq!(value.to_json().unwrap(), json);
+            let mut out = Vec::new();
+            value.write_json(&mut out).unwrap();
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&out).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn validate_paths() {
+        let bad_regex = RbRef::Object(RbObject::new_from_slice("Foo", &[
+            ("@pattern", RbRef::new_regex("a+".to_owned(), 0x1FF).into_any()),
+        ])).into_any();
+        let root = RbAny::from(RbHash::from_pairs(vec![
+            (RbAny::symbol_from("users"), RbAny::from(vec![RbAny::Int(1), bad_regex])),
+        ]));
+        match root.validate() {
+            Err(Error::Unserializable { path, .. }) => assert_eq!(path, "root[:users][1].@pattern"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let bad_class = RbRef::UserClass(RbClass { name: "Foo".into(), data: RbAny::Int(1) }).into_any();
+        assert!(bad_class.validate().is_err());
+        assert!(reader_parse("\x04\x08[\x07[\x06I\"\tTest\x06:\x06ET@\x06").validate().is_ok());
+    }
+
+    #[test]
+    fn any_len() {
+        let value = reader_parse("\x04\x08[\x07[\x00{\x06i\x06i\x07");
+        assert_eq!(value.len(), Some(2));
+        assert_eq!(value.as_array().unwrap()[0].is_empty(), Some(true));
+        assert_eq!(value.as_array().unwrap()[1].len(), Some(1));
+        let obj = RbObject::new_from_slice("Foo", &[("@a", RbAny::Nil)]).into_object().into_any();
+        assert_eq!(obj.len(), Some(1));
+        assert_eq!(RbAny::Int(3).len(), None);
+        assert_eq!(RbAny::from("abc").is_empty(), None);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml_export() {
+        let obj = RbObject::new_from_slice("Point", &[
+            ("@x", RbAny::Int(1)),
+            ("@tag", RbAny::symbol_from("origin")),
+        ]).into_object().into_any();
+        let root = RbAny::from(vec![obj.clone(), obj, RbAny::from("hi")]);
+        let yaml = root.to_yaml().unwrap();
+        assert_eq!(yaml, "- !ruby/object:Point\n  x: 1\n  tag: !ruby/symbol origin\n- !ruby/ref 2\n- hi\n");
+    }
+
+    #[test]
+    fn null_terminated_floats() {
+        let value = RbAny::from(1.5f64);
+        let options = RbWriterOptions { null_terminated_floats: true, ..Default::default() };
+        let mut buf = Vec::new();
+        RbWriter::with_options(&mut buf, options).write(&value).unwrap();
+        assert_eq!(buf, b"\x04\x08f\x091.5\x00");
+        assert_eq!(from_bytes(&buf).unwrap(), value);
+        assert_eq!(writer_write(&value), b"\x04\x08f\x081.5");
+    }
+
+    #[test]
+    fn node_serialized_len() {
+        let inp = b"\x04\x08[\x08I\"\x06x\x06:\x06ET[\x07@\x06@\x06i\x06";
+        let value = from_bytes(inp).unwrap();
+        assert_eq!(value.serialized_len().unwrap(), inp.len() - 2);
+        // On its own the string is written in full, and the encoding symbol defined
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].serialized_len().unwrap(), 9);
+        assert_eq!(items[1].serialized_len().unwrap(), 13);
+        assert_eq!(items[2].serialized_len().unwrap(), 2);
+    }
+
+    #[test]
+    fn max_output_len() {
+        let value = RbAny::from(vec![RbAny::from("test"), RbAny::Int(1000)]);
+        let len = serialized_len(&value).unwrap();
+        let write = |limit: usize| {
+            let options = RbWriterOptions { max_output_len: Some(limit), ..Default::default() };
+            let mut buf = Vec::new();
+            let result = RbWriter::with_options(&mut buf, options).write(&value);
+            (result, buf)
+        };
+        let (result, buf) = write(len);
+        assert_eq!(result.unwrap(), len);
+        assert_eq!(buf, writer_write(&value));
+        let (result, buf) = write(len - 1);
+        assert!(matches!(result, Err(crate::ThurgoodError::OutputTooLarge { limit }) if limit == len - 1));
+        assert!(buf.len() < len);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn plain_json() {
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{"name": "bob", "tags": [1, 2.5, null, true], "id": 5000000000, "age": 30}"#).unwrap();
+        let value = RbAny::from_plain_json(&json);
+        let hash = value.as_hash().unwrap();
+        let keys: Vec<_> = hash.keys().map(|k| k.as_string().unwrap().as_str()).collect();
+        assert_eq!(keys, ["name", "tags", "id", "age"]);
+        let values: Vec<_> = hash.values().collect();
+        assert_eq!(values[3], &RbAny::Int(30));
+        assert_eq!(values[2], &RbAny::from(RbRef::BigInt(5000000000i64.into())));
+        assert!(values[1].deep_eq(&RbAny::from(vec![
+            RbAny::Int(1), RbAny::from(2.5f64), RbAny::Nil, RbAny::True,
+        ])));
+
+        let value = RbAny::from_plain_json_with(&json, PlainJsonObjects::SymbolKeys);
+        assert_eq!(value.get(RbAny::symbol_from("age")), Some(&RbAny::Int(30)));
+        assert_eq!(value.get("age"), None);
+
+        let obj = RbAny::from_plain_json_as_object(&json, "User").unwrap();
+        assert!(obj.name == "User");
+        assert_eq!(obj.field_names().map(|k| k.as_str().unwrap()).collect::<Vec<_>>(), ["@name", "@tags", "@id", "@age"]);
+        assert_eq!(obj.get("@name"), Some(&RbAny::from("bob")));
+        assert!(RbAny::from_plain_json_as_object(&serde_json::json!([1]), "User").is_none());
+    }
+
+    #[test]
+    fn object_indices() {
+        let inp = "\x04\x08[\x08[\x06I\"\tTest\x06:\x06ET@\x06@\x07";
+        let mut rd = RbReader::from_slice(inp.as_bytes());
+        let value = rd.read().unwrap();
+        let read_indices = rd.object_indices();
+        let ar = value.as_array().unwrap();
+        assert_eq!(read_indices.len(), 3);
+        assert_eq!(read_indices[&rc_get_ptr(value.as_rc().unwrap())], 0);
+        assert_eq!(read_indices[&rc_get_ptr(ar[0].as_rc().unwrap())], 1);
+        assert_eq!(read_indices[&rc_get_ptr(ar[2].as_rc().unwrap())], 2);
+        assert_eq!(value.object_indices().unwrap(), read_indices);
+        let unwritable = RbAny::from(vec![RbRef::new_regex("a".to_owned(), 0x100).into_any()]);
+        assert!(unwritable.object_indices().is_err());
+    }
+
+    #[test]
+    fn writer_on_object() {
+        use std::{cell::RefCell, rc::Rc};
+        let shared = RbAny::from("Test");
+        let value = RbAny::from(vec![RbAny::from(vec![shared.clone()]), shared]);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let mut wr = RbWriter::new(Vec::new())
+            .with_on_object(move |obj, index| log.borrow_mut().push((rc_get_ptr(obj), index)));
+        wr.write(&value).unwrap();
+        // Only the first time each object is written is reported
+        let seen = seen.borrow();
+        assert_eq!(seen.len(), 3);
+        let expected: std::collections::HashMap<_, _> = seen.iter().cloned().collect();
+        assert_eq!(&expected, wr.object_indices());
+    }
+
+    #[test]
+    fn empty_root_containers() {
+        // Marshal.dump([]), Marshal.dump({}), Marshal.dump(Hash.new(0))
+        let value = reader_parse("\x04\x08[\x00");
+        assert_eq!(value.as_array().map(|v| v.len()), Some(0));
+        assert_write(&value, b"\x04\x08[\x00");
+        assert_write(&RbAny::from(Vec::<RbAny>::new()), b"\x04\x08[\x00");
+
+        let value = reader_parse("\x04\x08{\x00");
+        let hash = value.as_hash().unwrap();
+        assert!(hash.is_empty() && hash.default.is_none());
+        assert_write(&value, b"\x04\x08{\x00");
+        assert_write(&RbAny::from(RbHash::new()), b"\x04\x08{\x00");
+
+        let value = reader_parse("\x04\x08}\x00i\x00");
+        let hash = value.as_hash().unwrap();
+        assert!(hash.is_empty());
+        assert_eq!(hash.default.as_deref(), Some(&RbAny::Int(0)));
+        assert_write(&value, b"\x04\x08}\x00i\x00");
+        assert_write(&RbAny::from(RbHash::with_default(RbAny::Int(0))), b"\x04\x08}\x00i\x00");
+
+        // Reading an empty container doesn't consume the value after it
+        let value = reader_parse("\x04\x08[\x08[\x00{\x00i\x06");
+        let expected = RbAny::from(vec![RbAny::from(Vec::<RbAny>::new()), RbAny::from(RbHash::new()), RbAny::Int(1)]);
+        assert!(value.deep_eq(&expected));
+        assert_write(&expected, b"\x04\x08[\x08[\x00{\x00i\x06");
+    }
+
+    #[test]
+    fn try_walk() {
+        use std::ops::ControlFlow;
+        let bar = RbObject::new_from_slice("Bar", &[("@id", RbAny::Int(2))]).into_object().into_any();
+        let value = RbAny::from(vec![
+            RbObject::new_from_slice("Foo", &[("@id", RbAny::Int(1))]).into_object().into_any(),
+            bar.clone(),
+            bar,
+            RbAny::from(vec![RbAny::Int(3)]),
+        ]);
+        let mut visited = 0;
+        let found = value.try_walk(|node| {
+            visited += 1;
+            match node.as_object() {
+                Some(obj) if obj.name == "Bar" => ControlFlow::Break(obj.get("@id").cloned()),
+                _ => ControlFlow::Continue(()),
+            }
+        });
+        assert_eq!(found, Some(Some(RbAny::Int(2))));
+        // The root, Foo and its @id, then Bar
+        assert_eq!(visited, 4);
+        // Shared values are only visited once
+        let mut count = 0;
+        assert_eq!(value.try_walk(|_| { count += 1; ControlFlow::<()>::Continue(()) }), None);
+        assert_eq!(count, 7);
+    }
+
+    #[test]
+    fn any_get() {
+        let obj = RbObject::new_from_slice("Foo", &[("@name", RbAny::from("bob"))]).into_object().into_any();
+        let mut root = RbAny::from(RbHash::from_pairs(vec![
+            (RbAny::symbol_from("users"), RbAny::from(vec![obj])),
+            (RbAny::from("key"), RbAny::Int(7)),
+        ]));
+        let user = root.get(RbAny::symbol_from("users")).and_then(|v| v.get(0)).unwrap();
+        assert_eq!(user.get("@name"), Some(&RbAny::from("bob")));
+        assert_eq!(user.get(RbAny::symbol_from("@name")), Some(&RbAny::from("bob")));
+        assert_eq!(root.get("key"), Some(&RbAny::Int(7)));
+        assert_eq!(root.get(0), None);
+        assert_eq!(RbAny::Int(1).get(0), None);
+        *root.get_mut(RbAny::symbol_from("users")).and_then(|v| v.get_mut(0))
+            .and_then(|v| v.get_mut("@name")).unwrap() = RbAny::from("alice");
+        let users = root.get(RbAny::symbol_from("users")).unwrap();
+        assert_eq!(users.get(0).and_then(|v| v.get("@name")), Some(&RbAny::from("alice")));
+    }
+
+    #[test]
+    fn forward_object_ref() {
+        match from_bytes(b"\x04\x08[\x07@\x07i\x06") {
+            Err(Error::ForwardObjectRef(2)) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+        // A reference to an object which is still being read is recursion, not a forward reference.
+        let value = from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        assert!(RcType::ptr_eq(value.as_rc().unwrap(), value.get(0).unwrap().as_rc().unwrap()));
+    }
+
+    #[test]
+    fn explode() {
+        let shared = RbAny::from(vec![RbAny::Int(1)]);
+        let root = RbAny::from(vec![shared.clone(), shared]);
+        let exploded = root.explode();
+        assert!(exploded.deep_eq(&root));
+        let (a, b) = (exploded.get(0).unwrap(), exploded.get(1).unwrap());
+        assert!(!RcType::ptr_eq(a.as_rc().unwrap(), b.as_rc().unwrap()));
+        assert!(!RcType::ptr_eq(a.as_rc().unwrap(), root.get(0).unwrap().as_rc().unwrap()));
+
+        let recursive = from_bytes(b"\x04\x08[\x07i\x06@\x00").unwrap();
+        assert!(recursive.explode().deep_eq(&RbAny::from(vec![RbAny::Int(1), RbAny::Nil])));
+    }
+
+    #[test]
+    fn write_to_vec() {
+        let inp = "\x04\x08[\x08[\x06I\"\tTest\x06:\x06ET@\x06@\x07";
+        let value = reader_parse(inp);
+        assert_eq!(to_vec(&value).unwrap(), inp.as_bytes());
+        let mut out = io::Cursor::new(Vec::new());
+        assert_eq!(to_writer(&mut out, &value).unwrap(), inp.len());
+        assert_eq!(out.into_inner(), inp.as_bytes());
+    }
+
+    #[test]
+    fn hash_default_shared() {
+        // s = "x"; h = Hash.new(s); h[1] = s; [h, s]
+        let inp = "\x04\x08[\x07}\x06i\x06I\"\x06x\x06:\x06ET@\x07@\x07";
+        let value = reader_parse(inp);
+        let hash = value.get(0).unwrap().as_hash().unwrap();
+        let s = value.get(1).unwrap().as_rc().unwrap();
+        assert!(RcType::ptr_eq(hash.default.as_ref().unwrap().as_rc().unwrap(), s));
+        assert_write(&value, inp.as_bytes());
+
+        // s = "x"; [Hash.new(s), s]
+        let inp = "\x04\x08[\x07}\x00I\"\x06x\x06:\x06ET@\x07";
+        let value = reader_parse(inp);
+        let s = value.get(1).unwrap().as_rc().unwrap();
+        assert!(RcType::ptr_eq(value.get(0).unwrap().as_hash().unwrap().default.as_ref().unwrap().as_rc().unwrap(), s));
+        assert_eq!(value.object_indices().unwrap()[&rc_get_ptr(s)], 2);
+        assert_write(&value, inp.as_bytes());
+
+        // a = []; [Hash.new(a), a, "y"]
+        let inp = "\x04\x08[\x08}\x00[\x00@\x07I\"\x06y\x06:\x06ET";
+        let value = reader_parse(inp);
+        assert_write(&value, inp.as_bytes());
+    }
+
+    #[test]
+    fn apply_patch() {
+        let settings = RbObject::new_from_slice("Settings", &[
+            ("@volume", RbAny::Int(5)),
+            ("@keys", RbAny::from(vec![RbAny::Int(1), RbAny::Int(2)])),
+        ]).into_object().into_any();
+        let mut root = RbAny::from(RbHash::from_pairs(vec![
+            (RbAny::from("settings"), settings.clone()),
+            (RbAny::from("alias"), settings.clone()),
+        ]));
+        let patch = RbAny::from(RbHash::from_pairs(vec![
+            (RbAny::from("settings"), RbObject::new_from_slice("Settings", &[
+                ("@volume", RbAny::Int(9)),
+                ("@keys", RbAny::from(RbHash::from_pairs(vec![(RbAny::Int(1), RbAny::Int(7))]))),
+                ("@muted", RbAny::True),
+            ]).into_object().into_any()),
+            (RbAny::from("new"), RbAny::Nil),
+        ]));
+        root.apply_patch(&patch).unwrap();
+        let patched = root.get("settings").unwrap();
+        assert_eq!(patched.get("@volume"), Some(&RbAny::Int(9)));
+        assert_eq!(patched.get("@muted"), Some(&RbAny::True));
+        assert!(patched.get("@keys").unwrap().deep_eq(&RbAny::from(vec![RbAny::Int(1), RbAny::Int(7)])));
+        assert_eq!(root.get("alias").unwrap().get("@volume"), Some(&RbAny::Int(5)));
+        assert_eq!(settings.get("@volume"), Some(&RbAny::Int(5)));
+        assert_eq!(root.len(), Some(3));
+
+        let bad = RbAny::from(RbHash::from_pairs(vec![(RbAny::Int(5), RbAny::Nil)]));
+        let mut ar = RbAny::from(vec![RbAny::Int(1)]);
+        assert!(matches!(ar.apply_patch(&bad), Err(Error::BadPatch(_))));
+
+        // h = {}; h[:a] = h
+        let cyclic = reader_parse("\x04\x08{\x06:\x06a@\x00");
+        let inner = RbHash::from_pairs(vec![(RbAny::sym("a"), RbAny::from(RbHash::new()))]);
+        let mut target = RbAny::from(RbHash::from_pairs(vec![(RbAny::sym("a"), RbAny::from(inner))]));
+        assert!(matches!(target.apply_patch(&cyclic), Err(Error::BadPatch(_))));
+        // Patching a value which contains itself is fine
+        let mut target = cyclic.clone();
+        target.apply_patch(&RbAny::from(RbHash::from_pairs(vec![(RbAny::sym("b"), RbAny::Nil)]))).unwrap();
+        assert_eq!(target.len(), Some(2));
+    }
+
+    #[test]
+    fn try_from_any() {
+        use std::convert::TryFrom;
+        assert_eq!(i64::try_from(&RbAny::Int(-3)).unwrap(), -3);
+        let big = RbAny::from(RbRef::BigInt(5000000000i64.into()));
+        assert_eq!(i64::try_from(&big).unwrap(), 5000000000);
+        assert_eq!(f64::try_from(&RbAny::from(1.5f64)).unwrap(), 1.5);
+        assert!(bool::try_from(&RbAny::True).unwrap());
+        assert_eq!(String::try_from(&reader_parse("\x04\x08I\"\x06x\x06:\x06ET")).unwrap(), "x");
+        assert_eq!(Vec::<RbAny>::try_from(&RbAny::from(vec![RbAny::Nil])).unwrap(), vec![RbAny::Nil]);
+        match String::try_from(&RbAny::Int(1)) {
+            Err(Error::UnexpectedType { expected: crate::RbType::Str, found: crate::RbType::Int, .. }) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn numeric_compare() {
+        let three = RbAny::Int(3);
+        let three_f = RbAny::from(3.0f64);
+        let three_big = RbAny::from(RbRef::BigInt(3.into()));
+        assert!(!three.deep_eq(&three_f));
+        assert!(RbCompare::numeric().eq(&three, &three_f));
+        assert!(RbCompare::numeric().eq(&three_big, &three));
+        assert!(RbCompare::numeric().eq(&three_big, &three_f));
+        assert!(RbCompare::numeric().cmp(&three, &RbAny::from(3.5f64)).is_lt());
+        let a = RbAny::from(vec![three, RbAny::from("x")]);
+        let b = RbAny::from(vec![three_f, RbAny::from("x")]);
+        assert!(!a.deep_eq(&b));
+        assert!(RbCompare::numeric().eq(&a, &b));
+    }
+
+    #[test]
+    fn debug_format() {
+        let value = reader_parse("\x04\x08[\x08[\x06I\"\tTest\x06:\x06ET@\x06@\x07");
+        assert_eq!(format!("{:?}", value), "[&2 [&3 Str(\"Test\")], #<ref 2>, #<ref 3>]");
+        let obj = RbObject::new_from_slice("Foo", &[("@a", RbAny::Int(1))]).into_object().into_any();
+        assert_eq!(format!("{:?}", obj), "Object(Foo){@a: Int(1)}");
+        let deep = RbAny::from(vec![RbAny::from(vec![RbAny::from(vec![RbAny::from(vec![obj.clone()])])])]);
+        assert_eq!(format!("{:?}", deep), "[[[[&5 Object(Foo){1}]]]]");
+        let recursive = from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        assert_eq!(format!("{:?}", recursive), "&1 [#<ref 1>]");
+    }
+
+    #[test]
+    fn symbol_eq_str() {
+        let sym = RbSymbol::from("name");
+        assert!(sym.eq_str("name"));
+        assert!(sym == "name");
+        assert!(sym != "other");
+        assert!("nam" != sym);
+        assert!(*"name" == sym);
+        assert!(RbSymbol::new(vec![0xFF]) != "\u{FF}");
+        assert_eq!(sym.len(), 4);
+        assert!(!sym.is_empty());
+        assert!(RbSymbol::from("").is_empty());
+    }
+
+    #[test]
+    fn array_hash_ivars() {
+        // a = [1, 2, 3]; a.instance_variable_set(:@custom, 1)
+        let inp = "\x04\x08I[\x08i\x06i\x07i\x08\x06:\x0C@customi\x06";
+        let value = reader_parse(inp);
+        assert_eq!(value.get_type(), crate::RbType::Array);
+        assert_eq!(value.get(0), Some(&RbAny::Int(1)));
+        assert_eq!(value.as_array().unwrap().len(), 3);
+        let ivars = value.as_rbref().unwrap().ivars().unwrap();
+        assert_eq!(ivars.get(&RbSymbol::from("@custom")), Some(&RbAny::Int(1)));
+        assert_write(&value, inp.as_bytes());
+
+        // h = {a: 1}; h.instance_variable_set(:@x, 2)
+        let inp = "\x04\x08I{\x06:\x06ai\x06\x06:\x07@xi\x07";
+        let value = reader_parse(inp);
+        assert_eq!(value.as_hash().unwrap().len(), 1);
+        assert_write(&value, inp.as_bytes());
+
+        // The instance is only counted once in the object table
+        let inp = "\x04\x08[\x07I[\x06i\x06\x06:\x07@ci\x06@\x06";
+        let value = reader_parse(inp);
+        assert!(RcType::ptr_eq(value.get(0).unwrap().as_rc().unwrap(), value.get(1).unwrap().as_rc().unwrap()));
+        assert_write(&value, inp.as_bytes());
+
+        // Instances whose objects can't be compared aren't equal
+        let with_ivars = |v: f64| {
+            let mut ivars = RbFields::new();
+            ivars.insert(RbSymbol::from("@x"), RbAny::Int(1));
+            RbRef::WithIvars { object: Box::new(RbRef::from(v)), ivars }.into_any()
+        };
+        assert!(!with_ivars(f64::NAN).deep_eq(&with_ivars(1.0)));
+        assert!(!with_ivars(f64::NAN).deep_eq(&with_ivars(f64::NAN)));
+        assert!(with_ivars(f64::NAN).structural_eq(&with_ivars(f64::NAN)));
+        assert!(with_ivars(1.0).deep_eq(&with_ivars(1.0)));
+    }
+
+    #[test]
+    fn any_truthy() {
+        assert!(!RbAny::Nil.truthy());
+        assert!(!RbAny::False.truthy());
+        assert!(RbAny::True.truthy());
+        assert!(RbAny::Int(0).truthy());
+        assert!(RbAny::from("").truthy());
+        assert_eq!(RbAny::Int(0).as_bool(), None);
+    }
+
+    #[test]
+    fn hash_complex_keys() {
+        // { [1, 2] => "x" }
+        let inp = "\x04\x08{\x06[\x07i\x06i\x07I\"\x06x\x06:\x06ET";
+        let value = reader_parse(inp);
+        assert_eq!(value.get(RbAny::from(vec![RbAny::Int(1), RbAny::Int(2)])), Some(&RbAny::from("x")));
+        assert_write(&value, inp.as_bytes());
+
+        // k = [1, 2]; [{ k => 1 }, k]
+        let inp = "\x04\x08[\x07{\x06[\x07i\x06i\x07i\x06@\x07";
+        let value = reader_parse(inp);
+        let key = value.get(0).unwrap().as_hash().unwrap().keys().next().unwrap();
+        assert!(RcType::ptr_eq(key.as_rc().unwrap(), value.get(1).unwrap().as_rc().unwrap()));
+        assert_write(&value, inp.as_bytes());
+
+        // o = Foo.new; { o => o }
+        let inp = "\x04\x08{\x06o:\x08Foo\x00@\x06";
+        let value = reader_parse(inp);
+        let (key, val) = value.as_hash().unwrap().iter().next().unwrap();
+        assert!(RcType::ptr_eq(key.as_rc().unwrap(), val.as_rc().unwrap()));
+        assert_write(&value, inp.as_bytes());
+    }
+
+    #[test]
+    fn bogus_length() {
+        // An array claiming i32::MAX elements must fail on missing data, not allocation
+        assert!(from_bytes(b"\x04\x08[\x04\xff\xff\xff\x7f").is_err());
+        assert!(from_bytes(b"\x04\x08{\x04\xff\xff\xff\x7f").is_err());
+    }
+
+    #[test]
+    fn rename_class() {
+        let obj = RbObject::new_from_slice("Old", &[("@a", RbAny::Int(1))]).into_object().into_any();
+        let mut value = RbAny::from(vec![
+            obj.clone(),
+            obj.clone(),
+            RbRef::ClassRef("Old".to_owned()).into_any(),
+            RbRef::Extended { module: RbSymbol::from("Old"), object: RbAny::from("x") }.into_any(),
+            RbRef::new_object("Other", &[]).into_any(),
+        ]);
+        assert_eq!(value.rename_class("Old", "New"), 3);
+        // The shared object is renamed once and stays shared, the original is unaffected
+        let ar = value.as_array().unwrap();
+        assert!(RcType::ptr_eq(ar[0].as_rc().unwrap(), ar[1].as_rc().unwrap()));
+        assert_eq!(ar[0].as_object().unwrap().name, "New");
+        assert_eq!(obj.as_object().unwrap().name, "Old");
+        assert_eq!(ar[2].as_rbref(), Some(&RbRef::ClassRef("New".to_owned())));
+        assert_eq!(ar[4].as_object().unwrap().name, "Other");
+        assert_eq!(value.rename_class("Old", "New"), 0);
+    }
+
+    #[test]
+    fn class_accessors() {
+        // Marshal.dump of an object whose class defines marshal_dump returning 1
+        let value = reader_parse("\x04\x08U:\x08Fooi\x06");
+        let r = value.as_rbref().unwrap();
+        assert!(r.as_data().is_none() && r.as_user_class().is_none());
+        let class = r.as_user_marshal().unwrap();
+        assert_eq!(class.name, "Foo");
+        assert_eq!(class.wrapped_data(), &RbAny::Int(1));
+    }
+
+    #[test]
+    fn version_error() {
+        let err = from_bytes(b"\x05\x08i\x06").unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::Version { major: 5, minor: 8 }));
+        assert_eq!(err.to_string(), "Unsupported Marshal version 5.8, only version 4 is supported");
+    }
+
+    #[test]
+    fn not_marshal_error() {
+        let err = from_reader(&b"\x1f\x8b\x08\x00"[..]).unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::NotMarshal { hint: "input looks gzip-compressed" }));
+        assert_eq!(err.to_string(), "Input isn't Marshal data: input looks gzip-compressed");
+        assert!(matches!(from_bytes(b"\x78\x9c\x01"), Err(crate::ThurgoodError::NotMarshal { .. })));
+        assert!(matches!(from_bytes(b"BAhpBg=="), Err(crate::ThurgoodError::NotMarshal { .. })));
+        // Other bytes are still reported as a bad version
+        assert!(matches!(from_bytes(b"\x78\x00"), Err(crate::ThurgoodError::Version { major: 0x78, minor: 0 })));
+    }
+
+    #[test]
+    fn newer_minor_version() {
+        let mut rd = RbReader::from_slice(b"\x04\x09[\x06i\x06");
+        assert_eq!(rd.version(), None);
+        assert!(rd.read().unwrap().deep_eq(&RbAny::from(vec![RbAny::Int(1)])));
+        assert_eq!(rd.version(), Some((4, 9)));
+        // Types which don't exist in 4.8 are still an error
+        let err = from_bytes(b"\x04\x09Z").unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::BadTypeByte { byte: b'Z', .. }));
+        // Writing always uses 4.8
+        assert_write(&RbAny::Int(1), b"\x04\x08i\x06");
+    }
+
+    #[test]
+    fn flatten() {
+        let user = RbObject::new_from_slice("User", &[
+            ("@name", RbAny::from("Alice")),
+            ("@age", RbAny::Int(30)),
+        ]).into_object().into_any();
+        let mut hash = RbHash::new();
+        hash.insert(RbAny::symbol_from("users"), RbAny::from(vec![user.clone(), user]));
+        hash.insert(RbAny::Int(1), RbAny::Nil);
+        let value = RbAny::from(hash);
+        let flat: Vec<_> = value.flatten().map(|(path, v)| (path, v.clone())).collect();
+        assert_eq!(flat, vec![
+            ("users[0].@name".to_owned(), RbAny::from("Alice")),
+            ("users[0].@age".to_owned(), RbAny::Int(30)),
+            ("[Int(1)]".to_owned(), RbAny::Nil),
+        ]);
+
+        let recursive = from_bytes(b"\x04\x08[\x07i\x06@\x00").unwrap();
+        assert_eq!(recursive.flatten().count(), 1);
+    }
+
+    #[test]
+    fn regex_flags() {
+        use crate::consts::{RE_FIXED_ENCODING, RE_IGNORECASE, RE_OPTIONS_MASK};
+        // /foo/u
+        let inp = "\x04\x08I/\x08foo\x10\x06:\x06ET";
+        let value = reader_parse(inp);
+        assert_eq!(value.as_rbref(), Some(&RbRef::new_regex("foo".to_owned(), RE_FIXED_ENCODING)));
+        assert_write(&value, inp.as_bytes());
+        // /foo/iu
+        let inp = "\x04\x08I/\x08foo\x11\x06:\x06ET";
+        let value = reader_parse(inp);
+        if let Some(RbRef::Regex { flags, .. }) = value.as_rbref() {
+            assert_eq!(flags & RE_OPTIONS_MASK, RE_IGNORECASE);
+        } else {
+            panic!("expected a regex");
+        }
+        assert_write(&value, inp.as_bytes());
+        // Flags are written as one byte, so larger values can't be written
+        let wide = RbRef::new_regex("foo".to_owned(), 0x101).into_any();
+        assert!(matches!(to_vec(&wide), Err(crate::ThurgoodError::BadRegexFlags(0x101))));
+        let wide = RbRef::RegexI { content: b"foo".to_vec(), flags: 0x100, metadata: RbFields::new() }.into_any();
+        assert!(matches!(to_vec(&wide), Err(crate::ThurgoodError::BadRegexFlags(0x100))));
+    }
+
+    #[test]
+    fn regex_info() {
+        // /foo/iu, /foo/xmn, and a bit Ruby doesn't define
+        for flags in [0x11u32, 0x26, 0x07, 0x100 | 0x01, u32::MAX].iter() {
+            assert_eq!(RbRegexInfo::from_flags(*flags).to_flags(), *flags);
+        }
+        let info = RbRegexInfo::from_flags(0x11);
+        assert!(info.ignore_case && info.fixed_encoding && !info.multiline && !info.no_encoding);
+        let value = reader_parse("\x04\x08I/\x08foo\x11\x06:\x06ET");
+        assert_eq!(value.as_rbref().unwrap().regex_info(), Some(info));
+        let built = RbRef::new_regex_with("foo".to_owned(), RbRegexInfo { multiline: true, ..info }).into_any();
+        assert_write(&built, b"\x04\x08I/\x08foo\x15\x06:\x06ET");
+        assert_eq!(RbRef::Str("a".into()).regex_info(), None);
+    }
+
+    #[test]
+    fn sort_fields() {
+        let mut a = RbObject::new_from_slice("Foo", &[("@b", RbAny::Int(2)), ("@a", RbAny::Int(1))]);
+        let b = RbObject::new_from_slice("Foo", &[("@a", RbAny::Int(1)), ("@b", RbAny::Int(2))]);
+        assert!(!a.clone().into_object().into_any().deep_eq(&b.clone().into_object().into_any()));
+        a.sort_fields();
+        assert!(a.clone().into_object().into_any().deep_eq(&b.into_object().into_any()));
+        assert_eq!(a.fields.keys().next(), Some(&RbSymbol::from("@a")));
+    }
+
+    #[test]
+    fn equal_symbols_dedup() {
+        // Independently allocated symbols, one of them referring to a shared buffer
+        let buf: RcType<[u8]> = RcType::from(&b"xxname"[..]);
+        let value = RbAny::from(vec![
+            RbAny::Symbol(RbSymbol::from("name")),
+            RbAny::Symbol(RbSymbol::new(b"name".to_vec())),
+            RbAny::Symbol(RbSymbol::from_shared(buf, 2..6)),
+        ]);
+        assert_write(&value, b"\x04\x08[\x08:\x09name;\x00;\x00");
+    }
+
+    #[test]
+    fn stream_writer() {
+        let mut wr = RbStreamWriter::new(Vec::new());
+        wr.begin_hash(2).unwrap();
+        wr.write_symbol("a").unwrap();
+        wr.begin_object("Foo", 1).unwrap();
+        wr.write_symbol("@x").unwrap();
+        wr.write_float(1.5).unwrap();
+        wr.end_object().unwrap();
+        wr.write_symbol("a").unwrap();
+        wr.write_nil().unwrap();
+        wr.end_hash().unwrap();
+        let out = wr.finish().unwrap();
+        assert_eq!(escape_bytes(&out), escape_bytes(b"\x04\x08{\x07:\x06ao:\x08Foo\x06:\x07@xf\x081.5;\x000"));
+
+        // Values written whole can be referenced again, after the streamed objects
+        let s = RbAny::from("s");
+        let mut wr = RbStreamWriter::new(Vec::new());
+        wr.begin_array(3).unwrap();
+        wr.write_str("x").unwrap();
+        wr.write_value(&s).unwrap();
+        wr.write_value(&s).unwrap();
+        wr.end_array().unwrap();
+        let value = from_bytes(&wr.finish().unwrap()).unwrap();
+        assert!(RcType::ptr_eq(value.get(1).unwrap().as_rc().unwrap(), value.get(2).unwrap().as_rc().unwrap()));
+
+        // Mismatched counts are errors
+        let mut wr = RbStreamWriter::new(Vec::new());
+        wr.begin_array(1).unwrap();
+        assert!(wr.end_array().is_err());
+        wr.write_int(1).unwrap();
+        assert!(wr.write_int(2).is_err());
+        assert!(wr.end_hash().is_err());
+        wr.end_array().unwrap();
+        assert!(wr.write_int(3).is_err());
+        assert!(RbStreamWriter::new(Vec::new()).finish().is_err());
+
+        // Field names must be symbols, and lengths which overflow are errors rather than panics
+        let mut wr = RbStreamWriter::new(Vec::new());
+        wr.begin_object("Foo", 1).unwrap();
+        assert!(matches!(wr.write_str("@x"), Err(crate::ThurgoodError::BadStream(_))));
+        wr.write_symbol("@x").unwrap();
+        wr.write_str("x").unwrap();
+        wr.end_object().unwrap();
+        assert!(matches!(RbStreamWriter::new(Vec::new()).begin_hash(usize::MAX), Err(crate::ThurgoodError::TooLarge(_))));
+        assert!(matches!(RbStreamWriter::new(Vec::new()).begin_object("Foo", usize::MAX), Err(crate::ThurgoodError::TooLarge(_))));
+    }
+
+    #[test]
+    fn any_is_a() {
+        let value = reader_parse("\x04\x08[\x0Ao:\x08Foo\x00{\x00I\"\x06x\x06:\x06ETi\x06U:\x08Bari\x06");
+        let ar = value.as_array().unwrap();
+        assert!(value.is_a("Array"));
+        assert!(ar[0].is_a("Foo") && !ar[0].is_a("Object"));
+        assert!(ar[1].is_a("Hash"));
+        assert!(ar[2].is_a("String"));
+        assert_eq!(ar[3].class_name(), Some("Integer"));
+        assert!(ar[4].is_a("Bar"));
+        assert!(RbAny::Nil.is_a("NilClass"));
+    }
+
+    #[test]
+    fn hash_variants() {
+        // Hash with compare_by_identity, which Ruby writes as a user class of Hash
+        let inp = "\x04\x08C:\x09Hash{\x06i\x06i\x07";
+        let value = reader_parse(inp);
+        assert_eq!(value.as_rbref().unwrap().as_user_class().unwrap().name, "Hash");
+        assert_write(&value, inp.as_bytes());
+        // Hash flagged with ruby2_keywords, which Ruby writes as the instance variable K
+        let inp = "\x04\x08I{\x06:\x06ai\x06\x06:\x06KT";
+        let value = reader_parse(inp);
+        assert_write(&value, inp.as_bytes());
+        // An unknown marker after a hash is reported where it appears
+        let err = from_bytes(b"\x04\x08[\x07{\x06i\x06i\x07Xi\x06").unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::BadTypeByte { byte: b'X', position: Some(10) }));
+        assert_eq!(err.to_string(), "Unknown type byte 0x58 at offset 10");
     }
 
-    fn escape_str(src: &[u8]) -> String {
-        let mut out = String::new();
-        for b in src {
-            let c = char::from(*b);
-            if c.is_ascii_alphanumeric() || c.is_ascii_punctuation() {
-                out.push(c);
+    #[test]
+    fn special_floats() {
+        let cases: [(RbFloat, &str); 5] = [
+            (RbFloat::inf(), "\x04\x08f\x08inf"),
+            (RbFloat::neg_inf(), "\x04\x08f\x09-inf"),
+            (RbFloat::nan(), "\x04\x08f\x08nan"),
+            (RbFloat(0.0), "\x04\x08f\x060"),
+            (RbFloat(-0.0), "\x04\x08f\x07-0"),
+        ];
+        for (float, inp) in cases.iter() {
+            assert_write(&RbAny::from(float.0), inp.as_bytes());
+            // NaN never compares equal, so `reader_parse` can't be used here
+            let value = from_bytes(inp.as_bytes()).unwrap();
+            let read = value.as_rbref().unwrap().as_float().unwrap().0;
+            if float.0.is_nan() {
+                assert!(read.is_nan());
             } else {
-                out.push_str(&format!("\\x{:02X}", *b));
+                assert_eq!(read.to_bits(), float.0.to_bits());
+            }
+            assert_write(&value, inp.as_bytes());
+        }
+    }
+
+    #[test]
+    fn writer_counts() {
+        let inp = "\x04\x08[\x07o:\x08Foo\x07:\n@nameI\"\tJack\x06:\x06ET:\t@agei\x1Eo;\x00\x07;\x06I\"\tJane\x06;\x07T;\x08i\x1D";
+        let value = reader_parse(inp);
+        let mut wr = RbWriter::new(Vec::new());
+        wr.write(&value).unwrap();
+        // :Foo, :@name, :E, :@age
+        assert_eq!(wr.symbol_count(), 4);
+        // The array, two objects, and two strings
+        assert_eq!(wr.object_count(), 5);
+    }
+
+    #[test]
+    fn class_constructors() {
+        assert_write(&RbRef::new_user_data("Foo", b"ab".to_vec()).into_any(), b"\x04\x08u:\x08Foo\x07ab");
+        assert_write(&RbRef::new_user_marshal("Foo", RbAny::Int(1)).into_any(), b"\x04\x08U:\x08Fooi\x06");
+        assert_write(&RbRef::new_data("Foo", RbAny::Nil).into_any(), b"\x04\x08d:\x08Foo0");
+        assert_write(&RbRef::new_user_class("Foo", RbAny::from(vec![])).into_any(), b"\x04\x08C:\x08Foo[\x00");
+    }
+
+    #[test]
+    fn float_text_round_trip() {
+        // Floats as written by Ruby
+        let cases: [(f64, &str); 14] = [
+            (1.5, "1.5"), (100.0, "1e2"), (1.0, "1"), (-2.5, "-2.5"), (0.001, "0.001"),
+            (0.0001, "0.0001"), (1e-5, "1e-5"), (123456.0, "123456"), (1e300, "1e300"),
+            (1.7976931348623157e308, "1.7976931348623157e308"), ("3.14159".parse().unwrap(), "3.14159"),
+            (1e20, "1e20"), (-0.0, "-0"), (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (v, text) in cases.iter() {
+            let mut expected = vec![4, 8, b'f', text.len() as u8 + 5];
+            expected.extend_from_slice(text.as_bytes());
+            assert_write(&RbAny::from(*v), &expected);
+            assert_eq!(from_bytes(&expected).unwrap().as_rbref().unwrap().as_float().unwrap().0, *v);
+        }
+
+        // Parsing what we write gives back the same bits
+        let mut state = 0x2545F4914F6CDD1Du64;
+        for _ in 0..10000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let v = f64::from_bits(state);
+            if !v.is_finite() {
+                continue;
             }
+            let bytes = writer_write(&RbAny::from(v));
+            let read = from_bytes(&bytes).unwrap().as_rbref().unwrap().as_float().unwrap().0;
+            assert_eq!(read.to_bits(), v.to_bits(), "{:?}", String::from_utf8_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn replace_child() {
+        let mut value = RbAny::from(vec![RbAny::Int(1), RbAny::Int(2)]);
+        let alias = value.clone();
+        assert_eq!(value.replace_child(0, RbAny::Int(5)), Some(RbAny::Int(1)));
+        assert_eq!(value.get(0), Some(&RbAny::Int(5)));
+        // The shared array was copied, so the alias is unchanged
+        assert_eq!(alias.get(0), Some(&RbAny::Int(1)));
+        assert_eq!(value.replace_child(5, RbAny::Nil), None);
+
+        let mut child = value.get(1).unwrap().clone();
+        assert_eq!(child.take(), RbAny::Int(2));
+        assert!(child.is_nil());
+        assert_eq!(child.replace(RbAny::True), RbAny::Nil);
+    }
+
+    #[test]
+    #[cfg(feature = "arc")]
+    fn arc_matches_rc() {
+        let inputs: [&[u8]; 6] = [
+            b"\x04\x08[\no:\x08Foo\x07:\n@nameI\"\tJack\x06:\x06ET:\t@agei\x1E@\x06{\x06:\x08key@\x06o;\x00\x07;\x06I\"\tJane\x06;\x07T;\x08i\x1D@\t",
+            b"\x04\x08[\x07[\x06I\"\tTest\x06:\x06ET@\x06",
+            b"\x04\x08[\x07}\x06i\x06I\"\x06x\x06:\x06ET@\x07@\x07",
+            b"\x04\x08[\x06@\x00",
+            b"\x04\x08{\x06i\x06@\x00",
+            b"\x04\x08o:\x08Foo\x06:\x07@x@\x00",
+        ];
+        for inp in inputs.iter() {
+            let rc_value = from_bytes(inp).unwrap();
+            let arc_value = crate::arc::from_bytes(inp).unwrap();
+            assert_eq!(format!("{:?}", rc_value), format!("{:?}", arc_value));
+            assert_eq!(escape_bytes(&crate::arc::to_vec(&arc_value).unwrap()), escape_bytes(inp));
+            assert_eq!(escape_bytes(&writer_write(&rc_value)), escape_bytes(inp));
+        }
+        let value = crate::arc::from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        assert!(std::sync::Arc::ptr_eq(value.as_rc().unwrap(), value.get(0).unwrap().as_rc().unwrap()));
+    }
+
+    #[test]
+    #[cfg(feature = "arc")]
+    fn rb_any_like() {
+        use crate::{RbAnyLike, RefCounted};
+        // Count the references in an array which point at its first element, for either module
+        fn count_shared<A: RbAnyLike>(items: &[A]) -> usize {
+            let first = match items.first().and_then(|v| v.as_ptr()) {
+                Some(p) => RefCounted::as_ptr(p),
+                None => return 0,
+            };
+            items.iter().filter(|v| v.as_ptr().map(RefCounted::as_ptr) == Some(first)).count()
+        }
+        let inp = b"\x04\x08[\x08[\x00@\x06[\x00";
+        let rc_value = from_bytes(inp).unwrap();
+        let arc_value = crate::arc::from_bytes(inp).unwrap();
+        assert_eq!(count_shared(rc_value.as_array().unwrap()), 2);
+        assert_eq!(count_shared(arc_value.as_array().unwrap()), 2);
+
+        fn rebuild<A: RbAnyLike>(value: &A) -> A {
+            let mut ptr = value.as_ptr().unwrap().clone();
+            RefCounted::make_mut(&mut ptr);
+            A::from_ptr(ptr)
+        }
+        let copy = rebuild(&arc_value);
+        assert!(copy.deep_eq(&arc_value));
+        assert_eq!(RbAnyLike::get_type(&copy), crate::RbType::Array);
+
+        // Read the names of users with either module
+        fn names<A: RbAnyLike>(value: &A) -> Vec<String> {
+            let users = value.entries().unwrap()
+                .find(|(k, _)| k.symbol_str() == Some("users"))
+                .map(|(_, v)| v).unwrap();
+            users.as_array().unwrap().iter()
+                .filter(|u| u.class_name() == Some("User"))
+                .map(|u| {
+                    let (_, name) = u.entries().unwrap().find(|(k, _)| k.symbol_str() == Some("@name")).unwrap();
+                    String::from_utf8(name.as_bytes().unwrap().to_vec()).unwrap()
+                })
+                .collect()
+        }
+        // {users: [#<User @name="Alice" @admin=true>, nil, 3]}
+        let inp = b"\x04\x08{\x06:\x0ausers[\x08o:\x09User\x07:\x0a@nameI\"\x0aAlice\x06:\x06ET:\x0b@adminT0i\x08";
+        let rc_value = from_bytes(inp).unwrap();
+        let arc_value = crate::arc::from_bytes(inp).unwrap();
+        assert_eq!(names(&rc_value), vec!["Alice".to_owned()]);
+        assert_eq!(names(&arc_value), vec!["Alice".to_owned()]);
+        let users = RbAnyLike::get_child(&arc_value, &crate::arc::RbAny::sym("users")).unwrap();
+        let items = RbAnyLike::as_array(users).unwrap();
+        assert!(RbAnyLike::is_nil(&items[1]));
+        assert_eq!(RbAnyLike::as_int(&items[2]), Some(3));
+        let admin = RbAnyLike::get_child(&items[0], &crate::arc::RbAny::from("@admin")).unwrap();
+        assert_eq!(RbAnyLike::as_bool(admin), Some(true));
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn hash_to_json_object() {
+        let mut hash = RbHash::new();
+        hash.insert(RbAny::symbol_from("name"), RbAny::from("x"));
+        hash.insert(RbAny::from("size"), RbAny::Int(2));
+        assert_eq!(hash.to_json_object(), Some(serde_json::json!({"name": "x", "size": 2})));
+        hash.insert(RbAny::Int(1), RbAny::Nil);
+        assert_eq!(hash.to_json_object(), None);
+    }
+
+    #[test]
+    fn iter_array_hash() {
+        let value = reader_parse("\x04\x08[\x07i\x06{\x06i\x07i\x08");
+        assert_eq!(value.iter_array().count(), 2);
+        assert_eq!(value.iter_hash().count(), 0);
+        let hash = value.get(1).unwrap();
+        assert_eq!(hash.iter_hash().collect::<Vec<_>>(), vec![(&RbAny::Int(2), &RbAny::Int(3))]);
+        assert_eq!(RbAny::Nil.iter_array().count(), 0);
+    }
+
+    #[test]
+    fn write_without_object_refs() {
+        let options = RbWriterOptions { emit_object_refs: false, ..Default::default() };
+        let write = |value: &RbAny| {
+            let mut buf = Vec::new();
+            RbWriter::with_options(&mut buf, options.clone()).write(value).map(|_| buf)
+        };
+        let value = reader_parse("\x04\x08[\x07[\x06I\"\tTest\x06:\x06ET@\x06");
+        let out = write(&value).unwrap();
+        assert_eq!(escape_bytes(&out), escape_bytes(b"\x04\x08[\x07[\x06I\"\tTest\x06:\x06ET[\x06I\"\tTest\x06;\x00T"));
+        assert!(from_bytes(&out).unwrap().deep_eq(&value));
+
+        let recursive = from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        assert!(matches!(write(&recursive), Err(crate::ThurgoodError::RecursiveValue)));
+
+        // `[e:M "a", 1]`: the extended string takes up an index, the `Extended` around it doesn't
+        let value = RbAny::from(vec![RbAny::from("a").extend_with("M"), RbAny::Int(1)]);
+        for emit_object_refs in [true, false].iter().copied() {
+            use std::{cell::RefCell, rc::Rc};
+            let seen = Rc::new(RefCell::new(Vec::new()));
+            let log = seen.clone();
+            let options = RbWriterOptions { emit_object_refs, ..Default::default() };
+            let mut wr = RbWriter::with_options(Vec::new(), options)
+                .with_on_object(move |obj, index| log.borrow_mut().push((obj.get_type(), index)));
+            wr.write(&value).unwrap();
+            assert_eq!(wr.object_count(), 2);
+            assert_eq!(*seen.borrow(), vec![(crate::RbType::Array, 0), (crate::RbType::Str, 1)]);
+        }
+    }
+
+    #[test]
+    fn empty_symbol_string() {
+        let inp = "\x04\x08[\x08:\x00;\x00I\"\x00\x06:\x06ET";
+        let value = reader_parse(inp);
+        assert_eq!(value.get(0), Some(&RbAny::Symbol(RbSymbol::default())));
+        assert_eq!(value.get(1), Some(&RbAny::Symbol(RbSymbol::default())));
+        assert_eq!(value.get(2).unwrap().as_string().map(|s| s.as_str()), Some(""));
+        assert_write(&value, inp.as_bytes());
+        let built = RbAny::from(vec![
+            RbAny::Symbol(RbSymbol::default()),
+            RbAny::Symbol(RbSymbol::from("")),
+            RbAny::from(""),
+        ]);
+        assert_write(&built, inp.as_bytes());
+        let shared = from_bytes_shared(&RcType::from(inp.as_bytes())).unwrap();
+        assert!(shared.deep_eq(&value));
+    }
+
+    #[test]
+    fn pointer_id() {
+        let value = reader_parse("\x04\x08[\x08[\x00@\x06[\x00");
+        let ids: Vec<_> = value.iter_array().map(|v| v.pointer_id().unwrap()).collect();
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[0], ids[2]);
+        assert_eq!(RbAny::Int(1).pointer_id(), None);
+    }
+
+    #[test]
+    fn negative_indices() {
+        use crate::ThurgoodError as E;
+        assert!(matches!(from_bytes(b"\x04\x08;\xfa"), Err(E::BadSymbolRef(-1))));
+        assert!(matches!(from_bytes(b"\x04\x08@\xfa"), Err(E::BadObjectRef(-1))));
+        assert!(matches!(from_bytes(b"\x04\x08\"\xfa"), Err(E::BadLength(-1))));
+        assert!(matches!(from_reader(&b"\x04\x08:\xfa"[..]), Err(E::BadLength(-1))));
+        assert!(matches!(from_bytes(b"\x04\x08[\xfa"), Err(E::BadLength(-1))));
+    }
+
+    #[test]
+    fn inspect_pretty() {
+        use crate::rc::dump::{inspect_pretty, InspectOptions};
+        let inspect = |v: &RbAny, max_depth: usize| {
+            let mut out = Vec::new();
+            inspect_pretty(&mut out, v, &InspectOptions { max_depth, ..Default::default() }).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let value = RbAny::from(vec![
+            RbAny::Int(1),
+            RbAny::from("a\"#{b}"),
+            RbAny::Symbol(RbSymbol::from("a b")),
+            RbAny::from(vec![RbAny::Nil]),
+            RbRef::new_object("Foo", &[(RbSymbol::from("@x"), RbAny::True)]).into_any(),
+        ]);
+        assert_eq!(inspect(&value, 8), concat!(
+            "[\n",
+            "  1,\n",
+            "  \"a\\\"\\#{b}\",\n",
+            "  :\"a b\",\n",
+            "  [\n    nil\n  ],\n",
+            "  #<Foo\n    @x=true\n  >\n",
+            "]",
+        ));
+        assert_eq!(inspect(&value, 1), "[\n  1,\n  \"a\\\"\\#{b}\",\n  :\"a b\",\n  [...],\n  #<Foo ...>\n]");
+        assert_eq!(inspect(&value, 0), "[...]");
+        // Recursive data is cut at the first repeat regardless of depth
+        let recursive = reader_parse("\x04\x08[\x06@\x00");
+        assert_eq!(inspect(&recursive, 100), "[\n  [...]\n]");
+    }
+
+    #[test]
+    fn new_extended() {
+        // o = Object.new; o.extend(A); o.extend(B)
+        let inp = "\x04\x08e:\x06Be:\x06Ao:\x0BObject\x00";
+        let value = reader_parse(inp);
+        let built = RbRef::new_object("Object", &[]).into_any().extend_with("A").extend_with("B");
+        assert!(built.deep_eq(&value));
+        assert_write(&built, inp.as_bytes());
+        let single = RbRef::new_extended("A", RbAny::from(vec![])).into_any();
+        assert_write(&single, b"\x04\x08e:\x06A[\x00");
+    }
+
+    #[test]
+    fn sym_and_string() {
+        assert_eq!(RbAny::sym("a"), RbAny::symbol_from("a"));
+        assert_eq!(RbAny::sym(String::from("a")), RbSymbol::from("a").as_any());
+        assert_eq!(RbAny::sym(&RbSymbol::from("a")).as_symbol().map(|s| s.as_str()), Some(Some("a")));
+        let from: RbAny = "a".into();
+        assert!(from.as_symbol().is_none());
+        assert!(RbAny::string("a").deep_eq(&from));
+        assert!(RbAny::string(String::from("a")).deep_eq(&from));
+        assert_write(&RbAny::from(vec![RbAny::sym("a"), RbAny::string("a")]), b"\x04\x08[\x07:\x06aI\"\x06a\x06:\x06ET");
+    }
+
+    #[test]
+    fn normalize_ints() {
+        // [2**30 as a bignum, 2**30 as an Int, 5 as a bignum]
+        let inp = b"\x04\x08[\x08l+\x07\x00\x00\x00@i\x04\x00\x00\x00@l+\x07\x05\x00\x00\x00";
+        let value = from_bytes(inp).unwrap();
+        let big = value.get(0).unwrap();
+        let small = value.get(1).unwrap();
+        assert!(!big.deep_eq(small));
+        assert!(RbCompare::numeric().eq(big, small));
+
+        let mut demoted = value.clone();
+        assert_eq!(demoted.normalize_ints(NormalizeInts::Demote), 2);
+        assert_eq!(demoted.get(0).unwrap(), &RbAny::Int(1 << 30));
+        assert_eq!(demoted.get(2).unwrap(), &RbAny::Int(5));
+        // The original is unchanged
+        assert!(value.get(2).unwrap().as_rbref().is_some());
+
+        let mut promoted = value.clone();
+        assert_eq!(promoted.normalize_ints(NormalizeInts::Promote), 1);
+        assert!(promoted.get(0).unwrap().deep_eq(promoted.get(1).unwrap()));
+        let mut keys = RbAny::from(RbHash::from_pairs(vec![(big.clone(), RbAny::Nil)]));
+        assert_eq!(keys.normalize_ints(NormalizeInts::Demote), 1);
+        assert_eq!(keys.as_hash().unwrap().keys().next(), Some(&RbAny::Int(1 << 30)));
+        assert_write(&promoted, b"\x04\x08[\x08l+\x07\x00\x00\x00@l+\x07\x00\x00\x00@l+\x06\x05\x00");
+    }
+
+    #[test]
+    fn object_iter_str() {
+        let mut obj = RbObject::new_from_slice("Foo", &[("@a", RbAny::Int(1))]);
+        obj.insert(RbSymbol::new(b"@\xFF".to_vec()), RbAny::Int(2));
+        obj.insert("@b", RbAny::Int(3));
+        let names: Vec<_> = obj.iter_str().map(|(k, v)| (k, v.as_int().unwrap())).collect();
+        assert_eq!(names, vec![("@a", 1), ("@b", 3)]);
+        let names: Vec<_> = obj.iter_str_lossy().map(|(k, _)| k.into_owned()).collect();
+        assert_eq!(names, vec!["@a", "@\u{FFFD}", "@b"]);
+    }
+
+    #[test]
+    fn is_tree_acyclic() {
+        let tree = reader_parse("\x04\x08[\x07[\x06i\x06[\x00");
+        assert!(tree.is_tree() && tree.is_acyclic());
+        // [a, a]
+        let shared = reader_parse("\x04\x08[\x07[\x00@\x06");
+        assert!(!shared.is_tree() && shared.is_acyclic());
+        // a = []; a << a
+        let recursive = reader_parse("\x04\x08[\x06@\x00");
+        assert!(!recursive.is_tree() && !recursive.is_acyclic());
+        // The cycle may pass through an object
+        let recursive = reader_parse("\x04\x08o:\x08Foo\x06:\x07@a[\x06@\x00");
+        assert!(!recursive.is_acyclic());
+        assert!(RbAny::Int(1).is_tree());
+        assert!(tree.explode().is_tree() && shared.explode().is_tree());
+    }
+
+    #[test]
+    fn reader_with_symbols() {
+        let seed = vec![RbSymbol::from("a"), RbSymbol::from("b")];
+        let inp = b"\x04\x08[\x09;\x06;\x00:\x06c;\x07";
+        let value = RbReader::from_slice(inp).with_symbols(seed.clone()).read().unwrap();
+        assert!(value.deep_eq(&RbAny::from(vec![RbAny::sym("b"), RbAny::sym("a"), RbAny::sym("c"), RbAny::sym("c")])));
+        let value = RbReader::new(&inp[..]).with_symbols(seed).read().unwrap();
+        assert_eq!(value.get(3), Some(&RbAny::sym("c")));
+        assert!(matches!(from_bytes(inp), Err(crate::ThurgoodError::BadSymbolRef(1))));
+    }
+
+    #[test]
+    fn compare_ignore_field_order() {
+        let a = RbRef::new_object("Foo", &[(RbSymbol::from("@a"), RbAny::Int(1)), (RbSymbol::from("@b"), RbAny::Int(2))]).into_any();
+        let b = RbRef::new_object("Foo", &[(RbSymbol::from("@b"), RbAny::Int(2)), (RbSymbol::from("@a"), RbAny::Int(1))]).into_any();
+        let c = RbRef::new_object("Foo", &[(RbSymbol::from("@b"), RbAny::Int(2)), (RbSymbol::from("@a"), RbAny::Int(3))]).into_any();
+        let unordered = RbCompareOptions { ignore_field_order: true, ..Default::default() };
+        assert!(!a.deep_eq(&b));
+        assert!(a.deep_eq_with(&b, unordered.clone()));
+        assert!(!a.deep_eq_with(&c, unordered.clone()));
+
+        let h1 = RbAny::from(RbHash::from_pairs(vec![(RbAny::sym("x"), a.clone()), (RbAny::Int(1), RbAny::from(1.0))]));
+        let h2 = RbAny::from(RbHash::from_pairs(vec![(RbAny::Int(1), RbAny::Int(1)), (RbAny::sym("x"), b.clone())]));
+        assert!(!h1.deep_eq(&h2));
+        assert!(!h1.deep_eq_with(&h2, unordered.clone()));
+        assert!(h1.deep_eq_with(&h2, RbCompareOptions { numeric: true, ignore_field_order: true, ..Default::default() }));
+
+        // Hashes of different sizes are never equal, even if one is a prefix of the other
+        let h3 = RbAny::from(RbHash::from_pairs(vec![(RbAny::sym("x"), a.clone())]));
+        assert!(!h1.deep_eq(&h3));
+        assert!(!h1.deep_eq_with(&h3, unordered));
+    }
+
+    #[test]
+    fn compare_float_tolerance() {
+        assert!(RbFloat(0.1 + 0.2).approx_eq(&RbFloat(0.3), 1e-12));
+        assert!(!RbFloat(0.1).approx_eq(&RbFloat(0.2), 1e-12));
+        assert!(RbFloat::inf().approx_eq(&RbFloat::inf(), 0.0));
+        assert!(!RbFloat::inf().approx_eq(&RbFloat::neg_inf(), 1e300));
+        assert!(!RbFloat::nan().approx_eq(&RbFloat::nan(), f64::INFINITY));
+        assert_ne!(RbFloat(0.1 + 0.2), RbFloat(0.3));
+
+        let a = RbAny::from(vec![RbAny::from(0.1 + 0.2), RbAny::Int(1)]);
+        let b = RbAny::from(vec![RbAny::from(0.3), RbAny::Int(1)]);
+        let loose = RbCompareOptions { float_tolerance: Some(1e-9), ..Default::default() };
+        assert!(!a.deep_eq(&b));
+        assert!(a.deep_eq_with(&b, loose.clone()));
+        assert!(a.deep_eq_with(&b, RbCompareOptions { numeric: true, ..loose.clone() }));
+        assert!(!RbAny::from(f64::NAN).deep_eq_with(&RbAny::from(0.0), loose.clone()));
+        assert!(!RbAny::from(1.0).deep_eq_with(&RbAny::from(1.1), loose));
+    }
+
+    #[test]
+    fn regex_accessors() {
+        use crate::consts::{RE_IGNORECASE, RE_MULTILINE};
+        let mut value = RbAny::from(vec![
+            reader_parse("\x04\x08I/\x06a\x01\x06:\x06ET"),
+            RbRef::RegexI { content: b"b".to_vec(), flags: 0, metadata: RbFields::new() }.into_any(),
+        ]);
+        let arr = value.as_array().unwrap();
+        let utf8 = arr[0].as_rbref().unwrap();
+        let bin = arr[1].as_rbref().unwrap();
+        assert_eq!(utf8.as_regex(), Some((&b"a"[..], RE_IGNORECASE)));
+        assert_eq!(utf8.regex_pattern_str(), Some("a"));
+        assert_eq!(bin.as_regex(), Some((&b"b"[..], 0)));
+        assert_eq!(bin.regex_pattern_str(), None);
+        assert_eq!(RbRef::Str("a".into()).as_regex(), None);
+        *value.get_mut(1).unwrap().as_rbref_mut().unwrap().regex_flags_mut().unwrap() |= RE_MULTILINE;
+        assert_eq!(value.get(1).unwrap().as_rbref().unwrap().as_regex(), Some((&b"b"[..], RE_MULTILINE)));
+    }
+
+    #[test]
+    fn read_counting() {
+        // Two documents which both define symbol 0, followed by some other data
+        let inp = b"\x04\x08[\x07:\x06a;\x00\x04\x08[\x06:\x06bXYZ";
+        let mut rd = RbReader::new(&inp[..]);
+        let (first, len) = rd.read_counting().unwrap();
+        assert_eq!(len, 9);
+        assert!(first.deep_eq(&RbAny::from(vec![RbAny::sym("a"), RbAny::sym("a")])));
+        let (second, len) = rd.read_counting().unwrap();
+        assert_eq!(len, 7);
+        assert!(second.deep_eq(&RbAny::from(vec![RbAny::sym("b")])));
+
+        let mut rd = RbReader::from_slice(inp);
+        assert_eq!(rd.read_counting().unwrap().1, 9);
+        assert_eq!(rd.read_counting().unwrap().1, 7);
+        assert!(rd.read().is_err());
+    }
+
+    #[test]
+    fn object_field_names() {
+        let obj = RbObject::new_from_slice("Foo", &[("@b", RbAny::Int(1)), ("@a", RbAny::Nil)]);
+        assert!(obj.contains_field("@a"));
+        assert!(obj.contains_field(RbSymbol::from("@b")));
+        assert!(!obj.contains_field("a"));
+        let names: Vec<_> = obj.field_names().map(|k| k.as_str().unwrap()).collect();
+        assert_eq!(names, vec!["@b", "@a"]);
+        assert!(obj.keys().eq(obj.field_names()));
+        assert_eq!(obj.values().collect::<Vec<_>>(), vec![&RbAny::Int(1), &RbAny::Nil]);
+        let mut obj = obj;
+        obj.values_mut().for_each(|v| *v = RbAny::True);
+        assert_eq!(obj.get("@a"), Some(&RbAny::True));
+    }
+
+    #[test]
+    fn repeated_class_symbol() {
+        // [o:Foo, [o:Foo, [o:Foo]]], each with a field @a
+        let inp = "\x04\x08[\x07o:\x08Foo\x06:\x07@ai\x06[\x07o;\x00\x06;\x06i\x07[\x06o;\x00\x06;\x06i\x08";
+        let value = reader_parse(inp);
+        assert_write(&value, inp.as_bytes());
+        let shared = from_bytes_shared(&RcType::from(inp.as_bytes())).unwrap();
+        assert_write(&shared, inp.as_bytes());
+
+        // Each name is a separate RbSymbol, some owned and some borrowed from a buffer
+        let buf: RcType<[u8]> = RcType::from(&b"Foo@a"[..]);
+        let names = [RbSymbol::from("Foo"), RbSymbol::from_shared(buf.clone(), 0..3), RbSymbol::new(b"Foo".to_vec())];
+        let field = |i: i32| -> RbAny {
+            let name = if i % 2 == 0 { RbSymbol::from("@a") } else { RbSymbol::from_shared(buf.clone(), 3..5) };
+            RbRef::new_object(names[i as usize].clone(), &[(name, RbAny::Int(i + 1))]).into_any()
+        };
+        let built = RbAny::from(vec![field(0), RbAny::from(vec![field(1), RbAny::from(vec![field(2)])])]);
+        assert_write(&built, inp.as_bytes());
+    }
+
+    #[test]
+    fn coerce_to() {
+        use std::convert::TryFrom;
+        use crate::RbType as T;
+        let s = |v: &RbAny| v.coerce_to(T::Str).and_then(|v| v.as_string().cloned());
+        assert_eq!(s(&RbAny::Int(-5)), Some("-5".to_owned()));
+        assert_eq!(s(&RbAny::sym("abc")), Some("abc".to_owned()));
+        assert_eq!(s(&RbAny::Nil), Some("".to_owned()));
+        assert_eq!(s(&RbAny::True), Some("true".to_owned()));
+        assert_eq!(s(&RbAny::from(100.0)), Some("100.0".to_owned()));
+        assert_eq!(s(&RbAny::from(1e20)), Some("1.0e+20".to_owned()));
+        assert_eq!(s(&RbAny::from(1.5)), Some("1.5".to_owned()));
+        assert_eq!(s(&RbAny::from("3.14159".parse::<f64>().unwrap())), Some("3.14159".to_owned()));
+        assert_eq!(s(&RbAny::from(1.5e-5)), Some("1.5e-05".to_owned()));
+        assert_eq!(s(&RbAny::from(f64::NEG_INFINITY)), Some("-Infinity".to_owned()));
+        assert_eq!(s(&RbAny::from(vec![])), None);
+
+        assert_eq!(RbAny::from(" 42 ").coerce_to(T::Int), Some(RbAny::Int(42)));
+        assert_eq!(RbAny::from("4x").coerce_to(T::Int), None);
+        assert_eq!(RbAny::from(-2.7).coerce_to(T::Int), Some(RbAny::Int(-2)));
+        assert_eq!(RbAny::from(f64::NAN).coerce_to(T::Int), None);
+        let big = RbAny::from("1073741824").coerce_to(T::Int).unwrap();
+        assert_eq!(big.get_type(), T::BigInt);
+        assert_eq!(s(&big), Some("1073741824".to_owned()));
+
+        assert_eq!(RbAny::from("2.5").coerce_to(T::Float).and_then(|v| f64::try_from(&v).ok()), Some(2.5));
+        assert_eq!(RbAny::Int(3).coerce_to(T::Float).and_then(|v| f64::try_from(&v).ok()), Some(3.0));
+        assert_eq!(RbAny::from("nan").coerce_to(T::Float), None);
+        assert_eq!(RbAny::from("x").coerce_to(T::Symbol), Some(RbAny::sym("x")));
+        assert_eq!(RbAny::from("false").coerce_to(T::Bool), Some(RbAny::False));
+        assert_eq!(RbAny::Int(1).coerce_to(T::Bool), None);
+        assert_eq!(RbAny::Int(1).coerce_to(T::Int), Some(RbAny::Int(1)));
+
+        let hash = RbAny::from(RbHash::from_pairs(vec![(RbAny::sym("a"), RbAny::Int(1))]));
+        let arr = hash.coerce_to(T::Array).unwrap();
+        assert!(arr.deep_eq(&RbAny::from(vec![RbAny::from(vec![RbAny::sym("a"), RbAny::Int(1)])])));
+    }
+
+    #[test]
+    fn headerless_values() {
+        let shared = RbAny::from(vec![RbAny::Int(1)]);
+        let values = [RbAny::sym("a"), shared.clone(), RbAny::from(vec![RbAny::sym("a"), shared])];
+        let mut wr = RbWriter::new(Vec::new());
+        for v in values.iter() {
+            wr.write_value(v).unwrap();
+        }
+        let out = wr.into_inner();
+        assert_eq!(escape_bytes(&out), escape_bytes(b":\x06a[\x06i\x06[\x07;\x00@\x00"));
+
+        let mut rd = RbReader::from_slice(&out);
+        let read: Vec<_> = (0..3).map(|_| rd.read_value().unwrap()).collect();
+        assert!(read[0].deep_eq(&values[0]));
+        assert!(read[2].deep_eq(&values[2]));
+        assert_eq!(read[1].pointer_id(), read[2].get(1).unwrap().pointer_id());
+    }
+
+    #[test]
+    fn duplicate_hash_keys() {
+        let strict = |inp: &[u8]| {
+            let mut rd = RbReader::from_slice(inp);
+            rd.strict_hash_keys = true;
+            rd.read()
+        };
+        let cases: [&[u8]; 3] = [
+            // {1 => 2, 1 => 3}
+            b"\x04\x08{\x07i\x06i\x07i\x06i\x08",
+            // {"a" => 1, "a" => 2} with separate string objects
+            b"\x04\x08{\x07I\"\x06a\x06:\x06ETi\x06I\"\x06a\x06;\x00Ti\x07",
+            // An object with two fields named @a
+            b"\x04\x08o:\x08Foo\x07:\x07@ai\x06;\x06i\x07",
+        ];
+        for inp in cases.iter() {
+            assert!(from_bytes(inp).is_ok());
+            assert!(matches!(strict(inp), Err(crate::ThurgoodError::DuplicateHashKey(_))), "{}", escape_bytes(inp));
+        }
+        // Equal-looking keys of different types aren't duplicates: {"a" => 1, :a => 2, 1 => 3}
+        let ok = b"\x04\x08{\x08I\"\x06a\x06:\x06ETi\x06:\x06ai\x07i\x06i\x08";
+        assert_eq!(strict(ok).unwrap().as_hash().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn rename_symbol() {
+        // [o:Foo{@a: :a}, {:a => 1}, :a, :b] where the object is also shared
+        let inp = "\x04\x08[\x0Ao:\x08Foo\x06:\x07@a:\x06a{\x06;\x07i\x06;\x07:\x06b@\x06";
+        let mut value = reader_parse(inp);
+        let original = value.clone();
+        assert_eq!(value.rename_symbol(&RbSymbol::from("a"), &RbSymbol::from("z")), 3);
+        assert_eq!(value.rename_symbol(&RbSymbol::from("@a"), &RbSymbol::from("@y")), 1);
+        assert_eq!(value.rename_symbol(&RbSymbol::from("Foo"), &RbSymbol::from("Bar")), 1);
+        assert_eq!(value.rename_symbol(&RbSymbol::from("missing"), &RbSymbol::from("x")), 0);
+        let expected = "\x04\x08[\x0Ao:\x08Bar\x06:\x07@y:\x06z{\x06;\x07i\x06;\x07:\x06b@\x06";
+        assert_write(&value, expected.as_bytes());
+        // The clone shares nothing that was changed
+        assert_write(&original, inp.as_bytes());
+    }
+
+    #[test]
+    fn symbol_encoding() {
+        // [:é, :é, "x"], the encoding of :é takes the second symbol index
+        let inp = "\x04\x08[\x08I:\x07\u{e9}\x06:\x06ET;\x00I\"\x06x\x06;\x06T";
+        let value = reader_parse(inp);
+        let sym = value.get(0).unwrap().as_symbol().unwrap();
+        assert_eq!(sym.as_str(), Some("\u{e9}"));
+        assert_eq!(sym.encoding(), &SymbolEncoding::Utf8);
+        assert_eq!(value.get(1).unwrap().as_symbol().unwrap().encoding(), &SymbolEncoding::Utf8);
+        assert_write(&value, inp.as_bytes());
+        let built = RbAny::from(vec![RbAny::sym("\u{e9}"), RbAny::sym("\u{e9}"), RbAny::from("x")]);
+        assert_write(&built, inp.as_bytes());
+
+        // [Shift_JIS symbol, s, s], where the encoding name takes object index 1
+        let inp = b"\x04\x08[\x08I:\x07\x82\xA0\x06:\x0Dencoding\"\x0EShift_JISI\"\x06a\x06:\x06ET@\x07";
+        let value = from_bytes(inp).unwrap();
+        let sym = value.get(0).unwrap().as_symbol().unwrap();
+        assert_eq!(sym.as_bytes(), b"\x82\xA0");
+        assert_eq!(sym.encoding(), &SymbolEncoding::Named("Shift_JIS".to_owned()));
+        assert_eq!(value.get(1).unwrap().pointer_id(), value.get(2).unwrap().pointer_id());
+        assert_write(&value, inp);
+
+        // Binary symbols aren't wrapped
+        let inp = b"\x04\x08:\x06\xFF";
+        let value = from_bytes(inp).unwrap();
+        assert_eq!(value.as_symbol().unwrap().encoding(), &SymbolEncoding::Binary);
+        assert_write(&value, inp);
+        let sym = RbSymbol::new(b"\xFF".to_vec()).with_encoding(SymbolEncoding::Binary);
+        assert_write(&sym.as_any(), inp);
+    }
+
+    #[test]
+    fn any_as_bytes() {
+        assert_eq!(RbAny::from("ab").as_bytes(), Some(&b"ab"[..]));
+        assert_eq!(RbAny::sym("ab").as_bytes(), Some(&b"ab"[..]));
+        let bin = reader_parse_loose(b"\x04\x08\"\x06\xFF");
+        assert_eq!(bin.as_bytes(), Some(&b"\xFF"[..]));
+        assert_eq!(RbAny::Int(1).as_bytes(), None);
+        assert_eq!(RbAny::from(vec![]).as_bytes(), None);
+    }
+
+    #[test]
+    fn multi_byte_negative_ints() {
+        for &v in &[-124, -129, -256, -257, -65536, -65537, -(1 << 30), i32::MIN, 123, 256, 65536, i32::MAX] {
+            let data = writer_write(&RbAny::Int(v));
+            assert_eq!(from_bytes(&data).unwrap().as_int(), Some(v), "{}", v);
+        }
+        // Like Ruby, the high bytes of negative values are left out
+        assert_write(&RbAny::Int(-129), b"\x04\x08i\xff\x7f");
+        assert_write(&RbAny::Int(-257), b"\x04\x08i\xfe\xff\xfe");
+        // ...but they may be included
+        assert_eq!(from_bytes(b"\x04\x08i\xfc\x7f\xff\xff\xff").unwrap().as_int(), Some(-129));
+        assert_eq!(from_bytes(b"\x04\x08i\xfc\x00\x00\x00\x80").unwrap().as_int(), Some(i32::MIN));
+    }
+
+    #[test]
+    fn try_parse_never_panics() {
+        // Nesting deeper than the limit is an error rather than a stack overflow
+        let mut deep = b"\x04\x08".to_vec();
+        deep.extend(b"[\x06".repeat(100_000));
+        deep.push(b'0');
+        assert!(matches!(try_parse(&deep), Err(crate::ThurgoodError::TooDeep(_))));
+        // The same goes for nested user classes, which are read without `read_entry`
+        for prefix in [&b"\x04\x08C:\x06A"[..], b"\x04\x08IC:\x06A"] {
+            let mut deep = prefix.to_vec();
+            deep.extend(b"C;\x00".repeat(1_000_000));
+            deep.extend(b"[\x00");
+            assert!(matches!(try_parse(&deep), Err(crate::ThurgoodError::TooDeep(_))));
+        }
+        let ok_depth = TRY_PARSE_MAX_DEPTH - 1;
+        assert!(try_parse(&deep[..2 + ok_depth * 2]).is_err());
+        let mut shallow = deep[..2 + ok_depth * 2].to_vec();
+        shallow.push(b'0');
+        assert!(try_parse(&shallow).is_ok());
+        // A huge length doesn't allocate before the data turns out to be missing
+        assert!(from_reader(&b"\x04\x08\"\x04\xff\xff\xff\x7f"[..]).is_err());
+
+        // Mutate some valid documents at random, any result but a panic is fine
+        let seeds = [
+            writer_write(&RbAny::from(vec![RbAny::from("test"), RbAny::Int(-300), RbAny::from(1.5f32)])),
+            b"\x04\x08[\x07I\"\ttest\x06:\x06ET{\x06:\x06aI\"\x06b\x06;\x00T".to_vec(),
+            b"\x04\x08o:\x08Foo\x07:\x07@a[\x06@\x00:\x07@bl+\x07\x00\x00\x00\x80".to_vec(),
+            b"\x04\x08}\x06I:\x07\xc3\xa9\x06:\x06ETI/\x06a\x00\x06;\x06Fe:\x06M[\x00".to_vec(),
+        ];
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut rand = move |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        for _ in 0..20_000 {
+            let mut data = seeds[rand(seeds.len())].clone();
+            for _ in 0..=rand(4) {
+                let i = rand(data.len());
+                match rand(4) {
+                    0 => data[i] = rand(256) as u8,
+                    1 => data.insert(i, rand(256) as u8),
+                    2 => { data.remove(i); },
+                    _ => data.truncate(i.max(2)),
+                }
+                if data.is_empty() {
+                    break;
+                }
+            }
+            let _ = try_parse(&data);
+        }
+    }
+
+    #[test]
+    fn retain_entries() {
+        let mut hash = RbHash::from_pairs(vec![
+            (RbAny::from("a"), RbAny::Int(1)),
+            (RbAny::from("b"), RbAny::Nil),
+            (RbAny::from("c"), RbAny::Int(3)),
+            (RbAny::from("d"), RbAny::Nil),
+        ]);
+        hash.set_default(RbAny::Int(0));
+        hash.retain(|_, v| !v.is_nil());
+        assert_eq!(hash.values().cloned().collect::<Vec<_>>(), vec![RbAny::Int(1), RbAny::Int(3)]);
+        assert_eq!(hash.keys().map(|k| k.as_string().unwrap().as_str()).collect::<Vec<_>>(), vec!["a", "c"]);
+        assert!(hash.has_default());
+
+        let mut obj = RbObject::new_from_slice("Foo", &[
+            ("@a", RbAny::Int(1)), ("@password", RbAny::from("hunter2")), ("@b", RbAny::Nil), ("@c", RbAny::Int(2)),
+        ]);
+        obj.retain(|k, v| k.as_str() != Some("@password") && !v.is_nil());
+        assert_eq!(obj.field_names().map(|k| k.as_str().unwrap()).collect::<Vec<_>>(), vec!["@a", "@c"]);
+    }
+
+    #[test]
+    fn ruby2_keywords_hash() {
+        // Marshal.dump(Hash.ruby2_keywords_hash({a: 1}))
+        let inp = b"\x04\x08I{\x06:\x06ai\x06\x06:\x06KT";
+        let value = from_bytes(inp).unwrap();
+        let hash = value.as_rbref().unwrap().as_hash().unwrap();
+        assert!(hash.ruby2_keywords);
+        assert_eq!(hash.len(), 1);
+        assert_write(&value, inp);
+        let mut plain = hash.clone();
+        plain.ruby2_keywords = false;
+        assert!(!value.deep_eq(&RbAny::from(plain.clone())));
+        assert_write(&RbAny::from(plain), b"\x04\x08{\x06:\x06ai\x06");
+
+        // Any other ivars come after the flag
+        let inp = b"\x04\x08[\x07I}\x00i\x00\x07:\x06KT:\x07@xi\x07@\x06";
+        let value = from_bytes(inp).unwrap();
+        assert_write(&value, inp);
+        let first = value.as_array().unwrap()[0].clone();
+        match first.as_rbref().unwrap() {
+            RbRef::WithIvars { object, ivars } => {
+                assert!(matches!(object.as_ref(), RbRef::Hash(h) if h.ruby2_keywords));
+                assert_eq!(ivars.keys().map(|k| k.as_str().unwrap()).collect::<Vec<_>>(), vec!["@x"]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "digest")]
+    fn content_digest() {
+        // Field and hash entry order doesn't matter, and neither does sharing
+        let a = from_bytes(b"\x04\x08[\x07o:\x08Foo\x07:\x07@ai\x06:\x07@bI\"\x06x\x06:\x06ET@\x07").unwrap();
+        let b = from_bytes(b"\x04\x08[\x07o:\x08Foo\x07:\x07@bI\"\x06x\x06:\x06ET:\x07@ai\x06I\"\x06x\x06;\x07T").unwrap();
+        assert_eq!(a.content_digest(), b.content_digest());
+        let h1 = from_bytes(b"\x04\x08{\x07i\x06i\x07i\x08i\x09").unwrap();
+        let h2 = from_bytes(b"\x04\x08{\x07i\x08i\x09i\x06i\x07").unwrap();
+        assert_eq!(h1.content_digest(), h2.content_digest());
+        // ...but array order and values do
+        let c = from_bytes(b"\x04\x08[\x07i\x06i\x07").unwrap();
+        let d = from_bytes(b"\x04\x08[\x07i\x07i\x06").unwrap();
+        assert_ne!(c.content_digest(), d.content_digest());
+        assert_ne!(h1.content_digest(), from_bytes(b"\x04\x08{\x07i\x06i\x07i\x08i\x0a").unwrap().content_digest());
+        assert_ne!(RbAny::from("1").content_digest(), RbAny::sym("1").content_digest());
+
+        // Recursive values are digested deterministically: a = []; a << a
+        let r1 = from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        let r2 = from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        assert_eq!(r1.content_digest(), r2.content_digest());
+        assert_ne!(r1.content_digest(), from_bytes(b"\x04\x08[\x06[\x00").unwrap().content_digest());
+        // [a, a] where a = [a] is the same as [b, c] where b = [b] and c = [c]
+        let shared = from_bytes(b"\x04\x08[\x07[\x06@\x06@\x06").unwrap();
+        let copies = from_bytes(b"\x04\x08[\x07[\x06@\x06[\x06@\x07").unwrap();
+        assert_eq!(shared.content_digest(), copies.content_digest());
+    }
+
+    #[test]
+    fn non_symbol_field_name() {
+        // An ivar named by an integer
+        let err = from_bytes(b"\x04\x08I\"\x06x\x06i\x06T").unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::UnexpectedType {
+            expected: crate::RbType::Symbol, found: crate::RbType::Int, position: Some(7) }));
+        assert_eq!(err.to_string(), "Expected Symbol but found Integer at offset 7");
+        let err = from_reader(&b"\x04\x08o:\x08Foo\x06\"\x06aT"[..]).unwrap_err();
+        assert_eq!(err.to_string(), "Expected Symbol but found String at offset 9");
+        // Hashes may have any keys, including when they're the payload of a user-marshal object
+        let value = from_bytes(b"\x04\x08U:\x08Foo{\x06i\x06I\"\x06a\x06:\x06ET").unwrap();
+        let data = value.as_rbref().unwrap().as_user_marshal().unwrap().data.clone();
+        assert_eq!(data.as_rbref().unwrap().as_hash().unwrap().get(&RbAny::Int(1)).unwrap().as_string().unwrap(), "a");
+    }
+
+    #[test]
+    fn structural_eq() {
+        let nan1 = from_bytes(b"\x04\x08[\x06f\x08nan").unwrap();
+        let nan2 = from_bytes(b"\x04\x08[\x06f\x08nan").unwrap();
+        assert!(!nan1.deep_eq(&nan2));
+        assert!(nan1.structural_eq(&nan2));
+        assert!(!nan1.structural_eq(&from_bytes(b"\x04\x08[\x06f\x061").unwrap()));
+        // A user-marshal object whose data is itself
+        let rec1 = from_bytes(b"\x04\x08U:\x06M@\x00").unwrap();
+        let rec2 = from_bytes(b"\x04\x08U:\x06M@\x00").unwrap();
+        assert!(!rec1.deep_eq(&rec2));
+        assert!(rec1.structural_eq(&rec2));
+        assert!(!rec1.structural_eq(&from_bytes(b"\x04\x08U:\x06N@\x00").unwrap()));
+        // Sharing doesn't matter
+        let shared = from_bytes(b"\x04\x08[\x07[\x06i\x06@\x06").unwrap();
+        let copies = from_bytes(b"\x04\x08[\x07[\x06i\x06[\x06i\x06").unwrap();
+        assert!(shared.structural_eq(&copies));
+        assert!(!shared.structural_eq(&from_bytes(b"\x04\x08[\x07[\x06i\x06[\x06i\x07").unwrap()));
+    }
+
+    #[test]
+    fn root_scalars() {
+        // What Marshal.dump writes for each of these on its own
+        let cases: Vec<(RbAny, &[u8])> = vec![
+            (RbAny::sym("foo"), b"\x04\x08:\x08foo"),
+            (RbAny::Int(5), b"\x04\x08i\x0a"),
+            (RbAny::Int(-1000), b"\x04\x08i\xfe\x18\xfc"),
+            (RbAny::Nil, b"\x04\x080"),
+            (RbAny::True, b"\x04\x08T"),
+            (RbAny::from("abc"), b"\x04\x08I\"\x08abc\x06:\x06ET"),
+            (RbAny::from(1.5f64), b"\x04\x08f\x081.5"),
+        ];
+        for (value, expected) in cases.iter() {
+            assert_write(value, expected);
+            assert!(from_bytes(expected).unwrap().deep_eq(value));
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let old = from_bytes(b"\x04\x08[\x08o:\x08Foo\x07:\x07@aI\"\x09Jack\x06:\x06ET:\x07@bi\x06{\x06i\x06i\x07i\x06").unwrap();
+        let new = from_bytes(b"\x04\x08[\x07o:\x08Foo\x07:\x07@aI\"\x09Jane\x06:\x06ET:\x07@ci\x06{\x07i\x06i\x07i\x07i\x08").unwrap();
+        let diff = old.diff(&new);
+        let lines: Vec<_> = diff.iter().map(|d| d.to_string()).collect();
+        assert_eq!(lines, vec![
+            "~ [0].@a: Str(\"Jack\") -> Str(\"Jane\")",
+            "- [0].@b: Int(1)",
+            "+ [0].@c: Int(1)",
+            "+ [1][2]: Int(3)",
+            "- [2]: Int(1)",
+        ]);
+        // Paths lead to the values in each tree
+        assert_eq!(RbAny::find_child(&new, diff[0].path()).unwrap().as_string().unwrap(), "Jane");
+        assert_eq!(RbAny::find_child(&old, diff[1].path()), Some(&RbAny::Int(1)));
+        assert!(old.diff(&old.explode()).is_empty());
+        assert_eq!(RbAny::Int(1).diff(&RbAny::Nil)[0].to_string(), "~ <root>: Int(1) -> Nil");
+
+        // Recursive values terminate
+        let rec1 = from_bytes(b"\x04\x08[\x07i\x06@\x00").unwrap();
+        let rec2 = from_bytes(b"\x04\x08[\x07i\x07@\x00").unwrap();
+        assert_eq!(rec1.diff(&rec2).iter().map(|d| d.to_string()).collect::<Vec<_>>(), vec!["~ [0]: Int(1) -> Int(2)"]);
+    }
+
+    #[test]
+    fn make_patch() {
+        let old = from_bytes(b"\x04\x08[\x08o:\x08Foo\x07:\x07@aI\"\x09Jack\x06:\x06ET:\x07@bi\x06{\x06i\x06i\x07[\x09i\x06i\x07i\x08i\x09").unwrap();
+        let new = from_bytes(b"\x04\x08[\x08o:\x08Foo\x07:\x07@aI\"\x09Jane\x06:\x06ET:\x07@ci\x06{\x07i\x06i\x07i\x07i\x08[\x06i\x06").unwrap();
+        let patch = RbAny::make_patch(&old, &new);
+        let mut target = old.clone();
+        patch.apply(&mut target).unwrap();
+        assert!(target.deep_eq(&new));
+        // The original is untouched
+        assert_eq!(old.get(2).unwrap().len(), Some(4));
+        // Trailing elements are removed from the end so the indexes stay valid
+        let removed: Vec<_> = patch.ops.iter().filter_map(|op| match op {
+            PatchOp::Remove { path } if path.len() == 2 => path[1].as_int(),
+            _ => None,
+        }).collect();
+        assert_eq!(removed, vec![3, 2, 1]);
+
+        // A patch can be written as Marshal data and read back
+        let stored = from_bytes(&writer_write(&patch.to_any())).unwrap();
+        let mut target = old.clone();
+        RbPatch::from_any(&stored).unwrap().apply(&mut target).unwrap();
+        assert!(target.deep_eq(&new));
+        assert!(RbAny::make_patch(&old, &old.explode()).is_empty());
+        assert!(RbPatch::from_any(&RbAny::from(vec![RbAny::Int(1)])).is_err());
+
+        // Array edits shift the elements after them
+        let mut ar = RbAny::from(vec![RbAny::Int(1), RbAny::Int(2)]);
+        RbPatch { ops: vec![
+            PatchOp::Insert { path: vec![RbAny::Int(0)], value: RbAny::Int(0) },
+            PatchOp::Remove { path: vec![RbAny::Int(1)] },
+        ] }.apply(&mut ar).unwrap();
+        assert!(ar.deep_eq(&RbAny::from(vec![RbAny::Int(0), RbAny::Int(2)])));
+        let bad = RbPatch { ops: vec![PatchOp::Remove { path: vec![RbAny::Int(5)] }] };
+        assert!(matches!(bad.apply(&mut ar), Err(crate::ThurgoodError::BadPatch(_))));
+
+        // A change inside a value shared within the document is made at every path to it
+        let shared = RbAny::from(vec![RbAny::Int(1)]);
+        let old = RbAny::from(vec![shared.clone(), shared.clone()]);
+        let new = RbAny::from(vec![RbAny::from(vec![RbAny::Int(2)]), RbAny::from(vec![RbAny::Int(2)])]);
+        let mut target = old.clone();
+        RbAny::make_patch(&old, &new).apply(&mut target).unwrap();
+        assert!(target.deep_eq(&new));
+        assert_eq!(shared.get(0), Some(&RbAny::Int(1)));
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json_flatten_objects() {
+        let user = RbObject::new_from_slice("User", &[("@name", RbAny::string("Alice")), ("@age", RbAny::Int(30))]);
+        let value = RbAny::from(vec![RbRef::Object(user).into_any()]);
+        let mut conv = RbToJson::new();
+        conv.flatten_objects = true;
+        assert_eq!(conv.to_json(&value).unwrap()["data"][0], serde_json::json!({"name": "Alice", "age": 30}));
+        // The tagged form is the default
+        assert_eq!(value.to_json().unwrap()["data"][0]["@"], "User");
+    }
+
+    #[test]
+    fn size_hint() {
+        let obj = RbObject::new_from_slice("User", &[("@name", RbAny::string("Alice")), ("@age", RbAny::Int(30))]);
+        let obj = RbRef::Object(obj).into_any();
+        let single = RbAny::from(vec![obj.clone()]).size_hint();
+        // Shared values are only counted once
+        let shared = RbAny::from(vec![obj.clone(), obj.clone(), obj.clone()]).size_hint();
+        assert!(shared < single + 10);
+        let copies = RbAny::from(vec![obj.to_owned_deep(), obj.to_owned_deep(), obj]).size_hint();
+        assert!(copies > single * 2);
+        #[cfg(feature = "json")]
+        {
+            let value = from_bytes(b"\x04\x08[\x07I\"\ttest\x06:\x06ET{\x06:\x06ai\x06").unwrap();
+            let len = value.to_json().unwrap().to_string().len();
+            let hint = value.size_hint();
+            assert!(hint >= len / 2 && hint <= len * 2, "{} vs {}", hint, len);
+        }
+    }
+
+    #[test]
+    fn lenient_user_data() {
+        // `Z` isn't a type byte, so the contents of Foo can't be read
+        let inp = b"\x04\x08[\x06U:\x08Foo[\x07:\x06aZ\x01\x02";
+        assert!(matches!(from_bytes(inp), Err(crate::ThurgoodError::BadTypeByte { byte: b'Z', .. })));
+        let undecoded = |value: &RbAny| match value.as_array().unwrap()[0].as_rbref() {
+            Some(RbRef::UserMarshal(v)) => v.undecoded().map(|raw| raw.to_vec()),
+            _ => None,
+        };
+        let mut rd = RbReader::from_slice(inp);
+        rd.lenient_user_data = true;
+        let value = rd.read().unwrap();
+        assert_eq!(undecoded(&value).unwrap(), b"[\x07:\x06aZ\x01\x02");
+        assert_write(&value, inp);
+        let mut rd = RbReader::new(&inp[..]);
+        rd.lenient_user_data = true;
+        assert_eq!(undecoded(&rd.read().unwrap()).unwrap(), b"[\x07:\x06aZ\x01\x02");
+
+        // Contents which can be read are unaffected
+        let mut rd = RbReader::from_slice(b"\x04\x08[\x06U:\x08Fooi\x06");
+        rd.lenient_user_data = true;
+        assert_eq!(undecoded(&rd.read().unwrap()), None);
+        // Only the last value can be recovered, since the raw bytes take the rest of the input
+        for inp in [&b"\x04\x08[\x07U:\x08FooZi\x06"[..], b"\x04\x08[\x07d:\x08FooZi\x06"].iter() {
+            let mut rd = RbReader::from_slice(inp);
+            rd.lenient_user_data = true;
+            assert!(matches!(rd.read(), Err(crate::ThurgoodError::UndecodedNotLast(name)) if name == "Foo"));
+        }
+        let mut rd = RbReader::new(&b"\x04\x08{\x06U:\x08FooZi\x06i\x07"[..]);
+        rd.lenient_user_data = true;
+        assert!(matches!(rd.read(), Err(crate::ThurgoodError::UndecodedNotLast(_))));
+        // Unless an enclosing value can take the rest of the input instead
+        let inp = b"\x04\x08U:\x08Bar[\x07U:\x08FooZi\x06";
+        let mut rd = RbReader::from_slice(inp);
+        rd.lenient_user_data = true;
+        let value = rd.read().unwrap();
+        match value.as_rbref() {
+            Some(RbRef::UserMarshal(v)) => assert_eq!(v.undecoded(), Some(&b"[\x07U:\x08FooZi\x06"[..])),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_write(&value, inp);
+    }
+
+    #[test]
+    fn user_data_as_str() {
+        let value = from_bytes(b"\x04\x08u:\x08Foo\x0c{\"a\":1}").unwrap();
+        let data = match value.as_rbref().unwrap() {
+            RbRef::UserData(v) => v,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(data.as_str(), Some("{\"a\":1}"));
+        assert_eq!(data.as_bytes(), b"{\"a\":1}");
+        let data = RbUserData { name: RbSymbol::from("Foo"), data: b"\xff\x00".to_vec() };
+        assert_eq!(data.as_str(), None);
+    }
+
+    #[test]
+    fn rb_path() {
+        // {:users => [#<User @name="Alice">], "users" => []}
+        let root = from_bytes(b"\x04\x08{\x07:\x0ausers[\x06o:\x09User\x06:\x0a@nameI\"\x0aAlice\x06:\x06ETI\"\x0ausers\x06;\x08T[\x00").unwrap();
+        let path = RbPath::new().sym("users").index(0).sym("@name");
+        assert_eq!(RbAny::find_child(&root, &path).unwrap().as_string().unwrap(), "Alice");
+        assert_eq!(path.len(), 3);
+        assert_eq!(path[1], RbAny::Int(0));
+        // A string key is a different entry
+        assert!(RbAny::find_child(&root, &RbPath::new().key("users").index(0)).is_none());
+        assert_eq!(RbAny::find_child(&root, &RbPath::new().key("users")).unwrap().as_array().unwrap().len(), 0);
+        assert_eq!(RbAny::find_child(&root, &RbPath::new()), Some(&root));
+        assert_eq!(RbPath::new().key(5).into_keys(), vec![RbAny::Int(5)]);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("thurgood-file-round-trip-{}.bin", std::process::id()));
+        let value = RbAny::from(vec![RbAny::from("test"), RbAny::Int(300), RbAny::sym("x")]);
+        to_file(&path, &value).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), writer_write(&value));
+        let loaded = from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap().deep_eq(&value));
+        assert!(matches!(from_file(&path), Err(crate::ThurgoodError::IO(_))));
+    }
+
+    #[test]
+    fn new_string_bin_with() {
+        // "あ".encode("Shift_JIS")
+        let value = RbRef::new_string_bin_with(vec![0x82, 0xa0], "Shift_JIS").into_any();
+        let data = writer_write(&value);
+        assert!(data.starts_with(b"\x04\x08I\"\x07\x82\xa0\x06:\x0dencoding"));
+        assert!(from_bytes(&data).unwrap().deep_eq(&value));
+        match RbRef::new_string_bin_with(b"abc".to_vec(), "US-ASCII") {
+            RbRef::StrI { metadata, .. } => assert_eq!(metadata.get(&RbSymbol::from("E")), Some(&RbAny::False)),
+            other => panic!("unexpected {:?}", other),
+        }
+        // Binary strings don't record an encoding
+        assert_write(&RbRef::new_string_bin_with(vec![0xff], "ASCII-8BIT").into_any(), b"\x04\x08\"\x06\xff");
+    }
+
+    #[test]
+    fn symbol_interner() {
+        // #<User @name="Alice">, #<User @name="Bob">
+        let docs: [&[u8]; 2] = [
+            b"\x04\x08o:\x09User\x06:\x0a@nameI\"\x0aAlice\x06:\x06ET",
+            b"\x04\x08o:\x09User\x06:\x0a@nameI\"\x08Bob\x06:\x06ET",
+        ];
+        let mut interner = SymbolInterner::new();
+        let mut values = Vec::new();
+        for doc in docs.iter() {
+            let mut rd = RbReader::from_slice(doc).with_interner(interner);
+            values.push(rd.read().unwrap());
+            interner = rd.take_interner().unwrap();
+        }
+        // "User", "@name" and "E" are each stored once
+        assert_eq!(interner.len(), 3);
+        assert_eq!(interner.reused(), 3);
+        let names: Vec<&RbSymbol> = values.iter().map(|v| &v.as_object().unwrap().name).collect();
+        assert_eq!(names[0].as_bytes().as_ptr(), names[1].as_bytes().as_ptr());
+        assert_eq!(names[0], &RbSymbol::from("User"));
+        // Nothing else changes
+        assert!(values[1].deep_eq(&from_bytes(docs[1]).unwrap()));
+        assert_eq!(to_vec(&values[0]).unwrap(), docs[0]);
+    }
+
+    #[test]
+    fn struct_round_trip() {
+        // Point = Struct.new(:x, :y); Point.new(1, 2)
+        let point = reader_parse("\x04\x08S:\x0aPoint\x07:\x06xi\x06:\x06yi\x07");
+        assert!(point.is_struct());
+        assert!(point.as_object().is_none());
+        let st = point.as_struct().unwrap();
+        assert_eq!(st.name, RbSymbol::from("Point"));
+        assert_eq!(st.fields.keys().map(|k| k.as_str().unwrap()).collect::<Vec<_>>(), vec!["x", "y"]);
+        assert_write(&point, b"\x04\x08S:\x0aPoint\x07:\x06xi\x06:\x06yi\x07");
+        // class Point3 < Point; end; Point3.new(2, 1), members keep their order
+        let sub = reader_parse("\x04\x08S:\x0bPoint3\x07:\x06yi\x06:\x06xi\x07");
+        assert_eq!(sub.class_name(), Some("Point3"));
+        assert_write(&sub, b"\x04\x08S:\x0bPoint3\x07:\x06yi\x06:\x06xi\x07");
+        assert!(!reader_parse("\x04\x08o:\x0aPoint\x00").is_struct());
+        // A member which isn't a symbol, or a missing class name
+        assert!(matches!(from_bytes(b"\x04\x08S:\x0aPoint\x06i\x06i\x07"),
+            Err(crate::ThurgoodError::UnexpectedType { position: Some(11), .. })));
+        assert!(matches!(from_bytes(b"\x04\x08Si\x06\x00"), Err(crate::ThurgoodError::UnexpectedType { .. })));
+    }
+
+    #[test]
+    fn max_string_and_symbol_len() {
+        // [:abc, "hello"]
+        let data = b"\x04\x08[\x07:\x08abcI\"\x0ahello\x06:\x06ET";
+        let mut rd = RbReader::from_slice(data);
+        rd.max_symbol_len = Some(3);
+        rd.max_string_len = Some(5);
+        assert!(rd.read().is_ok());
+        let mut rd = RbReader::from_slice(data);
+        rd.max_string_len = Some(4);
+        let err = rd.read().unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::TooLong { kind: crate::RbType::Str, len: 5, max: 4, position: Some(11) }));
+        assert_eq!(err.to_string(), "String of 5 bytes is longer than the limit of 4 bytes at offset 11");
+        let mut rd = RbReader::from_slice(data);
+        rd.max_symbol_len = Some(2);
+        assert!(matches!(rd.read(), Err(crate::ThurgoodError::TooLong { kind: crate::RbType::Symbol, len: 3, .. })));
+        // Plain strings are limited too
+        let mut rd = RbReader::from_slice(b"\x04\x08\"\x08abc");
+        rd.max_string_len = Some(2);
+        assert!(matches!(rd.read(), Err(crate::ThurgoodError::TooLong { .. })));
+    }
+
+    #[test]
+    fn on_symbol() {
+        fn only_ivars(name: &[u8]) -> crate::TResult<()> {
+            match name {
+                [b'@', ..] | b"E" => Ok(()),
+                _ => Err(crate::ThurgoodError::RejectedSymbol(String::from_utf8_lossy(name).into_owned())),
+            }
+        }
+        // [#<Foo @a="x">, :Foo]
+        let data = b"\x04\x08[\x07o:\x08Foo\x06:\x07@aI\"\x06x\x06:\x06ET;\x00";
+        assert!(from_bytes(data).is_ok());
+        let mut rd = RbReader::from_slice(data);
+        rd.on_symbol = Some(only_ivars);
+        let err = rd.read().unwrap_err();
+        assert!(matches!(&err, crate::ThurgoodError::RejectedSymbol(name) if name == "Foo"));
+        assert_eq!(err.to_string(), "Symbol Foo isn't allowed");
+        // Symbol references aren't checked again
+        let mut rd = RbReader::from_slice(b"\x04\x08[\x07:\x07@a;\x00");
+        rd.on_symbol = Some(only_ivars);
+        assert!(rd.read().is_ok());
+    }
+
+    #[test]
+    fn map_strings() {
+        // a = "ab"; x = [a, a]; x << x
+        let value = reader_parse("\x04\x08[\x08I\"\x07ab\x06:\x06ET@\x06@\x00");
+        let upper = value.map_strings(|s| s.to_ascii_uppercase());
+        let items = upper.as_array().unwrap();
+        assert_eq!(items[0].as_string().unwrap(), "AB");
+        assert_eq!(items[0].pointer_id(), items[1].pointer_id());
+        assert_eq!(items[2].pointer_id(), upper.pointer_id());
+        // The original is unchanged
+        assert_eq!(value.as_array().unwrap()[0].as_string().unwrap(), "ab");
+        assert_ne!(upper.pointer_id(), value.pointer_id());
+        // Invalid UTF-8 results become binary strings
+        let bin = value.map_strings(|_| vec![0xff]);
+        assert_eq!(bin.as_array().unwrap()[1].as_bytes(), Some(&b"\xff"[..]));
+        assert!(bin.as_array().unwrap()[1].as_string().is_none());
+
+        // o = Foo.new; o.instance_variable_set(:@self, o); o.instance_variable_set(:@name, "a")
+        let value = reader_parse("\x04\x08o:\x08Foo\x07:\n@self@\x00:\n@name\"\x06a");
+        let upper = value.map_strings(|s| s.to_ascii_uppercase());
+        let inner = upper.get("@self").unwrap();
+        assert_eq!(inner.pointer_id(), upper.pointer_id());
+        assert_eq!(inner.get("@name").unwrap().as_bytes(), Some(&b"A"[..]));
+        assert_write(&upper, b"\x04\x08o:\x08Foo\x07:\n@self@\x00:\n@name\"\x06A");
+        assert_eq!(value.get("@name").unwrap().as_bytes(), Some(&b"a"[..]));
+    }
+
+    #[test]
+    fn class_module_refs() {
+        // [Comparable (as 'M'), String, Comparable], {String => 1, String (as 'M') => 2}
+        for data in [
+            &b"\x04\x08[\x08M\x0fComparablec\x0bStringm\x0fComparable"[..],
+            &b"\x04\x08{\x07c\x0bStringi\x06M\x0bStringi\x07"[..],
+        ].iter() {
+            let value = reader_parse_loose(data);
+            assert_write(&value, data);
+        }
+        let class = RbRef::ClassRef("String".to_owned()).into_any();
+        let class_module = RbRef::ClassModuleRef("String".to_owned()).into_any();
+        assert_ne!(class, class_module);
+        assert!(!class.deep_eq(&class_module));
+        assert_eq!(class, RbRef::ClassRef("String".to_owned()).into_any());
+        assert!(!RbRef::ModuleRef("String".to_owned()).into_any().deep_eq(&class));
+        assert_eq!(reader_parse("\x04\x08{\x07c\x0bStringi\x06M\x0bStringi\x07").len(), Some(2));
+    }
+
+    #[test]
+    fn to_owned_deep() {
+        // a = "ab"; x = [a, a, {a => 1}]; x << x
+        let value = reader_parse("\x04\x08[\x09\"\x07ab@\x06{\x06@\x06i\x06@\x00");
+        let copy = value.to_owned_deep();
+        assert!(copy.deep_eq(&value));
+        let mut original_ptrs = std::collections::HashSet::new();
+        value.walk(|v| { original_ptrs.insert(v.pointer_id()); });
+        copy.walk(|v| assert!(v.pointer_id().is_none() || !original_ptrs.contains(&v.pointer_id())));
+        let items = copy.as_array().unwrap();
+        assert_eq!(items[0].pointer_id(), items[1].pointer_id());
+        assert_eq!(items[2].iter_hash().next().unwrap().0.pointer_id(), items[0].pointer_id());
+        assert_eq!(items[3].pointer_id(), copy.pointer_id());
+
+        // o = Foo.new; o.instance_variable_set(:@self, o); o.instance_variable_set(:@name, "a")
+        let value = reader_parse("\x04\x08o:\x08Foo\x07:\n@self@\x00:\n@name\"\x06a");
+        let mut copy = value.to_owned_deep();
+        assert!(copy.deep_eq(&value));
+        assert_eq!(copy.get("@self").unwrap().pointer_id(), copy.pointer_id());
+        let mut original_ptrs = std::collections::HashSet::new();
+        value.walk(|v| { original_ptrs.insert(v.pointer_id()); });
+        copy.walk(|v| assert!(v.pointer_id().is_none() || !original_ptrs.contains(&v.pointer_id())));
+        copy.objects_named_mut("Foo", |obj| {
+            obj.insert("@name", RbAny::from("b"));
+        });
+        assert_eq!(copy.get("@self").unwrap().get("@name").unwrap().as_bytes(), Some(&b"b"[..]));
+        assert_eq!(value.get("@name").unwrap().as_bytes(), Some(&b"a"[..]));
+        assert_eq!(value.get("@self").unwrap().pointer_id(), value.pointer_id());
+        assert_write(&value, b"\x04\x08o:\x08Foo\x07:\n@self@\x00:\n@name\"\x06a");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn write_json() {
+        let inputs: [&[u8]; 5] = [
+            // {:users => [#<User @name="Alice">], "users" => []}
+            b"\x04\x08{\x07:\x0ausers[\x06o:\x09User\x06:\x0a@nameI\"\x0aAlice\x06:\x06ETI\"\x0ausers\x06;\x08T[\x00",
+            // a = "ab"; x = [a, a, {a => 1}]; x << x
+            b"\x04\x08[\x09\"\x07ab@\x06{\x06@\x06i\x06@\x00",
+            // a = [1]; a.instance_variable_set(:@a, [2]); [a, [].extend(Comparable), 1.5, "\xff".b]
+            b"\x04\x08[\x09I[\x06i\x06\x06:\x07@a[\x06i\x07e:\x0fComparable[\x00f\x081.5\"\x06\xff",
+            // h = Hash.new(5); h[1] = nil; Struct S; [h, S.new(h), /ab/]
+            b"\x04\x08[\x08}\x06i\x060i\x0aS:\x06S\x06:\x06a@\x06I/\x07ab\x00\x06:\x06EF",
+            b"\x04\x08i\x06",
+        ];
+        for data in inputs.iter() {
+            let value = reader_parse_loose(data);
+            let mut out = Vec::new();
+            value.write_json(&mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), serde_json::to_string(&value.to_json().unwrap()).unwrap());
+        }
+        let bad = RbAny::from(vec![RbAny::Symbol(RbSymbol::new(vec![0xff]))]);
+        assert!(bad.to_json().is_none());
+        assert!(matches!(bad.write_json(Vec::new()), Err(crate::ThurgoodError::IO(_))));
+    }
+
+    #[test]
+    fn hash_default_value() {
+        // Hash.new(5)
+        let mut value = reader_parse("\x04\x08}\x00i\x0a");
+        let hash = value.as_hash_mut().unwrap();
+        assert_eq!(hash.default_value(), Some(&RbAny::Int(5)));
+        *hash.default_value_mut().unwrap() = RbAny::Int(6);
+        assert_write(&value, b"\x04\x08}\x00i\x0b");
+        assert_eq!(RbHash::new().default_value(), None);
+        assert!(RbHash::new().default_value_mut().is_none());
+    }
+
+    #[test]
+    fn symbol_and_object_tables() {
+        // ["b", :a, "b"(same), :a, :E] - symbols and objects are numbered separately
+        let value = reader_parse("\x04\x08[\x0aI\"\x06b\x06:\x06ET:\x06a@\x06;\x06;\x00");
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].pointer_id(), items[2].pointer_id());
+        assert_eq!(items[3], RbAny::sym("a"));
+        assert_eq!(items[4], RbAny::sym("E"));
+        // A symbol's encoding name is an object, but the symbol isn't: [:"あ" (Shift_JIS), "Shift_JIS"]
+        let value = reader_parse_loose(b"\x04\x08[\x07I:\x07\x82\xa0\x06:\x0dencoding\"\x0eShift_JIS@\x06");
+        assert_eq!(value.as_array().unwrap()[1].as_bytes(), Some(&b"Shift_JIS"[..]));
+        // class MyStr < String; end; s = MyStr.new("a"); [s, s, "b", "b" (same)]
+        // The contents of a user class don't have an object index of their own
+        let inp = "\x04\x08[\x09IC:\x0aMyStr\"\x06a\x06:\x06ET@\x06I\"\x06b\x06;\x06T@\x07";
+        let value = reader_parse(inp);
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].as_rbref().unwrap().as_user_class().unwrap().data.as_string().unwrap(), "a");
+        assert_eq!(items[0].pointer_id(), items[1].pointer_id());
+        assert_eq!(items[3].as_string().unwrap(), "b");
+        assert_write(&value, inp.as_bytes());
+        // class MyArray < Array; end; a = MyArray.new; a << a; a.instance_variable_set(:@x, 1)
+        let inp = "\x04\x08IC:\x0cMyArray[\x06@\x00\x06:\x07@xi\x06";
+        let value = reader_parse(inp);
+        assert_write(&value, inp.as_bytes());
+        let class = value.as_rbref().unwrap().as_user_class().unwrap();
+        assert_eq!(class.data.as_rbref().unwrap().as_array().unwrap()[0].pointer_id(), value.pointer_id());
+    }
+
+    #[test]
+    fn reset() {
+        let first = RbAny::from(vec![RbAny::sym("a"), RbAny::from("x")]);
+        let second = RbAny::from(vec![RbAny::sym("b"), RbAny::sym("a")]);
+        let mut wr = RbWriter::new(Vec::new());
+        wr.write(&first).unwrap();
+        let out = wr.reset(Vec::new());
+        assert_eq!(out, writer_write(&first));
+        assert_eq!((wr.symbol_count(), wr.object_count()), (0, 0));
+        wr.write(&second).unwrap();
+        assert_eq!(wr.into_inner(), writer_write(&second));
+        // [:a, @0]
+        let mut rd = RbReader::from_slice(b"\x04\x08[\x07:\x06a@\x00");
+        rd.read().unwrap();
+        rd.reset(crate::SliceSource::new(b";\x00"));
+        assert!(matches!(rd.read_value(), Err(crate::ThurgoodError::BadSymbolRef(0))));
+        assert_eq!(rd.version(), None);
+    }
+
+    #[test]
+    fn empty_object() {
+        let obj = RbObject::empty("Foo");
+        assert!(obj.is_empty());
+        let value = obj.into_object().into_any();
+        assert_write(&value, b"\x04\x08o:\x08Foo\x00");
+        assert!(reader_parse("\x04\x08o:\x08Foo\x00").deep_eq(&value));
+        assert_eq!(format!("{:?}", value), "Object(Foo){}");
+        let mut out = Vec::new();
+        crate::rc::dump::inspect_pretty(&mut out, &value, &Default::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "#<Foo>");
+        #[cfg(feature = "json")]
+        assert_eq!(value.to_json().unwrap().to_string(), r#"{"@":"Foo","@id":1,"fields":{}}"#);
+    }
+
+    #[test]
+    fn collect_user_data() {
+        let shared = RbRef::new_user_data("Foo", b"x".to_vec()).into_any();
+        let obj = RbRef::new_object("Bar", &[(RbSymbol::from("@b"), RbRef::new_user_data("Baz", b"y".to_vec()).into_any())]).into_any();
+        let mut root = RbAny::from(vec![shared.clone(), obj.clone(), shared, RbAny::Int(1)]);
+        let found = root.collect_user_data();
+        let summary: Vec<(Vec<RbAny>, &str)> = found.iter()
+            .map(|(path, data)| (path.to_vec(), data.name.as_str().unwrap()))
+            .collect();
+        assert_eq!(summary, vec![
+            (vec![RbAny::Int(0)], "Foo"),
+            (vec![RbAny::Int(1), RbAny::sym("@b")], "Baz"),
+            (vec![RbAny::Int(2)], "Foo"),
+        ]);
+        // Splice decoded values back in
+        let paths: Vec<RbPath> = found.into_iter().map(|(path, _)| path).collect();
+        for path in paths.iter() {
+            let old = root.replace_at(path, RbAny::from("decoded")).unwrap();
+            assert!(matches!(old.as_rbref(), Some(RbRef::UserData(_))));
+        }
+        assert!(root.collect_user_data().is_empty());
+        assert_eq!(RbAny::find_child(&root, &paths[1]).unwrap().as_string().unwrap(), "decoded");
+        // The original object is unaffected
+        assert!(matches!(obj.as_object().unwrap().get("@b").unwrap().as_rbref(), Some(RbRef::UserData(_))));
+        assert!(root.replace_at(&RbPath::new().index(9), RbAny::Nil).is_none());
+        assert_eq!(root.replace_at(&RbPath::new(), RbAny::Nil).unwrap().len(), Some(4));
+    }
+
+    #[test]
+    fn from_bytes_binary_string() {
+        let value = RbAny::from(&b"\xff\x00"[..]);
+        assert_write(&value, b"\x04\x08\"\x07\xff\x00");
+        assert!(value.as_string().is_none());
+        assert!(RbRef::from(vec![0xffu8]).into_any().deep_eq(&RbRef::new_string_bin(vec![0xff]).into_any()));
+        assert!(RbRef::from(&b"ab"[..]).into_any().deep_eq(&RbAny::from(&b"ab"[..])));
+        // Text still converts to a UTF-8 string, and an empty Vec to an array
+        assert_eq!(RbAny::from("ab").as_string().unwrap(), "ab");
+        assert_eq!(RbAny::from(vec![]).as_array().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn symbol_str() {
+        assert_eq!(RbAny::sym("ok").symbol_str(), Some("ok"));
+        assert_eq!(RbAny::from("ok").symbol_str(), None);
+        assert_eq!(RbAny::Symbol(RbSymbol::new(vec![0xff])).symbol_str(), None);
+    }
+
+    #[test]
+    fn ruby_symbol_order() {
+        // Marshal.dump of a Point with @x = 1, @y = "hi", @z = :pt assigned in that order
+        let ruby = b"\x04\x08o:\x0aPoint\x08:\x07@xi\x06:\x07@yI\"\x07hi\x06:\x06ET:\x07@z:\x07pt";
+        let point = RbObject::new_from_slice("Point", &[
+            ("@x", RbAny::Int(1)),
+            ("@y", RbAny::from("hi")),
+            ("@z", RbAny::symbol_from("pt")),
+        ]).into_object().into_any();
+        assert_write(&point, ruby);
+
+        // Ruby writes a string's encoding before its other ivars
+        let mut metadata = RbFields::new();
+        metadata.insert(RbSymbol::from("@tag"), RbAny::Int(1));
+        metadata.insert(RbSymbol::from("E"), RbAny::True);
+        let tagged = RbRef::StrI { content: b"hi".to_vec(), metadata }.into_any();
+        assert_write(&tagged, b"\x04\x08I\"\x07hi\x07:\x06ET:\x09@tagi\x06");
+
+        let options = RbWriterOptions { sort_ivars: true, ..Default::default() };
+        let write = |value: &RbAny| {
+            let mut buf = Vec::new();
+            RbWriter::with_options(&mut buf, options.clone()).write(value).unwrap();
+            buf
+        };
+        let reversed = RbObject::new_from_slice("Point", &[
+            ("@z", RbAny::symbol_from("pt")),
+            ("@y", RbAny::from("hi")),
+            ("@x", RbAny::Int(1)),
+        ]).into_object().into_any();
+        assert_eq!(write(&reversed), ruby);
+        assert_eq!(write(&point), ruby);
+    }
+
+    #[test]
+    fn hash_sort_keys() {
+        let mut a = reader_parse("\x04\x08{\x08i\x07TI\"\x06b\x06:\x06ETTi\x06F");
+        let b = reader_parse("\x04\x08{\x08i\x06Fi\x07TI\"\x06b\x06:\x06ETT");
+        assert!(!a.deep_eq(&b));
+        a.as_hash_mut().unwrap().sort_keys();
+        assert!(a.deep_eq(&b));
+        assert_write(&a, b"\x04\x08{\x08i\x06Fi\x07TI\"\x06b\x06:\x06ETT");
+    }
+
+    #[test]
+    fn hash_pairs() {
+        let pairs = vec![(RbAny::Int(2), RbAny::True), (RbAny::from("a"), RbAny::Nil)];
+        let mut hash = RbHash::from_pairs(pairs.clone());
+        assert_eq!(hash.to_pairs(), pairs);
+        hash.set_default(RbAny::Int(0));
+        let mut reversed = hash.into_pairs();
+        reversed.reverse();
+        assert_eq!(RbHash::from_pairs(reversed).map.keys().collect::<Vec<_>>(), vec![&RbAny::from("a"), &RbAny::Int(2)]);
+    }
+
+    #[test]
+    fn array_and_hash_of() {
+        let ints = RbAny::array_of([1, 2, 3]);
+        assert!(ints.deep_eq(&RbAny::from(vec![RbAny::Int(1), RbAny::Int(2), RbAny::Int(3)])));
+        let strs = RbAny::array_of(vec!["a", "b"]);
+        assert!(strs.deep_eq(&RbAny::from(vec![RbAny::from("a"), RbAny::from("b")])));
+        assert!(RbAny::array_of(Vec::<i32>::new()).as_array().unwrap().is_empty());
+
+        let hash = RbAny::hash_of([("a", 1), ("b", 2)]);
+        let expected = RbHash::from_pairs(vec![(RbAny::from("a"), RbAny::Int(1)), (RbAny::from("b"), RbAny::Int(2))]);
+        assert!(hash.deep_eq(&RbAny::from(expected)));
+        let hash = RbAny::hash_of([(RbAny::sym("a"), 1), (RbAny::sym("b"), 2), (RbAny::sym("a"), 3)]);
+        assert_eq!(hash.as_hash().unwrap().get(&RbAny::sym("a")), Some(&RbAny::Int(3)));
+        assert_eq!(hash.len(), Some(2));
+    }
+
+    #[test]
+    fn hash_dedup_keys() {
+        let key = || RbAny::array_of([1, 2]);
+        let pairs = vec![(key(), RbAny::Int(1)), (RbAny::sym("a"), RbAny::Int(2)), (key(), RbAny::Int(3))];
+        let mut hash = RbHash::from_pairs(pairs.clone());
+        assert_eq!(hash.len(), 3);
+        assert_eq!(hash.dedup_keys(DuplicateKeys::KeepFirst), 1);
+        assert_eq!(hash.values().cloned().collect::<Vec<_>>(), vec![RbAny::Int(1), RbAny::Int(2)]);
+        assert_eq!(hash.dedup_keys(DuplicateKeys::KeepFirst), 0);
+
+        let mut hash = RbHash::from_pairs(pairs);
+        assert_eq!(hash.dedup_keys(DuplicateKeys::KeepLast), 1);
+        assert_eq!(hash.values().cloned().collect::<Vec<_>>(), vec![RbAny::Int(2), RbAny::Int(3)]);
+        assert_write(&RbAny::from(hash), b"\x04\x08{\x07:\x06ai\x07[\x07i\x06i\x07i\x08");
+    }
+
+    #[test]
+    fn shared_strings() {
+        let inp = "\x04\x08[\x08I\"\x06x\x06:\x06ETI\"\x06y\x06;\x00T@\x06";
+        let value = reader_parse(inp);
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].pointer_id(), items[2].pointer_id());
+        assert_ne!(items[0].pointer_id(), items[1].pointer_id());
+        assert_write(&value, inp.as_bytes());
+
+        let x = RbAny::from("x");
+        let built = RbAny::from(vec![x.clone(), RbAny::from("y"), x]);
+        assert_write(&built, inp.as_bytes());
+
+        // The name of a non-default encoding is a string which takes an object index too
+        let inp = b"\x04\x08[\x08I\"\x06a\x06:\x0dencoding\"\x0eShift_JISI\"\x06b\x06;\x00@\x07@\x06";
+        let value = reader_parse_loose(inp);
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].pointer_id(), items[2].pointer_id());
+        assert_write(&value, inp);
+    }
+
+    #[test]
+    fn preview() {
+        let obj = RbObject::new_from_slice("Foo", &[("@a", RbAny::Int(1)), ("@b", RbAny::Nil)]).into_object().into_any();
+        let value = RbAny::from(vec![RbAny::Int(1), RbAny::from("é\n"), obj]);
+        assert_eq!(value.preview(100), "[1, \"é\\n\", #<Foo @a=1, @b=nil>]");
+        assert_eq!(value.preview(6), "[1, \"é...");
+        assert_eq!(value.preview(0), "...");
+
+        // Writing stops early, rather than walking the whole value
+        let mut big = RbAny::from(vec![]);
+        for _ in 0..40 {
+            big = RbAny::from(vec![big.clone(), big]);
+        }
+        assert_eq!(big.preview(10), "[[[[[[[[[[...");
+        let recursive = from_bytes(b"\x04\x08[\x06@\x00").unwrap();
+        assert_eq!(recursive.preview(20), "[[...]]");
+    }
+
+    #[test]
+    fn bare_strings() {
+        // "abc".b and "\xff\x00".b are binary, so Ruby writes them without an encoding
+        for data in [&b"\x04\x08\"\x08abc"[..], &b"\x04\x08\"\x07\xff\x00"[..]].iter() {
+            let value = from_bytes(data).unwrap();
+            assert!(value.as_string().is_none());
+            assert_write(&value, data);
+        }
+        let value = reader_parse("\x04\x08[\x07\"\x08abc@\x06");
+        assert_eq!(value.get(0).unwrap().as_bytes(), Some(&b"abc"[..]));
+        assert_write(&value, b"\x04\x08[\x07\"\x08abc@\x06");
+        // Strings marked as UTF-8 still have to be valid unless allow_bin_strings is set
+        let invalid = b"\x04\x08I\"\x06\xff\x06:\x06ET";
+        assert!(matches!(from_bytes(invalid), Err(crate::ThurgoodError::Utf8 { .. })));
+        assert_write(&reader_parse_loose(invalid), invalid);
+    }
+
+    #[test]
+    fn encoding_name_ivar() {
+        // A string with `:encoding => "UTF-8"` rather than `:E => true`
+        let inp = b"\x04\x08[\x07I\"\x08abc\x06:\x0dencoding\"\x0aUTF-8I\"\x06b\x06;\x00\"\x0dUS-ASCII";
+        let value = reader_parse_loose(inp);
+        assert_eq!(value.get(0).unwrap().as_string().map(|s| s.as_str()), Some("abc"));
+        assert!(value.get(1).unwrap().deep_eq(&RbRef::new_string_bin_with(b"b".to_vec(), "US-ASCII").into_any()));
+        // Both are written the way Ruby writes them
+        assert_write(&value, b"\x04\x08[\x07I\"\x08abc\x06:\x06ETI\"\x06b\x06;\x00F");
+        // Other encodings are kept by name
+        let value = reader_parse_loose(b"\x04\x08I\"\x06b\x06:\x0dencoding\"\x0eShift_JIS");
+        assert!(value.as_string().is_none());
+        assert_write(&value, b"\x04\x08I\"\x06b\x06:\x0dencoding\"\x0eShift_JIS");
+    }
+
+    #[test]
+    fn utf8_error_position() {
+        let inp = b"\x04\x08[\x06I\"\x11abc\xff\x00\x01\x02\x03\x04\x05\x06\x07\x06:\x06ET";
+        let err = from_reader(&inp[..]).unwrap_err();
+        match &err {
+            crate::ThurgoodError::Utf8 { valid_up_to, position, snippet } => {
+                assert_eq!(*valid_up_to, 3);
+                assert_eq!(*position, Some(10));
+                assert_eq!(&**snippet, "ff 00 01 02 03 04 05 06 ...");
+            },
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(err.to_string(), "Invalid UTF-8 after 3 bytes of a string at offset 10: ff 00 01 02 03 04 05 06 ...");
+        let err = from_bytes(b"\x04\x08I\"\x06\xff\x06:\x06ET").unwrap_err();
+        assert!(matches!(err, crate::ThurgoodError::Utf8 { position: Some(5), ref snippet, .. } if &**snippet == "ff"));
+    }
+
+    #[test]
+    fn read_version_then_body() {
+        let mut rd = RbReader::from_slice(b"\x04\x09[\x06:\x06a\x04\x08;\x00");
+        assert_eq!(rd.read_version().unwrap(), (4, 9));
+        assert_eq!(rd.version(), Some((4, 9)));
+        assert!(rd.read_body().unwrap().deep_eq(&RbAny::from(vec![RbAny::sym("a")])));
+        // Each document starts with fresh tables
+        assert_eq!(rd.read_version().unwrap(), (4, 8));
+        assert!(matches!(rd.read_body(), Err(crate::ThurgoodError::BadSymbolRef(0))));
+        let mut rd = RbReader::from_slice(b"\x03\x00i\x06");
+        assert!(matches!(rd.read_version(), Err(crate::ThurgoodError::Version { major: 3, minor: 0 })));
+    }
+
+    #[test]
+    fn nodes() {
+        // a = "ab"; x = [a, {a => :b}]; x << x
+        let value = reader_parse("\x04\x08[\x08I\"\x07ab\x06:\x06ET{\x06@\x06:\x06b@\x00");
+        let nodes = value.nodes();
+        let kinds: Vec<_> = nodes.iter().map(|n| n.kind).collect();
+        assert_eq!(kinds, [crate::RbType::Array, crate::RbType::Str, crate::RbType::Hash, crate::RbType::Symbol]);
+        let parents: Vec<_> = nodes.iter().map(|n| n.parent).collect();
+        assert_eq!(parents, [None, Some(0), Some(0), Some(2)]);
+        assert_eq!(nodes[1].value.as_string().unwrap(), "ab");
+        assert_eq!(RbAny::Int(1).nodes().len(), 1);
+    }
+
+    #[cfg(feature = "encodings")]
+    #[test]
+    fn decode_strings() {
+        // ["あ".encode("Shift_JIS"), "い".encode("Windows-31J"), "\xff".b, "ok"]
+        let inp = b"\x04\x08[\x09I\"\x07\x82\xa0\x06:\x0dencoding\"\x0eShift_JISI\"\x07\x82\xa2\x06;\x00\"\x10Windows-31J\"\x06\xffI\"\x07ok\x06:\x06ET";
+        let mut value = from_bytes(inp).unwrap();
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].as_rbref().unwrap().decode_str().as_deref(), Some("\u{3042}"));
+        assert_eq!(items[2].as_rbref().unwrap().decode_str(), None);
+        assert_eq!(items[3].as_rbref().unwrap().decode_str().as_deref(), Some("ok"));
+        assert_eq!(RbRef::new_string_bin_with(vec![0x82], "Shift_JIS").decode_str(), None);
+        value.decode_strings();
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].as_string().unwrap(), "\u{3042}");
+        assert_eq!(items[1].as_string().unwrap(), "\u{3044}");
+        assert_eq!(items[2].as_bytes(), Some(&b"\xff"[..]));
+        assert!(items[2].as_string().is_none());
+    }
+
+    #[test]
+    fn object_matches() {
+        let obj = RbObject::new_from_slice("User", &[
+            ("@name", RbAny::from("bob")),
+            ("@age", RbAny::Int(30)),
+            ("@admin", RbAny::False),
+        ]);
+        assert_eq!(obj.matches(&[("@admin", RbAny::False), ("@name", RbAny::from("bob")), ("@age", RbAny::Int(30))]), Ok(()));
+        let err = obj.matches(&[("@name", RbAny::from("alice")), ("@age", RbAny::Int(30)), ("@email", RbAny::Nil)]).unwrap_err();
+        assert_eq!(err, concat!(
+            "User doesn't match:\n",
+            "@name: expected \"alice\", found \"bob\"\n",
+            "@email: missing, expected nil\n",
+            "@admin: unexpected field = false"));
+    }
+
+    #[test]
+    fn prune_nil() {
+        let settings = RbObject::new_from_slice("Settings", &[
+            ("@theme", RbAny::Nil),
+            ("@tags", RbAny::from(vec![])),
+            ("@extra", RbAny::from(RbHash::from_pairs(vec![(RbAny::sym("a"), RbAny::Nil)]))),
+            ("@items", RbAny::from(vec![RbAny::Nil])),
+        ]).into_object().into_any();
+        let original = RbAny::from(vec![settings.clone(), settings]);
+
+        let mut value = original.clone();
+        assert_eq!(value.prune_nil(PruneNil::NilOnly), 2);
+        let items = value.as_array().unwrap();
+        assert_eq!(items[0].pointer_id(), items[1].pointer_id());
+        let obj = items[0].as_rbref().unwrap().as_object().unwrap();
+        assert_eq!(obj.field_names().map(|k| k.as_str().unwrap()).collect::<Vec<_>>(), ["@tags", "@extra", "@items"]);
+        assert_eq!(obj.get("@extra").unwrap().len(), Some(0));
+
+        let mut value = original.clone();
+        assert_eq!(value.prune_nil(PruneNil::NilAndEmpty), 4);
+        let obj = value.get(0).unwrap().as_rbref().unwrap().as_object().unwrap();
+        assert!(obj.matches(&[("@items", RbAny::from(vec![RbAny::Nil]))]).is_ok());
+        // The original is unchanged
+        assert_eq!(original.get(0).unwrap().len(), Some(4));
+        assert!(!RbAny::from(RbHash::with_default(RbAny::Int(0))).is_empty_container());
+        assert!(RbAny::from(vec![]).is_empty_container());
+    }
+
+    #[test]
+    fn marshal_tags() {
+        use crate::consts::{MarshalTag, T_HASH_DEFAULT};
+        for tag in MarshalTag::ALL.iter() {
+            assert_eq!(MarshalTag::from_byte(tag.to_byte()), Some(*tag));
+        }
+        assert_eq!(MarshalTag::from_byte(T_HASH_DEFAULT), Some(MarshalTag::HashDefault));
+        assert_eq!(MarshalTag::from_byte(b'x'), None);
+        assert!(matches!(from_bytes(b"\x04\x08[\x06x"),
+            Err(crate::ThurgoodError::BadTypeByte { byte: b'x', position: Some(4) })));
+        assert!(matches!(from_bytes(b"\x04\x08C:\x0aMyStr0"),
+            Err(crate::ThurgoodError::BadTypeByte { byte: b'0', position: Some(10) })));
+    }
+
+    #[test]
+    fn self_referential_hash() {
+        // h = {}; h[:self] = h
+        let inp = "\x04\x08{\x06:\x09self@\x00";
+        let value = reader_parse(inp);
+        let inner = value.get(RbAny::symbol_from("self")).unwrap();
+        assert_eq!(inner.pointer_id(), value.pointer_id());
+        assert_write(&value, inp.as_bytes());
+        // Through a nested hash, and as a default value: h = Hash.new; h[:a] = {b: h}; h.default = h
+        let inp = "\x04\x08}\x06:\x06a{\x06:\x06b@\x00@\x00";
+        let value = reader_parse(inp);
+        let nested = value.get(RbAny::symbol_from("a")).unwrap();
+        assert_eq!(nested.get(RbAny::symbol_from("b")).unwrap().pointer_id(), value.pointer_id());
+        assert_eq!(value.as_hash().unwrap().default_value().unwrap().pointer_id(), value.pointer_id());
+        assert_write(&value, inp.as_bytes());
+        #[cfg(feature = "arc")]
+        {
+            let value = crate::arc::from_bytes(inp.as_bytes()).unwrap();
+            assert_eq!(value.as_hash().unwrap().default_value().unwrap().pointer_id(), value.pointer_id());
+        }
+        // As its own key: h = {}; h[h] = 1
+        let inp = "\x04\x08{\x06@\x00i\x06";
+        let value = reader_parse(inp);
+        assert_eq!(value.as_hash().unwrap().keys().next().unwrap().pointer_id(), value.pointer_id());
+        assert_write(&value, inp.as_bytes());
+    }
+
+    #[test]
+    fn self_referential_objects() {
+        // o = Foo.new; o.instance_variable_set(:@self, o); [o, o]
+        let inp = "\x04\x08[\x07o:\x08Foo\x06:\x0a@self@\x06@\x06";
+        let value = reader_parse(inp);
+        let obj = value.get(0).unwrap();
+        assert_eq!(obj.pointer_id(), value.get(1).unwrap().pointer_id());
+        assert_eq!(obj.get("@self").unwrap().pointer_id(), obj.pointer_id());
+        assert!(!value.is_acyclic());
+        assert_write(&value, inp.as_bytes());
+        assert!(from_bytes(&writer_write(&value)).unwrap().deep_eq(&value));
+
+        // h = {}; h[h] = 1 - the key is hashed before it's read, so it must still be found after
+        let inp = "\x04\x08{\x06@\x00i\x06";
+        let value = reader_parse(inp);
+        assert_eq!(value.as_hash().unwrap().get(&value), Some(&RbAny::Int(1)));
+        assert_write(&value, inp.as_bytes());
+        // h = {1.0 => 2}; h[h] = 3 - the unfinished key doesn't collide with other keys
+        let value = reader_parse("\x04\x08{\x07f\x061i\x07@\x00i\x08");
+        assert_eq!(value.len(), Some(2));
+        assert_eq!(value.as_hash().unwrap().get(&value), Some(&RbAny::Int(3)));
+    }
+
+    #[test]
+    fn array_elements() {
+        // [#<User @name="Alice">, #<User @name="Bob">, :ok]
+        let inp = b"\x04\x08[\x08o:\x09User\x06:\x0a@nameI\"\x0aAlice\x06:\x06ETo;\x00\x06;\x06I\"\x08Bob\x06;\x07T:\x07ok";
+        let items: Vec<_> = RbReader::from_slice(inp).array_elements().map(|v| v.unwrap()).collect();
+        assert_eq!(items.len(), 3);
+        assert!(items[1].as_object().unwrap().matches(&[("@name", RbAny::from("Bob"))]).is_ok());
+        assert_eq!(items[2], RbAny::sym("ok"));
+        let whole = from_bytes(inp).unwrap();
+        assert!(RbAny::from(items).deep_eq(&whole));
+
+        let mut iter = RbReader::from_slice(b"\x04\x08{\x00").array_elements();
+        assert!(matches!(iter.next(), Some(Err(crate::ThurgoodError::UnexpectedType {
+            expected: crate::RbType::Array, found: crate::RbType::Hash, position: Some(2) }))));
+        assert!(iter.next().is_none());
+        // Reading stops at the first error
+        let mut iter = RbReader::from_slice(b"\x04\x08[\x08i\x06xi\x07").array_elements();
+        assert_eq!(iter.next().unwrap().unwrap(), RbAny::Int(1));
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+        // The root array is never constructed, so it can't be referred to: a = [1]; a << [a]
+        let mut iter = RbReader::from_slice(b"\x04\x08[\x07i\x06[\x06@\x00").array_elements();
+        assert_eq!(iter.next().unwrap().unwrap(), RbAny::Int(1));
+        assert!(matches!(iter.next(), Some(Err(crate::ThurgoodError::ForwardObjectRef(0)))));
+        assert!(iter.next().is_none());
+        // Other objects can still be referred to
+        let items: Vec<_> = RbReader::from_slice(b"\x04\x08[\x07[\x00@\x06").array_elements()
+            .map(|v| v.unwrap()).collect();
+        assert!(RcType::ptr_eq(items[0].as_rc().unwrap(), items[1].as_rc().unwrap()));
+    }
+
+    #[test]
+    fn raw_ints() {
+        // [5, 300, 0] with 5 and 0 written as one-byte-payload ints and 300 written in 3 bytes
+        let inp: &[u8] = b"\x04\x08[\x08i\x01\x05i\x03\x2c\x01\x00i\x01\x00";
+        let mut rd = RbReader::new(io::Cursor::new(inp));
+        rd.record_raw_ints = true;
+        let value = rd.read().unwrap();
+        assert!(value.deep_eq(&RbAny::from(vec![RbAny::Int(5), RbAny::Int(300), RbAny::Int(0)])));
+        // The array length is integer 0
+        let mut keys: Vec<_> = rd.raw_ints().keys().copied().collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2, 3]);
+
+        let mut wr = RbWriter::new(Vec::new()).with_raw_ints(rd.raw_ints().clone());
+        wr.write(&value).unwrap();
+        assert_eq!(escape_bytes(&wr.reset(Vec::new())), escape_bytes(inp));
+        // By default integers are written the way Ruby does
+        assert_write(&value, b"\x04\x08[\x08i\x0ai\x02\x2c\x01i\x00");
+
+        // An integer that changed is written the usual way
+        let changed = RbAny::from(vec![RbAny::Int(6), RbAny::Int(300), RbAny::Int(0)]);
+        let mut wr = RbWriter::new(Vec::new()).with_raw_ints(rd.raw_ints().clone());
+        wr.write(&changed).unwrap();
+        assert_eq!(escape_bytes(&wr.reset(Vec::new())), escape_bytes(b"\x04\x08[\x08i\x0bi\x03\x2c\x01\x00i\x01\x00"));
+        // After a reset the recorded integers no longer apply
+        wr.write(&value).unwrap();
+        assert_eq!(escape_bytes(&wr.reset(Vec::new())), escape_bytes(b"\x04\x08[\x08i\x0ai\x02\x2c\x01i\x00"));
+    }
+
+    #[test]
+    fn as_f64() {
+        let value = reader_parse("\x04\x08[\x0bi\x07f\x081.5l+\n\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00l-\x07\x00\x00\x00@0:\x06a");
+        let nums: Vec<_> = value.as_rbref().unwrap().as_array().unwrap().iter().map(|v| v.as_f64()).collect();
+        assert_eq!(nums, vec![Some(2.0), Some(1.5), Some(2f64.powi(64)), Some(-1073741824.0), None, None]);
+        let huge = RbAny::from(RbRef::BigInt(num_bigint::BigInt::from(1) << 2000));
+        assert_eq!(huge.as_f64(), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn to_slice() {
+        let value = reader_parse("\x04\x08[\x07I\"\x08abc\x06:\x06ETi\x02\x2c\x01");
+        let len = serialized_len(&value).unwrap();
+        assert_eq!(len, writer_write(&value).len());
+        let mut buf = [0u8; 32];
+        assert_eq!(crate::rc::to_slice(&mut buf, &value).unwrap(), len);
+        assert_eq!(&buf[..len], &writer_write(&value)[..]);
+        let mut small = [0u8; 8];
+        assert!(matches!(crate::rc::to_slice(&mut small, &value),
+            Err(crate::ThurgoodError::BufferTooSmall { needed, available: 8 }) if needed == len));
+    }
+
+    #[test]
+    fn replace_node() {
+        let shared = RbAny::from(vec![RbAny::Int(1)]);
+        let equal = RbAny::from(vec![RbAny::Int(1)]);
+        let inner = RbAny::from(vec![shared.clone(), equal.clone()]);
+        let untouched = RbAny::from(vec![RbAny::Int(2)]);
+        let mut value = RbAny::from(vec![shared.clone(), inner.clone(), shared.clone(), untouched.clone()]);
+        let alias = value.clone();
+        assert_eq!(value.replace_node(&shared, RbAny::sym("new")), 3);
+        assert_eq!(value.get(0), Some(&RbAny::sym("new")));
+        assert_eq!(value.get(1).unwrap().get(0), Some(&RbAny::sym("new")));
+        assert_eq!(value.get(1).unwrap().get(1).unwrap().pointer_id(), equal.pointer_id());
+        assert_eq!(value.get(3).unwrap().pointer_id(), untouched.pointer_id());
+        // Aliases of the copied containers are unaffected
+        assert_eq!(alias.get(0).unwrap().pointer_id(), shared.pointer_id());
+        assert_eq!(inner.get(0).unwrap().pointer_id(), shared.pointer_id());
+        assert_eq!(value.replace_node(&RbAny::Int(1), RbAny::Nil), 0);
+
+        // a = [[1]]; a << a
+        let mut value = reader_parse("\x04\x08[\x07[\x06i\x06@\x00");
+        let original = value.clone();
+        let target = value.get(0).unwrap().clone();
+        assert_eq!(value.replace_node(&target, RbAny::Int(5)), 1);
+        assert_eq!(value.get(0), Some(&RbAny::Int(5)));
+        assert_eq!(value.get(1).unwrap().pointer_id(), value.pointer_id());
+        assert_eq!(original.get(0).unwrap().pointer_id(), target.pointer_id());
+        assert_write(&value, b"\x04\x08[\x07i\x0a@\x00");
+        // o = Foo.new; o.instance_variable_set(:@self, o); [o, o]
+        let mut value = reader_parse("\x04\x08[\x07o:\x08Foo\x06:\x0a@self@\x06@\x06");
+        let target = value.get(0).unwrap().clone();
+        assert_eq!(value.replace_node(&target, RbAny::Nil), 2);
+        assert_eq!(value.len(), Some(2));
+        assert!(value.iter_array().all(|v| v.is_nil()));
+        let mut root = value.clone();
+        assert_eq!(root.replace_node(&value, RbAny::True), 1);
+        assert_eq!(root, RbAny::True);
+    }
+
+    #[test]
+    fn identity_hash_keys() {
+        // `h = {}.compare_by_identity; h["a"] = 1; h["a".dup] = 2`, strings are separate keys
+        let inp = "\x04\x08C:\x09Hash{\x07I\"\x06a\x06:\x06ETi\x06I\"\x06a\x06;\x06Ti\x07";
+        let mut value = RbReader::from_slice(inp.as_bytes()).read().unwrap();
+        assert_eq!(value.as_rbref().and_then(|v| v.as_user_class()).and_then(|v| v.data.len()), Some(2));
+        assert_write(&value, inp.as_bytes());
+        // The hash remembers it compares by identity, so adding another equal key adds an entry
+        let mut hash = value.as_rbref_mut().and_then(|v| v.as_user_class_mut()).unwrap().data.take();
+        let table = hash.as_hash_mut().unwrap();
+        assert!(table.compare_by_identity);
+        let key = RbAny::from("a");
+        for _ in 0..100 {
+            table.insert(RbAny::from("a"), RbAny::Int(3));
+        }
+        assert_eq!(table.insert(key.clone(), RbAny::Int(4)), None);
+        assert_eq!(table.insert(key.clone(), RbAny::Int(5)), Some(RbAny::Int(4)));
+        assert_eq!(table.len(), 103);
+        let class = RbRef::ClassRef("String".to_owned()).into_any();
+        table.insert(class.clone(), RbAny::Nil);
+        table.insert(RbRef::ClassRef("String".to_owned()).into_any(), RbAny::True);
+        assert_eq!(table.len(), 104);
+        assert_eq!(table.get(&class), Some(&RbAny::True));
+        // A hash which compares by identity is written as a `Hash` user class if it isn't one
+        let mut bare = RbHash::new();
+        bare.compare_by_identity = true;
+        bare.insert(RbAny::from("a"), RbAny::Int(1));
+        bare.insert(RbAny::from("a"), RbAny::Int(2));
+        assert_write(&RbAny::from(bare), inp.as_bytes());
+        // The same keys in a normal hash are duplicates, which `strict_hash_keys` rejects
+        let plain = "\x04\x08{\x07I\"\x06a\x06:\x06ETi\x06I\"\x06a\x06;\x00Ti\x07";
+        let mut rd = RbReader::from_slice(plain.as_bytes());
+        rd.strict_hash_keys = true;
+        assert!(matches!(rd.read(), Err(crate::ThurgoodError::DuplicateHashKey(_))));
+        let mut rd = RbReader::from_slice(inp.as_bytes());
+        rd.strict_hash_keys = true;
+        assert!(rd.read().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "arena")]
+    fn arena() {
+        use crate::arena::{ArenaKind, ArenaReader, ArenaValue, ObjectId};
+        let bump = bumpalo::Bump::new();
+        let mut reader = ArenaReader::new(&bump);
+        // An object referring to itself, a shared string, and a symbol link
+        let inp = b"\x04\x08[\x08o:\x08Foo\x07:\n@self@\x06:\x08@idI\"\x06x\x06:\x06ET@\x07l+\x07\x00\x00\x00@";
+        let doc = reader.read(inp).unwrap();
+        assert_eq!(doc.objects().len(), 4);
+        let items = match doc.node(doc.root()).unwrap().kind {
+            ArenaKind::Array(items) => items,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(items[0], ArenaValue::Object(ObjectId(1)));
+        let (class, fields) = match doc.node(items[0]).unwrap().kind {
+            ArenaKind::Object { class, fields } => (class, fields),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(class, b"Foo");
+        assert_eq!(fields[0], (&b"@self"[..], items[0]));
+        assert_eq!(items[1], fields[1].1);
+        let string = doc.node(items[1]).unwrap();
+        assert_eq!(string.kind, ArenaKind::Str(b"x"));
+        assert_eq!(string.ivars, &[(&b"E"[..], ArenaValue::True)]);
+        assert_eq!(doc.node(items[2]).unwrap().kind,
+            ArenaKind::BigInt { negative: false, magnitude: &[0, 0, 0, 0x40] });
+        // User classes, extended objects, and hashes with a default
+        let inp = b"\x04\x08[\x08IC:\x0aMyStr\"\x06a\x06:\x06ETe:\x06Mo:\x08Foo\x00}\x06i\x06f\x081.5i\x00";
+        let doc = reader.read(inp).unwrap();
+        let items = match doc.node(doc.root()).unwrap().kind {
+            ArenaKind::Array(items) => items,
+            other => panic!("{:?}", other),
+        };
+        let my_str = doc.node(items[0]).unwrap();
+        assert_eq!((my_str.kind, my_str.user_class), (ArenaKind::Str(b"a"), Some(&b"MyStr"[..])));
+        assert_eq!(my_str.ivars.len(), 1);
+        assert_eq!(doc.node(items[1]).unwrap().extended, &[&b"M"[..]]);
+        match doc.node(items[2]).unwrap().kind {
+            ArenaKind::Hash { entries, default } => {
+                assert_eq!(default, Some(ArenaValue::Int(0)));
+                assert_eq!(entries[0].0, ArenaValue::Int(1));
+                assert_eq!(doc.node(entries[0].1).unwrap().kind, ArenaKind::Float(1.5));
+            },
+            other => panic!("{:?}", other),
         }
-        out
+        // Bad input is reported like `RbReader` reports it
+        assert!(matches!(reader.read(b"\x04\x08[\x06@\x07"), Err(crate::ThurgoodError::ForwardObjectRef(2))));
+        assert!(matches!(reader.read(b"\x04\x08;\x00"), Err(crate::ThurgoodError::BadSymbolRef(0))));
+        reader.max_depth = Some(8);
+        let deep = [&b"\x04\x08"[..], &b"[\x06".repeat(8), b"0"].concat();
+        assert!(matches!(reader.read(&deep), Err(crate::ThurgoodError::TooDeep(8))));
+        let deep = [&b"\x04\x08"[..], &b"C:\x06C".repeat(8), b"[\x00"].concat();
+        assert!(matches!(reader.read(&deep), Err(crate::ThurgoodError::TooDeep(8))));
     }
 }
@@ -24,3 +24,33 @@ pub enum RbType {
     ObjectRef,
     Extended,
 }
+
+impl RbType {
+    /// Returns the name of the Ruby class (or kind of value) this type stands for, for
+    /// error messages.
+    pub fn ruby_name(&self) -> &'static str {
+        match self {
+            RbType::Int => "Integer",
+            RbType::Bool => "true or false",
+            RbType::Float => "Float",
+            RbType::Nil => "nil",
+            RbType::BigInt => "Integer (bignum)",
+            RbType::Symbol => "Symbol",
+            RbType::Array => "Array",
+            RbType::Str => "String",
+            RbType::Regex => "Regexp",
+            RbType::Hash => "Hash",
+            RbType::Struct => "Struct",
+            RbType::Object => "Object",
+            RbType::ClassRef => "Class",
+            RbType::ModuleRef => "Module",
+            RbType::ClassModuleRef => "Class or Module",
+            RbType::Data => "Data",
+            RbType::UserClass => "subclass of a core type",
+            RbType::UserData => "object with _dump",
+            RbType::UserMarshal => "object with marshal_dump",
+            RbType::ObjectRef => "object reference",
+            RbType::Extended => "extended object",
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+use crate::RbType;
+
+/// The reference-counted pointer operations `rc` and `arc` rely on, implemented by both `Rc`
+/// and `Arc`, so code can be written once and used with either.
+///
+/// The two modules are still compiled from the same source rather than being generic over
+/// this, which keeps their types concrete and their APIs unchanged.
+pub trait RefCounted<T>: Clone + Deref<Target = T> {
+    fn new(value: T) -> Self;
+
+    /// Same as `Rc::get_mut`.
+    fn get_mut(this: &mut Self) -> Option<&mut T>;
+
+    /// Same as `Rc::make_mut`.
+    fn make_mut(this: &mut Self) -> &mut T where T: Clone;
+
+    /// Same as `Rc::as_ptr`, used to tell whether two references share a value.
+    fn as_ptr(this: &Self) -> *const T;
+}
+
+impl<T> RefCounted<T> for Rc<T> {
+    fn new(value: T) -> Self {
+        Rc::new(value)
+    }
+
+    fn get_mut(this: &mut Self) -> Option<&mut T> {
+        Rc::get_mut(this)
+    }
+
+    fn make_mut(this: &mut Self) -> &mut T where T: Clone {
+        Rc::make_mut(this)
+    }
+
+    fn as_ptr(this: &Self) -> *const T {
+        Rc::as_ptr(this)
+    }
+}
+
+impl<T> RefCounted<T> for Arc<T> {
+    fn new(value: T) -> Self {
+        Arc::new(value)
+    }
+
+    fn get_mut(this: &mut Self) -> Option<&mut T> {
+        Arc::get_mut(this)
+    }
+
+    fn make_mut(this: &mut Self) -> &mut T where T: Clone {
+        Arc::make_mut(this)
+    }
+
+    fn as_ptr(this: &Self) -> *const T {
+        Arc::as_ptr(this)
+    }
+}
+
+/// Implemented by both `rc::RbAny` and `arc::RbAny`, for code which should work with either.
+///
+/// This covers reading values: their type and contents, and their references. Each method
+/// is the same as the `RbAny` method of the same name. Building or changing values still
+/// needs the types of one module (e.g. `RbRef`, `RbHash`), which aren't part of this trait.
+pub trait RbAnyLike: Clone {
+    /// `RbRef` of the same module.
+    type Ref: Clone;
+    /// `Rc<RbRef>` or `Arc<RbRef>`.
+    type Ptr: RefCounted<Self::Ref>;
+
+    fn get_type(&self) -> RbType;
+
+    /// Returns the reference held by this value, if it holds one.
+    fn as_ptr(&self) -> Option<&Self::Ptr>;
+
+    fn as_ptr_mut(&mut self) -> Option<&mut Self::Ptr>;
+
+    /// Wrap a reference in a value.
+    fn from_ptr(ptr: Self::Ptr) -> Self;
+
+    /// Same as `RbAny::deep_eq`.
+    fn deep_eq(&self, other: &Self) -> bool;
+
+    fn is_nil(&self) -> bool;
+
+    fn as_int(&self) -> Option<i32>;
+
+    fn as_bool(&self) -> Option<bool>;
+
+    /// Returns the raw bytes of a string or symbol.
+    fn as_bytes(&self) -> Option<&[u8]>;
+
+    fn symbol_str(&self) -> Option<&str>;
+
+    fn class_name(&self) -> Option<&str>;
+
+    fn as_array(&self) -> Option<&[Self]>;
+
+    /// Returns an array element, hash entry, or object field, like `RbAny::get`.
+    fn get_child(&self, key: &Self) -> Option<&Self>;
+
+    /// Iterate over the entries of a hash or the fields of an object or struct, see
+    /// `RbAny::entries`.
+    fn entries<'a>(&'a self) -> Option<Box<dyn Iterator<Item = (Self, &'a Self)> + 'a>>;
+}
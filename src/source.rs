@@ -0,0 +1,201 @@
+use std::io::{self, Read};
+use crate::error::TResult;
+
+/// The most bytes `IoSource` allocates before any of them have been read.
+const READ_CHUNK: usize = 1 << 16;
+
+/// A source of bytes for the reader.
+///
+/// This is implemented by `IoSource` for any `io::Read` and by `SliceSource` for data
+/// which is already in memory. The slice version reads directly from the slice without
+/// any intermediate copies.
+pub trait ByteSource {
+    /// Fill `buf` completely, or return an error if there aren't enough bytes.
+    fn read_exact(&mut self, buf: &mut [u8]) -> TResult<()>;
+
+    /// Read exactly `len` bytes and return them.
+    fn read_vec(&mut self, len: usize) -> TResult<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Skip over the next `len` bytes.
+    fn skip(&mut self, len: usize) -> TResult<()> {
+        self.read_vec(len).map(|_| ())
+    }
+
+    /// Returns the number of bytes read so far, if the source keeps track of it.
+    fn position(&self) -> Option<usize> {
+        None
+    }
+
+    /// Read a single byte.
+    fn read_byte(&mut self) -> TResult<u8> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Start keeping the bytes read from here on, so they can be returned by `take_rest_since`,
+    /// and return a mark for the current position. Calls may be nested, each one followed by
+    /// `end_record`. Returns None if the source can't do this, which is the default.
+    fn begin_record(&mut self) -> Option<usize> {
+        None
+    }
+
+    /// Stop keeping the bytes kept for the matching `begin_record`.
+    fn end_record(&mut self) {}
+
+    /// Return the bytes read since `mark` (from `begin_record`) followed by the rest of the
+    /// input, consuming it.
+    fn take_rest_since(&mut self, _mark: usize) -> TResult<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "byte source doesn't keep what it read").into())
+    }
+}
+
+/// Reads from any `io::Read`, keeping track of the number of bytes read.
+#[derive(Clone, Debug)]
+pub struct IoSource<R> {
+    src: R,
+    pos: usize,
+    /// Bytes read since the outermost `begin_record`, and the position they start at.
+    record: Option<(usize, Vec<u8>)>,
+    /// Number of `begin_record` calls without a matching `end_record`.
+    record_depth: usize,
+}
+
+impl<R> IoSource<R> {
+    pub fn new(src: R) -> Self {
+        Self { src, pos: 0, record: None, record_depth: 0 }
+    }
+
+    /// Returns a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.src
+    }
+
+    /// Returns the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.src
+    }
+}
+
+impl<R: io::Read> ByteSource for IoSource<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> TResult<()> {
+        self.src.read_exact(buf)?;
+        self.pos += buf.len();
+        if let Some((_, record)) = &mut self.record {
+            record.extend_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    fn read_vec(&mut self, len: usize) -> TResult<Vec<u8>> {
+        // The length comes from the input, so the buffer grows as data actually arrives
+        // instead of being allocated up front.
+        let mut buf = Vec::with_capacity(len.min(READ_CHUNK));
+        (&mut self.src).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.pos += len;
+        if let Some((_, record)) = &mut self.record {
+            record.extend_from_slice(&buf);
+        }
+        Ok(buf)
+    }
+
+    fn position(&self) -> Option<usize> {
+        Some(self.pos)
+    }
+
+    fn begin_record(&mut self) -> Option<usize> {
+        if self.record.is_none() {
+            self.record = Some((self.pos, Vec::new()));
+        }
+        self.record_depth += 1;
+        Some(self.pos)
+    }
+
+    fn end_record(&mut self) {
+        self.record_depth -= 1;
+        if self.record_depth == 0 {
+            self.record = None;
+        }
+    }
+
+    fn take_rest_since(&mut self, mark: usize) -> TResult<Vec<u8>> {
+        let mut rest = match &self.record {
+            Some((start, record)) if *start <= mark => record[mark - start..].to_vec(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "position wasn't recorded").into()),
+        };
+        self.pos += self.src.read_to_end(&mut rest)?;
+        Ok(rest)
+    }
+}
+
+/// Reads from an in-memory slice, keeping track of the current position.
+#[derive(Clone, Debug)]
+pub struct SliceSource<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceSource<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Returns the number of bytes read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the bytes that haven't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    /// Consume and return the next `len` bytes of the slice.
+    pub fn take(&mut self, len: usize) -> TResult<&'a [u8]> {
+        let rest = self.remaining();
+        if len > rest.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+}
+
+impl<'a> ByteSource for SliceSource<'a> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> TResult<()> {
+        buf.copy_from_slice(self.take(buf.len())?);
+        Ok(())
+    }
+
+    fn read_vec(&mut self, len: usize) -> TResult<Vec<u8>> {
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn skip(&mut self, len: usize) -> TResult<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn position(&self) -> Option<usize> {
+        Some(self.pos)
+    }
+
+    fn read_byte(&mut self) -> TResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn begin_record(&mut self) -> Option<usize> {
+        Some(self.pos)
+    }
+
+    fn take_rest_since(&mut self, mark: usize) -> TResult<Vec<u8>> {
+        self.pos = self.data.len();
+        Ok(self.data[mark..].to_vec())
+    }
+}
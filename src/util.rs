@@ -0,0 +1,67 @@
+//! Small utilities for working with raw Marshal data.
+
+/// Render `src` as a readable string, useful for comparing or debugging Marshal output.
+///
+/// ASCII letters, digits, and punctuation are written as-is, a backslash is written as `\\`,
+/// and every other byte is written as `\xNN`. The result can be turned back into bytes with `unescape`.
+pub fn escape_bytes(src: &[u8]) -> String {
+    let mut out = String::new();
+    for b in src {
+        let c = char::from(*b);
+        if c == '\\' {
+            out.push_str("\\\\");
+        } else if c.is_ascii_alphanumeric() || c.is_ascii_punctuation() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("\\x{:02X}", *b));
+        }
+    }
+    out
+}
+
+/// Convert an escaped string back into bytes, this is the inverse of `escape_bytes`.
+///
+/// In addition to `\xNN` and `\\` this understands `\n`, `\r`, `\t`, `\0`, and `\"`
+/// so that strings can be copied from Rust source. Any other escape sequence, including
+/// a malformed `\x`, is kept as-is.
+pub fn unescape(src: &str) -> Vec<u8> {
+    let bytes = src.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 >= bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let escaped = match bytes[i + 1] {
+            b'\\' => Some(b'\\'),
+            b'"' => Some(b'"'),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            b'0' => Some(0),
+            _ => None,
+        };
+        if let Some(b) = escaped {
+            out.push(b);
+            i += 2;
+        } else if bytes[i + 1] == b'x' {
+            let hex = bytes.get(i + 2..i + 4)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(b) = hex {
+                out.push(b);
+                i += 4;
+            } else {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    out
+}